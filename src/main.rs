@@ -1,5 +1,6 @@
-use chrono::{DateTime, NaiveDate, Utc, TimeZone, NaiveDateTime, FixedOffset};
-use chrono::{Datelike, Timelike};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc, TimeZone};
+use chrono_tz::Tz;
+use chrono::{Datelike, Timelike, Weekday};
 use dotenv::dotenv;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -7,9 +8,23 @@ use std::env;
 use std::error::Error;
 use std::time::Duration;
 use tokio::time;
-use std::collections::HashMap;
+use tracing::{debug, error, info, trace, warn, Level};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{self, JoinError, JoinSet};
+use futures_util::stream::{self, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::fs::OpenOptions;
+use std::io::Write;
+use axum::{Router, routing::post, extract::State, http::{HeaderMap, StatusCode}, body::Bytes};
 use url::Url;
 use serde_json::json;
+use rusqlite::{params, Connection};
+use plotters::prelude::*;
+use image::ImageEncoder;
 
 // Updated structures for Travelpayouts API responses based on the actual JSON
 #[derive(Deserialize, Debug)]
@@ -18,9 +33,12 @@ struct FlightData {
     data: Option<Vec<FlightResult>>,
     currency: Option<String>,
     error: Option<String>,
+    // Set when the response didn't match the expected schema and was parsed manually
+    #[serde(skip)]
+    parse_warning: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct FlightResult {
     origin: String,
     destination: String,
@@ -38,63 +56,70 @@ struct FlightResult {
     return_transfers: Option<i64>,
     link: String,
     seats: Option<i64>,
+    // Currency of `price`; filled from the response's currency when the fare doesn't carry one
+    #[serde(default)]
+    currency: Option<String>,
+    // Layover airports of the outbound and return legs, when the response includes them
+    #[serde(default)]
+    connections: Vec<String>,
+    #[serde(default)]
+    return_connections: Vec<String>,
 }
 
-#[derive(Serialize)]
-struct SearchParams {
-    origin: String,
-    destination: String,
-    departure_at: String,
-    return_at: Option<String>,
-    currency: String,
-    token: String,
-}
+impl FlightResult {
+    // Price in the fare's own currency
+    fn display_price(&self, display: &DisplaySettings) -> String {
+        format_price_in(display, self.price, self.currency.as_deref().unwrap_or(BASE_CURRENCY))
+    }
 
-// Add these new structures for AirLabs API
-#[derive(Deserialize, Debug)]
-struct AirLabsResponse {
-    request: AirLabsRequest,
-    response: Vec<AirLabsFlight>,
-    error: Option<AirLabsError>,
-}
+    // Price with its secondary currency value; the conversion only applies to ruble fares
+    fn display_price_converted(&self, display: &DisplaySettings, conversion: Option<&CurrencyConversion>) -> String {
+        let currency = self.currency.as_deref().map_or(BASE_CURRENCY.to_string(), normalize_code);
+        if currency == BASE_CURRENCY {
+            format_price_converted(display, self.price, conversion)
+        } else {
+            self.display_price(display)
+        }
+    }
 
-#[derive(Deserialize, Debug)]
-struct AirLabsRequest {
-    lang: String,
-    currency: String,
-    time: i64,
-    id: String,
-    server: String,
-    host: String,
-    pid: i64,
-    key: Option<String>,
-    params: serde_json::Value,
-    version: i64,
-    method: String,
-    client: Option<serde_json::Value>,
+    // Stable key for a fare: the same flight at the same price is the same notification,
+    // however its message happens to be formatted
+    fn identity(&self) -> String {
+        format!("{}|{}", self.flight_key(), self.price)
+    }
+
+    // The physical flight regardless of price; the departure time keeps the same flight
+    // number on different dates apart
+    fn flight_key(&self) -> String {
+        format!("{}|{}", format_flight_designator(&self.airline, &self.flight_number), self.departure_at)
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct AirLabsError {
-    message: String,
-    code: i64,
+// Function to combine the airline code and flight number into the IATA designator, e.g. "DP 123".
+// Numbers that already carry the airline prefix ("DP123", "DP-123") are not prefixed again
+fn format_flight_designator(airline: &str, flight_number: &str) -> String {
+    let airline = airline.trim();
+    let flight_number = flight_number.trim();
+    let number = flight_number
+        .get(..airline.len())
+        .filter(|prefix| !airline.is_empty() && prefix.eq_ignore_ascii_case(airline))
+        .map(|_| flight_number[airline.len()..].trim_start_matches([' ', '-']))
+        .filter(|rest| !rest.is_empty())
+        .unwrap_or(flight_number);
+    
+    match (airline.is_empty(), number.is_empty()) {
+        (true, _) => number.to_string(),
+        (false, true) => airline.to_string(),
+        (false, false) => format!("{} {}", airline, number),
+    }
 }
 
+// AirLabs flight fields the bot reads
 #[derive(Deserialize, Debug)]
 struct AirLabsFlight {
-    flight_number: String,
-    airline_iata: Option<String>,
-    airline_icao: Option<String>,
-    dep_iata: Option<String>,
-    dep_icao: Option<String>,
-    arr_iata: Option<String>,
-    arr_icao: Option<String>,
     dep_time: Option<String>,
-    arr_time: Option<String>,
-    duration: Option<i64>,
     status: Option<String>,
     aircraft_icao: Option<String>,
-    reg_number: Option<String>,
     // Seat information fields - note that AirLabs may not provide exact seat availability
     seats_economy: Option<i64>,
     seats_business: Option<i64>,
@@ -113,13 +138,569 @@ fn format_duration(minutes: i64) -> String {
     }
 }
 
-// Function to convert ISO datetime to human readable Russian format
-fn format_datetime_ru(datetime_str: &str) -> String {
-    // Parse the ISO 8601 datetime string
+// How flight durations are displayed (DURATION_PRECISION)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DurationPrecision {
+    // Show exact minutes
+    Exact,
+    // Round longer durations to 15 minutes, and very long ones to whole hours
+    Rounded,
+}
+
+impl DurationPrecision {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "rounded" => DurationPrecision::Rounded,
+            _ => DurationPrecision::Exact,
+        }
+    }
+
+    // Function to round a duration for display only; filtering and sorting use the exact value
+    fn apply(self, minutes: i64) -> i64 {
+        let step = match self {
+            DurationPrecision::Exact => return minutes,
+            DurationPrecision::Rounded if minutes < 60 => return minutes,
+            DurationPrecision::Rounded if minutes < 10 * 60 => 15,
+            DurationPrecision::Rounded => 60,
+        };
+        
+        (minutes + step / 2) / step * step
+    }
+}
+
+// How places, times and prices are shown in messages
+#[derive(Debug, Clone)]
+struct DisplaySettings {
+    // User-defined labels for IATA codes, consulted before the built-in city map (PLACE_LABELS)
+    place_labels: HashMap<String, String>,
+    // Timezone times are shown in (DISPLAY_TIMEZONE or DISPLAY_UTC_OFFSET_HOURS)
+    timezone: Tz,
+    // Timezone assumed for departure times that come without an offset (SOURCE_TIMEZONE)
+    source_timezone: Tz,
+    // Digit grouping and decimal separator for prices (LOCALE)
+    locale: Locale,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            place_labels: HashMap::new(),
+            timezone: Tz::Etc__GMTMinus5,
+            source_timezone: Tz::Europe__Moscow,
+            locale: Locale::Ru,
+        }
+    }
+}
+
+// Function to resolve SOURCE_TIMEZONE, the origin's local time (an IANA name, default Europe/Moscow)
+fn parse_source_timezone(name: &str) -> Result<Tz, Box<dyn Error + Send + Sync>> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(Tz::Europe__Moscow);
+    }
+    name.parse::<Tz>()
+        .map_err(|_| BotError::Config(format!("SOURCE_TIMEZONE must be an IANA zone like Europe/Moscow, got {:?}", name)).into())
+}
+
+// Function to parse a Travelpayouts datetime given without an offset
+fn parse_naive_departure(datetime_str: &str) -> Option<NaiveDateTime> {
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(datetime_str, format).ok())
+}
+
+// Function to get a Travelpayouts departure as the local time at the departure airport, the
+// way AirLabs reports it: the time as written, with or without an offset
+fn parse_departure_local(datetime_str: &str) -> Option<NaiveDateTime> {
+    match DateTime::parse_from_rfc3339(datetime_str) {
+        Ok(dt) => Some(dt.naive_local()),
+        Err(_) => parse_naive_departure(datetime_str),
+    }
+}
+
+// Function to parse a Travelpayouts datetime into the display timezone. RFC 3339 is tried
+// first; a naive datetime without an offset is taken as SOURCE_TIMEZONE local time
+fn parse_departure_at(display: &DisplaySettings, datetime_str: &str) -> Option<DateTime<Tz>> {
     if let Ok(dt) = DateTime::parse_from_rfc3339(datetime_str) {
-        // Convert to local time (UTC+5)
-        let local_time = dt.with_timezone(&FixedOffset::east_opt(5 * 3600).unwrap());
+        return Some(dt.with_timezone(&display.timezone));
+    }
+    
+    let naive = parse_naive_departure(datetime_str)?;
+    display.source_timezone
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|dt| dt.with_timezone(&display.timezone))
+}
+
+// Function to resolve DISPLAY_TIMEZONE (an IANA name like `Europe/Moscow`), falling back to
+// a whole-hour DISPLAY_UTC_OFFSET_HOURS expressed as the matching `Etc/GMT` zone
+fn parse_display_timezone(name: &str, offset_hours: i32) -> Result<Tz, Box<dyn Error + Send + Sync>> {
+    let name = name.trim();
+    if !name.is_empty() {
+        return name
+            .parse::<Tz>()
+            .map_err(|_| BotError::Config(format!("DISPLAY_TIMEZONE must be an IANA zone like Europe/Moscow, got {:?}", name)).into());
+    }
+    
+    if !(-12..=14).contains(&offset_hours) {
+        return Err(BotError::Config(format!("DISPLAY_UTC_OFFSET_HOURS must be between -12 and 14, got {}", offset_hours)).into());
+    }
+    
+    // Etc/GMT zones use POSIX signs: Etc/GMT-5 is UTC+5
+    let zone = match offset_hours {
+        0 => "Etc/GMT".to_string(),
+        hours if hours > 0 => format!("Etc/GMT-{}", hours),
+        hours => format!("Etc/GMT+{}", -hours),
+    };
+    Ok(zone.parse::<Tz>()?)
+}
+
+// How much the bot prints to the console (VERBOSITY)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    // Warnings and errors only
+    Quiet,
+    // Progress of each cycle and date
+    Normal,
+    // Also every request made
+    Debug,
+    // Also raw API response bodies
+    Trace,
+}
+
+impl Verbosity {
+    // Accepts the presets and the matching log levels, e.g. `debug` or `warn`
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "quiet" | "warn" | "error" => Verbosity::Quiet,
+            "debug" => Verbosity::Debug,
+            "trace" => Verbosity::Trace,
+            _ => Verbosity::Normal,
+        }
+    }
+
+    // EnvFilter directive the preset corresponds to: this crate at the preset's level,
+    // dependencies at warn
+    fn filter_directive(self) -> String {
+        let level = match self {
+            Verbosity::Quiet => "warn",
+            Verbosity::Normal => "info",
+            Verbosity::Debug => "debug",
+            Verbosity::Trace => "trace",
+        };
+        format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level)
+    }
+}
+
+// Function to build the console log filter: RUST_LOG when set, e.g. `info` or
+// `warn,flights_schedule=debug`, otherwise the VERBOSITY preset
+fn log_filter(source: &ConfigSource, verbosity: Verbosity) -> Result<EnvFilter, BotError> {
+    let spec = source.get("RUST_LOG")
+        .filter(|spec| !spec.trim().is_empty())
+        .unwrap_or_else(|| verbosity.filter_directive());
+    EnvFilter::builder()
+        .parse(&spec)
+        .map_err(|e| BotError::Config(format!("Invalid RUST_LOG {:?}: {}", spec, e)))
+}
+
+// Function to install the console logger: info and below to stdout, warnings and errors to
+// stderr, as plain text lines or one JSON object per line (LOG_FORMAT=json)
+fn init_logging(source: &ConfigSource) -> Result<Verbosity, Box<dyn Error + Send + Sync>> {
+    let verbosity = Verbosity::from_env_value(&source.get("VERBOSITY").or_else(|| source.get("LOG_LEVEL")).unwrap_or_default());
+    let filter = log_filter(source, verbosity)?;
+    let json = match source.string("LOG_FORMAT").trim().to_lowercase().as_str() {
+        "" | "text" => false,
+        "json" => true,
+        other => return Err(BotError::Config(format!("LOG_FORMAT must be text or json, got {:?}", other)).into()),
+    };
+    
+    let writer = std::io::stderr.with_max_level(Level::WARN).or_else(std::io::stdout);
+    let logger = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+    let installed = if json { logger.json().try_init() } else { logger.try_init() };
+    installed.map_err(|e| e.to_string())?;
+    Ok(verbosity)
+}
+
+// Number formatting convention for prices (LOCALE)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Locale {
+    // "15 230 ₽", decimal comma
+    Ru,
+    // "15,230 ₽", decimal point
+    En,
+}
+
+impl Locale {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "en" => Locale::En,
+            _ => Locale::Ru,
+        }
+    }
+
+    fn thousands_separator(self) -> char {
+        match self {
+            Locale::Ru => ' ',
+            Locale::En => ',',
+        }
+    }
+
+    fn decimal_separator(self) -> char {
+        match self {
+            Locale::Ru => ',',
+            Locale::En => '.',
+        }
+    }
+}
+
+// Function to format an amount with the locale's digit grouping and the given number of decimals
+fn format_amount(amount: f64, decimals: usize, locale: Locale) -> String {
+    let rounded = format!("{:.*}", decimals, amount.abs());
+    let (whole, fraction) = rounded.split_once('.').unwrap_or((&rounded, ""));
+    let mut grouped = String::new();
+    
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i).is_multiple_of(3) {
+            grouped.push(locale.thousands_separator());
+        }
+        grouped.push(digit);
+    }
+    
+    if !fraction.is_empty() {
+        grouped.push(locale.decimal_separator());
+        grouped.push_str(fraction);
+    }
+    
+    if amount < 0.0 && rounded.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        format!("-{}", grouped)
+    } else {
+        grouped
+    }
+}
+
+// Function to format a ruble price in the configured locale
+fn format_price(display: &DisplaySettings, price: i64) -> String {
+    format!("{} ₽", format_amount(price as f64, 0, display.locale))
+}
+
+// Function to format a price in the given currency (a code like `usd`), with its symbol when known
+fn format_price_in(display: &DisplaySettings, price: i64, currency: &str) -> String {
+    let currency = normalize_code(currency);
+    let amount = format_amount(price as f64, 0, display.locale);
+    match currency_symbol(&currency) {
+        Some("₽") => format_price(display, price),
+        Some(symbol) => format!("{}{}", symbol, amount),
+        None => format!("{} {}", amount, currency),
+    }
+}
+
+// Conversion of ruble prices into the secondary display currency, valid for one cycle
+#[derive(Debug, Clone)]
+struct CurrencyConversion {
+    currency: String,
+    rate: f64,
+}
+
+// Function to pick the SECONDARY_CURRENCY conversion out of a cycle's exchange rates
+fn secondary_conversion(config: &Config, rates: &HashMap<String, f64>) -> Option<CurrencyConversion> {
+    rates.get(&config.secondary_currency).map(|rate| CurrencyConversion {
+        currency: config.secondary_currency.clone(),
+        rate: *rate,
+    })
+}
+
+// Function to get the display symbol for a currency code
+fn currency_symbol(currency: &str) -> Option<&'static str> {
+    match currency {
+        "RUB" => Some("₽"),
+        "USD" => Some("$"),
+        "EUR" => Some("€"),
+        "GBP" => Some("£"),
+        "CNY" => Some("¥"),
+        "KZT" => Some("₸"),
+        "TRY" => Some("₺"),
+        _ => None,
+    }
+}
+
+// Function to format a price with its approximate value in the secondary currency
+fn format_price_converted(display: &DisplaySettings, price: i64, conversion: Option<&CurrencyConversion>) -> String {
+    let Some(conversion) = conversion else {
+        return format_price(display, price);
+    };
+    
+    // Whole units for regular amounts, cents only for small ones
+    let amount = price as f64 * conversion.rate;
+    let decimals = if amount >= 10.0 { 0 } else { 2 };
+    let amount = format_amount(amount, decimals, display.locale);
+    
+    let converted = match currency_symbol(&conversion.currency) {
+        Some(symbol) => format!("{}{}", symbol, amount),
+        None => format!("{} {}", amount, conversion.currency),
+    };
+    
+    format!("{} (~{})", format_price(display, price), converted)
+}
+
+// Function to fetch exchange rates from RUB into other currencies
+async fn fetch_exchange_rates(
+    client: &Client,
+    rates_url: &str,
+) -> Result<HashMap<String, f64>, Box<dyn Error + Send + Sync>> {
+    let response = client.get(rates_url).send().await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Rates request failed with status {}: {}", status, text).into());
+    }
+    
+    let response_json: serde_json::Value = response.json().await?;
+    let rates = response_json
+        .get("rates")
+        .and_then(|r| r.as_object())
+        .ok_or("Rates response has no `rates` object")?
+        .iter()
+        .filter_map(|(currency, rate)| rate.as_f64().map(|rate| (currency.to_uppercase(), rate)))
+        .collect();
+    
+    Ok(rates)
+}
+
+// Currency all fares are compared in; exchange rates are relative to it
+const BASE_CURRENCY: &str = "RUB";
+
+// Function to convert a fare into the base currency for comparison; None when the rate is unknown
+fn normalized_price(flight: &FlightResult, rates: &HashMap<String, f64>, base: &str) -> Option<f64> {
+    let currency = flight.currency.as_deref().map_or(base.to_string(), normalize_code);
+    if currency == base {
+        return Some(flight.price as f64);
+    }
+    
+    let rate = rates.get(&currency).filter(|rate| **rate > 0.0)?;
+    let base_rate = if base == BASE_CURRENCY { 1.0 } else { *rates.get(base)? };
+    Some(flight.price as f64 / rate * base_rate)
+}
+
+// Function to find the cheapest fare, comparing prices in the base currency
+fn cheapest_flight<'a>(flights: &[&'a FlightResult], rates: &HashMap<String, f64>) -> Option<&'a FlightResult> {
+    flights
+        .iter()
+        .copied()
+        .min_by(|a, b| {
+            let a = normalized_price(a, rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+            let b = normalized_price(b, rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+            a.total_cmp(&b)
+        })
+}
+
+// Function to collect the fares in a response that pass the airline filter
+fn allowed_flights<'a>(flight_data: &'a FlightData, airline_filter: &AirlineFilter) -> Vec<&'a FlightResult> {
+    flight_data.data
+        .iter()
+        .flatten()
+        .filter(|flight| airline_filter.allows(&flight.airline))
+        .collect()
+}
+
+// Function to rank destinations by their cheapest allowed fare for a date, cheapest first
+fn rank_destinations<'a>(
+    results: &'a [RouteSearchResult],
+    airline_filter: &AirlineFilter,
+    rates: &HashMap<String, f64>,
+) -> Vec<(&'a Route, i64)> {
+    let mut ranking: Vec<(&Route, &FlightResult)> = results
+        .iter()
+        .filter_map(|(route, result)| {
+            let flights = allowed_flights(result.as_ref().ok()?, airline_filter);
+            cheapest_flight(&flights, rates).map(|flight| (route, flight))
+        })
+        .collect();
+    
+    ranking.sort_by(|(_, a), (_, b)| {
+        let a = normalized_price(a, rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+        let b = normalized_price(b, rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+        a.total_cmp(&b)
+    });
+    
+    ranking.into_iter().map(|(route, flight)| (route, flight.price)).collect()
+}
+
+// Function to format the cross-destination ranking for a date
+fn format_destination_ranking(display: &DisplaySettings, formatted_date: &str, ranking: &[(&Route, i64)], default_currency: &str) -> String {
+    let mut message = format!("🏆 <b>Лучшие направления на {}</b>\n\n", formatted_date);
+    
+    for (place, (route, price)) in ranking.iter().enumerate() {
+        message.push_str(&format!(
+            "{}. {} ({}) — {}\n",
+            place + 1,
+            get_city_name(display, &route.destination),
+            route.destination,
+            format_price_in(display, *price, route.currency(default_currency))
+        ));
+    }
+    
+    message
+}
+
+// Function to format the combined cheapest round trip, if both legs have fares
+// Both legs are searched in the route's currency, so one currency covers the whole trip
+fn format_round_trip(
+    display: &DisplaySettings,
+    route: &Route,
+    outbound: &[&FlightResult],
+    returns: &[&FlightResult],
+    rates: &HashMap<String, f64>,
+    default_currency: &str,
+) -> Option<String> {
+    let outbound = cheapest_flight(outbound, rates)?;
+    let inbound = cheapest_flight(returns, rates)?;
+    let currency = route.currency(default_currency);
+    
+    let leg = |flight: &FlightResult| format!(
+        "{} — {} {}, {}",
+        format_datetime_ru(display, &flight.departure_at),
+        escape_html(get_airline_name(&flight.airline)),
+        format_flight_designator(&flight.airline, &flight.flight_number),
+        format_price_in(display, flight.price, currency)
+    );
+    
+    Some(format!(
+        "🔁 <b>Туда и обратно: {} ⇄ {}</b>\n\n\
+        🛫 Туда: {}\n\
+        🛬 Обратно: {}\n\n\
+        💰 Итого: <b>{}</b>",
+        get_city_name(display, &route.origin),
+        get_city_name(display, &route.destination),
+        leg(outbound),
+        leg(inbound),
+        format_price_in(display, outbound.price + inbound.price, currency)
+    ))
+}
+
+// How many flights are listed per date before the rest are summarized
+const MAX_FLIGHTS_PER_DATE: usize = 5;
+
+// How flights of a date are laid out in notifications (GROUP_BY)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GroupBy {
+    // One message per flight
+    None,
+    // One digest with flights grouped under their airlines
+    Airline,
+}
+
+impl GroupBy {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "airline" => GroupBy::Airline,
+            _ => GroupBy::None,
+        }
+    }
+}
+
+// Function to render flights grouped by airline, cheapest airline first, each with its
+// cheapest fare and flight count. At most `max_flights` flights are listed across all groups
+fn format_flights_by_airline(
+    display: &DisplaySettings,
+    flights: &[&FlightResult],
+    max_flights: usize,
+    conversion: Option<&CurrencyConversion>,
+) -> String {
+    let mut groups: Vec<(&str, Vec<&FlightResult>)> = Vec::new();
+    for flight in flights {
+        match groups.iter_mut().find(|(airline, _)| *airline == flight.airline) {
+            Some((_, group)) => group.push(flight),
+            None => groups.push((&flight.airline, vec![flight])),
+        }
+    }
+    groups.sort_by_key(|(_, group)| group.iter().map(|flight| flight.price).min().unwrap_or_default());
+    
+    let mut message = String::new();
+    let mut listed = 0;
+    for (airline, group) in &groups {
+        let cheapest = group.iter().min_by_key(|flight| flight.price).map_or(String::new(), |flight| flight.display_price_converted(display, conversion));
+        message.push_str(&format!(
+            "✈️ <b>{}</b> — от {}, рейсов: {}\n",
+            escape_html(get_airline_name(airline)),
+            cheapest,
+            group.len()
+        ));
         
+        for flight in group {
+            if listed >= max_flights {
+                break;
+            }
+            listed += 1;
+            message.push_str(&format!(
+                "  • {} {} → {}, {} — {}\n",
+                format_flight_designator(&flight.airline, &flight.flight_number),
+                flight.origin_airport,
+                flight.destination_airport,
+                format_datetime_ru(display, &flight.departure_at),
+                flight.display_price(display)
+            ));
+        }
+        message.push('\n');
+    }
+    
+    if flights.len() > listed {
+        let remaining = flights.len() - listed;
+        message.push_str(&format!("... и еще {} {}", remaining, plural_ru(remaining as i64, "рейс", "рейса", "рейсов")));
+    }
+    
+    message.trim_end().to_string()
+}
+
+// How found fares are ordered in notifications (SORT_BY)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortBy {
+    // Keep the order returned by the API
+    Api,
+    // Cheapest first, compared in the base currency
+    Price,
+    // Earliest departure first
+    Departure,
+    // Shortest flight first
+    Duration,
+}
+
+impl SortBy {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "price" => SortBy::Price,
+            "departure" => SortBy::Departure,
+            "duration" => SortBy::Duration,
+            _ => SortBy::Api,
+        }
+    }
+}
+
+// Function to sort fares; with SortBy::Price, fares without a known rate go last
+fn sort_flights(flights: &mut [&FlightResult], sort_by: SortBy, rates: &HashMap<String, f64>) {
+    match sort_by {
+        SortBy::Api => {}
+        SortBy::Price => {
+            let unknown = flights.iter().filter(|f| normalized_price(f, rates, BASE_CURRENCY).is_none()).count();
+            if unknown > 0 {
+                warn!("No exchange rate for {} fares, sorting them last", unknown);
+            }
+            flights.sort_by(|a, b| {
+                let a = normalized_price(a, rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+                let b = normalized_price(b, rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+                a.total_cmp(&b)
+            });
+        }
+        SortBy::Departure => flights.sort_by(|a, b| a.departure_at.cmp(&b.departure_at)),
+        SortBy::Duration => flights.sort_by_key(|f| f.duration.unwrap_or(i64::MAX)),
+    }
+}
+
+// Function to convert ISO datetime to human readable Russian format
+fn format_datetime_ru(display: &DisplaySettings, datetime_str: &str) -> String {
+    // Parse the ISO 8601 datetime string in the display timezone
+    if let Some(local_time) = parse_departure_at(display, datetime_str) {
+
         // Format the date in Russian
         let day = local_time.day();
         let month = match local_time.month() {
@@ -148,9 +729,21 @@ fn format_datetime_ru(datetime_str: &str) -> String {
     }
 }
 
+// Function to escape text for Telegram HTML parse mode
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Function to normalize an IATA code: trims whitespace and uppercases
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
 // Function to get human-readable airline name
 fn get_airline_name(code: &str) -> &str {
-    match code {
+    match normalize_code(code).as_str() {
         "UT" => "Utair",
         "SU" => "Аэрофлот",
         "S7" => "S7 Airlines",
@@ -167,164 +760,429 @@ fn get_airline_name(code: &str) -> &str {
         "YC" => "ЯМАЛ",
         "7R" => "Руслайн",
         "KV" => "КрасАвиа",
-        _ => code,
+        _ => code.trim(),
     }
 }
 
+// Function to parse PLACE_LABELS in the form `MOW=Столица,LON=Лондон (все)`
+fn parse_place_labels(raw: &str) -> HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|entry| {
+            let (code, label) = entry.split_once('=')?;
+            let (code, label) = (code.trim(), label.trim());
+            if code.is_empty() || label.is_empty() {
+                return None;
+            }
+            Some((normalize_code(code), label.to_string()))
+        })
+        .collect()
+}
+
+// Built-in city data: IATA code, Russian name, English name
+const CITIES: &[(&str, &str, &str)] = &[
+    ("MOW", "Москва", "Moscow"),
+    ("LED", "Санкт-Петербург", "Saint Petersburg"),
+    ("UFA", "Уфа", "Ufa"),
+    ("USK", "Усинск", "Usinsk"),
+    ("KZN", "Казань", "Kazan"),
+    ("AER", "Сочи", "Sochi"),
+    ("SVX", "Екатеринбург", "Yekaterinburg"),
+    ("OVB", "Новосибирск", "Novosibirsk"),
+    ("VVO", "Владивосток", "Vladivostok"),
+    ("KGD", "Калининград", "Kaliningrad"),
+    ("ROV", "Ростов-на-Дону", "Rostov-on-Don"),
+    ("KRR", "Краснодар", "Krasnodar"),
+    ("SIP", "Симферополь", "Simferopol"),
+    ("GOJ", "Нижний Новгород", "Nizhny Novgorod"),
+    ("SGC", "Сургут", "Surgut"),
+    ("MRV", "Минеральные Воды", "Mineralnye Vody"),
+    ("CEK", "Челябинск", "Chelyabinsk"),
+    ("KUF", "Самара", "Samara"),
+    ("BAX", "Барнаул", "Barnaul"),
+    ("OMS", "Омск", "Omsk"),
+    ("TJM", "Тюмень", "Tyumen"),
+    ("IKT", "Иркутск", "Irkutsk"),
+    ("MMK", "Мурманск", "Murmansk"),
+    ("KJA", "Красноярск", "Krasnoyarsk"),
+    ("VOG", "Волгоград", "Volgograd"),
+];
+
 // Function to get human-readable city name from IATA code
-fn get_city_name(code: &str) -> &str {
-    match code {
-        "MOW" => "Москва",
-        "LED" => "Санкт-Петербург",
-        "UFA" => "Уфа",
-        "USK" => "Усинск",
-        "KZN" => "Казань",
-        "AER" => "Сочи",
-        "SVX" => "Екатеринбург",
-        "OVB" => "Новосибирск",
-        "VVO" => "Владивосток",
-        "KGD" => "Калининград",
-        "ROV" => "Ростов-на-Дону",
-        "KRR" => "Краснодар",
-        "SIP" => "Симферополь",
-        "GOJ" => "Нижний Новгород",
-        "SGC" => "Сургут",
-        "MRV" => "Минеральные Воды",
-        "CEK" => "Челябинск",
-        "KUF" => "Самара",
-        "BAX" => "Барнаул",
-        "OMS" => "Омск",
-        "TJM" => "Тюмень",
-        "IKT" => "Иркутск",
-        "MMK" => "Мурманск",
-        "KJA" => "Красноярск",
-        "VOG" => "Волгоград",
-        _ => code,
-    }
-}
-
-// Updated function to handle rate limiting with exponential backoff
-async fn send_telegram_notification(
-    client: &Client,
-    bot_token: &str,
-    chat_id: &str,
-    message: &str,
-    topic_id: &str,
-    inline_keyboard: Option<serde_json::Value>,
-) -> Result<(), Box<dyn Error>> {
-    let api_url = format!("https://api.telegram.org/bot{}/sendMessage", bot_token);
+// Precedence: PLACE_LABELS override > built-in city map > raw code
+fn get_city_name<'a>(display: &'a DisplaySettings, code: &'a str) -> &'a str {
+    let normalized = normalize_code(code);
+    if let Some(label) = display.place_labels.get(&normalized) {
+        return label;
+    }
     
-    let mut json_body = json!({
-        "chat_id": chat_id,
-        "text": message,
-        "parse_mode": "HTML",
-        "disable_web_page_preview": true
-    });
+    CITIES
+        .iter()
+        .find(|(iata, _, _)| *iata == normalized)
+        .map_or(code.trim(), |(_, name_ru, _)| name_ru)
+}
 
-     // Add message_thread_id only if topic_id is not empty and not "1"
-     if !topic_id.is_empty() && topic_id != "1" {
-        json_body["message_thread_id"] = json!(topic_id);
-    }
+// Function to fold a place name for comparison: lowercase, without accents, ё as е, hyphens as spaces
+fn fold_place_name(name: &str) -> String {
+    let folded: String = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'ё' => 'е',
+            'á' | 'à' | 'â' | 'ä' | 'ã' | 'å' => 'a',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'í' | 'ì' | 'î' | 'ï' => 'i',
+            'ó' | 'ò' | 'ô' | 'ö' | 'õ' => 'o',
+            'ú' | 'ù' | 'û' | 'ü' => 'u',
+            'ý' | 'ÿ' => 'y',
+            'ç' => 'c',
+            'ñ' => 'n',
+            '-' => ' ',
+            other => other,
+        })
+        .collect();
     
-    if let Some(keyboard) = inline_keyboard {
-        json_body["reply_markup"] = keyboard;
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Function to find IATA codes whose Russian or English city name matches the input;
+// exact matches win, otherwise names starting with the input are returned
+fn find_places_by_name(name: &str) -> Vec<&'static str> {
+    let query = fold_place_name(name);
+    if query.is_empty() {
+        return Vec::new();
     }
     
-    // Implement exponential backoff for rate limiting
-    let mut retry_count = 0;
-    let max_retries = 5;
-    let initial_delay = 1; // seconds
+    let names_match = |matches: &dyn Fn(&str) -> bool| -> Vec<&'static str> {
+        CITIES
+            .iter()
+            .filter(|(_, name_ru, name_en)| matches(&fold_place_name(name_ru)) || matches(&fold_place_name(name_en)))
+            .map(|(iata, _, _)| *iata)
+            .collect()
+    };
     
-    loop {
-    let response = client
-        .post(&api_url)
-        .json(&json_body)
-        .send()
-        .await?;
+    let exact = names_match(&|candidate| candidate == query);
+    if !exact.is_empty() {
+        return exact;
+    }
     
-        if response.status().is_success() {
-            // Add a small delay to avoid Telegram rate limits (30 messages per second is the limit)
-            time::sleep(Duration::from_millis(1000)).await;
-            return Ok(());
-        } else {
-        let status = response.status();
-        let text = response.text().await?;
-            
-            // If we hit the rate limit (429 Too Many Requests)
-            if status.as_u16() == 429 {
-                retry_count += 1;
-                
-                if retry_count > max_retries {
-                    return Err(format!("Exceeded maximum retries for Telegram API. Last error: {}", text).into());
-                }
-                
-                // Extract retry_after from response if available
-                let retry_after = if let Ok(error_json) = serde_json::from_str::<serde_json::Value>(&text) {
-                    error_json.get("parameters")
-                        .and_then(|p| p.get("retry_after"))
-                        .and_then(|r| r.as_f64())
-                        .unwrap_or_else(|| {
-                            // Calculate exponential backoff if retry_after not provided
-                            let backoff = initial_delay * 2_u64.pow(retry_count as u32);
-                            backoff as f64
-                        })
-                } else {
-                    // Fallback exponential backoff
-                    let backoff = initial_delay * 2_u64.pow(retry_count as u32);
-                    backoff as f64
-                };
-                
-                let wait_time = Duration::from_secs_f64(retry_after);
-                eprintln!("Telegram API rate limited (429). Waiting for {} seconds before retry {}/{}...", 
-                    wait_time.as_secs(), retry_count, max_retries);
-                
-                time::sleep(wait_time).await;
-                // Continue the loop to retry
-            } else {
-                // Other error, not rate limiting
-        eprintln!("Telegram API request failed with status {}: {}", status, text);
-                return Err(format!("Telegram API request failed: {}", text).into());
-            }
-        }
+    names_match(&|candidate| candidate.starts_with(&query))
+}
+
+// Function to resolve a free-text city name to its IATA code when the match is unambiguous
+fn resolve_place_by_name(name: &str) -> Option<String> {
+    match find_places_by_name(name).as_slice() {
+        [iata] => Some(iata.to_string()),
+        _ => None,
     }
 }
 
-// Updated function to send messages to multiple topic IDs with rate limit handling
-async fn send_telegram_multi_topic_notification(
-    client: &Client,
-    bot_token: &str,
-    chat_id: &str,
-    message: &str,
-    topic_ids: &[String],
-    inline_keyboard: Option<serde_json::Value>,
-) -> Result<(), Box<dyn Error>> {
-    for topic_id in topic_ids {
-        match send_telegram_notification(
-            client,
-            bot_token,
-            chat_id,
-            message,
-            topic_id,
-            inline_keyboard.clone()
-        ).await {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Error sending to topic {}: {}", topic_id, e);
-                // Continue with other topics even if one fails
-            }
-        }
+// Function to turn an ORIGIN/DESTINATION value into an IATA code; raw codes pass through
+fn resolve_place(value: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() || (trimmed.len() == 3 && trimmed.chars().all(|c| c.is_ascii_alphabetic())) {
+        return Ok(normalize_code(trimmed));
     }
     
-    Ok(())
+    if let Some(iata) = resolve_place_by_name(trimmed) {
+        return Ok(iata);
+    }
+    
+    let candidates = find_places_by_name(trimmed);
+    if candidates.is_empty() {
+        return Err(format!("Unknown place \"{}\": use an IATA code or a known city name", trimmed).into());
+    }
+    
+    let candidates: Vec<String> = candidates
+        .iter()
+        .map(|iata| format!("{} ({})", get_city_name(&DisplaySettings::default(), iata), iata))
+        .collect();
+    Err(format!("Ambiguous place \"{}\", candidates: {}", trimmed, candidates.join(", ")).into())
 }
 
-// Enhanced function for formatting DateTime<Utc> to Russian human-readable format
-fn format_utc_datetime_ru(dt: DateTime<Utc>) -> String {
-    // Convert to UTC+5
-    let local_time = dt.with_timezone(&FixedOffset::east_opt(5 * 3600).unwrap());
-    
-    // Format in Russian
-    let day = local_time.day();
+// Maximum number of retries for rate-limited Telegram requests
+const TELEGRAM_MAX_RETRIES: u32 = 5;
+
+// Maximum number of retries for one rate-limited Travelpayouts request before the date fails
+const TRAVELPAYOUTS_MAX_RETRIES: u32 = 3;
+
+// Maximum message length accepted by Telegram
+const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+// Maximum photo caption length accepted by Telegram
+const TELEGRAM_CAPTION_LIMIT: usize = 1024;
+
+// Room left in each chunk for closing and reopening HTML tags around a split
+const SPLIT_TAG_RESERVE: usize = 256;
+
+// Function to split a message into chunks of at most `limit` characters on line boundaries.
+// Tags still open at a split are closed at the end of the chunk and reopened in the next one
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    if text.chars().count() <= limit {
+        return vec![text.to_string()];
+    }
+    
+    let budget = limit.saturating_sub(SPLIT_TAG_RESERVE).max(1);
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    let mut open_tags: Vec<String> = Vec::new();
+    
+    for piece in text.split_inclusive('\n').flat_map(|line| split_long_line(line, budget)) {
+        let piece_len = piece.chars().count();
+        if current_len > 0 && current_len + piece_len > budget {
+            for tag in open_tags.iter().rev() {
+                current.push_str(&format!("</{}>", html_tag_name(tag)));
+            }
+            chunks.push(std::mem::take(&mut current));
+            
+            current = open_tags.concat();
+            current_len = current.chars().count();
+        }
+        
+        current.push_str(piece);
+        current_len += piece_len;
+        track_open_tags(&mut open_tags, piece);
+    }
+    
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    
+    chunks
+}
+
+// Function to cut a line longer than `max` characters, never inside a tag or an HTML entity
+fn split_long_line(line: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    
+    while rest.chars().count() > max {
+        let mut cut = rest.char_indices().nth(max).map_or(rest.len(), |(index, _)| index);
+        let prefix = &rest[..cut];
+        
+        // Move the cut back before an unfinished tag or entity
+        let tag_start = prefix.rfind('<').filter(|&start| !prefix[start..].contains('>'));
+        let entity_start = prefix.rfind('&').filter(|&start| !prefix[start..].contains(';'));
+        if let Some(start) = tag_start.into_iter().chain(entity_start).min()
+            && start > 0
+        {
+            cut = start;
+        }
+        
+        pieces.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+    
+    pieces.push(rest);
+    pieces
+}
+
+// Function to get the name of an HTML tag such as `<a href="...">`
+fn html_tag_name(tag: &str) -> &str {
+    tag.trim_start_matches('<')
+        .trim_start_matches('/')
+        .split(|c: char| c.is_whitespace() || c == '>')
+        .next()
+        .unwrap_or_default()
+}
+
+// Function to update the stack of open tags with the tags found in a piece of text
+fn track_open_tags(open_tags: &mut Vec<String>, text: &str) {
+    let mut rest = text;
+    
+    while let Some(start) = rest.find('<') {
+        let Some(length) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..=start + length];
+        
+        if tag.starts_with("</") {
+            let name = html_tag_name(tag);
+            if let Some(position) = open_tags.iter().rposition(|open| html_tag_name(open) == name) {
+                open_tags.remove(position);
+            }
+        } else if !tag.ends_with("/>") {
+            open_tags.push(tag.to_string());
+        }
+        
+        rest = &rest[start + length + 1..];
+    }
+}
+
+// Function to compute the wait before a retry: the server-advised retry_after if present,
+// otherwise exponential backoff
+fn retry_delay(response_text: &str, retry_count: u32) -> Duration {
+    let initial_delay = 1; // seconds
+    let backoff = (initial_delay * 2_u64.pow(retry_count)) as f64;
+    
+    let retry_after = serde_json::from_str::<serde_json::Value>(response_text)
+        .ok()
+        .and_then(|error_json| {
+            error_json.get("parameters")
+                .and_then(|p| p.get("retry_after"))
+                .and_then(|r| r.as_f64())
+        })
+        .unwrap_or(backoff);
+    
+    Duration::from_secs_f64(retry_after)
+}
+
+// The shared HTTP client and how many times its requests are retried after a connection failure
+// or timeout (NETWORK_MAX_RETRIES)
+#[derive(Clone)]
+struct HttpClient {
+    client: Client,
+    network_retries: u32,
+}
+
+// Function to send a request, retrying with exponential backoff when the server can't be reached
+// (DNS failure, refused connection) or doesn't answer in time. HTTP error statuses are returned as is.
+// The request is built anew for each attempt, so bodies that can't be cloned (multipart) are retried too
+async fn send_retrying_network_errors(
+    make_request: impl Fn() -> reqwest::RequestBuilder,
+    service: &str,
+    max_retries: u32,
+) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+    let mut retry_count = 0;
+    
+    loop {
+        let result = make_request().send().await;
+        
+        match result {
+            Err(e) if (e.is_connect() || e.is_timeout()) && retry_count < max_retries => {
+                retry_count += 1;
+                let wait_time = retry_delay("", retry_count);
+                if e.is_timeout() {
+                    warn!("{} API request timed out: {}. Waiting for {} seconds before retry {}/{}...",
+                        service, e, wait_time.as_secs(), retry_count, max_retries);
+                } else {
+                    warn!("{} API unreachable: {}. Waiting for {} seconds before retry {}/{}...",
+                        service, e, wait_time.as_secs(), retry_count, max_retries);
+                }
+                time::sleep(wait_time).await;
+            }
+            Err(e) if e.is_timeout() => {
+                error!("{} API request timed out after {} retries: {}", service, retry_count, e);
+                return Err(e.into());
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+// Function to send a request with exponential backoff on rate limiting (429),
+// and also on server errors (5xx) when `retry_server_errors` is set
+async fn send_with_backoff(
+    make_request: impl Fn() -> reqwest::RequestBuilder,
+    service: &str,
+    max_retries: u32,
+    network_retries: u32,
+    retry_server_errors: bool,
+    throttle: Option<&RequestThrottle>,
+) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+    let mut retry_count = 0;
+    
+    loop {
+        if let Some(throttle) = throttle {
+            throttle.wait().await;
+        }
+        
+        let response = send_retrying_network_errors(&make_request, service, network_retries).await?;
+        
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        
+        let text = response.text().await?;
+        let retryable = status.as_u16() == 429 || (retry_server_errors && status.is_server_error());
+        if !retryable {
+            error!("{} API request failed with status {}: {}", service, status, text);
+            return Err(format!("{} API request failed: {}", service, text).into());
+        }
+        
+        retry_count += 1;
+        if retry_count > max_retries {
+            return Err(format!("Exceeded maximum retries for {} API. Last error: {}", service, text).into());
+        }
+        
+        let wait_time = retry_delay(&text, retry_count);
+        warn!("{} API returned {}. Waiting for {} seconds before retry {}/{}...", 
+            service, status, wait_time.as_secs(), retry_count, max_retries);
+        
+        // A shared throttle holds back every request, not just this retry
+        match throttle {
+            Some(throttle) if status.as_u16() == 429 => throttle.trip(wait_time),
+            _ => time::sleep(wait_time).await,
+        }
+    }
+}
+
+// Default Telegram Bot API server; TELEGRAM_API_BASE points the bot at a self-hosted one
+const DEFAULT_TELEGRAM_API_BASE: &str = "https://api.telegram.org";
+
+// Function to build the URL of a Telegram Bot API method
+fn telegram_api_url(base: &str, bot_token: &str, method: &str) -> String {
+    format!("{}/bot{}/{}", base.trim_end_matches('/'), bot_token, method)
+}
+
+// Default Travelpayouts and AirLabs API servers; TRAVELPAYOUTS_API_BASE and AIRLABS_API_BASE
+// point the bot at a proxy or mirror
+const DEFAULT_TRAVELPAYOUTS_API_BASE: &str = "https://api.travelpayouts.com";
+const DEFAULT_AIRLABS_API_BASE: &str = "https://airlabs.co";
+
+// Function to build the URL of an API path under a configurable base
+fn api_url(base: &str, path: &str) -> String {
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+// Function to check an API base setting (TELEGRAM_API_BASE and the like) is an http(s) URL
+fn parse_api_base(name: &str, value: &str, default: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(default.to_string());
+    }
+    
+    let url = Url::parse(value).map_err(|e| BotError::Config(format!("{} is not a valid URL ({}): {}", name, e, value)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(BotError::Config(format!("{} must be an http or https URL, got {}", name, value)).into());
+    }
+    Ok(value.to_string())
+}
+
+// Function to pick the Russian plural form for a count: 1 рейс, 2 рейса, 5 рейсов, 11 рейсов, 21 рейс
+fn plural_ru<'a>(n: i64, one: &'a str, few: &'a str, many: &'a str) -> &'a str {
+    let n = n.unsigned_abs();
+    match (n % 10, n % 100) {
+        (_, 11..=14) => many,
+        (1, _) => one,
+        (2..=4, _) => few,
+        _ => many,
+    }
+}
+
+// Function to format a cycle duration as minutes and the remaining seconds, e.g. "2 минуты 10 секунд"
+fn format_cycle_duration_ru(duration: chrono::Duration) -> String {
+    let total_seconds = duration.num_seconds().max(0);
+    let minutes = total_seconds / 60;
+    let seconds = total_seconds % 60;
+    let seconds_text = format!("{} {}", seconds, plural_ru(seconds, "секунда", "секунды", "секунд"));
+    
+    if minutes == 0 {
+        return seconds_text;
+    }
+    
+    format!("{} {} {}", minutes, plural_ru(minutes, "минута", "минуты", "минут"), seconds_text)
+}
+
+// Enhanced function for formatting DateTime<Utc> to Russian human-readable format
+fn format_utc_datetime_ru(display: &DisplaySettings, dt: DateTime<Utc>) -> String {
+    // Convert to the display timezone
+    let local_time = dt.with_timezone(&display.timezone);
+    
+    // Format in Russian
+    let day = local_time.day();
     let month = match local_time.month() {
         1 => "января",
         2 => "февраля",
@@ -348,6 +1206,27 @@ fn format_utc_datetime_ru(dt: DateTime<Utc>) -> String {
     format!("{} {} {} в {}ч {}м {}с", day, month, year, hour, minute, second)
 }
 
+// Function to format a date for display, e.g. "5 сентября 2025"
+fn format_date_ru(date: &NaiveDate) -> String {
+    let month = match date.month() {
+        1 => "января",
+        2 => "февраля",
+        3 => "марта",
+        4 => "апреля",
+        5 => "мая",
+        6 => "июня",
+        7 => "июля",
+        8 => "августа",
+        9 => "сентября",
+        10 => "октября",
+        11 => "ноября",
+        12 => "декабря",
+        _ => "",
+    };
+    
+    format!("{} {} {}", date.day(), month, date.year())
+}
+
 // Function to format a date range for display
 fn format_date_range_ru(start_date: &NaiveDate, end_date: &NaiveDate) -> String {
     let start_day = start_date.day();
@@ -400,43 +1279,376 @@ fn format_date_range_ru(start_date: &NaiveDate, end_date: &NaiveDate) -> String
     }
 }
 
+// Pause applied to all Travelpayouts requests after a 429 without a Retry-After header
+const DEFAULT_RATE_LIMIT_PAUSE: Duration = Duration::from_secs(10);
+
+// Shared circuit for Travelpayouts requests: a 429 on any request pauses all of them
+// until the server-advised backoff has elapsed
+#[derive(Default)]
+struct RequestThrottle {
+    paused_until: Mutex<Option<time::Instant>>,
+    // File the pause is written to so it survives restarts
+    persist_path: OnceLock<String>,
+}
+
+impl RequestThrottle {
+    // Persist pauses to `path` and resume any pause left there by a previous run
+    fn persist_to(&self, path: &str) {
+        self.persist_path.set(path.to_string()).ok();
+        
+        let Some(until) = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| DateTime::parse_from_rfc3339(text.trim()).ok())
+        else {
+            return;
+        };
+        match until.with_timezone(&Utc).signed_duration_since(Utc::now()).to_std() {
+            Ok(remaining) => {
+                info!("Resuming rate limit pause from the previous run: {} seconds left", remaining.as_secs());
+                self.trip(remaining);
+            }
+            Err(_) => {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
+
+    // Wait until requests may be sent again
+    async fn wait(&self) {
+        loop {
+            let paused_until = *self.paused_until.lock().unwrap();
+            match paused_until {
+                Some(until) if until > time::Instant::now() => time::sleep_until(until).await,
+                Some(_) => {
+                    // The pause elapsed: clear it here and on disk
+                    *self.paused_until.lock().unwrap() = None;
+                    if let Some(path) = self.persist_path.get() {
+                        std::fs::remove_file(path).ok();
+                    }
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+
+    // Pause new requests, extending but never shortening an existing pause
+    fn trip(&self, pause: Duration) {
+        let until = time::Instant::now() + pause;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.is_none_or(|current| current < until) {
+            *paused_until = Some(until);
+            
+            if let Some(path) = self.persist_path.get() {
+                let until = Utc::now() + chrono::Duration::from_std(pause).unwrap_or_default();
+                if let Err(e) = std::fs::write(path, until.to_rfc3339()) {
+                    error!("Failed to persist rate limit pause to {}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+// Function to read a Retry-After header given in seconds
+fn retry_after_header(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+// Travelpayouts tokens used round-robin; a token that hits its quota is skipped until the next cycle
+struct ApiKeyPool {
+    keys: Vec<String>,
+    state: Mutex<ApiKeyPoolState>,
+}
+
+#[derive(Default)]
+struct ApiKeyPoolState {
+    next: usize,
+    exhausted: Vec<bool>,
+    // Tokens whose last request got a plain 429; a second one in a row exhausts the token
+    rate_limited: Vec<bool>,
+}
+
+impl ApiKeyPool {
+    fn new(raw: &str) -> Self {
+        let keys: Vec<String> = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect();
+        let state = ApiKeyPoolState {
+            next: 0,
+            exhausted: vec![false; keys.len()],
+            rate_limited: vec![false; keys.len()],
+        };
+        
+        Self { keys, state: Mutex::new(state) }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Function to pick the next usable token, returning its index and value
+    fn next_key(&self) -> Option<(usize, &str)> {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..self.keys.len() {
+            let index = state.next % self.keys.len();
+            state.next = index + 1;
+            if !state.exhausted[index] {
+                return Some((index, self.keys[index].as_str()));
+            }
+        }
+        None
+    }
+
+    // Function to record a 429 for a token and return whether it is now exhausted. A quota
+    // response exhausts it at once; a plain 429 only when the throttled retry of the same token
+    // got one too, and never for the last usable token, which waits for the throttle instead
+    fn record_rate_limit(&self, index: usize, quota: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let live_keys = state.exhausted.iter().filter(|exhausted| !**exhausted).count();
+        let repeated = std::mem::replace(&mut state.rate_limited[index], true);
+        
+        if quota || (repeated && live_keys > 1) {
+            state.exhausted[index] = true;
+        }
+        state.exhausted[index]
+    }
+
+    // Function to record an accepted request, so an earlier 429 no longer counts against the token
+    fn record_success(&self, index: usize) {
+        self.state.lock().unwrap().rate_limited[index] = false;
+    }
+
+    // Function to give every token another chance at the start of a cycle
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.exhausted.iter_mut().for_each(|exhausted| *exhausted = false);
+        state.rate_limited.iter_mut().for_each(|rate_limited| *rate_limited = false);
+    }
+}
+
+// Most Travelpayouts searches in flight at once, whatever SEARCH_CONCURRENCY, ROUTE_CONCURRENCY
+// and DATE_CONCURRENCY allow, to stay within the API rate limit
+const MAX_CONCURRENT_SEARCHES: usize = 6;
+
+// How many routes of a date and how many dates are searched in parallel, and how many
+// searches may be in flight at once overall (SEARCH_CONCURRENCY)
+#[derive(Debug, Clone, Copy)]
+struct SearchConcurrency {
+    routes: usize,
+    dates: usize,
+    searches: usize,
+}
+
+impl SearchConcurrency {
+    fn new(routes: usize, dates: usize, searches: usize) -> Self {
+        Self {
+            routes: routes.max(1),
+            dates: dates.max(1),
+            searches: searches.max(1),
+        }
+    }
+
+    // Searches allowed in flight at once across routes and dates
+    fn total(self) -> usize {
+        (self.routes * self.dates).min(self.searches).min(MAX_CONCURRENT_SEARCHES)
+    }
+}
+
+// Spawned tasks whose outputs are taken in the order they were spawned, whatever order they
+// finish in. Dropping it aborts the tasks still running
+struct OrderedTasks<T> {
+    tasks: JoinSet<T>,
+    positions: HashMap<task::Id, usize>,
+    finished: HashMap<usize, Result<T, JoinError>>,
+    spawned: usize,
+    taken: usize,
+}
+
+impl<T: Send + 'static> OrderedTasks<T> {
+    fn new() -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            positions: HashMap::new(),
+            finished: HashMap::new(),
+            spawned: 0,
+            taken: 0,
+        }
+    }
+
+    fn spawn(&mut self, future: impl Future<Output = T> + Send + 'static) {
+        let handle = self.tasks.spawn(future);
+        self.positions.insert(handle.id(), self.spawned);
+        self.spawned += 1;
+    }
+
+    // Output of the next task in spawn order, or its JoinError if it panicked; None once all were taken
+    async fn next(&mut self) -> Option<Result<T, JoinError>> {
+        loop {
+            if let Some(result) = self.finished.remove(&self.taken) {
+                self.taken += 1;
+                return Some(result);
+            }
+            let (id, result) = match self.tasks.join_next_with_id().await? {
+                Ok((id, output)) => (id, Ok(output)),
+                Err(e) => (e.id(), Err(e)),
+            };
+            self.finished.insert(self.positions[&id], result);
+        }
+    }
+}
+
+// Paging options for Travelpayouts searches (RESULTS_LIMIT and RESULTS_MAX_TOTAL)
+#[derive(Debug, Clone, Copy)]
+struct SearchPaging {
+    // Results requested per page
+    limit: usize,
+    // Stop fetching pages once this many results were collected
+    max_results: usize,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn search_flights(
-    client: &Client,
+    searcher: &TravelpayoutsSearcher,
     origin: &str,
     destination: &str,
     departure_date: &str,
-    api_key: &str,
-) -> Result<FlightData, Box<dyn Error>> {
+    api_keys: &ApiKeyPool,
+    direct: bool,
+    paging: SearchPaging,
+    currency: &str,
+) -> Result<FlightData, Box<dyn Error + Send + Sync>> {
+    // Never ask the API about a route with a missing end
+    if origin.trim().is_empty() || destination.trim().is_empty() {
+        return Err(BotError::Config(format!(
+            "Refusing to search with an empty origin or destination ({:?} -> {:?})", origin, destination
+        )).into());
+    }
+    
+    debug!("Searching flights from {} to {} on {}", origin, destination, departure_date);
+    
+    let limit = paging.limit.to_string();
+    let mut page = 1;
+    let mut flight_data: Option<FlightData> = None;
+    
+    loop {
+        let page_number = page.to_string();
+        let params = [
+            ("origin", origin),
+            ("destination", destination),
+            ("departure_at", departure_date),
+            ("return_at", ""),
+            ("currency", currency),
+            ("limit", limit.as_str()),
+            ("page", page_number.as_str()),
+            ("one_way", "true"),  // No return flights
+            ("direct", if direct { "true" } else { "false" }),
+        ];
+        
+        let page_data = match fetch_flights_page(searcher, &params, api_keys).await {
+            Ok(page_data) => page_data,
+            // Keep the pages fetched so far if a later page fails
+            Err(e) if flight_data.is_some() => {
+                error!("Error fetching page {} for {}: {}", page, departure_date, e);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let page_len = page_data.data.as_ref().map_or(0, Vec::len);
+        
+        let collected = match flight_data.as_mut() {
+            Some(existing) => {
+                merge_flight_results(existing, page_data);
+                existing
+            }
+            None => flight_data.insert(page_data),
+        };
+        let collected_len = collected.data.as_ref().map_or(0, Vec::len);
+        
+        // Stop on a short page or once enough results were collected
+        if !collected.success || page_len < paging.limit || collected_len >= paging.max_results {
+            break;
+        }
+        
+        page += 1;
+        
+        // Add a small delay between page requests to avoid rate limiting
+        time::sleep(Duration::from_secs(1)).await;
+    }
+    
+    let mut flight_data = flight_data.ok_or("No pages fetched")?;
+    if let Some(flights) = flight_data.data.as_mut() {
+        flights.truncate(paging.max_results);
+    }
+    
+    Ok(flight_data)
+}
+
+// Function to tell a 429 caused by a token's used-up quota from plain rate limiting.
+// Only the `error` field of the JSON body is looked at, not the rest of the response
+fn is_quota_response(body: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| value.get("error")?.as_str().map(str::to_lowercase))
+        .is_some_and(|error| error.contains("quota"))
+}
+
+// Function to fetch a single page of Travelpayouts results, rotating tokens on quota errors
+async fn fetch_flights_page(
+    searcher: &TravelpayoutsSearcher,
+    params: &[(&str, &str)],
+    api_keys: &ApiKeyPool,
+) -> Result<FlightData, Box<dyn Error + Send + Sync>> {
+    let throttle = &searcher.throttle;
     // Updated to the latest API endpoint
-    let url = "https://api.travelpayouts.com/aviasales/v3/prices_for_dates";
+    let url = api_url(&searcher.api_base, "/aviasales/v3/prices_for_dates");
+
+    // A plain 429 never exhausts the last token, so the attempts per request are capped
+    let max_attempts = TRAVELPAYOUTS_MAX_RETRIES + 1;
+    let mut attempts = 0;
     
-    let params = [
-        ("origin", origin),
-        ("destination", destination),
-        ("departure_at", departure_date),
-        ("return_at", ""),
-        ("currency", "rub"),  // Using RUB as currency
-        ("limit", "30"),      // Number of results
-        ("page", "1"),
-        ("one_way", "true"),  // No return flights
-        ("direct", "true"),   // Only direct flights
-        ("token", api_key),
-    ];
+    let response = loop {
+        if attempts == max_attempts {
+            return Err(BotError::RateLimited(attempts).into());
+        }
+        attempts += 1;
+        throttle.wait().await;
+        
+        let (key_index, api_key) = api_keys.next_key()
+            .ok_or(BotError::TokensExhausted)?;
+        
+        // The token stays out of the log
+        debug!("Request URL: {} {:?}", url, params);
 
-    // Create request URL for logging without consuming the builder
-    let request_url = {
-        let temp_request = client.get(url).query(&params);
-        temp_request.build()?.url().to_string()
+        let request = || searcher.http.client
+            .get(&url)
+            .query(params)
+            .query(&[("token", api_key)]);
+        let response = send_retrying_network_errors(request, "Travelpayouts", searcher.http.network_retries).await?;
+        
+        if response.status().as_u16() == 429 {
+            let pause = retry_after_header(&response).unwrap_or(DEFAULT_RATE_LIMIT_PAUSE);
+            let quota = is_quota_response(&response.text().await.unwrap_or_default());
+            if api_keys.record_rate_limit(key_index, quota) {
+                warn!("Travelpayouts token #{} hit its quota, skipping it for the rest of the cycle. Pausing requests for {} seconds",
+                    key_index + 1, pause.as_secs());
+            } else {
+                warn!("Travelpayouts token #{} was rate limited. Pausing requests for {} seconds", key_index + 1, pause.as_secs());
+            }
+            throttle.trip(pause);
+            continue;
+        }
+        
+        api_keys.record_success(key_index);
+        break response;
     };
-    println!("Searching flights from {} to {} on {}", origin, destination, departure_date);
-    println!("Request URL: {}", request_url);
-
-    // Create a fresh request
-    let response = client
-        .get(url)
-        .query(&params)
-        .send()
-        .await?;
     
     if !response.status().is_success() {
         let status = response.status();
@@ -446,16 +1658,30 @@ async fn search_flights(
     
     // Get the response body as text
     let response_text = response.text().await?;
-    println!("Raw API Response: {}", response_text);
+    trace!("Raw API Response: {}", response_text);
     
+    parse_flight_data(&response_text)
+}
+
+// Function to parse a Travelpayouts response, falling back to manual parsing on schema mismatch
+fn parse_flight_data(response_text: &str) -> Result<FlightData, Box<dyn Error + Send + Sync>> {
     // Try to directly parse the JSON response
-    let flight_data: FlightData = match serde_json::from_str(&response_text) {
+    let flight_data: FlightData = match serde_json::from_str(response_text) {
         Ok(data) => data,
         Err(e) => {
-            println!("Error parsing JSON: {}", e);
-            
             // Fallback to manual parsing
-            let json_value: serde_json::Value = serde_json::from_str(&response_text)?;
+            let json_value: serde_json::Value = serde_json::from_str(response_text)?;
+            
+            // Fingerprint the top-level keys so schema changes in the API are noticeable
+            let schema_fingerprint = match json_value.as_object() {
+                Some(object) => object.keys().cloned().collect::<Vec<_>>().join(", "),
+                None => "not a JSON object".to_string(),
+            };
+            let parse_warning = format!(
+                "Response did not match the expected schema: {}. Top-level keys: [{}]",
+                e, schema_fingerprint
+            );
+            warn!("{}", parse_warning);
             
             let success = json_value.get("success").and_then(|v| v.as_bool()).unwrap_or(false);
             let currency = json_value.get("currency").and_then(|v| v.as_str()).map(|s| s.to_string());
@@ -466,36 +1692,13 @@ async fn search_flights(
                 data: None,
                 currency,
                 error,
+                parse_warning: Some(parse_warning),
             };
             
             if success && json_value.get("data").is_some() {
                 let data = json_value.get("data").unwrap();
                 if let Some(items) = data.as_array() {
-                    let mut flights = Vec::new();
-                    
-                    for item in items {
-                        let flight_result = FlightResult {
-                            origin: item.get("origin").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            destination: item.get("destination").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            origin_airport: item.get("origin_airport").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            destination_airport: item.get("destination_airport").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            price: item.get("price").and_then(|v| v.as_i64()).unwrap_or(0),
-                            airline: item.get("airline").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            flight_number: item.get("flight_number").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            departure_at: item.get("departure_at").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            return_at: item.get("return_at").and_then(|v| v.as_str()).map(|s| s.to_string()),
-                            transfers: item.get("transfers").and_then(|v| v.as_i64()).unwrap_or(0),
-                            duration: item.get("duration").and_then(|v| v.as_i64()),
-                            duration_to: item.get("duration_to").and_then(|v| v.as_i64()),
-                            duration_back: item.get("duration_back").and_then(|v| v.as_i64()),
-                            return_transfers: item.get("return_transfers").and_then(|v| v.as_i64()),
-                            link: item.get("link").and_then(|v| v.as_str()).unwrap_or("").to_string(),
-                            seats: item.get("seats").and_then(|v| v.as_i64()),
-                        };
-                        
-                        flights.push(flight_result);
-                    }
-                    
+                    let flights = items.iter().filter_map(flight_result_from_json).collect();
                     flight_data.data = Some(flights);
                 }
             }
@@ -504,883 +1707,9499 @@ async fn search_flights(
         }
     };
     
-    Ok(flight_data)
-}
-
-fn date_range(start_date: NaiveDate, end_date: NaiveDate) -> Vec<NaiveDate> {
-    let mut dates = Vec::new();
-    let mut current_date = start_date;
-    
-    while current_date <= end_date {
-        dates.push(current_date);
-        current_date = current_date.succ_opt().unwrap();
+    let mut flight_data = flight_data;
+    if let Some(flights) = flight_data.data.as_mut() {
+        for flight in flights.iter_mut().filter(|flight| flight.currency.is_none()) {
+            flight.currency = flight_data.currency.clone();
+        }
+        
+        // A fare can't be free; a zero or negative price is a placeholder, not a bargain
+        flights.retain(|flight| {
+            if flight.price > 0 {
+                return true;
+            }
+            warn!("Skipping {} on {} with invalid price {}",
+                format_flight_designator(&flight.airline, &flight.flight_number), flight.departure_at, flight.price);
+            false
+        });
     }
     
-    dates
+    Ok(flight_data)
 }
 
-// Function to query AirLabs API for flight information
-async fn get_airlabs_flight_info(
-    client: &Client,
-    airline_code: &str,
-    flight_number: &str,
-    api_key: &str,
-) -> Result<Option<AirLabsFlight>, Box<dyn Error>> {
-    // Build the AirLabs API URL
-    let api_url = "https://airlabs.co/api/v9/flight";
+// Function to map a loosely shaped JSON fare into a FlightResult, defaulting missing fields
+// except the price: a fare without a positive price is skipped with a warning
+fn flight_result_from_json(item: &serde_json::Value) -> Option<FlightResult> {
+    let airline = item.get("airline").and_then(|v| v.as_str()).unwrap_or("");
+    let flight_number = item.get("flight_number").and_then(|v| v.as_str()).unwrap_or("");
+    let price = match item.get("price").and_then(|v| v.as_i64()) {
+        Some(price) if price > 0 => price,
+        Some(price) => {
+            warn!("Skipping fare {} with invalid price {}", format_flight_designator(airline, flight_number), price);
+            return None;
+        }
+        None => {
+            warn!("Skipping fare {} without a price: {}", format_flight_designator(airline, flight_number), item.get("price").unwrap_or(&serde_json::Value::Null));
+            return None;
+        }
+    };
     
-    let params = [
-        ("api_key", api_key),
-        ("flight_iata", &format!("{}{}", airline_code, flight_number)),
-    ];
+    Some(FlightResult {
+        origin: item.get("origin").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        destination: item.get("destination").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        origin_airport: item.get("origin_airport").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        destination_airport: item.get("destination_airport").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        price,
+        airline: airline.to_string(),
+        flight_number: flight_number.to_string(),
+        departure_at: item.get("departure_at").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        return_at: item.get("return_at").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        transfers: item.get("transfers").and_then(|v| v.as_i64()).unwrap_or(0),
+        duration: item.get("duration").and_then(|v| v.as_i64()),
+        duration_to: item.get("duration_to").and_then(|v| v.as_i64()),
+        duration_back: item.get("duration_back").and_then(|v| v.as_i64()),
+        return_transfers: item.get("return_transfers").and_then(|v| v.as_i64()),
+        link: item.get("link").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        seats: item.get("seats").and_then(|v| v.as_i64()),
+        currency: item.get("currency").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        connections: string_list(item.get("connections")),
+        return_connections: string_list(item.get("return_connections")),
+    })
+}
 
-    println!("Querying AirLabs API for flight: {}{}", airline_code, flight_number);
-    
-    // Make the request
-    let response = client
-        .get(api_url)
-        .query(&params)
-        .send()
-        .await?;
+// Function to parse DEPARTURE_DAYS: `weekdays`, `weekends` or a list like `mon,wed,fri`
+// An empty value means every day of the week
+fn parse_departure_days(raw: &str) -> Result<Vec<Weekday>, Box<dyn Error + Send + Sync>> {
+    use Weekday::*;
     
-    if !response.status().is_success() {
-        let status = response.status();
-        let text = response.text().await?;
-        eprintln!("AirLabs API request failed with status {}: {}", status, text);
-        return Err(format!("AirLabs API request failed: {}", text).into());
+    match raw.trim().to_lowercase().as_str() {
+        "" | "all" => Ok(vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun]),
+        "weekdays" => Ok(vec![Mon, Tue, Wed, Thu, Fri]),
+        "weekends" => Ok(vec![Sat, Sun]),
+        list => list
+            .split(',')
+            .map(|day| {
+                day.trim()
+                    .parse::<Weekday>()
+                    .map_err(|_| format!("Invalid day in DEPARTURE_DAYS: {}", day.trim()).into())
+            })
+            .collect(),
+    }
+}
+
+// Function to parse BLACKOUT_DATES: single dates and `start..end` ranges, comma-separated
+fn parse_blackout_dates(raw: &str) -> Result<Vec<(NaiveDate, NaiveDate)>, Box<dyn Error + Send + Sync>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| parse_config_date_range("BLACKOUT_DATES", entry))
+        .collect()
+}
+
+// Function to parse DATES: comma-separated dates, returned sorted without duplicates
+fn parse_explicit_dates(raw: &str) -> Result<Vec<NaiveDate>, Box<dyn Error + Send + Sync>> {
+    let mut dates = raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| parse_config_date("DATES", entry))
+        .collect::<Result<Vec<_>, _>>()?;
+    dates.sort();
+    dates.dedup();
+    Ok(dates)
+}
+
+// Function to parse a single date or a `start..end` range from a setting
+fn parse_config_date_range(name: &str, entry: &str) -> Result<(NaiveDate, NaiveDate), Box<dyn Error + Send + Sync>> {
+    let (start, end) = entry.split_once("..").unwrap_or((entry, entry));
+    let start = parse_config_date(name, start)?;
+    let end = parse_config_date(name, end)?;
+    if start > end {
+        return Err(BotError::Config(format!("{} range {} ends before it starts", name, entry.trim())).into());
+    }
+    Ok((start, end))
+}
+
+// Function to select up to `max_dates` dates starting at `offset`, wrapping around the end
+// A cap of 0 means no limit
+fn rotate_dates(dates: &[NaiveDate], offset: usize, max_dates: usize) -> Vec<NaiveDate> {
+    if max_dates == 0 || dates.len() <= max_dates {
+        return dates.to_vec();
     }
     
-    // Parse the response
-    let response_text = response.text().await?;
-    println!("AirLabs API response: {}", response_text);
-    
-    let airlabs_response: serde_json::Value = serde_json::from_str(&response_text)?;
+    (0..max_dates)
+        .map(|i| dates[(offset + i) % dates.len()])
+        .collect()
+}
+
+// Function to report how a saved Travelpayouts response deserializes: which parse path was
+// taken and, per FlightResult field, how many fares carried it versus fell back to a default
+fn response_field_coverage(response_text: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let flight_data = parse_flight_data(response_text)?;
+    let raw: serde_json::Value = serde_json::from_str(response_text)?;
+    let raw_items = raw.get("data").and_then(|data| data.as_array()).cloned().unwrap_or_default();
+    let flights = flight_data.data.as_deref().unwrap_or_default();
     
-    // Check if there's an error
-    if let Some(error) = airlabs_response.get("error") {
-        if let Some(message) = error.get("message").and_then(|m| m.as_str()) {
-            eprintln!("AirLabs API error: {}", message);
-            return Err(format!("AirLabs API error: {}", message).into());
+    // Parse each item on its own, the way the taken path would, so a dropped fare
+    // doesn't shift the comparison for every item after it
+    let direct = flight_data.parse_warning.is_none();
+    let mut pairs: Vec<(&serde_json::Value, serde_json::Value)> = Vec::new();
+    for item in &raw_items {
+        let flight = if direct {
+            serde_json::from_value::<FlightResult>(item.clone()).ok()
+        } else {
+            flight_result_from_json(item)
+        };
+        if let Some(flight) = flight.filter(|flight| flight.price > 0) {
+            pairs.push((item, serde_json::to_value(flight)?));
         }
     }
     
-    // Check if we have response data
-    if let Some(response_data) = airlabs_response.get("response") {
-        if let Some(flights) = response_data.as_array() {
-            if !flights.is_empty() {
-                // Try to parse the first flight
-                let flight: AirLabsFlight = serde_json::from_value(flights[0].clone())?;
-                return Ok(Some(flight));
+    let mut report = format!(
+        "Parse path: {}\nsuccess: {}, currency: {}, fares: {}\ndropped fares: {}\n",
+        match &flight_data.parse_warning {
+            Some(warning) => format!("manual fallback ({})", warning),
+            None => "direct".to_string(),
+        },
+        flight_data.success,
+        flight_data.currency.as_deref().unwrap_or("-"),
+        flights.len(),
+        raw_items.len() - pairs.len()
+    );
+    
+    // Field names come from the struct itself so the report follows schema changes
+    let fields: Vec<String> = match pairs.first() {
+        Some((_, serde_json::Value::Object(map))) => map.keys().cloned().collect(),
+        _ => return Ok(report),
+    };
+    
+    // A field is populated when the parsed value matches the response, mismatched when the
+    // response had it but it didn't survive parsing (e.g. a price sent as a string)
+    for field in fields {
+        let (mut populated, mut mismatched, mut defaulted) = (0, 0, 0);
+        for (item, flight) in &pairs {
+            match item.get(&field).filter(|value| !value.is_null()) {
+                Some(value) if flight.get(&field) == Some(value) => populated += 1,
+                Some(_) => mismatched += 1,
+                None => defaulted += 1,
             }
         }
+        report.push_str(&format!(
+            "{:<20} populated {:>3}, mismatched {:>3}, defaulted {:>3}\n",
+            field, populated, mismatched, defaulted
+        ));
     }
     
-    Ok(None)
+    Ok(report)
 }
 
-// Function to enrich flight data with AirLabs information
-async fn enrich_with_airlabs_data(
-    client: &Client,
-    flight: &FlightResult,
-    airlabs_api_key: &str,
-) -> Result<Option<AirLabsFlight>, Box<dyn Error>> {
-    // Extract airline code and flight number
-    let airline_code = &flight.airline;
-    let flight_number = &flight.flight_number;
+// Site that relative Travelpayouts links point into
+const BOOKING_BASE_URL: &str = "https://www.aviasales.ru";
+
+// Function to turn a fare's link into an absolute booking URL, adding the affiliate marker
+// (LINK_MARKER) while keeping any query parameters the link already has
+fn booking_url(link: &str, marker: &str) -> Option<String> {
+    let link = link.trim();
+    if link.is_empty() {
+        return None;
+    }
     
-    // Query AirLabs API
-    match get_airlabs_flight_info(client, airline_code, flight_number, airlabs_api_key).await {
-        Ok(airlabs_flight) => Ok(airlabs_flight),
-        Err(e) => {
-            eprintln!("Error getting AirLabs data: {}", e);
-            Ok(None)
-        }
+    let mut url = match Url::parse(link) {
+        Ok(url) => url,
+        Err(_) => Url::parse(BOOKING_BASE_URL).ok()?.join(link).ok()?,
+    };
+    
+    if !marker.is_empty() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| key != "marker")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair("marker", marker);
     }
+    
+    Some(url.to_string())
 }
 
-// Add these new structs to track search statistics
-#[derive(Debug, Default)]
-struct SearchStatistics {
-    total_dates_checked: usize,
-    dates_with_flights: usize,
-    dates_without_flights: usize,
-    total_flights_found: usize,
-    errors_encountered: usize,
-    flight_dates: Vec<(String, String)>, // (date, message_id)
+// Short links for booking URLs from SHORTENER_URL, cached per long URL for the bot's lifetime
+#[derive(Default)]
+struct LinkShortener {
+    cache: Mutex<HashMap<String, String>>,
 }
 
-impl SearchStatistics {
-    fn new() -> Self {
-        Self::default()
-    }
-
-    fn format_summary(&self) -> String {
-        let mut summary = format!(
-            "📊 <b>Статистика поиска:</b>\n\
-             ✓ Проверено дат: {}\n\
-             ✈️ Даты с рейсами: {}\n\
-             ❌ Даты без рейсов: {}\n\
-             🎫 Всего найдено рейсов: {}\n\
-             ⚠️ Ошибок: {}\n",
-            self.total_dates_checked,
-            self.dates_with_flights,
-            self.dates_without_flights,
-            self.total_flights_found,
-            self.errors_encountered
-        );
+impl LinkShortener {
+    // Shorten a URL, or return it unchanged when no shortener is configured or it fails
+    async fn shorten(&self, http: &HttpClient, config: &Config, url: &str) -> String {
+        if config.shortener_url.is_empty() {
+            return url.to_string();
+        }
+        if let Some(short) = self.cache.lock().unwrap().get(url) {
+            return short.clone();
+        }
         
-        if !self.flight_dates.is_empty() {
-            summary.push_str("\n<b>Даты с найденными рейсами:</b>\n");
-            for (date, message_id) in &self.flight_dates {
-                summary.push_str(&format!("• <a href=\"https://t.me/c/{}/{}\">{}</a>\n", 
-                    message_id.split('/').nth(0).unwrap_or(""),
-                    message_id.split('/').nth(1).unwrap_or(""),
-                    date
-                ));
+        match request_short_link(http, &config.shortener_url, &config.shortener_api_key, url).await {
+            Ok(short) => {
+                self.cache.lock().unwrap().insert(url.to_string(), short.clone());
+                short
+            }
+            Err(e) => {
+                error!("Failed to shorten {}: {}. Using the full link.", url, e);
+                url.to_string()
             }
         }
-        
-        summary
     }
 }
 
-// Add this function to update a Telegram message
-async fn update_telegram_message(
-    client: &Client,
-    bot_token: &str,
-    chat_id: &str,
-    message_id: &str,
-    message: &str,
-    topic_id: &str,
-) -> Result<(), Box<dyn Error>> {
-    let api_url = format!("https://api.telegram.org/bot{}/editMessageText", bot_token);
+// Function to ask the shortener for a short link. The long URL is POSTed as {"url": ...}, with the
+// API key as a bearer token; the reply is the short URL as plain text or in a `short_url`, `shortUrl`
+// or `link` JSON field
+async fn request_short_link(
+    http: &HttpClient,
+    shortener_url: &str,
+    api_key: &str,
+    url: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let body = json!({ "url": url });
+    let request = || {
+        let request = http.client.post(shortener_url).json(&body);
+        if api_key.is_empty() { request } else { request.bearer_auth(api_key) }
+    };
     
-    let mut json_body = json!({
-        "chat_id": chat_id,
-        "message_id": message_id,
-        "text": message,
-        "parse_mode": "HTML",
-        "disable_web_page_preview": true
-    });
+    let response = send_with_backoff(request, "Shortener", 2, http.network_retries, true, None).await?;
+    let body = response.text().await?;
+    let short = match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(json_value) => ["short_url", "shortUrl", "link"]
+            .iter()
+            .find_map(|field| json_value.get(field).and_then(|value| value.as_str()))
+            .map(str::to_string),
+        Err(_) => Some(body.trim().to_string()),
+    };
+    
+    short
+        .filter(|short| Url::parse(short).is_ok_and(|parsed| matches!(parsed.scheme(), "http" | "https")))
+        .ok_or_else(|| format!("Shortener returned no usable link: {}", body).into())
+}
 
-    // Add message_thread_id only if topic_id is not empty and not "1"
-    if !topic_id.is_empty() && topic_id != "1" {
-        json_body["message_thread_id"] = json!(topic_id);
+// Function to build the booking button for a fare, with the affiliate marker and a short link
+async fn booking_button<S: FlightSearcher, N: Notifier>(deps: &Deps<S, N>, config: &Config, link: &str) -> Option<serde_json::Value> {
+    let url = booking_url(link, &config.link_marker)?;
+    let url = deps.shortener.shorten(&deps.http, config, &url).await;
+    Some(json!({ "text": "🔗 Купить билет", "url": url }))
+}
+
+// Function to build the booking keyboard for a fare
+async fn booking_keyboard<S: FlightSearcher, N: Notifier>(deps: &Deps<S, N>, config: &Config, link: &str) -> Option<serde_json::Value> {
+    let button = booking_button(deps, config, link).await?;
+    Some(json!({ "inline_keyboard": [[button]] }))
+}
+
+// Function to build a found flight's keyboard: the booking button and, when the webhook
+// receiver can take the tap, a button that searches the flight's route and date again
+async fn flight_keyboard<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    flight: &FlightResult,
+    route: &Route,
+    departure_date: &str,
+) -> Option<serde_json::Value> {
+    let mut row: Vec<serde_json::Value> = booking_button(deps, config, &flight.link).await.into_iter().collect();
+    if deps.webhook_running {
+        row.push(json!({
+            "text": "🔄 Обновить",
+            "callback_data": format!("{}{}", REFRESH_CALLBACK_PREFIX, route_date_key(&route.origin, &route.destination, departure_date)),
+        }));
     }
-    
-    // Implement exponential backoff for rate limiting
-    let mut retry_count = 0;
-    let max_retries = 5;
-    let initial_delay = 1; // seconds
-    
-    loop {
-        let response = client
-            .post(&api_url)
-            .json(&json_body)
-            .send()
-            .await?;
-        
-        if response.status().is_success() {
-            // Add a small delay to avoid Telegram rate limits
-            time::sleep(Duration::from_millis(1000)).await;
-            return Ok(());
-        } else {
-            let status = response.status();
-            let text = response.text().await?;
-                
-            // If we hit the rate limit (429 Too Many Requests)
-            if status.as_u16() == 429 {
-                retry_count += 1;
-                
-                if retry_count > max_retries {
-                    return Err(format!("Exceeded maximum retries for Telegram API. Last error: {}", text).into());
-                }
-                
-                // Extract retry_after from response if available
-                let retry_after = if let Ok(error_json) = serde_json::from_str::<serde_json::Value>(&text) {
-                    error_json.get("parameters")
-                        .and_then(|p| p.get("retry_after"))
-                        .and_then(|r| r.as_f64())
-                        .unwrap_or_else(|| {
-                            // Calculate exponential backoff if retry_after not provided
-                            let backoff = initial_delay * 2_u64.pow(retry_count as u32);
-                            backoff as f64
-                        })
-                } else {
-                    // Fallback exponential backoff
-                    let backoff = initial_delay * 2_u64.pow(retry_count as u32);
-                    backoff as f64
-                };
-                
-                let wait_time = Duration::from_secs_f64(retry_after);
-                eprintln!("Telegram API rate limited (429). Waiting for {} seconds before retry {}/{}...", 
-                    wait_time.as_secs(), retry_count, max_retries);
-                
-                time::sleep(wait_time).await;
-            } else {
-                // Other error, not rate limiting
-                eprintln!("Telegram API request failed with status {}: {}", status, text);
-                return Err(format!("Telegram API request failed: {}", text).into());
-            }
-        }
+    if row.is_empty() {
+        return None;
     }
+    Some(json!({ "inline_keyboard": [row] }))
 }
 
-// Function to send a message and return the message ID
-async fn send_telegram_notification_with_id(
-    client: &Client,
-    bot_token: &str,
-    chat_id: &str,
-    message: &str,
-    topic_id: &str,
-    inline_keyboard: Option<serde_json::Value>,
-) -> Result<String, Box<dyn Error>> {
-    let api_url = format!("https://api.telegram.org/bot{}/sendMessage", bot_token);
-    
-    let mut json_body = json!({
-        "chat_id": chat_id,
-        "text": message,
-        "parse_mode": "HTML",
-        "disable_web_page_preview": true
-    });
+// Function to read a JSON array of strings, ignoring anything else
+fn string_list(value: Option<&serde_json::Value>) -> Vec<String> {
+    value
+        .and_then(|v| v.as_array())
+        .map(|items| items.iter().filter_map(|item| item.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default()
+}
 
-    // Add message_thread_id only if topic_id is not empty and not "1"
-    if !topic_id.is_empty() && topic_id != "1" {
-        json_body["message_thread_id"] = json!(topic_id);
+// Function to search both direct and connecting flights and merge the results
+async fn search_direct_and_connecting(
+    searcher: &TravelpayoutsSearcher,
+    origin: &str,
+    destination: &str,
+    departure_date: &str,
+    api_keys: &ApiKeyPool,
+    paging: SearchPaging,
+    currency: &str,
+) -> Result<FlightData, Box<dyn Error + Send + Sync>> {
+    let mut flight_data = search_flights(searcher, origin, destination, departure_date, api_keys, true, paging, currency).await?;
+    
+    // Respect the inter-request delay between the two calls
+    time::sleep(Duration::from_secs(1)).await;
+    
+    match search_flights(searcher, origin, destination, departure_date, api_keys, false, paging, currency).await {
+        Ok(connecting) => merge_flight_results(&mut flight_data, connecting),
+        Err(e) => error!("Error searching connecting flights for {}: {}", departure_date, e),
     }
     
-    if let Some(keyboard) = inline_keyboard {
-        json_body["reply_markup"] = keyboard;
+    Ok(flight_data)
+}
+
+// Function to merge a second result set into the first, skipping flights already present
+fn merge_flight_results(flight_data: &mut FlightData, other: FlightData) {
+    if !other.success {
+        return;
     }
     
-    // Implement exponential backoff for rate limiting
-    let mut retry_count = 0;
-    let max_retries = 5;
-    let initial_delay = 1; // seconds
+    flight_data.success = true;
+    let flights = flight_data.data.get_or_insert_with(Vec::new);
+    flights.extend(other.data.unwrap_or_default());
+    dedup_flights(flights);
+}
+
+// Function to collapse fares for the same physical flight into one, keeping the lowest price
+// at the position where the flight was first seen
+fn dedup_flights(flights: &mut Vec<FlightResult>) {
+    let before = flights.len();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut deduped: Vec<FlightResult> = Vec::with_capacity(before);
     
-    loop {
-        let response = client
-            .post(&api_url)
-            .json(&json_body)
-            .send()
-            .await?;
-        
-        if response.status().is_success() {
-            // Parse the response to get the message ID
-            let response_text = response.text().await?;
-            let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
-            
-            let message_id = response_json
-                .get("result")
-                .and_then(|result| result.get("message_id"))
-                .and_then(|id| id.as_i64())
-                .ok_or("Failed to get message ID from Telegram response")?;
-            
-            // Add a small delay to avoid Telegram rate limits
-            time::sleep(Duration::from_millis(1000)).await;
-            return Ok(message_id.to_string());
-        } else {
-            // ... existing error handling ...
-            // Same as in send_telegram_notification function
-            let status = response.status();
-            let text = response.text().await?;
-                
-            if status.as_u16() == 429 {
-                retry_count += 1;
-                
-                if retry_count > max_retries {
-                    return Err(format!("Exceeded maximum retries for Telegram API. Last error: {}", text).into());
+    for flight in flights.drain(..) {
+        match positions.get(&flight.flight_key()) {
+            Some(&i) => {
+                if flight.price < deduped[i].price {
+                    deduped[i] = flight;
                 }
-                
-                let retry_after = if let Ok(error_json) = serde_json::from_str::<serde_json::Value>(&text) {
-                    error_json.get("parameters")
-                        .and_then(|p| p.get("retry_after"))
-                        .and_then(|r| r.as_f64())
-                        .unwrap_or_else(|| {
-                            let backoff = initial_delay * 2_u64.pow(retry_count as u32);
-                            backoff as f64
-                        })
-                } else {
-                    let backoff = initial_delay * 2_u64.pow(retry_count as u32);
-                    backoff as f64
-                };
-                
-                let wait_time = Duration::from_secs_f64(retry_after);
-                eprintln!("Telegram API rate limited (429). Waiting for {} seconds before retry {}/{}...", 
-                    wait_time.as_secs(), retry_count, max_retries);
-                
-                time::sleep(wait_time).await;
-            } else {
-                eprintln!("Telegram API request failed with status {}: {}", status, text);
-                return Err(format!("Telegram API request failed: {}", text).into());
+            }
+            None => {
+                positions.insert(flight.flight_key(), deduped.len());
+                deduped.push(flight);
             }
         }
     }
+    
+    if deduped.len() < before {
+        debug!("Merged {} duplicate fares", before - deduped.len());
+    }
+    *flights = deduped;
 }
 
-// Add this new function to check for previous messages
-async fn get_previous_messages(
-    client: &Client,
-    bot_token: &str,
-    chat_id: &str,
-    topic_id: &str,
-    limit: i32,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let api_url = format!("https://api.telegram.org/bot{}/getChatHistory", bot_token);
-    
-    let mut json_body = json!({
-        "chat_id": chat_id,
-        "limit": limit
-    });
+// A source of fares for one route and date
+trait PriceProvider {
+    async fn search(&self, origin: &str, destination: &str, departure_date: &str) -> Result<Vec<FlightResult>, Box<dyn Error + Send + Sync>>;
+}
 
-    if !topic_id.is_empty() && topic_id != "1" {
-        json_body["message_thread_id"] = json!(topic_id);
-    }
-    
-    let response = client
-        .post(&api_url)
-        .json(&json_body)
-        .send()
-        .await?;
-    
-    if !response.status().is_success() {
-        let status = response.status();
-        let text = response.text().await?;
-        return Err(format!("Failed to get chat history: {} - {}", status, text).into());
-    }
-    
-    let response_text = response.text().await?;
-    let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
-    
-    let mut message_ids = Vec::new();
-    if let Some(messages) = response_json.get("result").and_then(|r| r.as_array()) {
-        for message in messages {
-            if let Some(message_id) = message.get("message_id").and_then(|id| id.as_i64()) {
-                message_ids.push(message_id.to_string());
-            }
+// Travelpayouts fares including connecting flights, for when no direct ones are found
+struct TravelpayoutsProvider<'a> {
+    searcher: &'a TravelpayoutsSearcher,
+    api_keys: &'a ApiKeyPool,
+    paging: SearchPaging,
+    currency: &'a str,
+}
+
+impl PriceProvider for TravelpayoutsProvider<'_> {
+    async fn search(&self, origin: &str, destination: &str, departure_date: &str) -> Result<Vec<FlightResult>, Box<dyn Error + Send + Sync>> {
+        let flight_data = search_flights(self.searcher, origin, destination, departure_date, self.api_keys, false, self.paging, self.currency).await?;
+        if !flight_data.success {
+            return Err(flight_data.error.unwrap_or_else(|| "Travelpayouts search failed".to_string()).into());
         }
+        Ok(flight_data.data.unwrap_or_default())
     }
-    
-    Ok(message_ids)
 }
 
-// Add this function to check if a message was sent in the last 48 hours
-async fn was_message_sent_recently(
-    client: &Client,
-    bot_token: &str,
-    chat_id: &str,
-    topic_id: &str,
-    message_text: &str,
-) -> Result<bool, Box<dyn Error>> {
-    // Get messages from the last 48 hours
-    let previous_messages = get_previous_messages(client, bot_token, chat_id, topic_id, 100).await?;
-    
-    // Check if a similar message exists
-    for message_id in previous_messages {
-        let api_url = format!("https://api.telegram.org/bot{}/getMessage", bot_token);
-        let json_body = json!({
-            "chat_id": chat_id,
-            "message_id": message_id
-        });
+// A generic JSON API returning an array of fares, or an object with a `data` array, using
+// the Travelpayouts field names. Fares without a currency are taken to be in rubles
+struct JsonApiProvider<'a> {
+    http: &'a HttpClient,
+    // URL with `{origin}`, `{destination}` and `{date}` placeholders
+    url_template: &'a str,
+}
+
+impl PriceProvider for JsonApiProvider<'_> {
+    async fn search(&self, origin: &str, destination: &str, departure_date: &str) -> Result<Vec<FlightResult>, Box<dyn Error + Send + Sync>> {
+        let url = self.url_template
+            .replace("{origin}", origin)
+            .replace("{destination}", destination)
+            .replace("{date}", departure_date);
+        debug!("Fallback request URL: {}", url);
         
-        let response = client
-            .post(&api_url)
-            .json(&json_body)
-            .send()
-            .await?;
+        let response = send_with_backoff(|| self.http.client.get(&url), "Fallback provider", 2, self.http.network_retries, true, None).await?;
+        let json_value: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+        let items = json_value.as_array()
+            .or_else(|| json_value.get("data").and_then(|data| data.as_array()))
+            .ok_or("Fallback provider response has no list of fares")?;
         
-        if response.status().is_success() {
-            let response_text = response.text().await?;
-            let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
-            
-            if let Some(message) = response_json.get("result").and_then(|r| r.get("text")) {
-                if let Some(text) = message.as_str() {
-                    // Compare the message text (ignoring timestamps and dynamic content)
-                    if text.contains(message_text) {
-                        return Ok(true);
-                    }
+        Ok(items
+            .iter()
+            .filter_map(|item| {
+                let mut flight = flight_result_from_json(item)?;
+                if flight.origin.is_empty() {
+                    flight.origin = origin.to_string();
                 }
-            }
-        }
+                if flight.destination.is_empty() {
+                    flight.destination = destination.to_string();
+                }
+                Some(flight)
+            })
+            .collect())
+    }
+}
+
+// Secondary fare source tried when Travelpayouts returns nothing (FALLBACK_PROVIDER)
+#[derive(Debug, Clone, PartialEq)]
+enum FallbackProvider {
+    // Travelpayouts connecting fares
+    Travelpayouts,
+    // A generic JSON API, see JsonApiProvider
+    JsonApi(String),
+}
+
+// Function to parse FALLBACK_PROVIDER: `travelpayouts`, or an http(s) URL template
+fn parse_fallback_provider(raw: &str) -> Result<Option<FallbackProvider>, BotError> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    if raw.eq_ignore_ascii_case("travelpayouts") {
+        return Ok(Some(FallbackProvider::Travelpayouts));
     }
     
-    Ok(false)
+    match Url::parse(raw) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(Some(FallbackProvider::JsonApi(raw.to_string()))),
+        _ => Err(BotError::Config(format!(
+            "FALLBACK_PROVIDER must be `travelpayouts` or an http(s) URL, got {:?}", raw
+        ))),
+    }
 }
 
-// TODO: Create schedule checker for date from 15 sept 2025 to 30 sept 2025
-// for available dates in the aero flights aviasales.ru each 6 hours
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Load environment variables from .env file
-    dotenv().ok();
+// Function to search the fallback provider when the primary found nothing, merging its fares
+async fn search_with_fallback(
+    searcher: &TravelpayoutsSearcher,
+    config: &Config,
+    route: &Route,
+    departure_date: &str,
+    mut flight_data: FlightData,
+) -> FlightData {
+    let primary_empty = flight_data.data.as_ref().is_none_or(Vec::is_empty);
+    let Some(fallback) = config.fallback_provider.as_ref().filter(|_| primary_empty) else {
+        return flight_data;
+    };
     
-    // Get API keys from environment variables
-    let aviasales_api_key = env::var("TRAVELPAYOUTS_API_KEY")
-        .expect("TRAVELPAYOUTS_API_KEY not found in environment variables");
+    info!("No fares from Travelpayouts for {}-{} on {}, trying the fallback provider", route.origin, route.destination, departure_date);
+    let result = match fallback {
+        FallbackProvider::Travelpayouts => TravelpayoutsProvider {
+            searcher,
+            api_keys: &config.aviasales_api_keys,
+            paging: config.search_paging,
+            currency: route.currency(&config.currency),
+        }.search(&route.origin, &route.destination, departure_date).await,
+        FallbackProvider::JsonApi(url_template) => JsonApiProvider {
+            http: &searcher.http,
+            url_template,
+        }.search(&route.origin, &route.destination, departure_date).await,
+    };
     
-    // Get Telegram bot token and chat ID from environment variables
-    let telegram_bot_token = env::var("TELEGRAM_BOT_TOKEN")
-        .unwrap_or_else(|_| {
-            println!("TELEGRAM_BOT_TOKEN not found in environment variables. Notifications will not be sent.");
-            String::new()
-        });
+    match result {
+        Ok(flights) => merge_flight_results(&mut flight_data, FlightData {
+            success: true,
+            data: Some(flights),
+            currency: None,
+            error: None,
+            parse_warning: None,
+        }),
+        Err(e) => error!("Fallback provider failed for {}-{} on {}: {}", route.origin, route.destination, departure_date, e),
+    }
     
-    let telegram_chat_id = env::var("TELEGRAM_CHAT_ID")
-        .unwrap_or_else(|_| {
-            println!("TELEGRAM_CHAT_ID not found in environment variables. Notifications will not be sent.");
-            String::new()
-        });
+    flight_data
+}
 
-    // Get general topic ID for flight notifications
-    let telegram_devlogs_topic_id = env::var("TELEGRAM_DEVLOGS_TOPIC_ID")
-        .unwrap_or_else(|_| {
-            println!("TELEGRAM_DEVLOGS_TOPIC_ID not found in environment variables. Dev logs notifications will not be sent.");
-            String::new()
-        });
-    let telegram_found_topic_id = env::var("TELEGRAM_FOUND_TOPIC_ID")
-        .unwrap_or_else(|_| {
-            println!("TELEGRAM_FOUND_TOPIC_ID not found in environment variables. Flight found notifications will not be sent.");
+// Function to compare the cheapest direct fare with the cheapest connecting fare
+fn format_direct_premium(display: &DisplaySettings, flights: &[&FlightResult]) -> Option<String> {
+    // Fares of one search share the route's currency, so raw prices compare fine
+    let direct_min = flights.iter().filter(|f| f.transfers == 0).min_by_key(|f| f.price);
+    let connecting_min = flights.iter().filter(|f| f.transfers > 0).min_by_key(|f| f.price);
+    
+    match (direct_min, connecting_min) {
+        (Some(direct), Some(connecting)) => Some(format!(
+            "прямой от {} / с пересадкой от {}",
+            direct.display_price(display),
+            connecting.display_price(display)
+        )),
+        (Some(direct), None) => Some(format!("прямой от {}", direct.display_price(display))),
+        (None, Some(connecting)) => Some(format!("с пересадкой от {}", connecting.display_price(display))),
+        (None, None) => None,
+    }
+}
+
+// Function to describe the transfers of one leg
+fn format_transfers(display: &DisplaySettings, transfers: i64, connections: &[String]) -> String {
+    if transfers == 0 {
+        "прямой".to_string()
+    } else if connections.is_empty() {
+        format!("пересадок: {}", transfers)
+    } else {
+        let airports: Vec<String> = connections.iter().map(|code| get_airport_name(display, code)).collect();
+        format!("пересадки: {}", airports.join(", "))
+    }
+}
+
+// Function to name an airport by the city it serves, keeping the code for clarity
+fn get_airport_name(display: &DisplaySettings, code: &str) -> String {
+    let name = get_city_name(display, code);
+    if name.eq_ignore_ascii_case(code) {
+        code.to_string()
+    } else {
+        format!("{} ({})", name, code)
+    }
+}
+
+// Function to render the outbound leg and, for round trips, the return leg with its own
+// departure time, duration and transfers
+fn format_trip(display: &DisplaySettings, flight: &FlightResult, precision: DurationPrecision) -> String {
+    let leg_details = |duration: Option<i64>, transfers: Option<i64>, connections: &[String]| -> String {
+        let details: Vec<String> = duration
+            .map(|minutes| format_duration(precision.apply(minutes)))
+            .into_iter()
+            .chain(transfers.map(|transfers| format_transfers(display, transfers, connections)))
+            .collect();
+        
+        if details.is_empty() {
             String::new()
-        });
+        } else {
+            format!(" ({})", details.join(", "))
+        }
+    };
     
-    // Get AirLabs API key
-    let airlabs_api_key = env::var("AIRLABS_API_KEY")
-        .unwrap_or_else(|_| {
-            println!("AIRLABS_API_KEY not found in environment variables. AirLabs enrichment will not be available.");
+    let Some(return_at) = flight.return_at.as_deref().filter(|value| !value.is_empty()) else {
+        // One-way fares only mention transfers when there are any
+        let transfers = if flight.transfers > 0 {
+            format!(" ({})", format_transfers(display, flight.transfers, &flight.connections))
+        } else {
             String::new()
-        });
+        };
+        return format!("🕒 Вылет: {}{}\n", format_datetime_ru(display, &flight.departure_at), transfers);
+    };
     
-    let enable_telegram = !telegram_bot_token.is_empty() && !telegram_chat_id.is_empty();
-    let enable_secondary_notifications = !telegram_bot_token.is_empty() && !telegram_chat_id.is_empty();
-    let enable_airlabs = !airlabs_api_key.is_empty();
+    format!(
+        "➡️ Туда: {}{}\n\
+         ⬅️ Обратно: {}{}\n",
+        format_datetime_ru(display, &flight.departure_at),
+        leg_details(flight.duration_to.or(flight.duration), Some(flight.transfers), &flight.connections),
+        format_datetime_ru(display, return_at),
+        leg_details(flight.duration_back, flight.return_transfers, &flight.return_connections)
+    )
+}
+
+// Function to show a price with its change since the last recorded one, e.g. "12 340 ₽ ↓ 800".
+// First sightings show the price alone
+fn format_price_trend(display: &DisplaySettings, price: i64, previous: Option<i64>, currency: &str) -> String {
+    let Some(previous) = previous else {
+        return format_price_in(display, price, currency);
+    };
     
-    // Create HTTP client
-    let client = Client::new();
+    let delta = price - previous;
+    let trend = match delta.signum() {
+        1 => format!("↑ {}", format_amount(delta as f64, 0, display.locale)),
+        -1 => format!("↓ {}", format_amount(-delta as f64, 0, display.locale)),
+        _ => "→".to_string(),
+    };
+    format!("{} {}", format_price_in(display, price, currency), trend)
+}
+
+// Function to describe a price that beat the stored minimum for PRICE_DROP_ONLY, e.g.
+// "было 15 000 ₽, стало 11 500 ₽, −3 500 ₽"
+fn format_price_drop(display: &DisplaySettings, previous_low: i64, price: i64, currency: &str) -> String {
+    format!(
+        "было {}, стало {}, −{}",
+        format_price_in(display, previous_low, currency),
+        format_price_in(display, price, currency),
+        format_price_in(display, previous_low - price, currency)
+    )
+}
+
+// Function to format the single message sent after the first cycle of SUPPRESS_FIRST_RUN
+fn format_init_summary(dates_with_flights: usize, flights_found: usize) -> String {
+    format!(
+        "🗂 <b>Инициализация завершена</b>\n\n\
+        Запомнено {} {} на {} {}.\n\
+        <i>Уведомления о новых рейсах и снижении цен начнутся со следующего цикла.</i>",
+        flights_found,
+        plural_ru(flights_found as i64, "рейс", "рейса", "рейсов"),
+        dates_with_flights,
+        plural_ru(dates_with_flights as i64, "дату", "даты", "дат")
+    )
+}
+
+// Function to rank the cheapest fares of the whole search window for SUMMARY_ONLY,
+// one line per fare with its route and date; None when nothing was found
+fn format_window_summary(
+    display: &DisplaySettings,
+    fares: &[FlightResult],
+    date_range: &str,
+    max_flights: usize,
+    rates: &HashMap<String, f64>,
+) -> Option<String> {
+    let mut fares: Vec<&FlightResult> = fares.iter().collect();
+    if fares.is_empty() {
+        return None;
+    }
+    sort_flights(&mut fares, SortBy::Price, rates);
     
-    // Define search parameters
-    let origin = env::var("ORIGIN")
-    .unwrap_or_else(|_| {
-        println!("ORIGIN not found in environment variables.");
-        String::new()
-    }); // Origin (all airports)
-    let destination = env::var("DESTINATION")
-    .unwrap_or_else(|_| {
-        println!("DESTINATION not found in environment variables.");
-        String::new()
-    }); // Destination
+    let mut message = format!("🏆 <b>Самые дешёвые билеты на {}</b>\n\n", date_range);
+    for (place, fare) in fares.iter().take(max_flights).enumerate() {
+        message.push_str(&format!(
+            "{}. {} → {}: {}\n",
+            place + 1,
+            get_city_name(display, &fare.origin),
+            get_city_name(display, &fare.destination),
+            format_flight_compact(display, fare)
+        ));
+    }
     
-    let start_date_env = env::var("START_DATE")
-    .unwrap_or_else(|_| {
-        println!("START_DATE not found in environment variables.");
-        String::new()
-    });
-    let end_date_env = env::var("END_DATE")
-    .unwrap_or_else(|_| {
-        println!("END_DATE not found in environment variables.");
-        String::new()
-    });
-    // Define date range
-    let start_date = NaiveDate::parse_from_str(&start_date_env, "%Y-%m-%d")?;
-    let end_date = NaiveDate::parse_from_str(&end_date_env, "%Y-%m-%d")?;
+    let remaining = fares.len().saturating_sub(max_flights);
+    if remaining > 0 {
+        message.push_str(&format!(
+            "\n... и еще {} {}",
+            remaining,
+            plural_ru(remaining as i64, "вариант", "варианта", "вариантов")
+        ));
+    }
     
-    // Create date range string for display
-    let date_range_str = format_date_range_ru(&start_date, &end_date);
+    Some(message)
+}
+
+// Function to render a flight as a single line for COMPACT mode, e.g.
+// "16.09 12 340 ₽ DP 14:30→17:10 прямой", with times in the display timezone
+fn format_flight_compact(display: &DisplaySettings, flight: &FlightResult) -> String {
+    let (date, times) = match parse_departure_at(display, &flight.departure_at) {
+        Some(departure) => {
+            let times = match flight.duration_to.or(flight.duration) {
+                Some(minutes) => format!(
+                    "{}→{}",
+                    departure.format("%H:%M"),
+                    (departure + chrono::Duration::minutes(minutes)).format("%H:%M")
+                ),
+                None => departure.format("%H:%M").to_string(),
+            };
+            (departure.format("%d.%m").to_string(), times)
+        }
+        None => (String::new(), flight.departure_at.clone()),
+    };
+    let transfers = match flight.transfers {
+        0 => "прямой".to_string(),
+        transfers => format!("{} {}", transfers, plural_ru(transfers, "пересадка", "пересадки", "пересадок")),
+    };
     
-    // Initialize statistics tracking
-    let mut stats = SearchStatistics::new();
-    let mut status_message_id: Option<String> = None;
+    format!("{} {} {} {} {}", date, flight.display_price(display), format_flight_designator(&flight.airline, &flight.flight_number), times, transfers)
+        .trim_start()
+        .to_string()
+}
 
-    let dates = date_range(start_date, end_date);
+// Function to point out the fastest flight for a date, next to the cheapest one when they differ
+fn format_fastest(display: &DisplaySettings, flights: &[&FlightResult], precision: DurationPrecision) -> Option<String> {
+    let fastest = flights
+        .iter()
+        .filter_map(|f| f.duration.map(|duration| (duration, *f)))
+        .min_by_key(|(duration, f)| (*duration, f.price))?;
+    let cheapest = flights.iter().min_by_key(|f| f.price)?;
     
-    // Check flights every 6 hours
-    let hours_interval = 6;
-    let check_interval = Duration::from_secs(hours_interval * 60 * 60);
+    let (duration, fastest) = fastest;
+    let fastest_line = format!(
+        "самый быстрый: {} ({})",
+        format_duration(precision.apply(duration)),
+        escape_html(get_airline_name(&fastest.airline))
+    );
     
-    // Send startup notification
-    if enable_telegram {
-        let origin_name = get_city_name(&origin);
-        let destination_name = get_city_name(&destination);
-        let startup_message = format!(
-            "🛫 <b>Программа поиска авиабилетов запущена!</b>\n\n\
-             Будет проверять прямые рейсы из <b>{}</b> в <b>{}</b> {}.\n\
-             Поиск будет происходить каждые {} часов.\n\n\
-             <i>Этот статус будет обновляться с результатами поиска.</i>",
-            origin_name, destination_name, date_range_str, hours_interval
-        );
+    if fastest.price <= cheapest.price {
+        Some(format!("{}, он же самый дешёвый", fastest_line))
+    } else {
+        Some(format!(
+            "{} за {} / самый дешёвый: {} ({})",
+            fastest_line,
+            fastest.display_price(display),
+            cheapest.display_price(display),
+            escape_html(get_airline_name(&cheapest.airline))
+        ))
+    }
+}
+
+fn date_range(start_date: NaiveDate, end_date: NaiveDate) -> Vec<NaiveDate> {
+    let mut dates = Vec::new();
+    let mut current_date = start_date;
+    
+    while current_date <= end_date {
+        dates.push(current_date);
+        current_date = current_date.succ_opt().unwrap();
+    }
+    
+    dates
+}
+
+// How far an AirLabs departure may be from the Travelpayouts one to count as the same flight
+const AIRLABS_MATCH_TOLERANCE_MINUTES: i64 = 120;
+
+// Function to pick the AirLabs flight departing closest to the expected local departure time
+// (codeshares and several daily frequencies share a flight number); None when none is close enough
+fn closest_airlabs_flight(flights: Vec<AirLabsFlight>, departure_at: &str) -> Option<AirLabsFlight> {
+    let expected = parse_departure_local(departure_at)?;
+    
+    flights
+        .into_iter()
+        .filter_map(|flight| {
+            let dep_time = NaiveDateTime::parse_from_str(flight.dep_time.as_deref()?, "%Y-%m-%d %H:%M").ok()?;
+            let offset = (dep_time - expected).num_minutes().abs();
+            (offset <= AIRLABS_MATCH_TOLERANCE_MINUTES).then_some((offset, flight))
+        })
+        .min_by_key(|(offset, _)| *offset)
+        .map(|(_, flight)| flight)
+}
+
+// Function to query AirLabs API for flight information
+// With an expected departure (Travelpayouts departure_at), returns the matching flight instead of the first one
+async fn get_airlabs_flight_info(
+    http: &HttpClient,
+    api_base: &str,
+    airline_code: &str,
+    flight_number: &str,
+    expected_departure: Option<&str>,
+    api_key: &str,
+    max_retries: u32,
+) -> Result<Option<AirLabsFlight>, Box<dyn Error + Send + Sync>> {
+    // Build the AirLabs API URL
+    let api_url = api_url(api_base, "/api/v9/flight");
+    
+    let params = [
+        ("api_key", api_key),
+        ("flight_iata", &format_flight_designator(airline_code, flight_number).replace(' ', "")),
+    ];
+
+    debug!("Querying AirLabs API for flight: {}", format_flight_designator(airline_code, flight_number));
+    
+    // Make the request, retrying on rate limiting and server errors
+    let request = || http.client.get(&api_url).query(&params);
+    let response = send_with_backoff(request, "AirLabs", max_retries, http.network_retries, true, None).await?;
+    
+    // Parse the response
+    let response_text = response.text().await?;
+    trace!("AirLabs API response: {}", response_text);
+    
+    let airlabs_response: serde_json::Value = serde_json::from_str(&response_text)?;
+    
+    // Check if there's an error
+    if let Some(error) = airlabs_response.get("error")
+        && let Some(message) = error.get("message").and_then(|m| m.as_str())
+    {
+        error!("AirLabs API error: {}", message);
+        return Err(format!("AirLabs API error: {}", message).into());
+    }
+    
+    // Check if we have response data
+    if let Some(flights) = airlabs_response.get("response").and_then(|r| r.as_array())
+        && !flights.is_empty()
+    {
+        let flights: Vec<AirLabsFlight> = serde_json::from_value(serde_json::Value::Array(flights.clone()))?;
+        
+        let Some(departure_at) = expected_departure else {
+            return Ok(flights.into_iter().next());
+        };
+        
+        let flight = closest_airlabs_flight(flights, departure_at);
+        if flight.is_none() {
+            info!("No AirLabs flight {} departs close to {}", format_flight_designator(airline_code, flight_number), departure_at);
+        }
+        return Ok(flight);
+    }
+    
+    Ok(None)
+}
+
+// Function to enrich flight data with AirLabs information
+// Returns Ok(None) when AirLabs has no such flight and Err when the request failed after retries
+async fn enrich_with_airlabs_data(
+    http: &HttpClient,
+    api_base: &str,
+    flight: &FlightResult,
+    airlabs_api_key: &str,
+    max_retries: u32,
+) -> Result<Option<AirLabsFlight>, Box<dyn Error + Send + Sync>> {
+    // Extract airline code and flight number
+    let airline_code = &flight.airline;
+    let flight_number = &flight.flight_number;
+    
+    // Query AirLabs API
+    get_airlabs_flight_info(http, api_base, airline_code, flight_number, Some(&flight.departure_at), airlabs_api_key, max_retries).await
+}
+
+// Airline filter configured via AIRLINES_ALLOW and AIRLINES_DENY (comma-separated IATA codes)
+#[derive(Debug, Default)]
+struct AirlineFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl AirlineFilter {
+    fn new(allow: &str, deny: &str) -> Self {
+        let parse = |raw: &str| -> Vec<String> {
+            raw.split(',')
+                .map(normalize_code)
+                .filter(|code| !code.is_empty())
+                .collect()
+        };
+        
+        Self {
+            allow: parse(allow),
+            deny: parse(deny),
+        }
+    }
+
+    // The allow-list takes precedence when both lists are set
+    fn allows(&self, airline: &str) -> bool {
+        let airline = normalize_code(airline);
+        if !self.allow.is_empty() {
+            return self.allow.contains(&airline);
+        }
+        
+        !self.deny.contains(&airline)
+    }
+
+    // Human-readable description of the active filter for the startup message
+    fn describe(&self) -> Option<String> {
+        let names = |codes: &[String]| -> String {
+            codes.iter()
+                .map(|code| get_airline_name(code))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
         
-        // Send startup message and store message ID
-        match send_telegram_notification_with_id(
-            &client, 
-            &telegram_bot_token, 
-            &telegram_chat_id, 
-            &startup_message, 
-            &telegram_devlogs_topic_id, 
+        if !self.allow.is_empty() {
+            Some(format!("только {}", names(&self.allow)))
+        } else if !self.deny.is_empty() {
+            Some(format!("кроме {}", names(&self.deny)))
+        } else {
             None
-        ).await {
-            Ok(message_id) => {
-                status_message_id = Some(message_id.clone());
-                println!("Status message created with ID: {}", message_id);
-            },
-            Err(e) => {
-                eprintln!("Failed to send initial status message: {}", e);
+        }
+    }
+}
+
+// Appends every found flight to a CSV file; writes are serialized through a mutex
+struct CsvExporter {
+    writer: Mutex<csv::Writer<std::fs::File>>,
+}
+
+impl CsvExporter {
+    fn open(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_new = file.metadata()?.len() == 0;
+        
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(file);
+        
+        // Write the header only when the file is first created
+        if is_new {
+            writer.write_record([
+                "found_at", "origin", "destination", "origin_airport", "destination_airport",
+                "departure_at", "return_at", "price", "currency", "airline", "flight_number",
+                "transfers", "duration",
+            ])?;
+        }
+        
+        Ok(Self { writer: Mutex::new(writer) })
+    }
+
+    fn write_flight(
+        &self,
+        flight: &FlightResult,
+        currency: &str,
+        found_at: DateTime<Utc>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut writer = self.writer.lock().map_err(|_| "CSV writer lock poisoned")?;
+        
+        writer.write_record([
+            found_at.to_rfc3339(),
+            flight.origin.clone(),
+            flight.destination.clone(),
+            flight.origin_airport.clone(),
+            flight.destination_airport.clone(),
+            flight.departure_at.clone(),
+            flight.return_at.clone().unwrap_or_default(),
+            flight.price.to_string(),
+            currency.to_string(),
+            flight.airline.clone(),
+            flight.flight_number.clone(),
+            flight.transfers.to_string(),
+            flight.duration.map(|d| d.to_string()).unwrap_or_default(),
+        ])?;
+        
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut writer = self.writer.lock().map_err(|_| "CSV writer lock poisoned")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// One line of the NDJSON output: the found flight plus where and when it was found
+#[derive(Serialize)]
+struct NdjsonRecord<'a> {
+    found_at: DateTime<Utc>,
+    route: String,
+    #[serde(flatten)]
+    flight: &'a FlightResult,
+}
+
+// Writes every found flight as a JSON line to stdout or a file; each line is flushed immediately
+struct NdjsonExporter {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl NdjsonExporter {
+    fn open(target: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let writer: Box<dyn Write + Send> = if target == "stdout" || target == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(target)?)
+        };
+        Ok(Self { writer: Mutex::new(writer) })
+    }
+
+    fn write_flight(
+        &self,
+        flight: &FlightResult,
+        route: &Route,
+        found_at: DateTime<Utc>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let record = NdjsonRecord {
+            found_at,
+            route: format!("{}-{}", route.origin, route.destination),
+            flight,
+        };
+        let line = serde_json::to_string(&record)?;
+        
+        let mut writer = self.writer.lock().map_err(|_| "NDJSON writer lock poisoned")?;
+        writeln!(writer, "{}", line)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// State persisted between runs in STATE_FILE
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct BotState {
+    // Last notification per route and date, used for the notification cooldown
+    #[serde(default)]
+    notifications: HashMap<String, NotificationRecord>,
+    // Observed cheapest prices per route and date
+    #[serde(default)]
+    price_history: PriceHistoryStore,
+    // When the daily recap schedule was last checked
+    #[serde(default)]
+    last_recap_check_at: Option<DateTime<Utc>>,
+    // Start of the next rotating slice of dates when MAX_DATES_PER_CYCLE is set
+    #[serde(default)]
+    date_offset: usize,
+    // Flight availability per route and date, used to detect sold-out dates
+    #[serde(default)]
+    availability: HashMap<String, DateAvailability>,
+    // Last AirLabs status seen per watched flight (WATCH_FLIGHTS), keyed by flight IATA designator
+    #[serde(default)]
+    flight_statuses: HashMap<String, String>,
+    // When each notification text was last sent, used to avoid resending within DEDUP_WINDOW_HOURS
+    #[serde(default)]
+    sent_messages: HashMap<String, DateTime<Utc>>,
+    // Status message per chat and topic, reused across restarts
+    #[serde(default)]
+    status_messages: HashMap<String, String>,
+    // When each status message was posted, keyed like `status_messages`
+    #[serde(default)]
+    status_posted_at: HashMap<String, DateTime<Utc>>,
+    // Price alerts group members added with /subscribe
+    #[serde(default)]
+    subscriptions: Vec<Subscription>,
+    // Flight key of each per-flight notification, keyed by message ID, for reactions
+    #[serde(default)]
+    flight_messages: HashMap<String, String>,
+    // Flight keys acknowledged with a 👍 reaction, not notified again at any price
+    #[serde(default)]
+    acknowledged_flights: HashSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DateAvailability {
+    had_flights: bool,
+    empty_cycles: u32,
+}
+
+// Number of consecutive empty cycles before a date is considered sold out
+const SOLD_OUT_EMPTY_CYCLES: u32 = 2;
+
+// Cheapest price observations per route and date, keyed by `route_date_key`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PriceHistoryStore {
+    #[serde(default)]
+    entries: HashMap<String, Vec<PricePoint>>,
+    // All-time cheapest price per route (`ORIGIN-DESTINATION`), kept beyond the retention window
+    #[serde(default)]
+    route_records: HashMap<String, i64>,
+    // Cheapest price notified per route and date (PRICE_DROP_ONLY), kept until the departure date
+    #[serde(default)]
+    date_lows: HashMap<String, i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PricePoint {
+    observed_at: DateTime<Utc>,
+    price: i64,
+}
+
+impl PriceHistoryStore {
+    // Record a price unless it moved no more than `noise_threshold` percent from the last
+    // recorded one; the first price is always recorded. Returns whether a point was added
+    fn record(&mut self, key: String, price: i64, noise_threshold: Option<f64>) -> bool {
+        let points = self.entries.entry(key).or_default();
+        if let (Some(last), Some(threshold)) = (points.last(), noise_threshold)
+            && (price - last.price).abs() as f64 <= last.price.abs() as f64 * threshold / 100.0
+        {
+            return false;
+        }
+        
+        points.push(PricePoint {
+            observed_at: Utc::now(),
+            price,
+        });
+        true
+    }
+
+    // Track the all-time cheapest price for a route; returns the previous record when it was
+    // beaten. The first observation only establishes the baseline
+    fn record_route_low(&mut self, route_key: &str, price: i64) -> Option<i64> {
+        match self.route_records.get(route_key).copied() {
+            Some(previous) if price < previous => {
+                self.route_records.insert(route_key.to_string(), price);
+                Some(previous)
             }
+            Some(_) => None,
+            None => {
+                self.route_records.insert(route_key.to_string(), price);
+                None
+            }
+        }
+    }
+
+    // Lowest price stored for a route and date
+    fn date_low(&self, key: &str) -> Option<i64> {
+        self.date_lows.get(key).copied()
+    }
+
+    // Store the price as the route and date's minimum unless a lower one is already stored
+    fn update_date_low(&mut self, key: &str, price: i64) {
+        let low = self.date_lows.entry(key.to_string()).or_insert(price);
+        *low = (*low).min(price);
+    }
+
+    // Most recently recorded price for a route and date
+    fn last_price(&self, key: &str) -> Option<i64> {
+        self.entries.get(key)?.last().map(|point| point.price)
+    }
+
+    // Drop observations older than the retention window
+    fn prune(&mut self, retention: chrono::Duration) {
+        let cutoff = Utc::now() - retention;
+        for points in self.entries.values_mut() {
+            points.retain(|point| point.observed_at >= cutoff);
         }
+        self.entries.retain(|_, points| !points.is_empty());
+        
+        // Minimums only matter until the departure date, the last part of the key
+        let today = Utc::now().date_naive();
+        self.date_lows.retain(|key, _| {
+            key.rsplit(':')
+                .next()
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+                .is_none_or(|date| date >= today)
+        });
+    }
+
+    // Price at the given percentile of the observations for a route and date, once there are
+    // at least `min_samples` of them
+    fn percentile(&self, key: &str, percentile: f64, min_samples: usize) -> Option<i64> {
+        let mut prices: Vec<i64> = self.entries.get(key)?.iter().map(|point| point.price).collect();
+        if prices.is_empty() || prices.len() < min_samples {
+            return None;
+        }
+        
+        prices.sort_unstable();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (prices.len() - 1) as f64).floor() as usize;
+        prices.get(rank).copied()
+    }
+
+    // Cheapest price per route and date observed since the given instant, sorted by key
+    fn cheapest_since(&self, since: DateTime<Utc>) -> Vec<(String, i64)> {
+        let mut cheapest: Vec<(String, i64)> = self.entries
+            .iter()
+            .filter_map(|(key, points)| {
+                points.iter()
+                    .filter(|point| point.observed_at >= since)
+                    .map(|point| point.price)
+                    .min()
+                    .map(|price| (key.clone(), price))
+            })
+            .collect();
+        
+        cheapest.sort();
+        cheapest
+    }
+}
+
+// Size of the rendered price history chart in pixels
+const PRICE_CHART_SIZE: (u32, u32) = (640, 320);
+
+// Function to render a price history as a PNG line chart; None when there is nothing to plot yet
+fn render_price_chart(points: &[PricePoint]) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return Ok(None);
+    };
+    if points.len() < 2 {
+        return Ok(None);
     }
     
-    loop {
-        // Reset statistics for this search cycle
-        stats = SearchStatistics::new();
-        
-        let search_start_time = Utc::now();
-        let formatted_start_time = format_utc_datetime_ru(search_start_time);
-        println!("Starting flight search at {}", formatted_start_time);
-        
-        if enable_telegram && status_message_id.is_some() {
-            let cycle_start_message = format!(
-                "🛫 <b>Программа поиска авиабилетов</b>\n\n\
-                🔍 Начат цикл поиска рейсов: {}\n\
-                🗓 Проверяемые даты: {}\n\n\
-                <i>Статус будет обновляться...</i>",
-                formatted_start_time, date_range_str
-            );
-            
-            // Update status message
-            if let Err(e) = update_telegram_message(
-                &client, 
-                &telegram_bot_token, 
-                &telegram_chat_id, 
-                &status_message_id.as_ref().unwrap(), 
-                &cycle_start_message, 
-                &telegram_devlogs_topic_id
-            ).await {
-                eprintln!("Failed to update status message: {}", e);
-            }
+    let start = first.observed_at.timestamp();
+    let end = last.observed_at.timestamp().max(start + 1);
+    let min_price = points.iter().map(|point| point.price).min().unwrap_or_default();
+    let max_price = points.iter().map(|point| point.price).max().unwrap_or_default();
+    let padding = ((max_price - min_price) / 10).max(100);
+    
+    let (width, height) = PRICE_CHART_SIZE;
+    let mut pixels = vec![0u8; (width * height * 3) as usize];
+    {
+        let root = BitMapBackend::with_buffer(&mut pixels, (width, height)).into_drawing_area();
+        root.fill(&WHITE)?;
+        
+        let mut chart = ChartBuilder::on(&root)
+            .margin(20)
+            .build_cartesian_2d(start..end, (min_price - padding)..(max_price + padding))?;
+        let series = points.iter().map(|point| (point.observed_at.timestamp(), point.price));
+        chart.draw_series(LineSeries::new(series.clone(), BLUE.stroke_width(3)))?;
+        chart.draw_series(series.map(|coord| Circle::new(coord, 4, BLUE.filled())))?;
+        
+        root.present()?;
+    }
+    
+    let mut png = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut png)
+        .write_image(&pixels, width, height, image::ColorType::Rgb8)?;
+    Ok(Some(png))
+}
+
+// How long price observations are kept
+const PRICE_HISTORY_RETENTION_DAYS: i64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct NotificationRecord {
+    last_notified_at: DateTime<Utc>,
+    price: i64,
+}
+
+// On-disk encoding of persisted state (PERSIST_FORMAT)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PersistFormat {
+    // Human-readable, the default
+    Json,
+    // Compact binary
+    Bincode,
+}
+
+impl PersistFormat {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "bincode" => PersistFormat::Bincode,
+            _ => PersistFormat::Json,
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            PersistFormat::Json => PersistFormat::Bincode,
+            PersistFormat::Bincode => PersistFormat::Json,
         }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        Ok(match self {
+            PersistFormat::Json => serde_json::to_vec_pretty(value)?,
+            PersistFormat::Bincode => bincode::serialize(value)?,
+        })
+    }
+
+    fn decode<T: serde::de::DeserializeOwned>(self, bytes: &[u8]) -> Result<T, Box<dyn Error + Send + Sync>> {
+        Ok(match self {
+            PersistFormat::Json => serde_json::from_slice(bytes)?,
+            PersistFormat::Bincode => bincode::deserialize(bytes)?,
+        })
+    }
+}
+
+// A file holding one persisted value in the configured format
+struct Store {
+    path: String,
+    format: PersistFormat,
+}
+
+impl Store {
+    // Read the value, also accepting the other format so a PERSIST_FORMAT change migrates the
+    // file on the next save. A missing or unreadable file yields the default value
+    fn load<T: serde::de::DeserializeOwned + Default>(&self) -> T {
+        let Ok(bytes) = std::fs::read(&self.path) else {
+            return T::default();
+        };
         
-        for date in &dates {
-            let departure_date = date.format("%Y-%m-%d").to_string();
-            
-            // Display the date in Russian format for logs
-            let formatted_date = format!("{} {} {}", 
-                date.day(), 
-                match date.month() {
-                    1 => "января",
-                    2 => "февраля",
-                    3 => "марта",
-                    4 => "апреля",
-                    5 => "мая",
-                    6 => "июня",
-                    7 => "июля",
-                    8 => "августа",
-                    9 => "сентября",
-                    10 => "октября",
-                    11 => "ноября",
-                    12 => "декабря",
-                    _ => "",
-                },
-                date.year()
-            );
-            
-            // Update statistics for checked date
-            stats.total_dates_checked += 1;
-            
-            match search_flights(&client, &origin, &destination, &departure_date, &aviasales_api_key).await {
-                Ok(flight_data) => {
-                    if flight_data.success {
-                        if let Some(flights) = flight_data.data.as_ref() {
-                            let flight_count = flights.len();
-                            println!("Found {} flights for {}", flight_count, formatted_date);
-                            
-                            let origin_name = get_city_name(&origin);
-                            let destination_name = get_city_name(&destination);
-                            
-                            if flight_count > 0 {
-                                // Update statistics
-                                stats.dates_with_flights += 1;
-                                stats.total_flights_found += flight_count;
-                                
-                                // Check if a similar message was sent recently
-                                let message_text = format!("Найдено {} рейсов на {}", flight_count, formatted_date);
-                                let was_recent = was_message_sent_recently(
-                                    &client,
-                                    &telegram_bot_token,
-                                    &telegram_chat_id,
-                                    &telegram_found_topic_id,
-                                    &message_text
-                                ).await?;
-                                
-                                if !was_recent {
-                                    let message_id = send_telegram_notification_with_id(
-                                        &client,
-                                        &telegram_bot_token,
-                                        &telegram_chat_id,
-                                        &format!("✅ Найдено <b>{} рейсов</b> на <b>{}</b> из {} в {}:\n\n", 
-                                            flight_count, formatted_date, origin_name, destination_name),
-                                        &telegram_found_topic_id,
-                                        None
-                                    ).await?;
-                                    
-                                    // Update statistics with message ID
-                                    stats.flight_dates.push((formatted_date.clone(), message_id));
-                                    
-                                    // Send flight details
-                                    for (i, flight) in flights.iter().enumerate() {
-                                        if i >= 5 {
-                                            // Limit to 5 flights in a single message
-                                            let message_text = format!("... и еще {} рейсов", flight_count - 5);
-                                            let was_recent = was_message_sent_recently(
-                                                &client,
-                                                &telegram_bot_token,
-                                                &telegram_chat_id,
-                                                &telegram_found_topic_id,
-                                                &message_text
-                                            ).await?;
-                                            
-                                            if !was_recent {
-                                                let message_id = send_telegram_notification_with_id(
-                                                    &client,
-                                                    &telegram_bot_token,
-                                                    &telegram_chat_id,
-                                                    &message_text,
-                                                    &telegram_found_topic_id,
-                                                    None
-                                                ).await?;
-                                            }
-                                            break;
-                                        }
-                                        
-                                        let origin_city = get_city_name(&flight.origin);
-                                        let destination_city = get_city_name(&flight.destination);
-                                        let airline_name = get_airline_name(&flight.airline);
-                                        let formatted_departure = format_datetime_ru(&flight.departure_at);
-                                        
-                                        let message_text = format!(
-                                            "🛫 <b>Рейс {}</b>: {} ({}) → {} ({})\n",
-                                            flight.flight_number,
-                                            origin_city,
-                                            flight.origin_airport,
-                                            destination_city,
-                                            flight.destination_airport
-                                        );
-                                        
-                                        let was_recent = was_message_sent_recently(
-                                            &client,
-                                            &telegram_bot_token,
-                                            &telegram_chat_id,
-                                            &telegram_found_topic_id,
-                                            &message_text
-                                        ).await?;
-                                        
-                                        if !was_recent {
-                                            send_telegram_notification(
-                                                &client,
-                                                &telegram_bot_token,
-                                                &telegram_chat_id,
-                                                &message_text,
-                                                &telegram_found_topic_id,
-                                                None
-                                            ).await?;
-                                        }
-                                    }
-                                    
-                                    // Now process AirLabs data for each flight if enabled
-                                    if enable_airlabs {
-                                        for flight in flights {
-                                            match enrich_with_airlabs_data(&client, flight, &airlabs_api_key).await {
-                                                Ok(Some(airlabs_flight)) => {
-                                                    // ... existing AirLabs processing code ...
-                                                    
-                                                    // Send AirLabs data to both chat IDs if seat info is available
-                                                    let mut has_seat_info = false;
-                                                    let mut airlabs_message = String::new();
-                                                    
-                                                    airlabs_message.push_str(&format!(
-                                                        "📊 <b>Дополнительная информация для рейса {}{}</b>:\n",
-                                                        flight.airline, flight.flight_number
-                                                    ));
-                                                    
-                                                    if let Some(status) = &airlabs_flight.status {
-                                                        airlabs_message.push_str(&format!("🚦 <b>Статус рейса</b>: {}\n", status));
-                                                    }
-                                                    
-                                                    if let Some(aircraft) = &airlabs_flight.aircraft_icao {
-                                                        airlabs_message.push_str(&format!("✈️ <b>Тип самолета</b>: {}\n", aircraft));
-                                                    }
-                                                    
-                                                    if let Some(economy) = airlabs_flight.seats_economy {
-                                                        airlabs_message.push_str(&format!("💺 <b>Мест в эконом-классе</b>: {}\n", economy));
-                                                        has_seat_info = true;
-                                                    }
-                                                    
-                                                    if let Some(business) = airlabs_flight.seats_business {
-                                                        airlabs_message.push_str(&format!("💺 <b>Мест в бизнес-классе</b>: {}\n", business));
-                                                        has_seat_info = true;
-                                                    }
-                                                    
-                                                    if let Some(first) = airlabs_flight.seats_first {
-                                                        airlabs_message.push_str(&format!("💺 <b>Мест в первом классе</b>: {}\n", first));
-                                                        has_seat_info = true;
-                                                    }
-                                                    
-                                                    if !airlabs_message.is_empty() {
-                                                        // Send to primary chat ID
-                                                        if enable_telegram {
-                                                            send_telegram_notification(
-                                                                &client,
-                                                                &telegram_bot_token,
-                                                                &telegram_chat_id,
-                                                                &airlabs_message,
-                                                                &telegram_found_topic_id,
-                                                                None
-                                                            ).await?;
-                                                        }
-                                                        
-                                                        // Send to secondary chat ID if has seat info
-                                                        if enable_secondary_notifications && has_seat_info {
-                                                            let secondary_airlabs_message = format!(
-                                                                "🚨 <b>ИНФОРМАЦИЯ О НАЛИЧИИ МЕСТ:</b> 🚨\n\n{}",
-                                                                airlabs_message
-                                                            );
-                                                            
-                                                            send_telegram_notification(
-                                                                &client,
-                                                                &telegram_bot_token,
-                                                                &telegram_chat_id,
-                                                                &secondary_airlabs_message,
-                                                                &telegram_found_topic_id,
-                                                                None
-                                                            ).await?;
-                                                        }
-                                                    }
-                                                },
-                                                Ok(None) => {
-                                                    println!("No AirLabs data found for flight {}{}", 
-                                                        flight.airline, flight.flight_number);
-                                                },
-                                                Err(e) => {
-                                                    eprintln!("Error fetching AirLabs data: {}", e);
-                                                }
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    // Update statistics
-                                    stats.dates_without_flights += 1;
-                                    println!("No flights found for {}", formatted_date);
-                                }
-                            } else {
-                                // Update statistics
-                                stats.dates_without_flights += 1;
-                                println!("No flights found for {}", formatted_date);
-                            }
-                        } else {
-                            // Update statistics
-                            stats.dates_without_flights += 1;
-                            println!("No flights found for {}", formatted_date);
-                        }
-                    }
+        match self.format.decode(&bytes) {
+            Ok(value) => value,
+            Err(e) => match self.format.other().decode(&bytes) {
+                Ok(value) => {
+                    info!("Read {} as {:?}; it will be saved as {:?}", self.path, self.format.other(), self.format);
+                    value
                 }
-                Err(e) => {
-                    // Update statistics for error
-                    stats.errors_encountered += 1;
-                    eprintln!("Error searching flights for {}: {}", formatted_date, e);
-                    
-                    // Send a separate error message
-                    if enable_telegram {
-                        let error_message = format!(
-                            "⚠️ <b>Ошибка при поиске рейсов</b>\n\n\
-                            📅 Дата: {}\n\
-                            ❌ Ошибка: {}\n\n\
-                            <i>Поиск продолжается...</i>",
-                            formatted_date,
-                            e
-                        );
-                        
-                        if let Err(send_err) = send_telegram_notification(
-                            &client,
-                            &telegram_bot_token,
-                            &telegram_chat_id,
-                            &error_message,
-                            &telegram_devlogs_topic_id,
-                            None
-                        ).await {
-                            eprintln!("Failed to send error message: {}", send_err);
-                        }
-                    }
-                    
-                    // Update status message without the error details
-                    if enable_telegram && status_message_id.is_some() {
-                        let progress_message = format!(
-                            "🛫 <b>Программа поиска авиабилетов</b>\n\n\
-                            🔍 Поиск начат: {}\n\
-                            🗓 Проверяемые даты: {}\n\n\
-                            {}\n\n\
-                            <i>Поиск в процессе ({}/{} дат проверено)...</i>",
-                            formatted_start_time,
-                            date_range_str,
-                            stats.format_summary(),
-                            stats.total_dates_checked,
-                            dates.len()
-                        );
-                        
-                        if let Err(update_err) = update_telegram_message(
-                            &client,
-                            &telegram_bot_token,
-                            &telegram_chat_id,
-                            &status_message_id.as_ref().unwrap(),
-                            &progress_message,
-                            &telegram_devlogs_topic_id
-                        ).await {
-                            eprintln!("Failed to update status message: {}", update_err);
-                        }
-                    }
+                Err(_) => {
+                    error!("Failed to parse state file {}: {}. Starting with empty state.", self.path, e);
+                    T::default()
                 }
-            }
+            },
+        }
+    }
+
+    fn save<T: Serialize>(&self, value: &T) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Write to a temporary file first so a crash never leaves a truncated state file
+        let tmp_path = format!("{}.tmp", self.path);
+        std::fs::write(&tmp_path, self.format.encode(value)?)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+// Every fare the bot has notified, in the SQLite database at SQLITE_PATH or DATABASE_URL. Keyed
+// on route, departure, flight and price, so a known flight at a new price counts as a new fare
+struct FareHistory {
+    connection: Mutex<Connection>,
+}
+
+impl FareHistory {
+    fn open(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS fares (
+                origin TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                departure_at TEXT NOT NULL,
+                flight_number TEXT NOT NULL,
+                price INTEGER NOT NULL,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                UNIQUE (origin, destination, departure_at, flight_number, price)
+            )",
+        )?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    // Whether the fare is missing from the history; a failed lookup counts as new so the fare
+    // is still notified
+    fn is_new(&self, flight: &FlightResult) -> bool {
+        let designator = format_flight_designator(&flight.airline, &flight.flight_number);
+        let found = self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM fares WHERE origin = ?1 AND destination = ?2
+                AND departure_at = ?3 AND flight_number = ?4 AND price = ?5)",
+            params![flight.origin, flight.destination, flight.departure_at, designator, flight.price],
+            |row| row.get::<_, bool>(0),
+        );
+        
+        match found {
+            Ok(found) => !found,
+            Err(e) => {
+                error!("Failed to look up fare {} in the fare history: {}", designator, e);
+                true
+            }
+        }
+    }
+
+    // Record that the fare was notified at `now`, keeping when it first was
+    fn record(&self, flight: &FlightResult, now: DateTime<Utc>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let now = now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO fares (origin, destination, departure_at, flight_number, price, first_seen, last_seen)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+                ON CONFLICT (origin, destination, departure_at, flight_number, price)
+                DO UPDATE SET last_seen = excluded.last_seen",
+            params![
+                flight.origin,
+                flight.destination,
+                flight.departure_at,
+                format_flight_designator(&flight.airline, &flight.flight_number),
+                flight.price,
+                now
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Forget fares that weren't notified within the retention window; returns how many were dropped
+    fn prune(&self, now: DateTime<Utc>, retention: chrono::Duration) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let cutoff = (now - retention).to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        Ok(self.connection.lock().unwrap().execute("DELETE FROM fares WHERE last_seen < ?1", params![cutoff])?)
+    }
+}
+
+// Function to find the fare history database: SQLITE_PATH, or DATABASE_URL as `sqlite://path`
+fn fare_history_path(source: &ConfigSource) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let path = source.string("SQLITE_PATH");
+    if !path.trim().is_empty() {
+        return Ok(Some(path.trim().to_string()));
+    }
+    
+    let url = source.string("DATABASE_URL");
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+    match url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:")) {
+        Some(path) if !path.is_empty() => Ok(Some(path.to_string())),
+        _ => Err(BotError::Config(format!("DATABASE_URL must be an SQLite URL like sqlite://fares.db, got {:?}", url)).into()),
+    }
+}
+
+impl BotState {
+    // A date is in cooldown if it was notified recently and the price hasn't improved since
+    fn is_in_cooldown(&self, key: &str, price: i64, cooldown: chrono::Duration) -> bool {
+        self.notifications.get(key).is_some_and(|record| {
+            Utc::now().signed_duration_since(record.last_notified_at) < cooldown
+                && price >= record.price
+        })
+    }
+
+    // Record whether a date had flights this cycle; returns true once a date that had flights
+    // stays empty for SOLD_OUT_EMPTY_CYCLES consecutive cycles
+    fn record_availability(&mut self, key: String, has_flights: bool) -> bool {
+        let availability = self.availability.entry(key).or_default();
+        
+        if has_flights {
+            availability.had_flights = true;
+            availability.empty_cycles = 0;
+            return false;
+        }
+        
+        if !availability.had_flights {
+            return false;
+        }
+        
+        availability.empty_cycles += 1;
+        if availability.empty_cycles >= SOLD_OUT_EMPTY_CYCLES {
+            availability.had_flights = false;
+            availability.empty_cycles = 0;
+            return true;
+        }
+        
+        false
+    }
+
+    // Record a watched flight's status; returns the previous status when it changed.
+    // The first observation only sets the baseline
+    fn record_flight_status(&mut self, flight_iata: &str, status: &str) -> Option<String> {
+        let previous = self.flight_statuses.insert(flight_iata.to_string(), status.to_string())?;
+        (previous != status).then_some(previous)
+    }
+
+    fn was_sent_recently(&self, message_text: &str, window: chrono::Duration) -> bool {
+        self.sent_messages
+            .get(message_text)
+            .is_some_and(|sent_at| Utc::now().signed_duration_since(*sent_at) < window)
+    }
+
+    fn record_sent(&mut self, message_text: &str) {
+        self.sent_messages.insert(message_text.to_string(), Utc::now());
+    }
+
+    // Forget messages sent before the dedup window. Flight messages and acknowledgements
+    // are kept until the flight departs, so a late reaction still counts
+    fn prune_sent_messages(&mut self, display: &DisplaySettings, window: chrono::Duration) {
+        let now = Utc::now();
+        let cutoff = now - window;
+        self.sent_messages.retain(|_, sent_at| *sent_at >= cutoff);
+        let departs_later = |flight_key: &String| {
+            flight_key
+                .split('|')
+                .nth(1)
+                .and_then(|departure_at| parse_departure_at(display, departure_at))
+                .is_some_and(|departure| departure > now)
+        };
+        self.flight_messages.retain(|_, flight_key| departs_later(flight_key));
+        self.acknowledged_flights.retain(departs_later);
+    }
+
+    // Acknowledge (👍) or un-acknowledge (👎) the flight of a per-flight notification;
+    // returns the flight key when the message is a known flight message
+    fn acknowledge_flight(&mut self, message_id: &str, acknowledged: bool) -> Option<String> {
+        let flight_key = self.flight_messages.get(message_id)?.clone();
+        if acknowledged {
+            self.acknowledged_flights.insert(flight_key.clone());
+        } else {
+            self.acknowledged_flights.remove(&flight_key);
+        }
+        Some(flight_key)
+    }
+
+    fn record_notification(&mut self, key: String, price: i64) {
+        self.notifications.insert(key, NotificationRecord {
+            last_notified_at: Utc::now(),
+            price,
+        });
+    }
+}
+
+// Most price alerts a single group member may subscribe to
+const MAX_SUBSCRIPTIONS_PER_USER: usize = 5;
+
+// A group member's own price alert for a route, added through the webhook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Subscription {
+    user_id: i64,
+    // Shown when the alert has to mention the member in the group
+    name: String,
+    route: Route,
+    max_price: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SubscriptionCommand {
+    Subscribe { route: Route, max_price: i64 },
+    // Remove the member's subscription for one route, or all of them
+    Unsubscribe { route: Option<Route> },
+}
+
+// A subscription command from a chat message, passed from the webhook receiver to the main loop
+#[derive(Debug)]
+struct SubscriptionRequest {
+    user_id: i64,
+    name: String,
+    message_id: i64,
+    topic_id: String,
+    command: Result<SubscriptionCommand, String>,
+}
+
+// Function to parse a /subscribe or /unsubscribe command; None for any other text
+fn parse_subscription_command(text: &str) -> Option<Result<SubscriptionCommand, String>> {
+    let mut parts = text.split_whitespace();
+    // Commands in groups may be addressed to the bot as /subscribe@bot_name
+    let command = parts.next()?.split('@').next()?;
+    let args: Vec<&str> = parts.collect();
+    
+    let parse_route = |origin: &str, destination: &str| {
+        let is_iata = |code: &str| code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic());
+        if is_iata(origin) && is_iata(destination) {
+            Ok(Route {
+                origin: origin.to_uppercase(),
+                destination: destination.to_uppercase(),
+                currency: None,
+                target_price: None,
+            })
+        } else {
+            Err("Укажите коды IATA, например MOW AER".to_string())
+        }
+    };
+    
+    match command {
+        "/subscribe" => Some(match args.as_slice() {
+            [origin, destination, max_price] => parse_route(origin, destination).and_then(|route| {
+                max_price.parse::<i64>()
+                    .ok()
+                    .filter(|price| *price > 0)
+                    .map(|max_price| SubscriptionCommand::Subscribe { route, max_price })
+                    .ok_or_else(|| format!("Некорректная цена: {}", max_price))
+            }),
+            _ => Err("Использование: /subscribe MOW AER 15000".to_string()),
+        }),
+        "/unsubscribe" => Some(match args.as_slice() {
+            [] => Ok(SubscriptionCommand::Unsubscribe { route: None }),
+            [origin, destination] => parse_route(origin, destination)
+                .map(|route| SubscriptionCommand::Unsubscribe { route: Some(route) }),
+            _ => Err("Использование: /unsubscribe или /unsubscribe MOW AER".to_string()),
+        }),
+        _ => None,
+    }
+}
+
+// Function to turn a chat message update into a subscription request, if it holds a command
+fn subscription_request(update: &serde_json::Value) -> Option<SubscriptionRequest> {
+    let message = update.get("message")?;
+    let command = parse_subscription_command(message.get("text")?.as_str()?)?;
+    let from = message.get("from")?;
+    let user_id = from.get("id")?.as_i64()?;
+    let name = from.get("username")
+        .and_then(|username| username.as_str())
+        .map(|username| format!("@{}", username))
+        .or_else(|| from.get("first_name").and_then(|name| name.as_str()).map(str::to_string))
+        .unwrap_or_else(|| user_id.to_string());
+    
+    Some(SubscriptionRequest {
+        user_id,
+        name,
+        message_id: message.get("message_id")?.as_i64()?,
+        topic_id: message.get("message_thread_id")
+            .and_then(|id| id.as_i64())
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        command,
+    })
+}
+
+// Prefix of the callback data of calendar keyboard buttons
+const CALENDAR_CALLBACK_PREFIX: &str = "calendar:";
+
+// A tap on a calendar keyboard date, passed from the webhook receiver to the main loop
+#[derive(Debug)]
+struct CalendarRequest {
+    callback_query_id: String,
+    // Departure date as YYYY-MM-DD
+    date: String,
+    topic_id: String,
+}
+
+// Prefix of the callback data of "Обновить" buttons, followed by `ORIGIN-DESTINATION:YYYY-MM-DD`
+const REFRESH_CALLBACK_PREFIX: &str = "refresh:";
+
+// A tap on a flight's "Обновить" button, passed from the webhook receiver to the main loop
+#[derive(Debug)]
+struct RefreshRequest {
+    callback_query_id: String,
+    origin: String,
+    destination: String,
+    // Departure date as YYYY-MM-DD
+    date: String,
+    topic_id: String,
+}
+
+// Chat interactions received by the webhook and handled by the main loop
+#[derive(Debug)]
+enum WebhookCommand {
+    Subscription(SubscriptionRequest),
+    Calendar(CalendarRequest),
+    Refresh(RefreshRequest),
+    // A 👍 (true) or 👎 (false) reaction to a message
+    Reaction { message_id: String, acknowledged: bool },
+}
+
+// Function to turn a reaction update into an acknowledgment: 👍 acknowledges, 👎 withdraws it
+fn reaction_command(update: &serde_json::Value) -> Option<WebhookCommand> {
+    let reaction = update.get("message_reaction")?;
+    let message_id = reaction.get("message_id")?.as_i64()?.to_string();
+    let has_emoji = |emoji: &str| reaction.get("new_reaction")
+        .and_then(|reactions| reactions.as_array())
+        .is_some_and(|reactions| reactions.iter().any(|r| r.get("emoji").and_then(|e| e.as_str()) == Some(emoji)));
+    
+    let acknowledged = if has_emoji("👍") {
+        true
+    } else if has_emoji("👎") {
+        false
+    } else {
+        return None;
+    };
+    Some(WebhookCommand::Reaction { message_id, acknowledged })
+}
+
+// Function to turn a callback query update into a calendar request, if it came from a date button
+fn calendar_request(update: &serde_json::Value) -> Option<CalendarRequest> {
+    let query = update.get("callback_query")?;
+    let date = query.get("data")?.as_str()?.strip_prefix(CALENDAR_CALLBACK_PREFIX)?;
+    
+    Some(CalendarRequest {
+        callback_query_id: query.get("id")?.as_str()?.to_string(),
+        date: date.to_string(),
+        topic_id: query.get("message")
+            .and_then(|message| message.get("message_thread_id"))
+            .and_then(|id| id.as_i64())
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+    })
+}
+
+// Function to turn a callback query update into a refresh request, if it came from a refresh button
+fn refresh_request(update: &serde_json::Value) -> Option<RefreshRequest> {
+    let query = update.get("callback_query")?;
+    let target = query.get("data")?.as_str()?.strip_prefix(REFRESH_CALLBACK_PREFIX)?;
+    let (route, date) = target.rsplit_once(':')?;
+    let (origin, destination) = route.split_once('-')?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    
+    Some(RefreshRequest {
+        callback_query_id: query.get("id")?.as_str()?.to_string(),
+        origin: origin.to_string(),
+        destination: destination.to_string(),
+        date: date.to_string(),
+        topic_id: query.get("message")
+            .and_then(|message| message.get("message_thread_id"))
+            .and_then(|id| id.as_i64())
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+    })
+}
+
+// Function to build an inline keyboard with a button per date, a week of dates per row
+fn calendar_keyboard(dates: &[NaiveDate]) -> Option<serde_json::Value> {
+    let mut dates = dates.to_vec();
+    dates.sort();
+    dates.dedup();
+    if dates.is_empty() {
+        return None;
+    }
+    
+    let rows: Vec<serde_json::Value> = dates
+        .chunks(7)
+        .map(|week| json!(week.iter().map(|date| json!({
+            "text": date.format("%d.%m").to_string(),
+            "callback_data": format!("{}{}", CALENDAR_CALLBACK_PREFIX, date.format("%Y-%m-%d")),
+        })).collect::<Vec<_>>()))
+        .collect();
+    
+    Some(json!({ "inline_keyboard": rows }))
+}
+
+impl BotState {
+    // Apply a member's subscription command; returns the reply for the chat
+    fn apply_subscription_command(&mut self, display: &DisplaySettings, user_id: i64, name: &str, command: SubscriptionCommand) -> String {
+        match command {
+            SubscriptionCommand::Subscribe { route, max_price } => {
+                let route_label = format!("{} → {}", route.origin, route.destination);
+                if let Some(existing) = self.subscriptions
+                    .iter_mut()
+                    .find(|subscription| subscription.user_id == user_id && subscription.route == route)
+                {
+                    existing.max_price = max_price;
+                    existing.name = name.to_string();
+                    return format!("🔔 Порог подписки {} обновлен: до {}", route_label, format_price(display, max_price));
+                }
+                
+                let count = self.subscriptions.iter().filter(|subscription| subscription.user_id == user_id).count();
+                if count >= MAX_SUBSCRIPTIONS_PER_USER {
+                    return format!(
+                        "⚠️ Достигнут лимит подписок ({}). Удалите лишние командой /unsubscribe",
+                        MAX_SUBSCRIPTIONS_PER_USER
+                    );
+                }
+                
+                self.subscriptions.push(Subscription {
+                    user_id,
+                    name: name.to_string(),
+                    route,
+                    max_price,
+                });
+                format!("🔔 Подписка оформлена: {} до {}", route_label, format_price(display, max_price))
+            }
+            SubscriptionCommand::Unsubscribe { route } => {
+                let before = self.subscriptions.len();
+                self.subscriptions.retain(|subscription| {
+                    subscription.user_id != user_id
+                        || route.as_ref().is_some_and(|route| subscription.route != *route)
+                });
+                
+                match before - self.subscriptions.len() {
+                    0 => "ℹ️ Подписок не найдено".to_string(),
+                    removed => format!("🔕 Удалено подписок: {}", removed),
+                }
+            }
+        }
+    }
+
+    // Distinct routes with at least one subscription
+    fn subscription_routes(&self) -> Vec<Route> {
+        let mut routes: Vec<Route> = Vec::new();
+        for subscription in &self.subscriptions {
+            if !routes.contains(&subscription.route) {
+                routes.push(subscription.route.clone());
+            }
+        }
+        routes
+    }
+}
+
+// Function to build the persisted-state key of a status message
+fn status_message_key(chat_id: &str, topic_id: &str) -> String {
+    format!("{}:{}", chat_id, topic_id)
+}
+
+// Search outcome for one route on one date
+type RouteSearchResult = (Route, Result<FlightData, Box<dyn Error + Send + Sync>>);
+
+// A single origin-destination pair searched each cycle
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Route {
+    origin: String,
+    destination: String,
+    // Search currency overriding CURRENCY for this route (ROUTE_CURRENCIES). Always written,
+    // since bincode state files can't skip fields
+    #[serde(default)]
+    currency: Option<String>,
+    // Dream fare for this route in its currency (TARGET_PRICES)
+    #[serde(default)]
+    target_price: Option<i64>,
+}
+
+// Routes are the same trip whatever their search settings, so a subscribed route matches a configured one
+impl PartialEq for Route {
+    fn eq(&self, other: &Self) -> bool {
+        self.origin == other.origin && self.destination == other.destination
+    }
+}
+
+impl Route {
+    fn reversed(&self) -> Route {
+        Route {
+            origin: self.destination.clone(),
+            destination: self.origin.clone(),
+            currency: self.currency.clone(),
+            target_price: self.target_price,
+        }
+    }
+
+    // Currency the route is searched and shown in
+    fn currency<'a>(&'a self, default: &'a str) -> &'a str {
+        self.currency.as_deref().unwrap_or(default)
+    }
+}
+
+// Function to build the state key for a route and departure date
+fn route_date_key(origin: &str, destination: &str, departure_date: &str) -> String {
+    format!("{}-{}:{}", origin, destination, departure_date)
+}
+
+// Function to check whether the daily recap hour (local time) passed since the last check
+fn is_recap_due(last_check: DateTime<Utc>, now: DateTime<Utc>, recap_hour: u32, timezone: Tz) -> bool {
+    let local_now = now.with_timezone(&timezone);
+    let Some(recap_time) = local_now
+        .date_naive()
+        .and_hms_opt(recap_hour, 0, 0)
+        .and_then(|naive| naive.and_local_timezone(timezone).earliest())
+    else {
+        return false;
+    };
+    
+    // Use yesterday's recap time if today's hasn't come yet
+    let recap_time = if recap_time > local_now {
+        recap_time - chrono::Duration::days(1)
+    } else {
+        recap_time
+    };
+    
+    last_check < recap_time
+}
+
+// Function to format the daily recap of the cheapest fare per date, each in its route's currency
+fn format_recap(cheapest: &[(String, i64)], config: &Config) -> String {
+    let mut recap = String::from("📅 <b>Ежедневная сводка: лучшие цены за сутки</b>\n\n<pre>");
+    
+    for (key, price) in cheapest {
+        let (route, date) = key.split_once(':').unwrap_or((key.as_str(), ""));
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map(|d| d.format("%d.%m.%Y").to_string())
+            .unwrap_or_else(|_| date.to_string());
+        let price = format_price_in(&config.display, *price, &config.route_currency(route));
+        recap.push_str(&format!("{:<11} {:<8} {:>10}\n", date, route, price));
+    }
+    
+    recap.push_str("</pre>");
+    recap
+}
+
+// Function to parse WATCH_FLIGHTS into (airline, number) pairs, e.g. `SU1234, s7 1020`
+fn parse_watch_flights(raw: &str) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>> {
+    raw.split(',')
+        .map(|entry| entry.replace(' ', "").to_uppercase())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            if entry.len() < 3 || !entry.is_ascii() {
+                return Err(format!("Invalid flight \"{}\" in WATCH_FLIGHTS, expected e.g. SU1234", entry).into());
+            }
+            let (airline, number) = entry.split_at(2);
+            Ok((airline.to_string(), number.to_string()))
+        })
+        .collect()
+}
+
+// Function to parse ROUTE_CURRENCIES into destination -> currency, e.g. `JFK:usd, IST:eur`
+fn parse_route_currencies(raw: &str) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (destination, currency) = entry.split_once(':')
+                .ok_or_else(|| format!("Invalid entry \"{}\" in ROUTE_CURRENCIES, expected e.g. JFK:usd", entry))?;
+            let currency = currency.trim().to_lowercase();
+            if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(format!("Invalid currency \"{}\" in ROUTE_CURRENCIES, expected a code like usd", currency).into());
+            }
+            Ok((normalize_code(destination), currency))
+        })
+        .collect()
+}
+
+// Function to parse TARGET_PRICES into destination -> price, e.g. `AER:8000, JFK:300`
+fn parse_target_prices(raw: &str) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let price = entry.split_once(':')
+                .and_then(|(destination, price)| Some((destination, price.trim().parse::<i64>().ok().filter(|price| *price > 0)?)));
+            match price {
+                Some((destination, price)) => Ok((normalize_code(destination), price)),
+                None => Err(format!("Invalid entry \"{}\" in TARGET_PRICES, expected e.g. AER:8000", entry).into()),
+            }
+        })
+        .collect()
+}
+
+// Group member pinged when a fare beats its route's target price (MENTION_USER)
+#[derive(Debug, Clone, PartialEq)]
+enum Mention {
+    Username(String),
+    UserId(i64),
+}
+
+impl Mention {
+    fn html(&self) -> String {
+        match self {
+            Mention::Username(username) => format!("@{}", username),
+            Mention::UserId(user_id) => format!("<a href=\"tg://user?id={}\">{}</a>", user_id, user_id),
+        }
+    }
+}
+
+// Function to parse MENTION_USER: a Telegram username (with or without @) or a numeric user ID
+fn parse_mention(raw: &str) -> Result<Option<Mention>, BotError> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(user_id) = raw.parse::<i64>() {
+        return Ok(Some(Mention::UserId(user_id)));
+    }
+    
+    // Usernames are 5-32 characters: letters, digits and underscores, starting with a letter
+    let username = raw.strip_prefix('@').unwrap_or(raw);
+    let valid = (5..=32).contains(&username.len())
+        && username.starts_with(|c: char| c.is_ascii_alphabetic())
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(BotError::Config(format!("MENTION_USER must be a Telegram username like @name or a user ID, got {:?}", raw)));
+    }
+    Ok(Some(Mention::Username(username.to_string())))
+}
+
+// Function to announce a fare at or below the route's target price, mentioning MENTION_USER
+fn format_target_hit(display: &DisplaySettings, route: &Route, price: i64, currency: &str, mention: Option<&Mention>) -> Option<String> {
+    let target = route.target_price.filter(|target| price <= *target)?;
+    let mention = mention.map_or(String::new(), |mention| format!("{}, ", mention.html()));
+    Some(format!("🎯 {}цена {} не выше цели {}!\n", mention, format_price_in(display, price, currency), format_price_in(display, target, currency)))
+}
+
+// Function to translate an AirLabs flight status for display
+fn format_flight_status_ru(status: &str) -> String {
+    match status {
+        "scheduled" => "по расписанию".to_string(),
+        "en-route" | "active" => "в полёте".to_string(),
+        "landed" => "приземлился".to_string(),
+        "delayed" => "задерживается".to_string(),
+        "cancelled" => "отменён".to_string(),
+        "diverted" => "перенаправлен".to_string(),
+        other => escape_html(other),
+    }
+}
+
+// Function to check AirLabs economy seats against MIN_ECONOMY_SEATS; None when seats are unknown
+fn meets_min_economy_seats(airlabs_flight: Option<&AirLabsFlight>, min_seats: i64) -> Option<bool> {
+    airlabs_flight
+        .and_then(|flight| flight.seats_economy)
+        .map(|seats| seats >= min_seats)
+}
+
+// Function to highlight a flight whose AirLabs economy seats meet MIN_ECONOMY_SEATS
+fn format_economy_seats_highlight(airlabs_flight: Option<&AirLabsFlight>, min_seats: Option<i64>) -> String {
+    match (airlabs_flight.and_then(|flight| flight.seats_economy), min_seats) {
+        (Some(seats), Some(min_seats)) if seats >= min_seats => format!("🔥 <b>Мест в эконом-классе: {} (не меньше {})</b>\n", seats, min_seats),
+        _ => String::new(),
+    }
+}
+
+// Function to check the Travelpayouts seats-at-this-price count against MIN_FARE_SEATS;
+// an unknown count passes, while a known zero does not
+fn meets_min_fare_seats(seats: Option<i64>, min_seats: i64) -> bool {
+    seats.is_none_or(|seats| seats >= min_seats)
+}
+
+// Function to describe the seats of a flight. Travelpayouts `seats` counts tickets left at this
+// fare; AirLabs `seats_economy` is an estimate of the cabin, not live availability
+fn format_seats(fare_seats: Option<i64>, cabin_seats: Option<i64>) -> String {
+    let fare_seats = fare_seats.filter(|seats| *seats > 0);
+    match (fare_seats, cabin_seats) {
+        (Some(fare), Some(cabin)) => format!(
+            "💺 Мест по тарифу: {} · мест в салоне (оценка): {}\n\
+            <i>По тарифу — сколько билетов осталось по этой цене, в салоне — примерная вместимость эконом-класса по данным AirLabs</i>\n",
+            fare, cabin
+        ),
+        (Some(fare), None) => format!("💺 Мест по тарифу: {}\n", fare),
+        (None, Some(cabin)) => format!("💺 Мест в салоне (оценка): {}\n", cabin),
+        (None, None) => String::new(),
+    }
+}
+
+// Add these new structs to track search statistics
+// All counters sit behind one lock so concurrent searches can update them through a shared
+// reference, and a snapshot never mixes values from before and after an update
+#[derive(Debug, Default)]
+struct SearchStatistics {
+    counts: Mutex<StatisticsSnapshot>,
+}
+
+// A date whose flights were notified this cycle, linked from the summary
+#[derive(Debug, Clone)]
+struct FlightDateSummary {
+    date: String,
+    message_id: String,
+    // Cheapest price in the route's currency
+    price: i64,
+    currency: String,
+    // The same price in BASE_CURRENCY, used for ranking; None without an exchange rate
+    base_price: Option<f64>,
+}
+
+// Statistics of a cycle; copies taken at one moment are used for formatting
+#[derive(Debug, Default, Clone)]
+struct StatisticsSnapshot {
+    total_dates_checked: usize,
+    dates_with_flights: usize,
+    dates_without_flights: usize,
+    total_flights_found: usize,
+    errors_encountered: usize,
+    suppressed_errors: usize,
+    airlabs_enriched: usize,
+    airlabs_skipped: usize,
+    // Searches and flights of reversed routes (BIDIRECTIONAL), counted apart from the outbound ones
+    return_searches: usize,
+    return_flights_found: usize,
+    flight_dates: Vec<FlightDateSummary>,
+}
+
+impl SearchStatistics {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    // Apply one update to the counters; everything changed in it is seen together
+    fn update(&self, change: impl FnOnce(&mut StatisticsSnapshot)) {
+        change(&mut self.counts.lock().unwrap());
+    }
+
+    fn record_flight_date(&self, flight_date: FlightDateSummary) {
+        self.update(|counts| counts.flight_dates.push(flight_date));
+    }
+
+    // Count an AirLabs call unless the per-cycle cap is already used up
+    fn reserve_airlabs_call(&self, max: Option<usize>) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        let reserved = max.is_none_or(|max| counts.airlabs_enriched < max);
+        if reserved {
+            counts.airlabs_enriched += 1;
+        } else {
+            counts.airlabs_skipped += 1;
+        }
+        reserved
+    }
+
+    fn snapshot(&self) -> StatisticsSnapshot {
+        self.counts.lock().unwrap().clone()
+    }
+
+    fn format_summary(&self, display: &DisplaySettings, max_dates: usize) -> String {
+        self.snapshot().format_summary(display, max_dates)
+    }
+}
+
+impl StatisticsSnapshot {
+    // At most `max_dates` dates with flights are linked, the cheapest ones, in date order
+    fn format_summary(&self, display: &DisplaySettings, max_dates: usize) -> String {
+        let mut summary = format!(
+            "📊 <b>Статистика поиска:</b>\n\
+             ✓ Проверено дат: {}\n\
+             ✈️ Даты с рейсами: {}\n\
+             ❌ Даты без рейсов: {}\n\
+             🎫 Всего найдено рейсов: {}\n\
+             ⚠️ Ошибок: {}\n",
+            self.total_dates_checked,
+            self.dates_with_flights,
+            self.dates_without_flights,
+            self.total_flights_found,
+            self.errors_encountered
+        );
+        
+        if self.return_searches > 0 {
+            summary.push_str(&format!(
+                "↔️ Туда: {} / обратно: {}\n",
+                self.total_flights_found - self.return_flights_found,
+                self.return_flights_found
+            ));
+        }
+        
+        if self.airlabs_skipped > 0 {
+            summary.push_str(&format!("🛰 Без данных AirLabs (лимит за цикл): {}\n", self.airlabs_skipped));
+        }
+        
+        if self.suppressed_errors > 0 {
+            summary.push_str(&format!("🔕 Повторных ошибок скрыто: {}\n", self.suppressed_errors));
+        }
+        
+        if !self.flight_dates.is_empty() {
+            summary.push_str("\n<b>Даты с найденными рейсами:</b>\n");
+            // Rank in the base currency so routes searched in different currencies compare fairly
+            let mut cheapest: Vec<usize> = (0..self.flight_dates.len()).collect();
+            cheapest.sort_by(|&a, &b| {
+                let a = self.flight_dates[a].base_price.unwrap_or(f64::INFINITY);
+                let b = self.flight_dates[b].base_price.unwrap_or(f64::INFINITY);
+                a.total_cmp(&b)
+            });
+            cheapest.truncate(max_dates);
+            cheapest.sort_unstable();
+            
+            for flight_date in cheapest.iter().map(|&i| &self.flight_dates[i]) {
+                summary.push_str(&format!("• <a href=\"https://t.me/c/{}/{}\">{}</a> — {}\n", 
+                    flight_date.message_id.split('/').nth(0).unwrap_or(""),
+                    flight_date.message_id.split('/').nth(1).unwrap_or(""),
+                    flight_date.date,
+                    format_price_in(display, flight_date.price, &flight_date.currency)
+                ));
+            }
+            
+            let remaining = self.flight_dates.len() - cheapest.len();
+            if remaining > 0 {
+                summary.push_str(&format!("... и ещё {} {}\n", remaining, plural_ru(remaining as i64, "дата", "даты", "дат")));
+            }
+        }
+        
+        summary
+    }
+
+    // Short heartbeat for cycles where nothing was found
+    fn format_no_flights_digest(&self, date_range: &str) -> String {
+        format!(
+            "🔍 <b>Рейсов не найдено</b>\n\n\
+            🗓 Проверяемые даты: {}\n\
+            ✓ Проверено дат: {}\n\
+            ⚠️ Ошибок: {}",
+            date_range,
+            self.total_dates_checked,
+            self.errors_encountered
+        )
+    }
+}
+
+// Function to classify an error so different kinds of failures aren't throttled together
+fn error_kind(error: &(dyn Error + 'static)) -> &'static str {
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        if e.is_decode() { "parse" } else { "network" }
+    } else if error.is::<serde_json::Error>() {
+        "parse"
+    } else {
+        "other"
+    }
+}
+
+// How serious an error is; errors at or above ALERT_SEVERITY are escalated to ALERT_CHAT_ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    // Routine failures such as a single date's search
+    Warning,
+    // A whole cycle failed or stalled
+    Error,
+    // The bot can't do its job until someone intervenes
+    Critical,
+}
+
+impl Severity {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "warning" => Severity::Warning,
+            "error" => Severity::Error,
+            _ => Severity::Critical,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Warning => "предупреждение",
+            Severity::Error => "ошибка",
+            Severity::Critical => "критическая",
+        }
+    }
+}
+
+// Function to rate a search error; exhausted tokens stop every search, anything else is routine
+fn search_error_severity(error: &(dyn Error + 'static)) -> Severity {
+    match error.downcast_ref::<BotError>() {
+        Some(BotError::TokensExhausted) => Severity::Critical,
+        _ => Severity::Warning,
+    }
+}
+
+// Remembers when each distinct error was last notified so identical errors are sent
+// at most once per ERROR_NOTIFY_COOLDOWN_MINUTES
+#[derive(Default)]
+struct ErrorNotifyThrottle {
+    last_sent: HashMap<String, DateTime<Utc>>,
+}
+
+impl ErrorNotifyThrottle {
+    // Returns true (and records the send) if the error may be notified now
+    fn should_notify(&mut self, key: &str, now: DateTime<Utc>, cooldown: chrono::Duration) -> bool {
+        if let Some(sent_at) = self.last_sent.get(key)
+            && now.signed_duration_since(*sent_at) < cooldown
+        {
+            return false;
+        }
+        self.last_sent.insert(key.to_string(), now);
+        true
+    }
+}
+
+// Add this function to update a Telegram message
+async fn update_telegram_message(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message_id: &str,
+    message: &str,
+    topic_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    telegram.throttle.wait().await;
+    
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "editMessageText");
+    
+    // An edited message can't grow into several, so keep what fits
+    let chunks = split_message(message, TELEGRAM_MESSAGE_LIMIT);
+    if chunks.len() > 1 {
+        warn!("Message {} is too long to edit in full, keeping the first of {} parts", message_id, chunks.len());
+    }
+    let message = chunks.first().map_or("", String::as_str);
+    
+    let mut json_body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id,
+        "text": message,
+        "parse_mode": "HTML",
+        "disable_web_page_preview": true
+    });
+
+    // Add message_thread_id only if topic_id is not empty and not "1"
+    if !topic_id.is_empty() && topic_id != "1" {
+        json_body["message_thread_id"] = json!(topic_id);
+    }
+    
+    let request = || telegram.http.client.post(&api_url).json(&json_body);
+    send_with_backoff(request, "Telegram", TELEGRAM_MAX_RETRIES, telegram.http.network_retries, false, Some(&telegram.throttle))
+        .await
+        .map_err(|e| -> Box<dyn Error + Send + Sync> {
+            if is_not_editable_error(&e.to_string()) {
+                BotError::MessageNotEditable(message_id.to_string()).into()
+            } else {
+                e
+            }
+        })?;
+    
+    // Add a small delay to avoid Telegram rate limits
+    time::sleep(Duration::from_millis(1000)).await;
+    Ok(())
+}
+
+// Function to recognize Telegram's refusal to edit a message that is too old or gone
+fn is_not_editable_error(description: &str) -> bool {
+    description.contains("message can't be edited") || description.contains("message to edit not found")
+}
+
+// How long a pipelined chunk waits for the previous one before starting anyway
+const TOPIC_PIPELINE_GAP: Duration = Duration::from_millis(150);
+
+// Function to send a message and return the message ID. Messages over Telegram's length limit
+// are split into chunks sent as replies to the first one, whose ID is returned
+async fn send_telegram_notification_with_id(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message: &str,
+    topic_id: &str,
+    inline_keyboard: Option<serde_json::Value>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let chunks = split_message(message, TELEGRAM_MESSAGE_LIMIT);
+    let last_index = chunks.len().saturating_sub(1);
+    let mut inline_keyboard = inline_keyboard;
+    
+    // The first chunk goes out alone: the rest are sent as replies to it
+    let Some((first_chunk, rest)) = chunks.split_first() else {
+        return Err("Nothing to send".into());
+    };
+    let first_keyboard = if last_index == 0 { inline_keyboard.take() } else { None };
+    let first_message_id = send_telegram_message_part(
+        telegram,
+        bot_token,
+        chat_id,
+        first_chunk,
+        topic_id,
+        first_keyboard,
+        None
+    ).await?;
+    
+    let pipeline = telegram.topic_pipeline.max(1);
+    let (turn_tx, turn_rx) = tokio::sync::watch::channel(0usize);
+    let turn_tx = &turn_tx;
+    let first_message_id_ref = first_message_id.as_str();
+    
+    // Up to `pipeline` chunks are in flight at once, but each one starts only after the previous
+    // one has been answered or TOPIC_PIPELINE_GAP has passed, so they reach Telegram in order
+    let results: Vec<Result<String, String>> = stream::iter(rest.iter().enumerate())
+        .map(|(i, chunk)| {
+            let keyboard = if i + 1 == last_index { inline_keyboard.take() } else { None };
+            let mut turn_rx = turn_rx.clone();
+            async move {
+                turn_rx.wait_for(|turn| *turn >= i).await.ok();
+                let send = send_telegram_message_part(
+                    telegram,
+                    bot_token,
+                    chat_id,
+                    chunk,
+                    topic_id,
+                    keyboard,
+                    Some(first_message_id_ref)
+                );
+                tokio::pin!(send);
+                let result = tokio::select! {
+                    result = &mut send => result,
+                    _ = time::sleep(TOPIC_PIPELINE_GAP) => {
+                        turn_tx.send_modify(|turn| *turn = (*turn).max(i + 1));
+                        send.await
+                    }
+                };
+                turn_tx.send_modify(|turn| *turn = (*turn).max(i + 1));
+                result.map_err(|e| e.to_string())
+            }
+        })
+        .buffered(pipeline)
+        .collect()
+        .await;
+    
+    for result in results {
+        result?;
+    }
+    
+    Ok(first_message_id)
+}
+
+// Function to send a single message that fits Telegram's length limit, optionally as a reply
+async fn send_telegram_message_part(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message: &str,
+    topic_id: &str,
+    inline_keyboard: Option<serde_json::Value>,
+    reply_to_message_id: Option<&str>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "sendMessage");
+    
+    let mut json_body = json!({
+        "chat_id": chat_id,
+        "text": message,
+        "parse_mode": "HTML",
+        "disable_web_page_preview": true
+    });
+
+    // Add message_thread_id only if topic_id is not empty and not "1"
+    if !topic_id.is_empty() && topic_id != "1" {
+        json_body["message_thread_id"] = json!(topic_id);
+    }
+    
+    if let Some(keyboard) = inline_keyboard {
+        json_body["reply_markup"] = keyboard;
+    }
+    
+    if let Some(reply_to) = reply_to_message_id {
+        json_body["reply_to_message_id"] = json!(reply_to);
+    }
+    
+    let request = || telegram.http.client.post(&api_url).json(&json_body);
+    let response = send_with_backoff(request, "Telegram", TELEGRAM_MAX_RETRIES, telegram.http.network_retries, false, Some(&telegram.throttle)).await?;
+    
+    // Parse the response to get the message ID
+    let response_text = response.text().await?;
+    let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
+    
+    let message_id = response_json
+        .get("result")
+        .and_then(|result| result.get("message_id"))
+        .and_then(|id| id.as_i64())
+        .ok_or("Failed to get message ID from Telegram response")?;
+    
+    // Add a small delay to avoid Telegram rate limits
+    time::sleep(Duration::from_millis(1000)).await;
+    Ok(message_id.to_string())
+}
+
+// Function to send a photo with an HTML caption and return its message ID
+async fn send_telegram_photo_with_id(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    photo: Vec<u8>,
+    caption: &str,
+    topic_id: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if caption.chars().count() > TELEGRAM_CAPTION_LIMIT {
+        return Err(format!("Photo caption is longer than {} characters", TELEGRAM_CAPTION_LIMIT).into());
+    }
+    
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "sendPhoto");
+    
+    // Multipart forms can't be cloned, so one is built for each attempt
+    let request = || {
+        let photo = reqwest::multipart::Part::bytes(photo.clone())
+            .file_name("price_chart.png")
+            .mime_str("image/png")
+            .expect("image/png is a valid MIME type");
+        let mut form = reqwest::multipart::Form::new()
+            .text("chat_id", chat_id.to_string())
+            .text("caption", caption.to_string())
+            .text("parse_mode", "HTML")
+            .part("photo", photo);
+        
+        // Add message_thread_id only if topic_id is not empty and not "1"
+        if !topic_id.is_empty() && topic_id != "1" {
+            form = form.text("message_thread_id", topic_id.to_string());
+        }
+        telegram.http.client.post(&api_url).multipart(form)
+    };
+    let response = send_with_backoff(request, "Telegram", TELEGRAM_MAX_RETRIES, telegram.http.network_retries, false, Some(&telegram.throttle)).await?;
+    
+    let response_json: serde_json::Value = response.json().await?;
+    let message_id = response_json
+        .get("result")
+        .and_then(|result| result.get("message_id"))
+        .and_then(|id| id.as_i64())
+        .ok_or("Failed to get message ID from Telegram response")?;
+    
+    // Add a small delay to avoid Telegram rate limits
+    time::sleep(Duration::from_millis(1000)).await;
+    Ok(message_id.to_string())
+}
+
+// Function to delete a Telegram message
+async fn delete_telegram_message(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "deleteMessage");
+    
+    let json_body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id
+    });
+    
+    let response = telegram.http.client
+        .post(&api_url)
+        .json(&json_body)
+        .send()
+        .await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Failed to delete message: {} - {}", status, text).into());
+    }
+    
+    Ok(())
+}
+
+// Function to pin a message in the chat without notifying members
+async fn pin_telegram_message(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "pinChatMessage");
+    
+    let json_body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id,
+        "disable_notification": true
+    });
+    
+    let response = telegram.http.client
+        .post(&api_url)
+        .json(&json_body)
+        .send()
+        .await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Failed to pin message: {} - {}", status, text).into());
+    }
+    
+    Ok(())
+}
+
+// Function to set an emoji reaction on a message
+async fn set_telegram_reaction(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message_id: &str,
+    emoji: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "setMessageReaction");
+    
+    let json_body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id,
+        "reaction": [{ "type": "emoji", "emoji": emoji }]
+    });
+    
+    let response = telegram.http.client
+        .post(&api_url)
+        .json(&json_body)
+        .send()
+        .await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Failed to set reaction: {} - {}", status, text).into());
+    }
+    
+    Ok(())
+}
+
+// Function to acknowledge a callback query so the tapped button stops loading
+async fn answer_callback_query(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    callback_query_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "answerCallbackQuery");
+    
+    let json_body = json!({
+        "callback_query_id": callback_query_id
+    });
+    
+    let response = telegram.http.client
+        .post(&api_url)
+        .json(&json_body)
+        .send()
+        .await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Failed to answer callback query: {} - {}", status, text).into());
+    }
+    
+    Ok(())
+}
+
+// Function to unpin a previously pinned message
+async fn unpin_telegram_message(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    message_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "unpinChatMessage");
+    
+    let json_body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id
+    });
+    
+    let response = telegram.http.client
+        .post(&api_url)
+        .json(&json_body)
+        .send()
+        .await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Failed to unpin message: {} - {}", status, text).into());
+    }
+    
+    Ok(())
+}
+
+// How the devlog status message is refreshed each cycle (STATUS_MODE)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StatusMode {
+    // Edit a single status message in place
+    Edit,
+    // Post a fresh status message every cycle
+    New,
+}
+
+impl StatusMode {
+    fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "new" => StatusMode::New,
+            _ => StatusMode::Edit,
+        }
+    }
+}
+
+// The status message being edited and when it was posted, if known
+#[derive(Debug, Default)]
+struct StatusMessage {
+    id: Option<String>,
+    posted_at: Option<DateTime<Utc>>,
+}
+
+impl StatusMessage {
+    fn posted(&mut self, message_id: String) {
+        self.id = Some(message_id);
+        self.posted_at = Some(Utc::now());
+    }
+}
+
+// Function to edit the status message, posting a new one if there is none, it is older than
+// STATUS_EDIT_MAX_AGE_HOURS or the edit fails
+async fn update_status_message<N: Notifier>(
+    notifier: &N,
+    bot_token: &str,
+    chat_id: &str,
+    topic_id: &str,
+    status: &mut StatusMessage,
+    message: &str,
+    max_age: chrono::Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(message_id) = status.id.as_deref() {
+        if status.posted_at.is_some_and(|posted_at| Utc::now() - posted_at > max_age) {
+            info!("Status message {} is too old to edit. Posting a new one.", message_id);
+        } else {
+            match notifier.edit_message(bot_token, chat_id, message_id, message, topic_id).await {
+                Ok(()) => return Ok(()),
+                Err(e) if matches!(e.downcast_ref::<BotError>(), Some(BotError::MessageNotEditable(_))) => {
+                    info!("{}. Posting a new status message.", e);
+                }
+                Err(e) => error!("Failed to edit status message {}: {}. Posting a new one.", message_id, e),
+            }
+        }
+    }
+    
+    let message_id = notifier.send_message(bot_token, chat_id, message, topic_id, None).await?;
+    status.posted(message_id);
+    Ok(())
+}
+
+// Shared state for the Telegram webhook receiver
+struct WebhookState {
+    secret: String,
+    chat_id: String,
+    // Commands are handled by the main loop, which owns the persisted state
+    commands: mpsc::UnboundedSender<WebhookCommand>,
+}
+
+// Compare two byte strings in constant time to avoid leaking the secret through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Function to extract the chat ID from any Telegram update type
+fn update_chat_id(update: &serde_json::Value) -> Option<String> {
+    let chat = ["message", "edited_message", "channel_post", "message_reaction"]
+        .iter()
+        .find_map(|kind| update.get(kind).and_then(|u| u.get("chat")))
+        .or_else(|| update.get("callback_query")
+            .and_then(|q| q.get("message"))
+            .and_then(|m| m.get("chat")))?;
+    
+    chat.get("id").and_then(|id| id.as_i64()).map(|id| id.to_string())
+}
+
+// Function to read TELEGRAM_WEBHOOK_SECRET for a configured webhook. A blank secret is
+// rejected: it would let anyone who can reach the receiver inject commands
+fn webhook_secret(source: &ConfigSource) -> Result<String, BotError> {
+    let secret = source.string("TELEGRAM_WEBHOOK_SECRET");
+    if secret.trim().is_empty() {
+        return Err(BotError::Config(
+            "TELEGRAM_WEBHOOK_SECRET must be set to a non-empty value when TELEGRAM_WEBHOOK_URL is configured".to_string()
+        ));
+    }
+    Ok(secret)
+}
+
+// Handler for incoming Telegram updates
+async fn handle_webhook(
+    State(state): State<Arc<WebhookState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    // Verify the secret token before touching the body
+    let provided_secret = headers
+        .get("X-Telegram-Bot-Api-Secret-Token")
+        .and_then(|value| value.to_str().ok());
+    
+    if !provided_secret.is_some_and(|provided| constant_time_eq(provided.as_bytes(), state.secret.as_bytes())) {
+        warn!("Rejected webhook request with missing or invalid secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+    
+    let update: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(update) => update,
+        Err(e) => {
+            error!("Failed to parse webhook update: {}", e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    
+    // Ignore updates from other chats; acknowledge them so Telegram doesn't redeliver
+    match update_chat_id(&update) {
+        Some(chat_id) if chat_id == state.chat_id => {
+            debug!("Received webhook update: {}", update);
+            let command = subscription_request(&update)
+                .map(WebhookCommand::Subscription)
+                .or_else(|| calendar_request(&update).map(WebhookCommand::Calendar))
+                .or_else(|| refresh_request(&update).map(WebhookCommand::Refresh))
+                .or_else(|| reaction_command(&update));
+            if let Some(command) = command
+                && state.commands.send(command).is_err()
+            {
+                warn!("Dropped webhook command: the main loop has stopped");
+            }
+        }
+        chat_id => {
+            warn!("Ignored webhook update from unexpected chat {:?}", chat_id);
+        }
+    }
+    
+    StatusCode::OK
+}
+
+// Function to register the webhook with Telegram and start the receiver
+async fn start_webhook_receiver(
+    telegram: &TelegramNotifier,
+    bot_token: &str,
+    chat_id: &str,
+    webhook_url: &str,
+    secret: &str,
+    bind_addr: &str,
+    commands: mpsc::UnboundedSender<WebhookCommand>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let path = Url::parse(webhook_url)?.path().to_string();
+    
+    let api_url = telegram_api_url(&telegram.api_base, bot_token, "setWebhook");
+    let json_body = json!({
+        "url": webhook_url,
+        "secret_token": secret,
+        "allowed_updates": ["message", "callback_query", "message_reaction"]
+    });
+    
+    let response = telegram.http.client
+        .post(&api_url)
+        .json(&json_body)
+        .send()
+        .await?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await?;
+        return Err(format!("Failed to set webhook: {} - {}", status, text).into());
+    }
+    
+    let state = Arc::new(WebhookState {
+        secret: secret.to_string(),
+        chat_id: chat_id.to_string(),
+        commands,
+    });
+    let app = Router::new()
+        .route(&path, post(handle_webhook))
+        .with_state(state);
+    
+    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
+    info!("Webhook receiver listening on {}{}", bind_addr, path);
+    
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Webhook receiver stopped: {}", e);
+        }
+    });
+    
+    Ok(())
+}
+
+// Errors raised by the bot itself, as opposed to those from libraries it calls
+#[derive(Debug)]
+enum BotError {
+    // A setting is missing or malformed
+    Config(String),
+    // Every Travelpayouts token hit its quota for this cycle
+    TokensExhausted,
+    // Telegram refuses to edit the message, e.g. because it is too old; retrying won't help
+    MessageNotEditable(String),
+    // Travelpayouts kept answering 429 for every attempt of one request
+    RateLimited(u32),
+}
+
+impl std::fmt::Display for BotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotError::Config(message) => write!(f, "{}", message),
+            BotError::TokensExhausted => write!(f, "All Travelpayouts API tokens have exhausted their quota"),
+            BotError::MessageNotEditable(message_id) => write!(f, "Telegram message {} can no longer be edited", message_id),
+            BotError::RateLimited(attempts) => write!(f, "Travelpayouts API still rate limited after {} attempts", attempts),
+        }
+    }
+}
+
+impl Error for BotError {}
+
+// Date formats accepted for START_DATE and END_DATE: ISO and the dotted Russian style
+const CONFIG_DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%d.%m.%Y"];
+
+// Function to parse a date setting, naming the setting and the offending value on failure
+fn parse_config_date(name: &str, value: &str) -> Result<NaiveDate, BotError> {
+    let value = value.trim();
+    CONFIG_DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        .ok_or_else(|| BotError::Config(format!(
+            "{} must be YYYY-MM-DD or DD.MM.YYYY, got {:?}", name, value
+        )))
+}
+
+// Source of configuration values: entries in CONFIG_FILE override environment variables
+struct ConfigSource {
+    file_values: HashMap<String, String>,
+}
+
+impl ConfigSource {
+    fn load(config_file: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut file_values = HashMap::new();
+        
+        if !config_file.is_empty() {
+            // Same KEY=value format as .env
+            let contents = std::fs::read_to_string(config_file)?;
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid line in {}: {}", config_file, line))?;
+                let value = value.trim().trim_matches('"').trim_matches('\'');
+                file_values.insert(key.trim().to_string(), value.to_string());
+            }
+        }
+        
+        Ok(Self { file_values })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.file_values
+            .get(name)
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
+
+    fn string(&self, name: &str) -> String {
+        self.get(name).unwrap_or_default()
+    }
+
+    // Read a value, logging what won't work when it is missing
+    fn string_or_warn(&self, name: &str, consequence: &str) -> String {
+        self.get(name).unwrap_or_else(|| {
+            if consequence.is_empty() {
+                info!("{} not found in environment variables.", name);
+            } else {
+                info!("{} not found in environment variables. {}", name, consequence);
+            }
+            String::new()
+        })
+    }
+
+    // Read a boolean flag
+    fn flag(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|value| {
+            matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+        })
+    }
+
+    // Read a numeric value with a default
+    fn parse<T: std::str::FromStr>(&self, name: &str, default: T) -> T {
+        match self.get(name) {
+            Some(value) if !value.trim().is_empty() => value.trim().parse().unwrap_or_else(|_| {
+                warn!("Invalid value for {}: {}. Using default.", name, value);
+                default
+            }),
+            _ => default,
+        }
+    }
+
+    // Read an optional numeric value; an invalid one is logged and left unset
+    fn optional<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        let value = self.get(name).filter(|value| !value.trim().is_empty())?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                warn!("Invalid value for {}: {}. Ignoring it.", name, value);
+                None
+            }
+        }
+    }
+}
+
+// Kinds of messages the bot sends, each routed to its own topic
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MessageCategory {
+    Status,
+    Error,
+    FoundFlight,
+    SeatInfo,
+    PriceDrop,
+}
+
+// Topic for each message category. By default status and errors go to the dev logs topic
+// and everything else to the found flights topic
+#[derive(Debug, Clone)]
+struct TopicRouter {
+    status: String,
+    error: String,
+    found_flight: String,
+    seat_info: String,
+    price_drop: String,
+}
+
+impl TopicRouter {
+    // Each TELEGRAM_<CATEGORY>_TOPIC_ID may be a topic id or the name of a configured topic:
+    // `devlogs`, `found` or `recap`
+    fn from_source(source: &ConfigSource, devlogs: &str, found: &str, recap: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let route = |name: &str, default: &str| -> Result<String, Box<dyn Error + Send + Sync>> {
+            let value = source.string(name);
+            let (referenced, topic) = match value.trim() {
+                "" => return Ok(default.to_string()),
+                "devlogs" => ("TELEGRAM_DEVLOGS_TOPIC_ID", devlogs),
+                "found" => ("TELEGRAM_FOUND_TOPIC_ID", found),
+                "recap" => ("TELEGRAM_RECAP_TOPIC_ID", recap),
+                id if id.parse::<i64>().is_ok() => return Ok(id.to_string()),
+                other => return Err(BotError::Config(format!(
+                    "{} must be a topic id or one of devlogs, found, recap, got {:?}", name, other
+                )).into()),
+            };
+            if topic.is_empty() {
+                return Err(BotError::Config(format!("{} refers to {}, which is not set", name, referenced)).into());
+            }
+            Ok(topic.to_string())
+        };
+        
+        Ok(Self {
+            status: route("TELEGRAM_STATUS_TOPIC_ID", devlogs)?,
+            error: route("TELEGRAM_ERROR_TOPIC_ID", devlogs)?,
+            found_flight: found.to_string(),
+            seat_info: route("TELEGRAM_SEAT_INFO_TOPIC_ID", found)?,
+            price_drop: route("TELEGRAM_PRICE_DROP_TOPIC_ID", found)?,
+        })
+    }
+
+    fn topic(&self, category: MessageCategory) -> &str {
+        match category {
+            MessageCategory::Status => &self.status,
+            MessageCategory::Error => &self.error,
+            MessageCategory::FoundFlight => &self.found_flight,
+            MessageCategory::SeatInfo => &self.seat_info,
+            MessageCategory::PriceDrop => &self.price_drop,
+        }
+    }
+}
+
+// Search and notification settings, reloadable from CONFIG_FILE between cycles
+struct Config {
+    aviasales_api_keys: ApiKeyPool,
+    telegram_bot_token: String,
+    telegram_chat_id: String,
+    telegram_devlogs_topic_id: String,
+    telegram_found_topic_id: String,
+    topics: TopicRouter,
+    alert_chat_id: String,
+    alert_topic_id: String,
+    alert_severity: Severity,
+    telegram_recap_topic_id: String,
+    recap_hour: u32,
+    airlabs_api_key: String,
+    airlabs_max_retries: u32,
+    airlabs_max_per_cycle: Option<usize>,
+    watch_flights: Vec<(String, String)>,
+    origin: String,
+    destinations: Vec<String>,
+    // Currency fares are searched in (CURRENCY), and per-destination overrides (ROUTE_CURRENCIES)
+    currency: String,
+    route_currencies: HashMap<String, String>,
+    // Dream fare per destination (TARGET_PRICES); beating it mentions MENTION_USER
+    target_prices: HashMap<String, i64>,
+    mention_user: Option<Mention>,
+    best_destination_only: bool,
+    // Also search every route in the opposite direction
+    bidirectional: bool,
+    // Send one ranked message with the window's cheapest fares per cycle instead of per-date notifications
+    summary_only: bool,
+    // Only record fares on the first cycle after deployment, without notifying about them
+    suppress_first_run: bool,
+    // Notify a route and date only when its cheapest price beats the lowest one stored
+    price_drop_only: bool,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    explicit_dates: Vec<NaiveDate>,
+    departure_days: Vec<Weekday>,
+    blackout_dates: Vec<(NaiveDate, NaiveDate)>,
+    outbound_date: Option<NaiveDate>,
+    return_window: Option<(NaiveDate, NaiveDate)>,
+    max_dates_per_cycle: usize,
+    airline_filter: AirlineFilter,
+    search_paging: SearchPaging,
+    search_concurrency: SearchConcurrency,
+    // How long a search keeps its slot after finishing (SEARCH_DELAY_MS)
+    search_delay: Duration,
+    show_direct_premium: bool,
+    fallback_provider: Option<FallbackProvider>,
+    duration_precision: DurationPrecision,
+    send_price_chart: bool,
+    min_economy_seats: Option<i64>,
+    min_fare_seats: Option<i64>,
+    secondary_currency: String,
+    display: DisplaySettings,
+    sort_by: SortBy,
+    group_by: GroupBy,
+    compact: bool,
+    show_price_trend: bool,
+    calendar_keyboard: bool,
+    link_marker: String,
+    shortener_url: String,
+    shortener_api_key: String,
+    rates_url: String,
+    notify_sold_out: bool,
+    notify_no_flights: bool,
+    notify_cooldown: chrono::Duration,
+    error_notify_cooldown: chrono::Duration,
+    dedup_window: chrono::Duration,
+    alert_percentile: Option<f64>,
+    price_noise_threshold: Option<f64>,
+    max_cycles: Option<u32>,
+    max_runtime: Option<Duration>,
+    watchdog_timeout: Option<Duration>,
+    // How often the wait between cycles checks for shutdown and CONFIG_FILE changes
+    wait_checkpoint: Duration,
+    schedule: Vec<NaiveTime>,
+    alert_min_samples: usize,
+    status_mode: StatusMode,
+    status_delete_previous: bool,
+    // How many dates with flights the cycle summary links at most
+    max_summary_dates: usize,
+    // Status messages older than this are replaced instead of edited
+    status_edit_max_age: chrono::Duration,
+    pin_status: bool,
+    react_on_drop: bool,
+    notify_record_low: bool,
+}
+
+impl Config {
+    fn from_source(source: &ConfigSource) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        // Get API keys; several comma-separated tokens are rotated
+        let aviasales_api_keys = ApiKeyPool::new(
+            &source.get("TRAVELPAYOUTS_API_KEY")
+                .ok_or("TRAVELPAYOUTS_API_KEY not found in environment variables")?
+        );
+        
+        // Get Telegram bot token, chat ID and topic IDs
+        let telegram_bot_token = source.string_or_warn("TELEGRAM_BOT_TOKEN", "Notifications will not be sent.");
+        let telegram_chat_id = source.string_or_warn("TELEGRAM_CHAT_ID", "Notifications will not be sent.");
+        let telegram_devlogs_topic_id = source.string_or_warn("TELEGRAM_DEVLOGS_TOPIC_ID", "Dev logs notifications will not be sent.");
+        let telegram_found_topic_id = source.string_or_warn("TELEGRAM_FOUND_TOPIC_ID", "Flight found notifications will not be sent.");
+        
+        let telegram_recap_topic_id = source.string("TELEGRAM_RECAP_TOPIC_ID");
+        let topics = TopicRouter::from_source(source, &telegram_devlogs_topic_id, &telegram_found_topic_id, &telegram_recap_topic_id)?;
+        
+        // Get AirLabs API key
+        let airlabs_api_key = source.string_or_warn("AIRLABS_API_KEY", "AirLabs enrichment will not be available.");
+        
+        // Define search parameters
+        let origin = resolve_place(&source.string_or_warn("ORIGIN", ""))?; // Origin (all airports)
+        let destinations = source.string_or_warn("DESTINATION", "") // Comma-separated destinations
+            .split(',')
+            .filter(|destination| !destination.trim().is_empty())
+            .map(resolve_place)
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        // Define date range; explicit DATES replace it, spanning from the first to the last date
+        let explicit_dates = parse_explicit_dates(&source.string("DATES"))?;
+        let (start_date, end_date) = match (explicit_dates.first(), explicit_dates.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => (
+                parse_config_date("START_DATE", &source.string_or_warn("START_DATE", ""))?,
+                parse_config_date("END_DATE", &source.string_or_warn("END_DATE", ""))?,
+            ),
+        };
+        
+        // Number of results per page and the total cap across pages
+        let results_limit = source.parse("RESULTS_LIMIT", 30);
+        let search_concurrency = source.parse("SEARCH_CONCURRENCY", 3);
+        
+        Ok(Self {
+            aviasales_api_keys,
+            telegram_bot_token,
+            telegram_chat_id,
+            telegram_devlogs_topic_id,
+            telegram_found_topic_id,
+            topics,
+            alert_chat_id: source.string("ALERT_CHAT_ID").trim().to_string(),
+            alert_topic_id: source.string("ALERT_TOPIC_ID").trim().to_string(),
+            alert_severity: Severity::from_env_value(&source.string("ALERT_SEVERITY")),
+            telegram_recap_topic_id,
+            recap_hour: source.parse("RECAP_HOUR", 9),
+            airlabs_api_key,
+            airlabs_max_retries: source.parse("AIRLABS_MAX_RETRIES", 3),
+            airlabs_max_per_cycle: source.optional("AIRLABS_MAX_PER_CYCLE"),
+            watch_flights: parse_watch_flights(&source.string("WATCH_FLIGHTS"))?,
+            origin,
+            destinations,
+            currency: source.get("CURRENCY").map_or("rub".to_string(), |currency| currency.trim().to_lowercase()),
+            route_currencies: parse_route_currencies(&source.string("ROUTE_CURRENCIES"))?,
+            target_prices: parse_target_prices(&source.string("TARGET_PRICES"))?,
+            mention_user: parse_mention(&source.string("MENTION_USER"))?,
+            best_destination_only: source.flag("BEST_DESTINATION_ONLY"),
+            summary_only: source.flag("SUMMARY_ONLY"),
+            suppress_first_run: source.flag("SUPPRESS_FIRST_RUN"),
+            price_drop_only: source.flag("PRICE_DROP_ONLY"),
+            bidirectional: source.flag("BIDIRECTIONAL"),
+            start_date,
+            end_date,
+            explicit_dates,
+            departure_days: parse_departure_days(&source.string("DEPARTURE_DAYS"))?,
+            blackout_dates: parse_blackout_dates(&source.string("BLACKOUT_DATES"))?,
+            outbound_date: source.get("OUTBOUND_DATE")
+                .filter(|value| !value.trim().is_empty())
+                .map(|value| parse_config_date("OUTBOUND_DATE", &value))
+                .transpose()?,
+            return_window: source.get("RETURN_WINDOW")
+                .filter(|value| !value.trim().is_empty())
+                .map(|value| parse_config_date_range("RETURN_WINDOW", &value))
+                .transpose()?,
+            max_dates_per_cycle: source.parse("MAX_DATES_PER_CYCLE", 0),
+            airline_filter: AirlineFilter::new(
+                &source.string("AIRLINES_ALLOW"),
+                &source.string("AIRLINES_DENY"),
+            ),
+            search_paging: SearchPaging {
+                limit: results_limit,
+                max_results: source.parse("RESULTS_MAX_TOTAL", results_limit),
+            },
+            search_concurrency: SearchConcurrency::new(
+                source.parse("ROUTE_CONCURRENCY", 1),
+                source.parse("DATE_CONCURRENCY", search_concurrency),
+                search_concurrency,
+            ),
+            search_delay: Duration::from_millis(source.parse("SEARCH_DELAY_MS", 1000)),
+            show_direct_premium: source.flag("SHOW_DIRECT_PREMIUM"),
+            fallback_provider: parse_fallback_provider(&source.string("FALLBACK_PROVIDER"))?,
+            duration_precision: DurationPrecision::from_env_value(&source.string("DURATION_PRECISION")),
+            send_price_chart: source.flag("SEND_PRICE_CHART"),
+            min_economy_seats: source.optional("MIN_ECONOMY_SEATS"),
+            min_fare_seats: source.optional("MIN_FARE_SEATS"),
+            secondary_currency: normalize_code(&source.string("SECONDARY_CURRENCY")),
+            display: DisplaySettings {
+                place_labels: parse_place_labels(&source.string("PLACE_LABELS")),
+                timezone: parse_display_timezone(
+                    &source.string("DISPLAY_TIMEZONE"),
+                    source.parse("DISPLAY_UTC_OFFSET_HOURS", 5),
+                )?,
+                source_timezone: parse_source_timezone(&source.string("SOURCE_TIMEZONE"))?,
+                locale: Locale::from_env_value(&source.string("LOCALE")),
+            },
+            sort_by: SortBy::from_env_value(&source.string("SORT_BY")),
+            group_by: GroupBy::from_env_value(&source.string("GROUP_BY")),
+            compact: source.flag("COMPACT"),
+            show_price_trend: source.flag("SHOW_PRICE_TREND"),
+            calendar_keyboard: source.flag("CALENDAR_KEYBOARD"),
+            link_marker: source.string("LINK_MARKER").trim().to_string(),
+            shortener_url: source.string("SHORTENER_URL").trim().to_string(),
+            shortener_api_key: source.string("SHORTENER_API_KEY").trim().to_string(),
+            rates_url: source.get("RATES_URL")
+                .unwrap_or_else(|| "https://open.er-api.com/v6/latest/RUB".to_string()),
+            notify_sold_out: source.flag("NOTIFY_SOLD_OUT"),
+            notify_no_flights: source.flag("NOTIFY_NO_FLIGHTS"),
+            notify_cooldown: chrono::Duration::hours(source.parse("NOTIFY_COOLDOWN_HOURS", 24)),
+            error_notify_cooldown: chrono::Duration::minutes(source.parse("ERROR_NOTIFY_COOLDOWN_MINUTES", 60)),
+            dedup_window: chrono::Duration::hours(source.parse("DEDUP_WINDOW_HOURS", 48)),
+            alert_percentile: source.optional("ALERT_PERCENTILE"),
+            price_noise_threshold: source.optional("PRICE_NOISE_THRESHOLD"),
+            max_cycles: source.optional("MAX_CYCLES"),
+            max_runtime: source.get("MAX_RUNTIME_MINUTES")
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+            watchdog_timeout: Some(source.parse("WATCHDOG_TIMEOUT_SECS", 1800_u64))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            wait_checkpoint: Duration::from_secs(source.parse("WAIT_CHECKPOINT_SECS", 30_u64).max(1)),
+            schedule: parse_schedule(&source.string("SCHEDULE"))?,
+            alert_min_samples: source.parse("ALERT_MIN_SAMPLES", 10),
+            status_mode: StatusMode::from_env_value(&source.string("STATUS_MODE")),
+            status_delete_previous: source.flag("STATUS_DELETE_PREVIOUS"),
+            max_summary_dates: source.parse("MAX_SUMMARY_DATES", 20),
+            status_edit_max_age: chrono::Duration::hours(source.parse("STATUS_EDIT_MAX_AGE_HOURS", 47)),
+            pin_status: source.flag("PIN_STATUS"),
+            react_on_drop: source.flag("REACT_ON_DROP"),
+            notify_record_low: source.flag("NOTIFY_RECORD_LOW"),
+        })
+    }
+
+    // Routes searched each cycle: the origin against every destination
+    // With BIDIRECTIONAL, each route is followed by its reverse
+    fn routes(&self) -> Vec<Route> {
+        self.destinations
+            .iter()
+            .flat_map(|destination| {
+                let route = Route {
+                    origin: self.origin.clone(),
+                    destination: destination.clone(),
+                    currency: self.route_currencies.get(destination).cloned(),
+                    target_price: self.target_prices.get(destination).copied(),
+                };
+                let reversed = self.bidirectional.then(|| route.reversed());
+                std::iter::once(route).chain(reversed)
+            })
+            .collect()
+    }
+
+    // Currency a route given as `ORIGIN-DESTINATION` is searched in; routes nobody configured,
+    // like subscribed ones, use CURRENCY
+    fn route_currency(&self, route_key: &str) -> String {
+        self.routes()
+            .into_iter()
+            .find(|route| format!("{}-{}", route.origin, route.destination) == route_key)
+            .and_then(|route| route.currency)
+            .unwrap_or_else(|| self.currency.clone())
+    }
+
+    // Whether an error of this severity goes to the alert chat
+    fn escalates(&self, severity: Severity) -> bool {
+        self.telegram_enabled() && !self.alert_chat_id.is_empty() && severity >= self.alert_severity
+    }
+
+    // How often cycles run, for the startup message
+    fn describe_schedule(&self) -> String {
+        if self.schedule.is_empty() {
+            return format!("каждые {} {}", CHECK_INTERVAL_HOURS, plural_ru(CHECK_INTERVAL_HOURS as i64, "час", "часа", "часов"));
+        }
+        let times: Vec<String> = self.schedule.iter().map(|time| time.format("%H:%M").to_string()).collect();
+        format!("ежедневно в {}", times.join(", "))
+    }
+
+    // Display names of all destinations
+    fn destination_names(&self) -> String {
+        self.destinations
+            .iter()
+            .map(|destination| get_city_name(&self.display, destination))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn telegram_enabled(&self) -> bool {
+        !self.telegram_bot_token.is_empty() && !self.telegram_chat_id.is_empty()
+    }
+
+    fn airlabs_enabled(&self) -> bool {
+        !self.airlabs_api_key.is_empty()
+    }
+
+    // Dates to search: the DATES list as given, or the configured range restricted to the departure weekdays
+    fn search_dates(&self) -> Vec<NaiveDate> {
+        if !self.explicit_dates.is_empty() {
+            return self.explicit_dates.clone();
+        }
+        
+        date_range(self.start_date, self.end_date)
+            .into_iter()
+            .filter(|date| self.departure_days.contains(&date.weekday()))
+            .filter(|date| !self.blackout_dates.iter().any(|(start, end)| (start..=end).contains(&date)))
+            .collect()
+    }
+}
+
+// Function to check a configuration for invalid or inconsistent values
+fn validate_config(config: &Config) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if config.aviasales_api_keys.is_empty() {
+        return Err("TRAVELPAYOUTS_API_KEY must contain at least one token".into());
+    }
+    
+    if config.origin.is_empty() {
+        return Err(BotError::Config("ORIGIN must be set to an IATA code or a city name".to_string()).into());
+    }
+    
+    if config.destinations.is_empty() {
+        return Err(BotError::Config("DESTINATION must be set to one or more IATA codes or city names".to_string()).into());
+    }
+    
+    if config.start_date > config.end_date {
+        return Err(format!("START_DATE {} is after END_DATE {}", config.start_date, config.end_date).into());
+    }
+    
+    if let Some(percentile) = config.alert_percentile
+        && !(0.0..=100.0).contains(&percentile)
+    {
+        return Err(format!("ALERT_PERCENTILE must be between 0 and 100, got {}", percentile).into());
+    }
+    
+    match (config.outbound_date, config.return_window) {
+        (Some(outbound_date), Some((return_start, _))) if return_start < outbound_date => {
+            return Err(BotError::Config(format!("RETURN_WINDOW starts before OUTBOUND_DATE {}", outbound_date)).into());
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            return Err(BotError::Config("OUTBOUND_DATE and RETURN_WINDOW must be set together".to_string()).into());
+        }
+        _ => {}
+    }
+    
+    if let Some(threshold) = config.price_noise_threshold
+        && threshold < 0.0
+    {
+        return Err(format!("PRICE_NOISE_THRESHOLD must not be negative, got {}", threshold).into());
+    }
+    
+    if config.recap_hour > 23 {
+        return Err(format!("RECAP_HOUR must be between 0 and 23, got {}", config.recap_hour).into());
+    }
+    
+    if config.search_paging.limit == 0 {
+        return Err("RESULTS_LIMIT must be greater than zero".into());
+    }
+    
+    Ok(())
+}
+
+// Function to load and validate the configuration from CONFIG_FILE and environment variables
+fn load_config(config_file: &str) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let source = ConfigSource::load(config_file)?;
+    let config = Config::from_source(&source)?;
+    validate_config(&config)?;
+    Ok(config)
+}
+
+// Function to get the modification time of the config file, if one is configured
+fn config_file_modified(config_file: &str) -> Option<std::time::SystemTime> {
+    if config_file.is_empty() {
+        return None;
+    }
+    
+    std::fs::metadata(config_file).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Function to reload the configuration when CONFIG_FILE changed since the last check; an
+// invalid new configuration is rejected and the current one kept
+async fn reload_config_if_changed<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config_file: &str,
+    config: &mut Arc<Config>,
+    config_modified: &mut Option<std::time::SystemTime>,
+) {
+    let modified = config_file_modified(config_file);
+    if modified == *config_modified {
+        return;
+    }
+    *config_modified = modified;
+    
+    let reload_message = match load_config(config_file) {
+        Ok(new_config) => {
+            *config = Arc::new(new_config);
+            info!("Configuration reloaded from {}", config_file);
+            "🔄 <b>Конфигурация перезагружена</b>\n\n<i>Новые настройки применены к этому циклу.</i>".to_string()
+        }
+        Err(e) => {
+            warn!("Rejected new configuration from {}: {}. Keeping the previous one.", config_file, e);
+            escalate_error(&deps.notifier, config, Severity::Critical, "config", config_file, &e.to_string()).await;
+            format!(
+                "⚠️ <b>Новая конфигурация отклонена</b>\n\n\
+                ❌ Ошибка: {}\n\n\
+                <i>Продолжаем с прежними настройками.</i>",
+                escape_html(&e.to_string())
+            )
+        }
+    };
+    
+    if config.telegram_enabled()
+        && let Err(e) = deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &reload_message,
+            config.topics.topic(MessageCategory::Status),
+            None
+        ).await
+    {
+        error!("Failed to send configuration reload notification: {}", e);
+    }
+}
+
+// User-Agent sent with every request unless USER_AGENT overrides it
+const DEFAULT_USER_AGENT: &str = concat!("flight-search-bot/", env!("CARGO_PKG_VERSION"));
+
+// Function to parse EXTRA_HEADERS, e.g. `X-Api-Client: bot; X-Trace: on`
+fn parse_extra_headers(raw: &str) -> Result<reqwest::header::HeaderMap, Box<dyn Error + Send + Sync>> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    
+    for entry in raw.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (name, value) = entry
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid header \"{}\" in EXTRA_HEADERS, expected Name: value", entry))?;
+        let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|e| format!("Invalid header name \"{}\" in EXTRA_HEADERS: {}", name.trim(), e))?;
+        let value = reqwest::header::HeaderValue::from_str(value.trim())
+            .map_err(|e| format!("Invalid value for header {} in EXTRA_HEADERS: {}", name, e))?;
+        headers.insert(name, value);
+    }
+    
+    Ok(headers)
+}
+
+// Function to build the configured proxies from HTTP_PROXY, HTTPS_PROXY and ALL_PROXY,
+// with optional PROXY_USERNAME/PROXY_PASSWORD and NO_PROXY exclusions
+fn configured_proxies(source: &ConfigSource) -> Result<Vec<reqwest::Proxy>, Box<dyn Error + Send + Sync>> {
+    let no_proxy = source.get("NO_PROXY").and_then(|list| reqwest::NoProxy::from_string(&list));
+    let username = source.string("PROXY_USERNAME");
+    let password = source.string("PROXY_PASSWORD");
+    let mut proxies = Vec::new();
+    
+    for name in ["HTTP_PROXY", "HTTPS_PROXY", "ALL_PROXY"] {
+        let Some(raw_url) = source.get(name).filter(|value| !value.trim().is_empty()) else {
+            continue;
+        };
+        
+        // Fail fast on a malformed proxy URL instead of on the first request
+        let proxy_url = Url::parse(raw_url.trim())
+            .map_err(|e| format!("Invalid {} \"{}\": {}", name, raw_url, e))?;
+        if !matches!(proxy_url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(format!("Unsupported proxy scheme in {}: {}", name, proxy_url.scheme()).into());
+        }
+        
+        let mut proxy = match name {
+            "HTTP_PROXY" => reqwest::Proxy::http(proxy_url.as_str())?,
+            "HTTPS_PROXY" => reqwest::Proxy::https(proxy_url.as_str())?,
+            _ => reqwest::Proxy::all(proxy_url.as_str())?,
+        };
+        if !username.is_empty() {
+            proxy = proxy.basic_auth(&username, &password);
+        }
+        
+        info!("Using proxy from {} ({})", name, proxy_url.host_str().unwrap_or_default());
+        proxies.push(proxy.no_proxy(no_proxy.clone()));
+    }
+    
+    Ok(proxies)
+}
+
+// Time to establish a connection, capped by HTTP_TIMEOUT_SECS
+const HTTP_CONNECT_TIMEOUT_SECS: u64 = 10;
+// Idle connections kept open per host for reuse
+const HTTP_POOL_MAX_IDLE_PER_HOST: usize = 4;
+
+// Function to build the shared HTTP client from startup settings
+fn build_http_client(source: &ConfigSource) -> Result<Client, Box<dyn Error + Send + Sync>> {
+    let user_agent = source.get("USER_AGENT")
+        .filter(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string());
+    let extra_headers = parse_extra_headers(&source.string("EXTRA_HEADERS"))?;
+    
+    // Bound every request so a hung connection can't stall the cycle; keep a few connections
+    // per host open for reuse across dates
+    let timeout_secs: u64 = source.parse("HTTP_TIMEOUT_SECS", 30);
+    if timeout_secs == 0 {
+        return Err(BotError::Config("HTTP_TIMEOUT_SECS must be greater than 0".to_string()).into());
+    }
+    
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .default_headers(extra_headers)
+        .timeout(Duration::from_secs(timeout_secs))
+        .connect_timeout(Duration::from_secs(HTTP_CONNECT_TIMEOUT_SECS.min(timeout_secs)))
+        .pool_max_idle_per_host(HTTP_POOL_MAX_IDLE_PER_HOST);
+    for proxy in configured_proxies(source)? {
+        builder = builder.proxy(proxy);
+    }
+    
+    let client = builder.build()?;
+    
+    Ok(client)
+}
+
+// Function to send a test message to every configured topic, optionally deleting it afterwards.
+// Returns each topic's setting name with the outcome
+async fn run_self_test<N: Notifier>(
+    notifier: &N,
+    config: &Config,
+    delete_messages: bool,
+) -> Vec<(&'static str, Result<(), String>)> {
+    let topics = [
+        ("TELEGRAM_DEVLOGS_TOPIC_ID", &config.telegram_devlogs_topic_id),
+        ("TELEGRAM_FOUND_TOPIC_ID", &config.telegram_found_topic_id),
+        ("TELEGRAM_RECAP_TOPIC_ID", &config.telegram_recap_topic_id),
+    ];
+    let mut results = Vec::new();
+    
+    for (name, topic_id) in topics {
+        // The recap topic is optional
+        if name == "TELEGRAM_RECAP_TOPIC_ID" && topic_id.is_empty() {
+            continue;
+        }
+        
+        let result = match notifier.send_message(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            "🧪 <i>Тестовое сообщение: проверка настроек бота</i>",
+            topic_id,
+            None
+        ).await {
+            Ok(message_id) => {
+                if delete_messages
+                    && let Err(e) = notifier.delete_message(&config.telegram_bot_token, &config.telegram_chat_id, &message_id).await
+                {
+                    error!("Failed to delete self-test message in {}: {}", name, e);
+                }
+                Ok(())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        
+        match &result {
+            Ok(()) => info!("Self-test: {} OK", name),
+            Err(e) => error!("Self-test: {} failed: {}", name, e),
+        }
+        results.push((name, result));
+    }
+    
+    results
+}
+
+// Function to format self-test results for the devlog topic
+fn format_self_test_report(results: &[(&str, Result<(), String>)]) -> String {
+    let mut report = "🧪 <b>Проверка настроек Telegram</b>\n\n".to_string();
+    
+    for (name, result) in results {
+        match result {
+            Ok(()) => report.push_str(&format!("✅ {}\n", name)),
+            Err(e) => report.push_str(&format!("❌ {}: {}\n", name, escape_html(e))),
+        }
+    }
+    
+    report
+}
+
+// Check flights every 6 hours
+const CHECK_INTERVAL_HOURS: u64 = 6;
+
+// Function to compute the wait before retrying a failed cycle: one minute, doubling per failure
+fn cycle_retry_delay(failed_cycles: u32) -> Duration {
+    Duration::from_secs(60 * 2_u64.pow(failed_cycles.saturating_sub(1).min(10)))
+}
+
+// Time of the last progress made by the running cycle, watched by the watchdog
+struct CycleProgress {
+    last_progress: Mutex<time::Instant>,
+}
+
+impl CycleProgress {
+    fn new() -> Self {
+        Self { last_progress: Mutex::new(time::Instant::now()) }
+    }
+
+    // Record that the cycle is still making progress
+    fn touch(&self) {
+        *self.last_progress.lock().unwrap() = time::Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_progress.lock().unwrap().elapsed()
+    }
+}
+
+// Function to run a cycle under the watchdog: if no progress is recorded within the
+// timeout, the cycle is aborted, reported to the dev logs topic and returned as failed
+async fn run_cycle_with_watchdog<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Arc<Config>,
+    state: &mut CycleState,
+    stats: &SearchStatistics,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    deps.progress.touch();
+    let Some(timeout) = config.watchdog_timeout else {
+        return run_cycle(deps, config, state, stats).await;
+    };
+    
+    let cycle = run_cycle(deps, config, state, stats);
+    tokio::pin!(cycle);
+    
+    loop {
+        let idle = deps.progress.idle_for();
+        if idle >= timeout {
+            break;
+        }
+        tokio::select! {
+            result = &mut cycle => return result,
+            _ = time::sleep(timeout - idle) => {}
+        }
+    }
+    
+    let idle_secs = deps.progress.idle_for().as_secs();
+    error!("Watchdog: no search progress for {} seconds, aborting the cycle", idle_secs);
+    
+    if config.telegram_enabled()
+        && let Err(e) = deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &format!(
+                "⏱ <b>Поиск завис</b>\n\n\
+                Нет прогресса уже {} сек.\n\n\
+                <i>Цикл прерван и будет перезапущен.</i>",
+                idle_secs
+            ),
+            config.topics.topic(MessageCategory::Error),
+            None
+        ).await
+    {
+        error!("Failed to send watchdog alert: {}", e);
+    }
+    escalate_error(
+        &deps.notifier,
+        config,
+        Severity::Error,
+        "watchdog",
+        "цикл поиска",
+        &format!("нет прогресса уже {} сек.", idle_secs)
+    ).await;
+    
+    Err(format!("cycle made no progress for {} seconds", idle_secs).into())
+}
+
+// Function to parse SCHEDULE: comma-separated local `HH:MM` times, returned sorted
+fn parse_schedule(raw: &str) -> Result<Vec<NaiveTime>, Box<dyn Error + Send + Sync>> {
+    let mut times = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|time| !time.is_empty())
+        .map(|time| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| BotError::Config(format!("SCHEDULE times must be HH:MM, got {:?}", time)).into())
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+    times.sort();
+    times.dedup();
+    Ok(times)
+}
+
+// Function to find the first scheduled local time strictly after `now`
+// A time that falls into a DST gap runs an hour later; in an overlap the earlier instant wins
+fn next_scheduled_run<Tz: TimeZone>(now: DateTime<Utc>, times: &[NaiveTime], tz: &Tz) -> Option<DateTime<Utc>> {
+    let today = now.with_timezone(tz).date_naive();
+    
+    (0..=2)
+        .filter_map(|days| today.checked_add_days(chrono::Days::new(days)))
+        .flat_map(|date| times.iter().map(move |time| date.and_time(*time)))
+        .filter_map(|naive| {
+            tz.from_local_datetime(&naive)
+                .earliest()
+                .or_else(|| tz.from_local_datetime(&(naive + chrono::Duration::hours(1))).earliest())
+        })
+        .map(|local| local.with_timezone(&Utc))
+        .filter(|run| *run > now)
+        .min()
+}
+
+// Function to compute how long to wait before the next cycle: until the next SCHEDULE time,
+// or the fixed check interval when no schedule is set
+fn next_cycle_delay(config: &Config, now: DateTime<Utc>, check_interval: Duration) -> Duration {
+    next_scheduled_run(now, &config.schedule, &config.display.timezone)
+        .and_then(|run| run.signed_duration_since(now).to_std().ok())
+        .unwrap_or(check_interval)
+}
+
+// Function to describe when the next cycle starts, for status messages
+fn format_next_cycle(config: &Config, now: DateTime<Utc>) -> String {
+    match next_scheduled_run(now, &config.schedule, &config.display.timezone) {
+        Some(run) => format!("в <b>{}</b>", run.with_timezone(&config.display.timezone).format("%H:%M")),
+        None => format!("через <b>{} {}</b>", CHECK_INTERVAL_HOURS, plural_ru(CHECK_INTERVAL_HOURS as i64, "час", "часа", "часов")),
+    }
+}
+
+// Function to check MAX_CYCLES and MAX_RUNTIME_MINUTES; returns why the bot should stop, if it should
+fn run_limit_reached(config: &Config, cycles: u32, elapsed: Duration) -> Option<String> {
+    if let Some(max_cycles) = config.max_cycles
+        && cycles >= max_cycles
+    {
+        return Some(format!("выполнено циклов: {} из {}", cycles, max_cycles));
+    }
+    if let Some(max_runtime) = config.max_runtime
+        && elapsed >= max_runtime
+    {
+        return Some(format!("истекло время работы: {} мин.", max_runtime.as_secs() / 60));
+    }
+    None
+}
+
+// Source of fares for a single date, so a cycle can run against something other than the live API
+// Searches run on spawned tasks, so searchers and their futures must be sendable
+trait FlightSearcher: Send + Sync + 'static {
+    fn search(&self, config: &Config, route: &Route, departure_date: &str) -> impl Future<Output = Result<FlightData, Box<dyn Error + Send + Sync>>> + Send;
+}
+
+// Travelpayouts searches through TRAVELPAYOUTS_API_BASE, sharing one rate limit pause
+struct TravelpayoutsSearcher {
+    http: HttpClient,
+    api_base: String,
+    throttle: RequestThrottle,
+}
+
+impl FlightSearcher for TravelpayoutsSearcher {
+    async fn search(&self, config: &Config, route: &Route, departure_date: &str) -> Result<FlightData, Box<dyn Error + Send + Sync>> {
+        let currency = route.currency(&config.currency);
+        let flight_data = if config.show_direct_premium {
+            search_direct_and_connecting(self, &route.origin, &route.destination, departure_date, &config.aviasales_api_keys, config.search_paging, currency).await?
+        } else {
+            search_flights(self, &route.origin, &route.destination, departure_date, &config.aviasales_api_keys, true, config.search_paging, currency).await?
+        };
+        
+        Ok(search_with_fallback(self, config, route, departure_date, flight_data).await)
+    }
+}
+
+// Destination for Telegram messages, so a cycle can run without the live Bot API
+trait Notifier {
+    // Send a message, split into parts if needed, and return the first part's ID
+    async fn send_message(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message: &str,
+        topic_id: &str,
+        inline_keyboard: Option<serde_json::Value>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+    
+    async fn send_message_part(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message: &str,
+        topic_id: &str,
+        inline_keyboard: Option<serde_json::Value>,
+        reply_to_message_id: Option<&str>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+    
+    async fn send_photo(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        photo: Vec<u8>,
+        caption: &str,
+        topic_id: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+    
+    async fn edit_message(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message_id: &str,
+        message: &str,
+        topic_id: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+    
+    async fn delete_message(&self, bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    
+    async fn pin_message(&self, bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    
+    async fn unpin_message(&self, bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    
+    async fn set_reaction(&self, bot_token: &str, chat_id: &str, message_id: &str, emoji: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    
+    async fn answer_callback_query(&self, bot_token: &str, callback_query_id: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    
+    async fn send_notification(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message: &str,
+        topic_id: &str,
+        inline_keyboard: Option<serde_json::Value>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send_message(bot_token, chat_id, message, topic_id, inline_keyboard).await?;
+        Ok(())
+    }
+}
+
+// Notifier backed by the Telegram Bot API at TELEGRAM_API_BASE
+struct TelegramNotifier {
+    http: HttpClient,
+    api_base: String,
+    // How many chunks of one message may be in flight to a topic at once (TOPIC_PIPELINE)
+    topic_pipeline: usize,
+    // Shared pause for all Telegram requests, set from Telegram's retry_after
+    throttle: RequestThrottle,
+}
+
+impl Notifier for TelegramNotifier {
+    async fn send_message(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message: &str,
+        topic_id: &str,
+        inline_keyboard: Option<serde_json::Value>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        send_telegram_notification_with_id(self, bot_token, chat_id, message, topic_id, inline_keyboard).await
+    }
+    
+    async fn send_message_part(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message: &str,
+        topic_id: &str,
+        inline_keyboard: Option<serde_json::Value>,
+        reply_to_message_id: Option<&str>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        send_telegram_message_part(self, bot_token, chat_id, message, topic_id, inline_keyboard, reply_to_message_id).await
+    }
+    
+    async fn send_photo(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        photo: Vec<u8>,
+        caption: &str,
+        topic_id: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        send_telegram_photo_with_id(self, bot_token, chat_id, photo, caption, topic_id).await
+    }
+    
+    async fn edit_message(
+        &self,
+        bot_token: &str,
+        chat_id: &str,
+        message_id: &str,
+        message: &str,
+        topic_id: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        update_telegram_message(self, bot_token, chat_id, message_id, message, topic_id).await
+    }
+    
+    async fn delete_message(&self, bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        delete_telegram_message(self, bot_token, chat_id, message_id).await
+    }
+    
+    async fn pin_message(&self, bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        pin_telegram_message(self, bot_token, chat_id, message_id).await
+    }
+    
+    async fn unpin_message(&self, bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        unpin_telegram_message(self, bot_token, chat_id, message_id).await
+    }
+    
+    async fn set_reaction(&self, bot_token: &str, chat_id: &str, message_id: &str, emoji: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        set_telegram_reaction(self, bot_token, chat_id, message_id, emoji).await
+    }
+    
+    async fn answer_callback_query(&self, bot_token: &str, callback_query_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        answer_callback_query(self, bot_token, callback_query_id).await
+    }
+}
+
+// Long-lived dependencies shared by every search cycle
+struct Deps<S: FlightSearcher, N: Notifier> {
+    http: HttpClient,
+    // AIRLABS_API_BASE
+    airlabs_api_base: String,
+    searcher: Arc<S>,
+    notifier: N,
+    csv_exporter: Option<CsvExporter>,
+    ndjson_exporter: Option<NdjsonExporter>,
+    state_store: Store,
+    // SQLITE_PATH or DATABASE_URL, when set
+    fare_history: Option<FareHistory>,
+    progress: CycleProgress,
+    shortener: LinkShortener,
+    // Whether the webhook receiver runs, so callback buttons reach the bot
+    webhook_running: bool,
+}
+
+// State carried over from one cycle to the next
+struct CycleState {
+    bot_state: BotState,
+    status_message: StatusMessage,
+    pinned_message_id: Option<String>,
+    error_throttle: ErrorNotifyThrottle,
+    // Last cycle's flight digests per departure date (YYYY-MM-DD), for the calendar keyboard
+    date_digests: HashMap<String, Vec<String>>,
+    // Fares found across all dates of the cycle, for the SUMMARY_ONLY message
+    window_fares: Vec<FlightResult>,
+    // Exchange rates fetched by the last cycle, reused for replies between cycles
+    exchange_rates: HashMap<String, f64>,
+    // No state was persisted before this run; cleared once the first cycle completes
+    first_run: bool,
+}
+
+impl CycleState {
+    // The first cycle only fills the dedup and price stores when SUPPRESS_FIRST_RUN is set
+    fn initializing(&self, config: &Config) -> bool {
+        self.first_run && config.suppress_first_run
+    }
+}
+
+// Function to mark a price-drop notification with a 🔥 reaction (REACT_ON_DROP)
+// Failures, e.g. missing reaction rights, are only logged
+async fn react_to_price_drop<S: FlightSearcher, N: Notifier>(deps: &Deps<S, N>, config: &Config, message_id: &str) {
+    if !config.react_on_drop {
+        return;
+    }
+    
+    if let Err(e) = deps.notifier.set_reaction(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        message_id,
+        "🔥"
+    ).await {
+        error!("Failed to react to price drop message {} (does the bot have reaction rights?): {}", message_id, e);
+    }
+}
+
+// Function to fetch AirLabs data for a date's flights, aligned with `flights`. Cheapest flights are
+// enriched first so AIRLABS_MAX_PER_CYCLE covers the best fares; failures leave a flight without data
+async fn fetch_airlabs_data<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    stats: &SearchStatistics,
+    flights: &[&FlightResult],
+    exchange_rates: &HashMap<String, f64>,
+) -> Vec<Option<AirLabsFlight>> {
+    let mut airlabs_data: Vec<Option<AirLabsFlight>> = flights.iter().map(|_| None).collect();
+    let mut by_price: Vec<usize> = (0..flights.len()).collect();
+    by_price.sort_by(|&a, &b| {
+        let a = normalized_price(flights[a], exchange_rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+        let b = normalized_price(flights[b], exchange_rates, BASE_CURRENCY).unwrap_or(f64::INFINITY);
+        a.total_cmp(&b)
+    });
+    
+    for i in by_price {
+        if !stats.reserve_airlabs_call(config.airlabs_max_per_cycle) {
+            continue;
+        }
+        
+        let flight = flights[i];
+        airlabs_data[i] = enrich_with_airlabs_data(&deps.http, &deps.airlabs_api_base, flight, &config.airlabs_api_key, config.airlabs_max_retries)
+            .await
+            .unwrap_or_else(|e| {
+                error!("AirLabs request failed for flight {}: {}",
+                    format_flight_designator(&flight.airline, &flight.flight_number), e);
+                None
+            });
+    }
+    
+    airlabs_data
+}
+
+// One route and date whose search results are being handled, with the cycle's currency data
+struct DateSearch<'a> {
+    route: &'a Route,
+    departure_date: &'a str,
+    formatted_date: &'a str,
+    currency_conversion: Option<&'a CurrencyConversion>,
+    exchange_rates: &'a HashMap<String, f64>,
+}
+
+// Function to warn that the API response didn't match the expected schema and was parsed best effort
+async fn send_schema_warning<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    formatted_date: &str,
+    warning: &str,
+) {
+    let warning_message = format!(
+        "⚠️ <b>Ответ API не соответствует ожидаемой схеме</b>\n\n\
+        📅 Дата: {}\n\
+        <code>{}</code>\n\n\
+        <i>Данные извлечены резервным разбором.</i>",
+        formatted_date,
+        escape_html(warning)
+    );
+    
+    if let Err(send_err) = deps.notifier.send_notification(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        &warning_message,
+        config.topics.topic(MessageCategory::Error),
+        None
+    ).await {
+        error!("Failed to send schema warning: {}", send_err);
+    }
+}
+
+// Function to notify NOTIFY_SOLD_OUT when a date lost all flights for two consecutive cycles
+async fn track_sold_out<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    date: &DateSearch<'_>,
+    flight_data: &FlightData,
+) {
+    let route = date.route;
+    let has_flights = flight_data.data
+        .as_ref()
+        .is_some_and(|all| all.iter().any(|f| config.airline_filter.allows(&f.airline)));
+    let availability_key = route_date_key(&route.origin, &route.destination, date.departure_date);
+    let sold_out = state.bot_state.record_availability(availability_key, has_flights);
+    if !sold_out || !config.notify_sold_out || !config.telegram_enabled() {
+        return;
+    }
+    
+    let sold_out_message = format!(
+        "🚫 Рейсы закончились на <b>{}</b> из {} в {}",
+        date.formatted_date,
+        get_city_name(&config.display, &route.origin),
+        get_city_name(&config.display, &route.destination)
+    );
+    
+    if let Err(e) = deps.notifier.send_notification(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        &sold_out_message,
+        config.topics.topic(MessageCategory::FoundFlight),
+        None
+    ).await {
+        error!("Failed to send sold-out notification: {}", e);
+    }
+}
+
+// Function to write found flights to the CSV export and the NDJSON output, if enabled
+fn export_found_flights<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    route: &Route,
+    flights: &[&FlightResult],
+    currency: &str,
+) {
+    let found_at = Utc::now();
+    if let Some(exporter) = &deps.csv_exporter {
+        for flight in flights {
+            if let Err(e) = exporter.write_flight(flight, currency, found_at) {
+                error!("Failed to write flight to CSV: {}", e);
+            }
+        }
+    }
+    
+    if let Some(exporter) = &deps.ndjson_exporter {
+        for flight in flights {
+            if let Err(e) = exporter.write_flight(flight, route, found_at) {
+                error!("Failed to write flight as NDJSON: {}", e);
+            }
+        }
+    }
+}
+
+// Function to keep the flights that pass the airline, MIN_FARE_SEATS and MIN_ECONOMY_SEATS
+// filters, sorted by SORT_BY. The AirLabs data of the kept flights comes back when the economy
+// seats filter had to fetch it
+async fn filter_found_flights<'a, S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    stats: &SearchStatistics,
+    date: &DateSearch<'_>,
+    all_flights: &'a [FlightResult],
+) -> (Vec<&'a FlightResult>, Option<Vec<Option<AirLabsFlight>>>) {
+    // Apply the airline allow/deny filter before counting
+    let flights: Vec<&FlightResult> = all_flights
+        .iter()
+        .filter(|flight| config.airline_filter.allows(&flight.airline))
+        .collect();
+    let filtered_out = all_flights.len() - flights.len();
+    if filtered_out > 0 {
+        info!("Filtered out {} flights by airline for {}", filtered_out, date.formatted_date);
+    }
+    
+    // Drop fares with too few seats left at this price
+    let mut flights: Vec<&FlightResult> = match config.min_fare_seats {
+        Some(min_seats) => {
+            let before = flights.len();
+            let flights: Vec<&FlightResult> = flights
+                .into_iter()
+                .filter(|flight| meets_min_fare_seats(flight.seats, min_seats))
+                .collect();
+            if before > flights.len() {
+                info!("Filtered out {} flights with fewer than {} fare seats for {}",
+                    before - flights.len(), min_seats, date.formatted_date);
+            }
+            flights
+        }
+        None => flights,
+    };
+    sort_flights(&mut flights, config.sort_by, date.exchange_rates);
+    
+    // With MIN_ECONOMY_SEATS, AirLabs seat counts decide which flights the date has at all,
+    // so they are fetched and applied before anything is counted or sent
+    let Some(min_seats) = config.min_economy_seats.filter(|_| config.airlabs_enabled()) else {
+        return (flights, None);
+    };
+    let enriched = fetch_airlabs_data(deps, config, stats, &flights, date.exchange_rates).await;
+    let (kept, kept_data): (Vec<&FlightResult>, Vec<Option<AirLabsFlight>>) = flights
+        .into_iter()
+        .zip(enriched)
+        .filter(|(flight, airlabs_flight)| {
+            let designator = format_flight_designator(&flight.airline, &flight.flight_number);
+            match meets_min_economy_seats(airlabs_flight.as_ref(), min_seats) {
+                Some(false) => {
+                    info!("Skipping flight {}: fewer than {} economy seats", designator, min_seats);
+                    false
+                }
+                Some(true) => true,
+                None => {
+                    info!("Seats unknown for flight {}", designator);
+                    true
+                }
+            }
+        })
+        .unzip();
+    (kept, Some(kept_data))
+}
+
+// Function to alert about a date's unusually low price and about a new all-time low for the route
+async fn send_price_alerts<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    date: &DateSearch<'_>,
+    cooldown_key: &str,
+    cheapest_price: i64,
+) {
+    let route = date.route;
+    let notify = config.telegram_enabled() && !state.initializing(config);
+    let currency = route.currency(&config.currency);
+    
+    // Alert when the price is unusually low compared to what was seen for this date
+    if let Some(alert_percentile) = config.alert_percentile
+        && let Some(threshold) = state.bot_state.price_history.percentile(cooldown_key, alert_percentile, config.alert_min_samples)
+        && cheapest_price < threshold
+    {
+        let alert_key = format!("alert {} {}", cooldown_key, cheapest_price);
+        if notify && !state.bot_state.was_sent_recently(&alert_key, config.dedup_window) {
+            let alert_message = format!(
+                "📉 <b>Необычно низкая цена</b> на <b>{}</b> из {} в {}\n\n\
+                💰 {} — дешевле, чем в {}% наблюдений (порог {})",
+                date.formatted_date,
+                get_city_name(&config.display, &route.origin),
+                get_city_name(&config.display, &route.destination),
+                format_price_in(&config.display, cheapest_price, currency),
+                100.0 - alert_percentile,
+                format_price_in(&config.display, threshold, currency)
+            );
+            
+            match deps.notifier.send_message(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &alert_message,
+                config.topics.topic(MessageCategory::PriceDrop),
+                None
+            ).await {
+                Ok(message_id) => {
+                    state.bot_state.record_sent(&alert_key);
+                    react_to_price_drop(deps, config, &message_id).await;
+                }
+                Err(e) => error!("Failed to send unusual price alert: {}", e),
+            }
+        }
+    }
+    
+    // Celebrate a new all-time low for the route
+    let route_key = format!("{}-{}", route.origin, route.destination);
+    if let Some(previous_record) = state.bot_state.price_history.record_route_low(&route_key, cheapest_price)
+        && config.notify_record_low
+        && notify
+    {
+        let record_message = format!(
+            "🏆 <b>Новый рекорд цены!</b>\n\n\
+            ✈️ {} → {}, {}\n\
+            💰 <b>{}</b> — прежний рекорд {}",
+            get_city_name(&config.display, &route.origin),
+            get_city_name(&config.display, &route.destination),
+            date.formatted_date,
+            format_price_in(&config.display, cheapest_price, currency),
+            format_price_in(&config.display, previous_record, currency)
+        );
+        
+        match deps.notifier.send_message(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &record_message,
+            config.topics.topic(MessageCategory::PriceDrop),
+            None
+        ).await {
+            Ok(message_id) => react_to_price_drop(deps, config, &message_id).await,
+            Err(e) => error!("Failed to send record low notification: {}", e),
+        }
+    }
+}
+
+// Function to build the header announcing a date's flights, with the target, direction, price
+// drop, trend, direct premium and fastest flight lines that apply
+fn format_found_header(
+    config: &Config,
+    date: &DateSearch<'_>,
+    flights: &[&FlightResult],
+    cheapest_price: i64,
+    previous_price: Option<i64>,
+    previous_low: Option<i64>,
+) -> String {
+    let route = date.route;
+    let currency = route.currency(&config.currency);
+    
+    // Reversed routes of BIDIRECTIONAL are labeled
+    let direction_line = match (config.bidirectional, route.origin != config.origin) {
+        (false, _) => "",
+        (true, false) => "➡️ <b>Туда</b>\n",
+        (true, true) => "⬅️ <b>Обратно</b>\n",
+    };
+    // Annotate the direct-flight premium when comparing against connections
+    let premium_line = match format_direct_premium(&config.display, flights) {
+        Some(premium) if config.show_direct_premium => format!("💰 {}\n", premium),
+        _ => String::new(),
+    };
+    let fastest_line = match format_fastest(&config.display, flights, config.duration_precision) {
+        Some(fastest) => format!("⚡ {}\n", fastest),
+        None => String::new(),
+    };
+    let trend_line = if config.show_price_trend {
+        format!("💰 Лучшая цена: {}\n", format_price_trend(&config.display, cheapest_price, previous_price, currency))
+    } else {
+        String::new()
+    };
+    let target_line = format_target_hit(&config.display, route, cheapest_price, currency, config.mention_user.as_ref())
+        .unwrap_or_default();
+    let drop_line = match previous_low {
+        Some(previous_low) if config.price_drop_only => format!(
+            "📉 Цена снизилась: {}\n",
+            format_price_drop(&config.display, previous_low, cheapest_price, currency)
+        ),
+        _ => String::new(),
+    };
+    
+    format!("{}{}✅ Найдено <b>{} {}</b> на <b>{}</b> из {} в {}:\n{}{}{}{}\n",
+        target_line,
+        direction_line,
+        flights.len(),
+        plural_ru(flights.len() as i64, "рейс", "рейса", "рейсов"),
+        date.formatted_date,
+        get_city_name(&config.display, &route.origin),
+        get_city_name(&config.display, &route.destination),
+        drop_line,
+        trend_line,
+        premium_line,
+        fastest_line
+    )
+}
+
+// Function to send the date header, as the caption of the price history chart when there is
+// more than one observation and the header fits in a caption; returns the id of the sent message
+async fn send_found_header<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &CycleState,
+    chart_cache: &mut HashMap<String, Vec<u8>>,
+    date: &DateSearch<'_>,
+    cooldown_key: &str,
+    header: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    // Tags count towards the limit here, so a header close to it is sent as text to be safe
+    let fits_caption = header.chars().count() <= TELEGRAM_CAPTION_LIMIT;
+    if config.send_price_chart && fits_caption && !chart_cache.contains_key(cooldown_key) {
+        let points = state.bot_state.price_history.entries.get(cooldown_key).map_or(&[][..], Vec::as_slice);
+        match render_price_chart(points) {
+            Ok(Some(png)) => {
+                chart_cache.insert(cooldown_key.to_string(), png);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to render price chart for {}: {}", date.formatted_date, e),
+        }
+    }
+    
+    if let Some(png) = chart_cache.get(cooldown_key).filter(|_| fits_caption) {
+        match deps.notifier.send_photo(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            png.clone(),
+            header,
+            config.topics.topic(MessageCategory::FoundFlight)
+        ).await {
+            Ok(message_id) => return Ok(message_id),
+            Err(e) => error!("Failed to send price chart, sending text instead: {}", e),
+        }
+    }
+    
+    deps.notifier.send_message(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        header,
+        config.topics.topic(MessageCategory::FoundFlight),
+        None
+    ).await
+}
+
+// Function to add fares to the fare history, if there is one, once a message showing them was sent
+fn record_notified_fares<'a, S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    flights: impl IntoIterator<Item = &'a FlightResult>,
+) {
+    let Some(fare_history) = &deps.fare_history else {
+        return;
+    };
+    
+    let now = Utc::now();
+    for flight in flights {
+        if let Err(e) = fare_history.record(flight, now) {
+            error!("Failed to record fare {} in the fare history: {}", format_flight_designator(&flight.airline, &flight.flight_number), e);
+        }
+    }
+}
+
+// Function to send the details of a date's flights: one digest with GROUP_BY, one message with
+// COMPACT, otherwise a message per flight up to MAX_FLIGHTS_PER_DATE
+async fn send_flight_details<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    date: &DateSearch<'_>,
+    cooldown_key: &str,
+    flights: &[(&FlightResult, Option<&AirLabsFlight>)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let identities = || flights.iter().map(|(flight, _)| flight.identity()).collect::<Vec<_>>().join(",");
+    
+    if config.group_by == GroupBy::Airline {
+        // One digest with the flights grouped under their airlines
+        let grouped: Vec<&FlightResult> = flights.iter().map(|(flight, _)| *flight).collect();
+        let digest = format_flights_by_airline(&config.display, &grouped, MAX_FLIGHTS_PER_DATE, date.currency_conversion);
+        let digest_key = format!("grouped {} {}", cooldown_key, identities());
+        
+        if !flights.is_empty() && !state.bot_state.was_sent_recently(&digest_key, config.dedup_window) {
+            deps.notifier.send_notification(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &digest,
+                config.topics.topic(MessageCategory::FoundFlight),
+                None
+            ).await?;
+            state.bot_state.record_sent(&digest_key);
+            record_notified_fares(deps, grouped);
+        }
+        return Ok(());
+    }
+    
+    if config.compact {
+        // One line per flight in a single message
+        let lines: Vec<String> = flights.iter().map(|(flight, _)| format_flight_compact(&config.display, flight)).collect();
+        let compact_key = format!("compact {} {}", cooldown_key, identities());
+        
+        if !flights.is_empty() && !state.bot_state.was_sent_recently(&compact_key, config.dedup_window) {
+            deps.notifier.send_notification(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &lines.join("\n"),
+                config.topics.topic(MessageCategory::FoundFlight),
+                None
+            ).await?;
+            state.bot_state.record_sent(&compact_key);
+            record_notified_fares(deps, flights.iter().map(|(flight, _)| *flight));
+        }
+        return Ok(());
+    }
+    
+    for (i, (flight, airlabs_flight)) in flights.iter().enumerate() {
+        if i >= MAX_FLIGHTS_PER_DATE {
+            // Limit the number of flights shown per date
+            let remaining = flights.len() - MAX_FLIGHTS_PER_DATE;
+            let message_text = format!("... и еще {} {}", remaining, plural_ru(remaining as i64, "рейс", "рейса", "рейсов"));
+            let dedup_key = format!("{} {}", cooldown_key, message_text);
+            
+            if !state.bot_state.was_sent_recently(&dedup_key, config.dedup_window) {
+                deps.notifier.send_notification(
+                    &config.telegram_bot_token,
+                    &config.telegram_chat_id,
+                    &message_text,
+                    config.topics.topic(MessageCategory::FoundFlight),
+                    None
+                ).await?;
+                state.bot_state.record_sent(&dedup_key);
+            }
+            break;
+        }
+        
+        let message_text = format!(
+            "🛫 <b>Рейс {}</b>: {} ({}) → {} ({})\n\
+             ✈️ {}\n\
+             {}\
+             💰 Цена: {}\n\
+             {}{}",
+            format_flight_designator(&flight.airline, &flight.flight_number),
+            get_city_name(&config.display, &flight.origin),
+            flight.origin_airport,
+            get_city_name(&config.display, &flight.destination),
+            flight.destination_airport,
+            get_airline_name(&flight.airline),
+            format_trip(&config.display, flight, config.duration_precision),
+            flight.display_price_converted(&config.display, date.currency_conversion),
+            format_seats(flight.seats, airlabs_flight.and_then(|f| f.seats_economy)),
+            format_economy_seats_highlight(*airlabs_flight, config.min_economy_seats)
+        );
+        
+        let sent_key = format!("flight {}", flight.identity());
+        if state.bot_state.acknowledged_flights.contains(&flight.flight_key()) {
+            info!("Skipping flight {}: acknowledged", format_flight_designator(&flight.airline, &flight.flight_number));
+        } else if !state.bot_state.was_sent_recently(&sent_key, config.dedup_window) {
+            // Booking button with the affiliate marker and a refresh button
+            let keyboard = flight_keyboard(deps, config, flight, date.route, date.departure_date).await;
+            let message_id = deps.notifier.send_message(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &message_text,
+                config.topics.topic(MessageCategory::FoundFlight),
+                keyboard
+            ).await?;
+            state.bot_state.record_sent(&sent_key);
+            state.bot_state.flight_messages.insert(message_id, flight.flight_key());
+            record_notified_fares(deps, [*flight]);
+        }
+    }
+    
+    Ok(())
+}
+
+// Function to send the AirLabs status, aircraft and cabin capacity of each flight that has them
+async fn send_airlabs_details<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    flights: &[(&FlightResult, Option<&AirLabsFlight>)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for (flight, airlabs_flight) in flights {
+        let designator = format_flight_designator(&flight.airline, &flight.flight_number);
+        let Some(airlabs_flight) = airlabs_flight else {
+            info!("No AirLabs data found for flight {}", designator);
+            continue;
+        };
+        
+        let mut has_seat_info = false;
+        let mut airlabs_message = format!("📊 <b>Дополнительная информация для рейса {}</b>:\n", designator);
+        
+        if let Some(status) = &airlabs_flight.status {
+            airlabs_message.push_str(&format!("🚦 <b>Статус рейса</b>: {}\n", status));
+        }
+        
+        if let Some(aircraft) = &airlabs_flight.aircraft_icao {
+            airlabs_message.push_str(&format!("✈️ <b>Тип самолета</b>: {}\n", aircraft));
+        }
+        
+        if let Some(economy) = airlabs_flight.seats_economy {
+            airlabs_message.push_str(&format!("💺 <b>Мест в салоне, эконом (оценка)</b>: {}\n", economy));
+            has_seat_info = true;
+        }
+        
+        if let Some(business) = airlabs_flight.seats_business {
+            airlabs_message.push_str(&format!("💺 <b>Мест в салоне, бизнес (оценка)</b>: {}\n", business));
+            has_seat_info = true;
+        }
+        
+        if let Some(first) = airlabs_flight.seats_first {
+            airlabs_message.push_str(&format!("💺 <b>Мест в салоне, первый класс (оценка)</b>: {}\n", first));
+            has_seat_info = true;
+        }
+        
+        if !config.telegram_enabled() {
+            continue;
+        }
+        
+        deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &airlabs_message,
+            config.topics.topic(MessageCategory::SeatInfo),
+            None
+        ).await?;
+        
+        // Repeat seat estimates with a warning that they are cabin capacity, not availability
+        if has_seat_info {
+            let seat_estimate_message = format!(
+                "🚨 <b>ОЦЕНКА МЕСТ В САЛОНЕ:</b> 🚨\n\n{}\n\
+                <i>Вместимость салона по данным AirLabs, а не наличие билетов в реальном времени.</i>",
+                airlabs_message
+            );
+            
+            deps.notifier.send_notification(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &seat_estimate_message,
+                config.topics.topic(MessageCategory::SeatInfo),
+                None
+            ).await?;
+        }
+    }
+    
+    Ok(())
+}
+
+// Function to handle the search results for one date: sold-out tracking, filtering,
+// export and notifications
+async fn process_search_result<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    stats: &SearchStatistics,
+    chart_cache: &mut HashMap<String, Vec<u8>>,
+    date: &DateSearch<'_>,
+    mut flight_data: FlightData,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let route = date.route;
+    let formatted_date = date.formatted_date;
+    let initializing = state.initializing(config);
+    
+    // The same flight can arrive twice from several pages or sources
+    if let Some(flights) = flight_data.data.as_mut() {
+        dedup_flights(flights);
+    }
+    
+    // Let maintainers know the API schema has changed
+    if let Some(warning) = &flight_data.parse_warning
+        && config.telegram_enabled()
+    {
+        send_schema_warning(deps, config, formatted_date, warning).await;
+    }
+    
+    if !flight_data.success {
+        return Ok(());
+    }
+    track_sold_out(deps, config, state, date, &flight_data).await;
+    
+    let Some(all_flights) = flight_data.data.as_deref() else {
+        // Update statistics
+        stats.update(|counts| counts.dates_without_flights += 1);
+        info!("No flights found for {}", formatted_date);
+        return Ok(());
+    };
+    let (flights, airlabs_data) = filter_found_flights(deps, config, stats, date, all_flights).await;
+    let flight_count = flights.len();
+    info!("Found {} flights for {}", flight_count, formatted_date);
+    
+    // With a fare history, only fares it doesn't hold yet are notified
+    let new_fares: Vec<bool> = flights
+        .iter()
+        .map(|flight| deps.fare_history.as_ref().is_none_or(|fare_history| fare_history.is_new(flight)))
+        .collect();
+    let has_new_fares = new_fares.contains(&true);
+    
+    export_found_flights(deps, route, &flights, flight_data.currency.as_deref().unwrap_or("rub"));
+    
+    // Reversed routes of BIDIRECTIONAL are counted separately
+    let is_return = route.origin != config.origin;
+    
+    // Update statistics
+    stats.update(|counts| {
+        if is_return {
+            counts.return_searches += 1;
+        }
+        if flight_count == 0 {
+            counts.dates_without_flights += 1;
+            return;
+        }
+        counts.dates_with_flights += 1;
+        counts.total_flights_found += flight_count;
+        if is_return {
+            counts.return_flights_found += flight_count;
+        }
+    });
+    
+    if flight_count == 0 {
+        info!("No flights found for {}", formatted_date);
+        return Ok(());
+    }
+    
+    // Keep the date's fares for the window summary
+    if config.summary_only {
+        state.window_fares.extend(flights.iter().map(|flight| (*flight).clone()));
+    }
+    
+    // Keep the date's digest for the calendar keyboard
+    if config.calendar_keyboard {
+        state.date_digests.entry(date.departure_date.to_string()).or_default().push(format!(
+            "🧭 <b>{} → {}</b>\n{}",
+            get_city_name(&config.display, &route.origin),
+            get_city_name(&config.display, &route.destination),
+            format_flights_by_airline(&config.display, &flights, MAX_FLIGHTS_PER_DATE, date.currency_conversion)
+        ));
+    }
+    
+    let cheapest_price = cheapest_flight(&flights, date.exchange_rates).map(|f| f.price).unwrap_or_default();
+    let cooldown_key = route_date_key(&route.origin, &route.destination, date.departure_date);
+    send_price_alerts(deps, config, state, date, &cooldown_key, cheapest_price).await;
+    
+    let previous_price = state.bot_state.price_history.last_price(&cooldown_key);
+    // With PRICE_DROP_ONLY the first price only sets the baseline, and the stored minimum
+    // is lowered once the drop has been notified
+    let previous_low = state.bot_state.price_history.date_low(&cooldown_key);
+    let beats_low = previous_low.is_some_and(|low| cheapest_price < low);
+    if !config.price_drop_only || previous_low.is_none() {
+        state.bot_state.price_history.update_date_low(&cooldown_key, cheapest_price);
+    }
+    state.bot_state.price_history.record(cooldown_key.clone(), cheapest_price, config.price_noise_threshold);
+    
+    // Skip dates notified within the cooldown unless the price improved
+    let in_cooldown = state.bot_state.is_in_cooldown(&cooldown_key, cheapest_price, config.notify_cooldown);
+    let is_price_drop = state.bot_state.notifications
+        .get(&cooldown_key)
+        .is_some_and(|record| cheapest_price < record.price);
+    if in_cooldown {
+        info!("Skipping notification for {}: notified within the cooldown", formatted_date);
+    } else if !has_new_fares {
+        info!("Skipping notification for {}: every fare was notified before", formatted_date);
+    } else if config.price_drop_only && !beats_low {
+        info!("Skipping notification for {}: no drop below the lowest price", formatted_date);
+    }
+    
+    // Check if a similar message was sent recently
+    let flights_word = plural_ru(flight_count as i64, "рейс", "рейса", "рейсов");
+    let message_text = format!("Найдено {} {} на {} {}-{}", flight_count, flights_word, formatted_date, route.origin, route.destination);
+    let was_recent = in_cooldown || !has_new_fares || if config.price_drop_only {
+        !beats_low
+    } else {
+        state.bot_state.was_sent_recently(&message_text, config.dedup_window)
+    };
+    
+    if was_recent {
+        return Ok(());
+    }
+    if initializing {
+        // Remember the date and its fares so the next cycle only notifies new fares and price drops
+        state.bot_state.record_sent(&message_text);
+        state.bot_state.record_notification(cooldown_key, cheapest_price);
+        record_notified_fares(deps, flights);
+        return Ok(());
+    }
+    if config.summary_only {
+        return Ok(());
+    }
+    
+    let header = format_found_header(config, date, &flights, cheapest_price, previous_price, previous_low);
+    let message_id = send_found_header(deps, config, state, chart_cache, date, &cooldown_key, &header).await?;
+    
+    if config.price_drop_only {
+        state.bot_state.price_history.update_date_low(&cooldown_key, cheapest_price);
+    }
+    if is_price_drop {
+        react_to_price_drop(deps, config, &message_id).await;
+    }
+    
+    // Update statistics with message ID
+    stats.record_flight_date(FlightDateSummary {
+        date: formatted_date.to_string(),
+        message_id,
+        price: cheapest_price,
+        currency: route.currency(&config.currency).to_string(),
+        base_price: cheapest_flight(&flights, date.exchange_rates)
+            .and_then(|flight| normalized_price(flight, date.exchange_rates, BASE_CURRENCY)),
+    });
+    state.bot_state.record_sent(&message_text);
+    state.bot_state.record_notification(cooldown_key.clone(), cheapest_price);
+    
+    // Only fares new to the fare history are detailed
+    let new_flights: Vec<&FlightResult> = flights
+        .iter()
+        .zip(&new_fares)
+        .filter(|(_, new)| **new)
+        .map(|(flight, _)| *flight)
+        .collect();
+    // Without MIN_ECONOMY_SEATS, AirLabs is only asked about flights that are detailed
+    let airlabs_data: Vec<Option<AirLabsFlight>> = match airlabs_data {
+        Some(airlabs_data) => airlabs_data
+            .into_iter()
+            .zip(&new_fares)
+            .filter(|(_, new)| **new)
+            .map(|(airlabs_flight, _)| airlabs_flight)
+            .collect(),
+        None if config.airlabs_enabled() => fetch_airlabs_data(deps, config, stats, &new_flights, date.exchange_rates).await,
+        None => new_flights.iter().map(|_| None).collect(),
+    };
+    let details: Vec<(&FlightResult, Option<&AirLabsFlight>)> = new_flights
+        .into_iter()
+        .zip(airlabs_data.iter().map(Option::as_ref))
+        .collect();
+    
+    send_flight_details(deps, config, state, date, &cooldown_key, &details).await?;
+    if config.airlabs_enabled() {
+        send_airlabs_details(deps, config, &details).await?;
+    }
+    
+    Ok(())
+}
+
+// Function to search both legs of a round trip one way each and describe the cheapest combination
+async fn find_round_trip<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    route: &Route,
+    outbound_date: NaiveDate,
+    return_dates: Vec<NaiveDate>,
+    rates: &HashMap<String, f64>,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let outbound_data = deps.searcher.search(config, route, &outbound_date.format("%Y-%m-%d").to_string()).await?;
+    
+    let reverse = route.reversed();
+    let mut return_data = Vec::new();
+    for return_date in return_dates {
+        time::sleep(config.search_delay).await;
+        return_data.push(deps.searcher.search(config, &reverse, &return_date.format("%Y-%m-%d").to_string()).await?);
+    }
+    
+    let outbound = allowed_flights(&outbound_data, &config.airline_filter);
+    let returns: Vec<&FlightResult> = return_data
+        .iter()
+        .flat_map(|data| allowed_flights(data, &config.airline_filter))
+        .collect();
+    
+    Ok(format_round_trip(&config.display, route, &outbound, &returns, rates, &config.currency))
+}
+
+// Function to show a subscription match in the fare's currency, with the ruble amount it was
+// compared against when the fare is in another currency
+fn format_subscription_price(display: &DisplaySettings, flight: &FlightResult, base_price: f64) -> String {
+    let currency = flight.currency.as_deref().map_or(BASE_CURRENCY.to_string(), normalize_code);
+    if currency == BASE_CURRENCY {
+        format_price(display, flight.price)
+    } else {
+        format!("{} (~{})", flight.display_price(display), format_price(display, base_price.round() as i64))
+    }
+}
+
+// Function to alert members whose subscription threshold a route's cheapest fare meets. The alert
+// goes to the member's private chat, or mentions them in the group if the bot can't message them
+async fn notify_subscribers<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    route: &Route,
+    formatted_date: &str,
+    flight_data: &FlightData,
+    rates: &HashMap<String, f64>,
+) {
+    let flights = allowed_flights(flight_data, &config.airline_filter);
+    let Some(cheapest) = cheapest_flight(&flights, rates) else {
+        return;
+    };
+    let Some(price) = normalized_price(cheapest, rates, BASE_CURRENCY) else {
+        return;
+    };
+    
+    let matching: Vec<Subscription> = state.bot_state.subscriptions
+        .iter()
+        .filter(|subscription| subscription.route == *route && price <= subscription.max_price as f64)
+        .cloned()
+        .collect();
+    
+    for subscription in matching {
+        let message = format!(
+            "🔔 <b>Цена по вашей подписке</b>\n\n\
+            🧭 Маршрут: {} ({}) → {} ({})\n\
+            📅 Дата: {}\n\
+            💰 Цена: {} (порог {})\n\
+            ✈️ Рейс: {}",
+            get_city_name(&config.display, &route.origin),
+            route.origin,
+            get_city_name(&config.display, &route.destination),
+            route.destination,
+            formatted_date,
+            format_subscription_price(&config.display, cheapest, price),
+            format_price(&config.display, subscription.max_price),
+            format_flight_designator(&cheapest.airline, &cheapest.flight_number)
+        );
+        let dedup_key = format!("subscription {} {}", subscription.user_id, message);
+        if state.bot_state.was_sent_recently(&dedup_key, config.dedup_window) {
+            continue;
+        }
+        
+        let keyboard = booking_keyboard(deps, config, &cheapest.link).await;
+        let direct = deps.notifier.send_message_part(
+            &config.telegram_bot_token,
+            &subscription.user_id.to_string(),
+            &message,
+            "",
+            keyboard.clone(),
+            None
+        ).await;
+        
+        let sent = match direct {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to message subscriber {} directly: {}. Mentioning them in the group.", subscription.user_id, e);
+                let mention = format!(
+                    "<a href=\"tg://user?id={}\">{}</a>\n{}",
+                    subscription.user_id,
+                    escape_html(&subscription.name),
+                    message
+                );
+                deps.notifier.send_notification(
+                    &config.telegram_bot_token,
+                    &config.telegram_chat_id,
+                    &mention,
+                    config.topics.topic(MessageCategory::FoundFlight),
+                    keyboard
+                ).await
+            }
+        };
+        
+        match sent {
+            Ok(()) => state.bot_state.record_sent(&dedup_key),
+            Err(e) => error!("Failed to notify subscriber {}: {}", subscription.user_id, e),
+        }
+    }
+}
+
+// Function to apply a subscription command from the chat, reply to it and persist the change
+async fn handle_subscription_request<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    request: SubscriptionRequest,
+) {
+    let reply = match request.command {
+        Ok(command) => {
+            let reply = state.bot_state.apply_subscription_command(&config.display, request.user_id, &request.name, command);
+            if let Err(e) = deps.state_store.save(&state.bot_state) {
+                error!("Failed to save state file {}: {}", deps.state_store.path, e);
+            }
+            reply
+        }
+        Err(usage) => format!("⚠️ {}", escape_html(&usage)),
+    };
+    
+    if let Err(e) = deps.notifier.send_message_part(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        &reply,
+        &request.topic_id,
+        None,
+        Some(&request.message_id.to_string())
+    ).await {
+        error!("Failed to answer subscription command: {}", e);
+    }
+}
+
+// Function to answer a calendar button tap with the date's digest from the last cycle
+async fn handle_calendar_request<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &CycleState,
+    request: CalendarRequest,
+) {
+    // Stop the button's loading indicator
+    if let Err(e) = deps.notifier.answer_callback_query(&config.telegram_bot_token, &request.callback_query_id).await {
+        error!("Failed to answer callback query: {}", e);
+    }
+    
+    let formatted_date = NaiveDate::parse_from_str(&request.date, "%Y-%m-%d")
+        .map(|date| format_date_ru(&date))
+        .unwrap_or_else(|_| request.date.clone());
+    let message = match state.date_digests.get(&request.date) {
+        Some(digests) => format!("📅 <b>Рейсы на {}</b>\n\n{}", formatted_date, digests.join("\n\n")),
+        None => format!("ℹ️ Нет данных о рейсах на {}. Они появятся после следующего цикла поиска.", formatted_date),
+    };
+    
+    if let Err(e) = deps.notifier.send_notification(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        &message,
+        &request.topic_id,
+        None
+    ).await {
+        error!("Failed to send calendar digest: {}", e);
+    }
+}
+
+// Function to answer a refresh button tap with the current fares for the flight's route and date
+async fn handle_refresh_request<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &CycleState,
+    request: RefreshRequest,
+) {
+    // Stop the button's loading indicator
+    if let Err(e) = deps.notifier.answer_callback_query(&config.telegram_bot_token, &request.callback_query_id).await {
+        error!("Failed to answer callback query: {}", e);
+    }
+    
+    let formatted_date = NaiveDate::parse_from_str(&request.date, "%Y-%m-%d")
+        .map(|date| format_date_ru(&date))
+        .unwrap_or_else(|_| request.date.clone());
+    let title = format!(
+        "🔄 <b>{} → {}, {}</b>",
+        get_city_name(&config.display, &request.origin),
+        get_city_name(&config.display, &request.destination),
+        formatted_date
+    );
+    
+    // Search with the configured route's settings when it is still watched
+    let route = config.routes()
+        .into_iter()
+        .find(|route| route.origin == request.origin && route.destination == request.destination)
+        .unwrap_or_else(|| Route {
+            origin: request.origin.clone(),
+            destination: request.destination.clone(),
+            currency: None,
+            target_price: None,
+        });
+    
+    let message = match deps.searcher.search(config, &route, &request.date).await {
+        Ok(flight_data) => {
+            let mut flights: Vec<&FlightResult> = flight_data.data
+                .as_deref()
+                .unwrap_or_default()
+                .iter()
+                .filter(|flight| config.airline_filter.allows(&flight.airline))
+                .collect();
+            sort_flights(&mut flights, SortBy::Price, &state.exchange_rates);
+            let conversion = secondary_conversion(config, &state.exchange_rates);
+            if flights.is_empty() {
+                format!("{}\n\nℹ️ Рейсов не найдено.", title)
+            } else {
+                format!("{}\n\n{}", title, format_flights_by_airline(&config.display, &flights, MAX_FLIGHTS_PER_DATE, conversion.as_ref()))
+            }
+        }
+        Err(e) => {
+            error!("Failed to refresh {}-{} for {}: {}", request.origin, request.destination, request.date, e);
+            format!("{}\n\n⚠️ Не удалось обновить данные, попробуйте позже.", title)
+        }
+    };
+    
+    if let Err(e) = deps.notifier.send_notification(
+        &config.telegram_bot_token,
+        &config.telegram_chat_id,
+        &message,
+        &request.topic_id,
+        None
+    ).await {
+        error!("Failed to send refreshed fares: {}", e);
+    }
+}
+
+// Set once a shutdown signal arrives; the bot stops after the current cycle
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Function to wait for Ctrl+C or, on Unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+// Why the wait between cycles ended
+#[derive(Debug, PartialEq)]
+enum WaitOutcome {
+    Elapsed,
+    // CONFIG_FILE changed, so the next cycle starts right away with the new settings
+    ConfigChanged,
+    Shutdown,
+}
+
+// Function to wait until the next cycle, handling webhook commands as they arrive. The wait is
+// split into WAIT_CHECKPOINT_SECS steps, checking for shutdown and CONFIG_FILE changes between them
+async fn wait_for_next_cycle<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Config,
+    state: &mut CycleState,
+    commands: &mut mpsc::UnboundedReceiver<WebhookCommand>,
+    delay: Duration,
+    config_file: &str,
+    config_modified: Option<std::time::SystemTime>,
+) -> WaitOutcome {
+    let deadline = time::Instant::now() + delay;
+    loop {
+        if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+            return WaitOutcome::Shutdown;
+        }
+        if config_file_modified(config_file) != config_modified {
+            info!("{} changed, starting the next cycle early", config_file);
+            return WaitOutcome::ConfigChanged;
+        }
+        
+        let now = time::Instant::now();
+        if now >= deadline {
+            return WaitOutcome::Elapsed;
+        }
+        
+        tokio::select! {
+            _ = time::sleep_until((now + config.wait_checkpoint).min(deadline)) => {}
+            Some(command) = commands.recv() => match command {
+                WebhookCommand::Subscription(request) => handle_subscription_request(deps, config, state, request).await,
+                WebhookCommand::Calendar(request) => handle_calendar_request(deps, config, state, request).await,
+                WebhookCommand::Refresh(request) => handle_refresh_request(deps, config, state, request).await,
+                WebhookCommand::Reaction { message_id, acknowledged } => {
+                    if let Some(flight_key) = state.bot_state.acknowledge_flight(&message_id, acknowledged) {
+                        info!("Flight {} {}", flight_key, if acknowledged { "acknowledged" } else { "no longer acknowledged" });
+                        if let Err(e) = deps.state_store.save(&state.bot_state) {
+                            error!("Failed to save state file {}: {}", deps.state_store.path, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Function to search every route for a date on spawned tasks, up to ROUTE_CONCURRENCY at a time.
+// Each search holds an owned permit of the cycle's search pool, which caps searches across all
+// dates, and keeps it for SEARCH_DELAY_MS afterwards to avoid rate limiting. Results keep the order of `routes`
+async fn search_date_routes<S: FlightSearcher>(
+    searcher: &Arc<S>,
+    config: &Arc<Config>,
+    search_pool: &Arc<Semaphore>,
+    routes: &[Route],
+    departure_date: &str,
+) -> Vec<RouteSearchResult> {
+    let route_pool = Arc::new(Semaphore::new(config.search_concurrency.routes));
+    let mut searches = OrderedTasks::new();
+    for route in routes {
+        let searcher = Arc::clone(searcher);
+        let config = Arc::clone(config);
+        let search_pool = Arc::clone(search_pool);
+        let route_pool = Arc::clone(&route_pool);
+        let route = route.clone();
+        let departure_date = departure_date.to_string();
+        searches.spawn(async move {
+            let _route_permit = route_pool.acquire_owned().await.expect("the route pool is never closed");
+            let _permit = search_pool.acquire_owned().await.expect("the search pool is never closed");
+            let result = searcher.search(&config, &route, &departure_date).await;
+            time::sleep(config.search_delay).await;
+            result
+        });
+    }
+    
+    let mut results = Vec::with_capacity(routes.len());
+    for route in routes {
+        let result = match searches.next().await {
+            Some(Ok(result)) => result,
+            Some(Err(e)) => Err(format!("Search task failed: {}", e).into()),
+            None => unreachable!("one search is spawned per route"),
+        };
+        results.push((route.clone(), result));
+    }
+    results
+}
+
+// Function to send an error to the alert chat when ALERT_CHAT_ID is set and the error is severe
+// enough. Routine reporting to the dev logs topic happens separately
+async fn escalate_error<N: Notifier>(
+    notifier: &N,
+    config: &Config,
+    severity: Severity,
+    kind: &str,
+    context: &str,
+    error: &str,
+) {
+    if !config.escalates(severity) {
+        return;
+    }
+    
+    let message = format!(
+        "🚨 <b>Эскалация: {}</b>\n\n\
+        🏷 Тип: {}\n\
+        📍 Контекст: {}\n\
+        ❌ Ошибка: {}",
+        severity.label(),
+        kind,
+        escape_html(context),
+        escape_html(error)
+    );
+    
+    if let Err(e) = notifier.send_notification(
+        &config.telegram_bot_token,
+        &config.alert_chat_id,
+        &message,
+        &config.alert_topic_id,
+        None
+    ).await {
+        error!("Failed to send escalated error to the alert chat: {}", e);
+    }
+}
+
+// Function to run one search cycle over all configured dates
+async fn run_cycle<S: FlightSearcher, N: Notifier>(
+    deps: &Deps<S, N>,
+    config: &Arc<Config>,
+    state: &mut CycleState,
+    stats: &SearchStatistics,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let enable_telegram = config.telegram_enabled();
+    let enable_airlabs = config.airlabs_enabled();
+    let dates = config.search_dates();
+    let routes = config.routes();
+    let date_range_str = format_date_range_ru(&config.start_date, &config.end_date);
+    
+    config.aviasales_api_keys.reset();
+    
+    // Price charts rendered during this cycle, keyed by route and date
+    let mut chart_cache: HashMap<String, Vec<u8>> = HashMap::new();
+    state.date_digests.clear();
+    state.window_fares.clear();
+    
+    let search_start_time = Utc::now();
+    let formatted_start_time = format_utc_datetime_ru(&config.display, search_start_time);
+    info!("Starting flight search at {}", formatted_start_time);
+    
+    if enable_telegram {
+        let cycle_start_message = format!(
+            "🛫 <b>Программа поиска авиабилетов</b>\n\n\
+            🔍 Начат цикл поиска рейсов: {}\n\
+            🗓 Проверяемые даты: {}\n\n\
+            <i>Статус будет обновляться...</i>",
+            formatted_start_time, date_range_str
+        );
+        
+        if config.status_mode == StatusMode::New {
+            // Post a fresh status message and optionally remove the previous cycle's one
+            let previous_status_id = state.status_message.id.take();
+            match deps.notifier.send_message(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &cycle_start_message,
+                config.topics.topic(MessageCategory::Status),
+                None
+            ).await {
+                Ok(message_id) => state.status_message.posted(message_id),
+                Err(e) => error!("Failed to send status message: {}", e),
+            }
+            
+            if config.status_delete_previous
+                && let Some(previous_id) = previous_status_id
+                && let Err(e) = deps.notifier.delete_message(
+                    &config.telegram_bot_token,
+                    &config.telegram_chat_id,
+                    &previous_id
+                ).await
+            {
+                error!("Failed to delete previous status message: {}", e);
+            }
+        } else if let Err(e) = update_status_message(
+            &deps.notifier, 
+            &config.telegram_bot_token, 
+            &config.telegram_chat_id, 
+            config.topics.topic(MessageCategory::Status),
+            &mut state.status_message, 
+            &cycle_start_message,
+            config.status_edit_max_age
+        ).await {
+            error!("Failed to update status message: {}", e);
+        }
+    }
+    
+    // Fetch exchange rates once per cycle; fall back to rubles only if that fails
+    let exchange_rates = if config.secondary_currency.is_empty() && config.sort_by != SortBy::Price && !config.summary_only
+        && config.route_currencies.is_empty() && config.currency == "rub" {
+        HashMap::new()
+    } else {
+        fetch_exchange_rates(&deps.http.client, &config.rates_url).await.unwrap_or_else(|e| {
+            error!("Failed to fetch exchange rates: {}", e);
+            HashMap::new()
+        })
+    };
+    let currency_conversion = secondary_conversion(config, &exchange_rates);
+    state.exchange_rates.clone_from(&exchange_rates);
+    
+    // Scan a rotating slice of the window when it exceeds the per-cycle cap
+    let cycle_dates = rotate_dates(&dates, state.bot_state.date_offset, config.max_dates_per_cycle);
+    if !dates.is_empty() {
+        state.bot_state.date_offset = (state.bot_state.date_offset + cycle_dates.len()) % dates.len();
+    }
+    
+    // Search the configured routes plus subscribed routes nobody configured
+    let mut search_routes = routes.clone();
+    if enable_telegram {
+        for route in state.bot_state.subscription_routes() {
+            if !search_routes.contains(&route) {
+                search_routes.push(route);
+            }
+        }
+    }
+    
+    // Search DATE_CONCURRENCY dates at once on spawned tasks while results are processed in date order
+    let search_pool = Arc::new(Semaphore::new(config.search_concurrency.total()));
+    let date_pool = Arc::new(Semaphore::new(config.search_concurrency.dates));
+    let search_routes = Arc::new(search_routes);
+    let mut date_searches = OrderedTasks::new();
+    for date in &cycle_dates {
+        let searcher = Arc::clone(&deps.searcher);
+        let config = Arc::clone(config);
+        let search_pool = Arc::clone(&search_pool);
+        let date_pool = Arc::clone(&date_pool);
+        let search_routes = Arc::clone(&search_routes);
+        let departure_date = date.format("%Y-%m-%d").to_string();
+        date_searches.spawn(async move {
+            let _date_permit = date_pool.acquire_owned().await.expect("the date pool is never closed");
+            search_date_routes(&searcher, &config, &search_pool, &search_routes, &departure_date).await
+        });
+    }
+    
+    for date in &cycle_dates {
+        let departure_date = date.format("%Y-%m-%d").to_string();
+        let mut search_results = match date_searches.next().await {
+            Some(Ok(results)) => results,
+            Some(Err(e)) => search_routes
+                .iter()
+                .map(|route| (route.clone(), Err(format!("Search task failed: {}", e).into())))
+                .collect(),
+            None => unreachable!("one search is spawned per date"),
+        };
+        
+        // Display the date in Russian format for logs
+        let formatted_date = format_date_ru(date);
+        
+        // Update statistics for checked date
+        stats.update(|counts| counts.total_dates_checked += 1);
+        deps.progress.touch();
+        
+        // Check members' subscriptions against every route searched for this date
+        let subscription_results = search_results.split_off(routes.len());
+        for (route, result) in &subscription_results {
+            if let Err(e) = result {
+                error!("Error searching subscribed route {}-{} for {}: {}", route.origin, route.destination, formatted_date, e);
+            }
+        }
+        if enable_telegram && !state.initializing(config) {
+            for (route, result) in search_results.iter().chain(&subscription_results) {
+                if let Ok(flight_data) = result {
+                    notify_subscribers(deps, config, state, route, &formatted_date, flight_data, &exchange_rates).await;
+                }
+            }
+        }
+        
+        // Rank destinations by their cheapest fare when searching several
+        let ranking = rank_destinations(&search_results, &config.airline_filter, &exchange_rates);
+        if ranking.len() > 1 && enable_telegram && !config.summary_only && !state.initializing(config) {
+            let ranking_message = format_destination_ranking(&config.display, &formatted_date, &ranking, &config.currency);
+            if !state.bot_state.was_sent_recently(&ranking_message, config.dedup_window) {
+                match deps.notifier.send_notification(
+                    &config.telegram_bot_token,
+                    &config.telegram_chat_id,
+                    &ranking_message,
+                    config.topics.topic(MessageCategory::FoundFlight),
+                    None
+                ).await {
+                    Ok(()) => state.bot_state.record_sent(&ranking_message),
+                    Err(e) => error!("Failed to send destination ranking: {}", e),
+                }
+            }
+        }
+        
+        // Only notify about the cheapest destination when asked to
+        if config.best_destination_only
+            && let Some((best_route, _)) = ranking.first()
+        {
+            let best_route = (*best_route).clone();
+            search_results.retain(|(route, result)| *route == best_route || result.is_err());
+        }
+        
+        for (route, search_result) in search_results {
+            // Process each route and date on its own so one failure never aborts the rest of the cycle
+            let date_result = match search_result {
+                Ok(flight_data) => {
+                    let date = DateSearch {
+                        route: &route,
+                        departure_date: &departure_date,
+                        formatted_date: &formatted_date,
+                        currency_conversion: currency_conversion.as_ref(),
+                        exchange_rates: &exchange_rates,
+                    };
+                    process_search_result(deps, config, state, stats, &mut chart_cache, &date, flight_data).await
+                }
+                Err(e) => Err(e),
+            };
+            
+            if let Err(e) = date_result {
+                // Update statistics for error
+                stats.update(|counts| counts.errors_encountered += 1);
+                error!("Error searching flights {}-{} for {}: {}", route.origin, route.destination, formatted_date, e);
+            
+                // Send a separate error message, unless the same error was reported recently
+                let error_key = format!("{}:{}-{}:{}", error_kind(e.as_ref()), route.origin, route.destination, e);
+                let notify_error = state.error_throttle.should_notify(&error_key, Utc::now(), config.error_notify_cooldown);
+                if !notify_error {
+                    stats.update(|counts| counts.suppressed_errors += 1);
+                }
+                if notify_error {
+                    escalate_error(
+                        &deps.notifier,
+                        config,
+                        search_error_severity(e.as_ref()),
+                        error_kind(e.as_ref()),
+                        &format!("поиск {} → {}, {}", route.origin, route.destination, formatted_date),
+                        &e.to_string()
+                    ).await;
+                }
+                if enable_telegram && notify_error {
+                    let error_message = format!(
+                        "⚠️ <b>Ошибка при поиске рейсов</b>\n\n\
+                        📅 Дата: {}\n\
+                        🧭 Маршрут: {} → {}\n\
+                        ❌ Ошибка: {}\n\n\
+                        <i>Поиск продолжается...</i>",
+                        formatted_date,
+                        get_city_name(&config.display, &route.origin),
+                        get_city_name(&config.display, &route.destination),
+                        e
+                    );
+                
+                    if let Err(send_err) = deps.notifier.send_notification(
+                        &config.telegram_bot_token,
+                        &config.telegram_chat_id,
+                        &error_message,
+                        config.topics.topic(MessageCategory::Error),
+                        None
+                    ).await {
+                        error!("Failed to send error message: {}", send_err);
+                    }
+                }
+            
+                // Update status message without the error details
+                if enable_telegram {
+                    let snapshot = stats.snapshot();
+                    let progress_message = format!(
+                        "🛫 <b>Программа поиска авиабилетов</b>\n\n\
+                        🔍 Поиск начат: {}\n\
+                        🗓 Проверяемые даты: {}\n\n\
+                        {}\n\n\
+                        <i>Поиск в процессе (проверено {} из {} {})...</i>",
+                        formatted_start_time,
+                        date_range_str,
+                        snapshot.format_summary(&config.display, config.max_summary_dates),
+                        snapshot.total_dates_checked,
+                        cycle_dates.len(),
+                        plural_ru(cycle_dates.len() as i64, "даты", "дат", "дат")
+                    );
+                
+                    if let Err(update_err) = update_status_message(
+                        &deps.notifier,
+                        &config.telegram_bot_token,
+                        &config.telegram_chat_id,
+                        config.topics.topic(MessageCategory::Status),
+                        &mut state.status_message,
+                        &progress_message,
+                        config.status_edit_max_age
+                    ).await {
+                        error!("Failed to update status message: {}", update_err);
+                    }
+                }
+            }
+        }
+    }
+    
+    // Watch for a round trip: the outbound date plus any return date in RETURN_WINDOW
+    if let (Some(outbound_date), Some((return_start, return_end))) = (config.outbound_date, config.return_window) {
+        for route in &routes {
+            match find_round_trip(deps, config, route, outbound_date, date_range(return_start, return_end), &exchange_rates).await {
+                Ok(Some(round_trip_message)) => {
+                    if enable_telegram && !state.bot_state.was_sent_recently(&round_trip_message, config.dedup_window) {
+                        match deps.notifier.send_notification(
+                            &config.telegram_bot_token,
+                            &config.telegram_chat_id,
+                            &round_trip_message,
+                            config.topics.topic(MessageCategory::FoundFlight),
+                            None
+                        ).await {
+                            Ok(()) => state.bot_state.record_sent(&round_trip_message),
+                            Err(e) => error!("Failed to send round trip notification: {}", e),
+                        }
+                    }
+                }
+                Ok(None) => info!("No round trip {}-{} with both legs available", route.origin, route.destination),
+                Err(e) => error!("Failed to search round trip {}-{}: {}", route.origin, route.destination, e),
+            }
+        }
+    }
+    
+    // Check watched flights for status changes
+    if enable_airlabs {
+        for (airline, number) in &config.watch_flights {
+            let flight_iata = format!("{}{}", airline, number);
+            let status = match get_airlabs_flight_info(&deps.http, &deps.airlabs_api_base, airline, number, None, &config.airlabs_api_key, config.airlabs_max_retries).await {
+                Ok(Some(flight)) => flight.status,
+                Ok(None) => None,
+                Err(e) => {
+                    error!("Failed to check status of watched flight {}: {}", flight_iata, e);
+                    continue;
+                }
+            };
+            
+            let Some(status) = status else {
+                info!("No status reported for watched flight {}", flight_iata);
+                continue;
+            };
+            
+            if let Some(previous) = state.bot_state.record_flight_status(&flight_iata, &status) {
+                info!("Watched flight {} changed status: {} -> {}", flight_iata, previous, status);
+                
+                if enable_telegram {
+                    let status_message = format!(
+                        "🛰 <b>Статус рейса {} изменился</b>\n\n\
+                        ✈️ {}\n\
+                        Было: {}\n\
+                        Стало: <b>{}</b>",
+                        flight_iata,
+                        get_airline_name(airline),
+                        format_flight_status_ru(&previous),
+                        format_flight_status_ru(&status)
+                    );
+                    
+                    if let Err(e) = deps.notifier.send_notification(
+                        &config.telegram_bot_token,
+                        &config.telegram_chat_id,
+                        &status_message,
+                        config.topics.topic(MessageCategory::FoundFlight),
+                        None
+                    ).await {
+                        error!("Failed to send flight status notification: {}", e);
+                    }
+                }
+            }
+        }
+    }
+    
+    // Send the daily recap once the recap hour has passed
+    let now = Utc::now();
+    if enable_telegram && !config.telegram_recap_topic_id.is_empty() {
+        let last_check = *state.bot_state.last_recap_check_at.get_or_insert(now);
+        if is_recap_due(last_check, now, config.recap_hour, config.display.timezone) {
+            let cheapest = state.bot_state.price_history.cheapest_since(now - chrono::Duration::hours(24));
+            if cheapest.is_empty() {
+                info!("Skipping daily recap: no flights found in the last 24 hours");
+            } else if let Err(e) = deps.notifier.send_notification(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &format_recap(&cheapest, config),
+                &config.telegram_recap_topic_id,
+                None
+            ).await {
+                error!("Failed to send daily recap: {}", e);
+            }
+        }
+    }
+    state.bot_state.last_recap_check_at = Some(now);
+    state.bot_state.price_history.prune(chrono::Duration::days(PRICE_HISTORY_RETENTION_DAYS));
+    if let Some(fare_history) = &deps.fare_history
+        && let Err(e) = fare_history.prune(now, chrono::Duration::days(PRICE_HISTORY_RETENTION_DAYS))
+    {
+        error!("Failed to prune the fare history: {}", e);
+    }
+    state.bot_state.prune_sent_messages(&config.display, config.dedup_window);
+    
+    // Persist state once per cycle
+    if let Some(message_id) = &state.status_message.id {
+        let key = status_message_key(&config.telegram_chat_id, config.topics.topic(MessageCategory::Status));
+        if let Some(posted_at) = state.status_message.posted_at {
+            state.bot_state.status_posted_at.insert(key.clone(), posted_at);
+        }
+        state.bot_state.status_messages.insert(key, message_id.clone());
+    }
+    if let Err(e) = deps.state_store.save(&state.bot_state) {
+        error!("Failed to save state file {}: {}", deps.state_store.path, e);
+    }
+    
+    // Flush the CSV export once per cycle
+    if let Some(exporter) = &deps.csv_exporter
+        && let Err(e) = exporter.flush()
+    {
+        error!("Failed to flush CSV export: {}", e);
+    }
+    
+    let search_end_time = Utc::now();
+    let formatted_end_time = format_utc_datetime_ru(&config.display, search_end_time);
+    let duration = search_end_time.signed_duration_since(search_start_time);
+    
+    let next_cycle_in = next_cycle_delay(config, search_end_time, Duration::from_secs(CHECK_INTERVAL_HOURS * 60 * 60));
+    info!("Completed flight search cycle at {}. Waiting {} minutes before next check.", formatted_end_time, next_cycle_in.as_secs() / 60);
+    
+    // Send a heartbeat when the whole cycle came up empty
+    let snapshot = stats.snapshot();
+    if enable_telegram && config.notify_no_flights && snapshot.total_flights_found == 0
+        && let Err(e) = deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &snapshot.format_no_flights_digest(&date_range_str),
+            config.topics.topic(MessageCategory::Status),
+            None
+        ).await
+    {
+        error!("Failed to send no flights digest: {}", e);
+    }
+    
+    // Report the first cycle's fares as recorded instead of notifying about each of them
+    if state.initializing(config) {
+        let init_message = format_init_summary(snapshot.dates_with_flights, snapshot.total_flights_found);
+        if enable_telegram
+            && let Err(e) = deps.notifier.send_notification(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &init_message,
+                config.topics.topic(MessageCategory::Status),
+                None
+            ).await
+        {
+            error!("Failed to send initialization summary: {}", e);
+        }
+        info!("First run: recorded existing fares without notifying");
+    }
+    
+    // Send the window's cheapest fares as one ranked message
+    if enable_telegram && config.summary_only && !state.initializing(config)
+        && let Some(summary) = format_window_summary(&config.display, &state.window_fares, &date_range_str, MAX_FLIGHTS_PER_DATE, &exchange_rates)
+        && !state.bot_state.was_sent_recently(&summary, config.dedup_window)
+    {
+        match deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &summary,
+            config.topics.topic(MessageCategory::FoundFlight),
+            None
+        ).await {
+            Ok(()) => state.bot_state.record_sent(&summary),
+            Err(e) => error!("Failed to send window summary: {}", e),
+        }
+    }
+    
+    // Offer the dates with flights as a tappable calendar
+    let flight_dates: Vec<NaiveDate> = state.date_digests
+        .keys()
+        .filter_map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        .collect();
+    if enable_telegram
+        && !state.initializing(config)
+        && let Some(keyboard) = calendar_keyboard(&flight_dates)
+        && let Err(e) = deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            "📅 <b>Даты с рейсами</b>\n\n<i>Нажмите на дату, чтобы получить подробную сводку.</i>",
+            config.topics.topic(MessageCategory::FoundFlight),
+            Some(keyboard)
+        ).await
+    {
+        error!("Failed to send calendar keyboard: {}", e);
+    }
+    
+    // Final status update with complete statistics
+    if enable_telegram {
+        let final_message = format!(
+            "🛫 <b>Программа поиска авиабилетов</b>\n\n\
+            ✅ <b>Цикл поиска завершен!</b>\n\
+            🕒 Начало: {}\n\
+            🕕 Окончание: {}\n\
+            ⏱ Длительность: {}\n\
+            🗓 Проверено дат: {}\n\n\
+            {}\n\n\
+            🔄 Следующий цикл {}",
+            formatted_start_time,
+            formatted_end_time,
+            format_cycle_duration_ru(duration),
+            cycle_dates.len(),
+            stats.format_summary(&config.display, config.max_summary_dates),
+            format_next_cycle(config, search_end_time)
+        );
+        
+        if let Err(e) = update_status_message(
+            &deps.notifier,
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            config.topics.topic(MessageCategory::Status),
+            &mut state.status_message,
+            &final_message,
+            config.status_edit_max_age
+        ).await {
+            error!("Failed to update final status message: {}", e);
+        }
+        
+        // Keep the status message pinned; only in edit mode so the pin doesn't churn every cycle
+        if config.pin_status
+            && config.status_mode == StatusMode::Edit
+            && let Some(message_id) = state.status_message.id.clone()
+            && state.pinned_message_id.as_deref() != Some(message_id.as_str())
+        {
+            if let Some(previous) = state.pinned_message_id.take()
+                && let Err(e) = deps.notifier.unpin_message(
+                    &config.telegram_bot_token,
+                    &config.telegram_chat_id,
+                    &previous
+                ).await
+            {
+                error!("Failed to unpin previous status message: {}", e);
+            }
+            
+            match deps.notifier.pin_message(
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &message_id
+            ).await {
+                Ok(()) => state.pinned_message_id = Some(message_id),
+                Err(e) => error!("Failed to pin status message (does the bot have pin rights?): {}", e),
+            }
+        }
+    }
+    
+    // State has been saved, so later cycles and restarts notify normally
+    state.first_run = false;
+    
+    Ok(())
+}
+
+// TODO: Create schedule checker for date from 15 sept 2025 to 30 sept 2025
+// for available dates in the aero flights aviasales.ru each 6 hours
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Offline check of a saved API response: `--validate-response <file>`
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--validate-response") {
+        let path = args.get(2).ok_or("Usage: --validate-response <file>")?;
+        print!("{}", response_field_coverage(&std::fs::read_to_string(path)?)?);
+        return Ok(());
+    }
+    
+    // Load environment variables from .env file
+    dotenv().ok();
+    
+    // Load the configuration; CONFIG_FILE entries override environment variables
+    let config_file = env::var("CONFIG_FILE").unwrap_or_default();
+    let source = ConfigSource::load(&config_file)?;
+    
+    // Set up console logging before anything is logged (applied at startup only)
+    let verbosity = init_logging(&source)?;
+    info!("Console verbosity: {:?} ({})", verbosity, verbosity.filter_directive());
+    
+    let mut config = Arc::new(Config::from_source(&source)?);
+    validate_config(&config)?;
+    let mut config_modified = config_file_modified(&config_file);
+    
+    // Load persisted state
+    let state_file = source.get("STATE_FILE").unwrap_or_else(|| "bot_state.json".to_string());
+    let state_store = Store {
+        path: state_file.clone(),
+        format: PersistFormat::from_env_value(&source.string("PERSIST_FORMAT")),
+    };
+    let first_run = !std::path::Path::new(&state_file).exists();
+    let bot_state: BotState = state_store.load();
+    
+    // Open the optional history of every fare notified
+    let fare_history = fare_history_path(&source)?
+        .map(|path| FareHistory::open(&path))
+        .transpose()?;
+    
+    // Get optional CSV export path
+    let csv_output_path = source.string("CSV_OUTPUT_PATH");
+    let csv_exporter = if csv_output_path.is_empty() {
+        None
+    } else {
+        Some(CsvExporter::open(&csv_output_path)?)
+    };
+    
+    // Get optional NDJSON output: "stdout" or a file path
+    let ndjson_output = source.string("OUTPUT_NDJSON");
+    let ndjson_exporter = if ndjson_output.is_empty() {
+        None
+    } else {
+        Some(NdjsonExporter::open(&ndjson_output)?)
+    };
+    
+    // Create the HTTP client and the API clients (applied at startup only)
+    let http = HttpClient {
+        client: build_http_client(&source)?,
+        network_retries: source.parse("NETWORK_MAX_RETRIES", 3),
+    };
+    let notifier = TelegramNotifier {
+        http: http.clone(),
+        api_base: parse_api_base("TELEGRAM_API_BASE", &source.string("TELEGRAM_API_BASE"), DEFAULT_TELEGRAM_API_BASE)?,
+        topic_pipeline: source.parse("TOPIC_PIPELINE", 1),
+        throttle: RequestThrottle::default(),
+    };
+    // Respect a Telegram rate limit pause left over from before a restart
+    notifier.throttle.persist_to(&format!("{}.telegram-pause", state_file));
+    let searcher = TravelpayoutsSearcher {
+        http: http.clone(),
+        api_base: parse_api_base("TRAVELPAYOUTS_API_BASE", &source.string("TRAVELPAYOUTS_API_BASE"), DEFAULT_TRAVELPAYOUTS_API_BASE)?,
+        throttle: RequestThrottle::default(),
+    };
+    let airlabs_api_base = parse_api_base("AIRLABS_API_BASE", &source.string("AIRLABS_API_BASE"), DEFAULT_AIRLABS_API_BASE)?;
+    
+    // Start the webhook receiver if a public webhook URL is configured
+    let telegram_webhook_url = source.string("TELEGRAM_WEBHOOK_URL");
+    let (webhook_commands_sender, mut webhook_commands) = mpsc::unbounded_channel();
+    let mut webhook_running = false;
+    if !telegram_webhook_url.is_empty() {
+        let webhook_secret = webhook_secret(&source)?;
+        let webhook_addr = source.get("TELEGRAM_WEBHOOK_ADDR")
+            .unwrap_or_else(|| "0.0.0.0:8080".to_string());
+        
+        if config.telegram_enabled() {
+            start_webhook_receiver(
+                &notifier,
+                &config.telegram_bot_token,
+                &config.telegram_chat_id,
+                &telegram_webhook_url,
+                &webhook_secret,
+                &webhook_addr,
+                webhook_commands_sender
+            ).await?;
+            webhook_running = true;
+        }
+    }
+    
+    // Optionally verify the token and topic IDs before the first cycle
+    let self_test = source.string("SELF_TEST").trim().to_lowercase();
+    if config.telegram_enabled() && (self_test == "strict" || source.flag("SELF_TEST")) {
+        let results = run_self_test(&notifier, &config, source.flag("SELF_TEST_DELETE")).await;
+        let failed: Vec<&str> = results.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| *name).collect();
+        
+        if let Err(e) = notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &format_self_test_report(&results),
+            config.topics.topic(MessageCategory::Status),
+            None
+        ).await {
+            error!("Failed to send self-test report: {}", e);
+        }
+        
+        if self_test == "strict" && !failed.is_empty() {
+            return Err(format!("Self-test failed for: {}", failed.join(", ")).into());
+        }
+    }
+    
+    let mut status_message = StatusMessage::default();
+    
+    let check_interval = Duration::from_secs(CHECK_INTERVAL_HOURS * 60 * 60);
+    
+    // Send startup notification
+    if config.telegram_enabled() {
+        let date_range_str = format_date_range_ru(&config.start_date, &config.end_date);
+        let origin_name = get_city_name(&config.display, &config.origin);
+        let destination_name = config.destination_names();
+        let filter_line = match config.airline_filter.describe() {
+            Some(description) => format!("Авиакомпании: {}.\n", escape_html(&description)),
+            None => String::new(),
+        };
+        let startup_message = format!(
+            "🛫 <b>Программа поиска авиабилетов запущена!</b>\n\n\
+             Будет проверять прямые рейсы из <b>{}</b> в <b>{}</b> {}.\n\
+             Поиск будет происходить {}.\n\
+             {}\n\
+             <i>Этот статус будет обновляться с результатами поиска.</i>",
+            origin_name, destination_name, date_range_str, config.describe_schedule(), filter_line
+        );
+        
+        // Reuse the status message from the previous run if it can still be edited
+        let status_key = status_message_key(&config.telegram_chat_id, config.topics.topic(MessageCategory::Status));
+        status_message = StatusMessage {
+            id: bot_state.status_messages.get(&status_key).cloned(),
+            posted_at: bot_state.status_posted_at.get(&status_key).copied(),
+        };
+        match update_status_message(
+            &notifier, 
+            &config.telegram_bot_token, 
+            &config.telegram_chat_id, 
+            config.topics.topic(MessageCategory::Status), 
+            &mut status_message, 
+            &startup_message,
+            config.status_edit_max_age
+        ).await {
+            Ok(()) => {
+                info!("Status message ID: {}", status_message.id.as_deref().unwrap_or_default());
+            },
+            Err(e) => {
+                error!("Failed to send initial status message: {}", e);
+            }
+        }
+    }
+    
+    let deps = Deps {
+        searcher: Arc::new(searcher),
+        notifier,
+        http,
+        airlabs_api_base,
+        csv_exporter,
+        ndjson_exporter,
+        state_store,
+        fare_history,
+        progress: CycleProgress::new(),
+        shortener: LinkShortener::default(),
+        webhook_running,
+    };
+    let mut failed_cycles: u32 = 0;
+    let mut completed_cycles: u32 = 0;
+    let started_at = time::Instant::now();
+    let mut state = CycleState {
+        bot_state,
+        status_message,
+        pinned_message_id: None,
+        error_throttle: ErrorNotifyThrottle::default(),
+        date_digests: HashMap::new(),
+        window_fares: Vec::new(),
+        exchange_rates: HashMap::new(),
+        first_run,
+    };
+    
+    // Stop gracefully on the first signal, immediately on the second
+    tokio::spawn(async {
+        shutdown_signal().await;
+        info!("Shutdown requested, stopping after the current cycle");
+        SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+        shutdown_signal().await;
+        warn!("Second shutdown signal, exiting now");
+        std::process::exit(130);
+    });
+    
+    let (stop_reason, last_stats) = loop {
+        // Reload the configuration if CONFIG_FILE changed since the last cycle
+        reload_config_if_changed(&deps, &config_file, &mut config, &mut config_modified).await;
+        
+        // Reset statistics for this search cycle
+        let stats = SearchStatistics::new();
+        
+        let cycle_result = run_cycle_with_watchdog(&deps, &config, &mut state, &stats).await;
+        completed_cycles += 1;
+        
+        // Stop cleanly once MAX_CYCLES or MAX_RUNTIME_MINUTES is reached
+        if let Some(reason) = run_limit_reached(&config, completed_cycles, started_at.elapsed()) {
+            break (reason, stats);
+        }
+        
+        // Back off and retry a failed cycle instead of exiting
+        if let Err(e) = cycle_result {
+            failed_cycles += 1;
+            let retry_in = cycle_retry_delay(failed_cycles).min(check_interval);
+            error!("Search cycle failed ({} in a row): {}. Retrying in {} seconds.",
+                failed_cycles, e, retry_in.as_secs());
+            escalate_error(
+                &deps.notifier,
+                &config,
+                Severity::Error,
+                error_kind(e.as_ref()),
+                &format!("цикл поиска, неудач подряд: {}", failed_cycles),
+                &e.to_string()
+            ).await;
+            if wait_for_next_cycle(&deps, &config, &mut state, &mut webhook_commands, retry_in, &config_file, config_modified).await == WaitOutcome::Shutdown {
+                break ("получен сигнал остановки".to_string(), stats);
+            }
+            continue;
+        }
+        failed_cycles = 0;
+        
+        let delay = next_cycle_delay(&config, Utc::now(), check_interval);
+        if wait_for_next_cycle(&deps, &config, &mut state, &mut webhook_commands, delay, &config_file, config_modified).await == WaitOutcome::Shutdown {
+            break ("получен сигнал остановки".to_string(), stats);
+        }
+        
+        // Don't start another cycle once the runtime is used up
+        if let Some(reason) = run_limit_reached(&config, completed_cycles, started_at.elapsed()) {
+            break (reason, stats);
+        }
+    };
+    
+    info!("Stopping after {} cycles: {}", completed_cycles, stop_reason);
+    
+    // Flush stores before exiting
+    if let Err(e) = deps.state_store.save(&state.bot_state) {
+        error!("Failed to save state file {}: {}", deps.state_store.path, e);
+    }
+    if let Some(exporter) = &deps.csv_exporter
+        && let Err(e) = exporter.flush()
+    {
+        error!("Failed to flush CSV export: {}", e);
+    }
+    
+    // Post a final summary
+    if config.telegram_enabled() {
+        let final_message = format!(
+            "🏁 <b>Программа поиска авиабилетов завершена</b>\n\n\
+            ⏹ Причина: {}\n\
+            🔁 Циклов выполнено: {}\n\
+            ⏱ Время работы: {} мин.\n\n\
+            <b>Последний цикл</b>\n{}",
+            stop_reason,
+            completed_cycles,
+            started_at.elapsed().as_secs() / 60,
+            last_stats.format_summary(&config.display, config.max_summary_dates)
+        );
+        if let Err(e) = deps.notifier.send_notification(
+            &config.telegram_bot_token,
+            &config.telegram_chat_id,
+            &final_message,
+            config.topics.topic(MessageCategory::Status),
+            None
+        ).await {
+            error!("Failed to send final summary: {}", e);
+        }
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // Configuration with only the required settings plus `extra`; CONFIG_FILE values take
+    // priority over environment variables, so the test doesn't depend on the host's .env
+    fn test_config(extra: &[(&str, &str)]) -> Arc<Config> {
+        Arc::new(Config::from_source(&test_source(extra)).unwrap())
+    }
+
+    fn test_source(extra: &[(&str, &str)]) -> ConfigSource {
+        let mut file_values: HashMap<String, String> = [
+            ("TELEGRAM_BOT_TOKEN", "test-token"),
+            ("TELEGRAM_CHAT_ID", "-100"),
+            ("TRAVELPAYOUTS_API_KEY", "test-key"),
+            ("ORIGIN", "MOW"),
+            ("DESTINATION", "AER"),
+            ("START_DATE", "2030-03-01"),
+            ("END_DATE", "2030-03-02"),
+            ("SEARCH_DELAY_MS", "0"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        for (key, value) in extra {
+            file_values.insert(key.to_string(), value.to_string());
+        }
+        ConfigSource { file_values }
+    }
+
+    fn test_flight(departure_at: &str, price: i64) -> FlightResult {
+        serde_json::from_value(json!({
+            "origin": "MOW",
+            "destination": "AER",
+            "origin_airport": "SVO",
+            "destination_airport": "AER",
+            "price": price,
+            "airline": "SU",
+            "flight_number": "1234",
+            "departure_at": departure_at,
+            "return_at": null,
+            "transfers": 0,
+            "duration": 150,
+            "duration_to": 150,
+            "duration_back": null,
+            "return_transfers": null,
+            "link": "/search/MOW0103AER1",
+            "seats": null
+        }))
+        .unwrap()
+    }
+
+    fn flight_data(flights: Vec<FlightResult>) -> FlightData {
+        FlightData {
+            success: true,
+            data: Some(flights),
+            currency: Some("rub".to_string()),
+            error: None,
+            parse_warning: None,
+        }
+    }
+
+    // Searcher answering from a table keyed by departure date or route and date; other dates fail
+    struct MockSearcher {
+        fares: Mutex<HashMap<String, Vec<FlightResult>>>,
+        // "ORIGIN-DESTINATION currency" of every search, in order
+        searches: Mutex<Vec<String>>,
+        // Departure date of every search, in order
+        dates: Mutex<Vec<String>>,
+        // Searches for this date never complete
+        stalled_date: Mutex<Option<String>>,
+        // How long each search takes, and how many ran at once at most
+        search_time: Mutex<Duration>,
+        in_flight: AtomicUsize,
+        peak_in_flight: AtomicUsize,
+    }
+
+    impl MockSearcher {
+        fn new(fares: HashMap<String, Vec<FlightResult>>) -> Self {
+            Self {
+                fares: Mutex::new(fares),
+                searches: Mutex::new(Vec::new()),
+                dates: Mutex::new(Vec::new()),
+                stalled_date: Mutex::new(None),
+                search_time: Mutex::new(Duration::ZERO),
+                in_flight: AtomicUsize::new(0),
+                peak_in_flight: AtomicUsize::new(0),
+            }
+        }
+
+        // Change the fares of a date for the following cycles
+        fn set_fares(&self, departure_date: &str, flights: Vec<FlightResult>) {
+            self.fares.lock().unwrap().insert(departure_date.to_string(), flights);
+        }
+    }
+
+    impl FlightSearcher for MockSearcher {
+        async fn search(&self, config: &Config, route: &Route, departure_date: &str) -> Result<FlightData, Box<dyn Error + Send + Sync>> {
+            self.searches.lock().unwrap().push(format!("{}-{} {}", route.origin, route.destination, route.currency(&config.currency)));
+            self.dates.lock().unwrap().push(departure_date.to_string());
+            if self.stalled_date.lock().unwrap().as_deref() == Some(departure_date) {
+                std::future::pending::<()>().await;
+            }
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            let search_time = *self.search_time.lock().unwrap();
+            time::sleep(search_time).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            // Fares for a specific route and date take priority over those for the date
+            let fares = self.fares.lock().unwrap();
+            let route_fares = fares.get(&route_date_key(&route.origin, &route.destination, departure_date));
+            match route_fares.or_else(|| fares.get(departure_date)) {
+                Some(flights) => Ok(flight_data(flights.clone())),
+                None => Err("API error: service unavailable".into()),
+            }
+        }
+    }
+
+    // Notifier that records every call instead of talking to Telegram
+    #[derive(Default)]
+    struct MockNotifier {
+        // (method, chat ID, text)
+        calls: Mutex<Vec<(&'static str, String, String)>>,
+        // Inline keyboards of sendMessage calls, in order
+        keyboards: Mutex<Vec<Option<serde_json::Value>>>,
+        // Messages containing this text fail to send
+        failing: Mutex<Option<&'static str>>,
+    }
+
+    impl MockNotifier {
+        fn check_failing(&self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            match *self.failing.lock().unwrap() {
+                Some(failing) if text.contains(failing) => Err("Bad Request: message is too long".into()),
+                _ => Ok(()),
+            }
+        }
+
+        fn record(&self, method: &'static str, chat_id: &str, text: &str) -> String {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((method, chat_id.to_string(), text.to_string()));
+            calls.len().to_string()
+        }
+
+        fn keyboards(&self) -> Vec<Option<serde_json::Value>> {
+            self.keyboards.lock().unwrap().clone()
+        }
+
+        fn methods(&self) -> Vec<&'static str> {
+            self.calls.lock().unwrap().iter().map(|(method, _, _)| *method).collect()
+        }
+
+        fn messages(&self) -> Vec<String> {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(method, _, _)| matches!(*method, "sendMessage" | "sendPhoto"))
+                .map(|(_, _, text)| text.clone())
+                .collect()
+        }
+    }
+
+    impl Notifier for MockNotifier {
+        async fn send_message(
+            &self,
+            _bot_token: &str,
+            chat_id: &str,
+            message: &str,
+            _topic_id: &str,
+            inline_keyboard: Option<serde_json::Value>,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            self.check_failing(message)?;
+            self.keyboards.lock().unwrap().push(inline_keyboard);
+            Ok(self.record("sendMessage", chat_id, message))
+        }
+
+        async fn send_message_part(
+            &self,
+            _bot_token: &str,
+            chat_id: &str,
+            message: &str,
+            _topic_id: &str,
+            _inline_keyboard: Option<serde_json::Value>,
+            _reply_to_message_id: Option<&str>,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            self.check_failing(message)?;
+            Ok(self.record("sendMessage", chat_id, message))
+        }
+
+        async fn send_photo(
+            &self,
+            _bot_token: &str,
+            chat_id: &str,
+            _photo: Vec<u8>,
+            caption: &str,
+            _topic_id: &str,
+        ) -> Result<String, Box<dyn Error + Send + Sync>> {
+            Ok(self.record("sendPhoto", chat_id, caption))
+        }
+
+        async fn edit_message(
+            &self,
+            _bot_token: &str,
+            chat_id: &str,
+            _message_id: &str,
+            message: &str,
+            _topic_id: &str,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.record("editMessageText", chat_id, message);
+            Ok(())
+        }
+
+        async fn delete_message(&self, _bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.record("deleteMessage", chat_id, message_id);
+            Ok(())
+        }
+
+        async fn pin_message(&self, _bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.record("pinChatMessage", chat_id, message_id);
+            Ok(())
+        }
+
+        async fn unpin_message(&self, _bot_token: &str, chat_id: &str, message_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.record("unpinChatMessage", chat_id, message_id);
+            Ok(())
+        }
+
+        async fn set_reaction(&self, _bot_token: &str, chat_id: &str, message_id: &str, emoji: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.record("setMessageReaction", chat_id, &format!("{} {}", message_id, emoji));
+            Ok(())
+        }
+
+        async fn answer_callback_query(&self, _bot_token: &str, callback_query_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.record("answerCallbackQuery", "", callback_query_id);
+            Ok(())
+        }
+    }
+
+    fn test_deps(searcher: MockSearcher) -> Deps<MockSearcher, MockNotifier> {
+        let state_path = std::env::temp_dir().join(format!("flights-test-state-{}-{:?}.json", std::process::id(), std::thread::current().id()));
+        Deps {
+            http: test_http(Client::new()),
+            airlabs_api_base: mock_api().base.clone(),
+            searcher: Arc::new(searcher),
+            notifier: MockNotifier::default(),
+            csv_exporter: None,
+            ndjson_exporter: None,
+            state_store: Store { path: state_path.to_string_lossy().into_owned(), format: PersistFormat::Json },
+            fare_history: None,
+            progress: CycleProgress::new(),
+            shortener: LinkShortener::default(),
+            webhook_running: false,
+        }
+    }
+
+    fn test_state() -> CycleState {
+        CycleState {
+            bot_state: BotState::default(),
+            status_message: StatusMessage::default(),
+            pinned_message_id: None,
+            error_throttle: ErrorNotifyThrottle::default(),
+            date_digests: HashMap::new(),
+            window_fares: Vec::new(),
+            exchange_rates: HashMap::new(),
+            first_run: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn run_cycle_notifies_found_flights_and_counts_errors() {
+        let config = test_config(&[]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)])]));
+        let deps = test_deps(searcher);
+        let mut state = test_state();
+        let stats = SearchStatistics::new();
+        
+        run_cycle(&deps, &config, &mut state, &stats).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(stats.snapshot().dates_with_flights, 1);
+        assert_eq!(stats.snapshot().errors_encountered, 1);
+        
+        // Cycle start status, the date header, the flight itself and the error report
+        let messages = deps.notifier.messages();
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+        assert_eq!(messages.iter().filter(|m| m.contains("Рейс SU 1234")).count(), 1, "{:?}", messages);
+        assert_eq!(messages.iter().filter(|m| m.contains("Ошибка при поиске рейсов")).count(), 1, "{:?}", messages);
+        
+        // Without the webhook receiver nobody would handle the refresh button
+        assert!(deps.notifier.keyboards().iter().all(|keyboard| refresh_buttons(keyboard) == 0));
+    }
+
+    fn webhook_state(secret: &str) -> (Arc<WebhookState>, mpsc::UnboundedReceiver<WebhookCommand>) {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let state = WebhookState { secret: secret.to_string(), chat_id: "-100".to_string(), commands };
+        (Arc::new(state), receiver)
+    }
+
+    fn webhook_headers(secret: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(secret) = secret {
+            headers.insert("X-Telegram-Bot-Api-Secret-Token", secret.parse().unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn webhook_rejects_missing_or_wrong_secret() {
+        let body = Bytes::from(json!({"message": {"chat": {"id": -100}, "text": "hi"}}).to_string());
+        let (state, _commands) = webhook_state("s3cret");
+        
+        assert_eq!(handle_webhook(State(state.clone()), webhook_headers(None), body.clone()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(handle_webhook(State(state.clone()), webhook_headers(Some("wrong")), body.clone()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(handle_webhook(State(state), webhook_headers(Some("s3cret")), body.clone()).await, StatusCode::OK);
+        
+        // Even an empty configured secret never matches a request without the header
+        let (state, _commands) = webhook_state("");
+        assert_eq!(handle_webhook(State(state), webhook_headers(None), body).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn webhook_secret_must_not_be_blank() {
+        let source = |secret: &str| ConfigSource {
+            file_values: HashMap::from([("TELEGRAM_WEBHOOK_SECRET".to_string(), secret.to_string())]),
+        };
+        
+        assert!(matches!(webhook_secret(&source("")), Err(BotError::Config(_))));
+        assert!(matches!(webhook_secret(&source("   ")), Err(BotError::Config(_))));
+        assert_eq!(webhook_secret(&source("s3cret")).unwrap(), "s3cret");
+    }
+
+    #[test]
+    fn rate_limited_token_rotates_to_the_next_one() {
+        let pool = ApiKeyPool::new("first, second");
+        let (index, key) = pool.next_key().unwrap();
+        assert_eq!((index, key), (0, "first"));
+        
+        // A plain 429 keeps the token but the next request goes to the second one
+        assert!(!pool.record_rate_limit(index, false));
+        assert_eq!(pool.next_key(), Some((1, "second")));
+        assert_eq!(pool.next_key(), Some((0, "first")));
+    }
+
+    #[test]
+    fn token_is_exhausted_by_quota_or_repeated_rate_limit() {
+        let pool = ApiKeyPool::new("first,second,third");
+        assert!(pool.record_rate_limit(0, true));
+        
+        // A success in between forgives an earlier 429
+        assert!(!pool.record_rate_limit(1, false));
+        pool.record_success(1);
+        assert!(!pool.record_rate_limit(1, false));
+        assert!(pool.record_rate_limit(1, false));
+        
+        assert_eq!(pool.next_key(), Some((2, "third")));
+        assert_eq!(pool.next_key(), Some((2, "third")));
+    }
+
+    #[test]
+    fn last_token_is_not_exhausted_by_plain_rate_limits() {
+        let pool = ApiKeyPool::new("only");
+        assert!(!pool.record_rate_limit(0, false));
+        assert!(!pool.record_rate_limit(0, false));
+        assert_eq!(pool.next_key(), Some((0, "only")));
+        
+        assert!(pool.record_rate_limit(0, true));
+        assert_eq!(pool.next_key(), None);
+    }
+
+    #[test]
+    fn quota_response_is_recognized() {
+        assert!(is_quota_response(r#"{"success":false,"error":"Quota exceeded"}"#));
+        assert!(!is_quota_response("Too Many Requests"));
+        // Mentions of the quota outside the error field don't count
+        assert!(!is_quota_response(r#"{"success":false,"error":"Too many requests","docs":"https://support.travelpayouts.com/quota"}"#));
+        assert!(!is_quota_response("Too many requests, see the quota page"));
+    }
+
+    #[tokio::test]
+    async fn plain_rate_limit_mentioning_the_quota_keeps_the_token() {
+        let api = mock_api();
+        let mut rate_limited = MockReply::json(429, json!({"success": false, "error": "Too many requests", "message": "Slow down to stay within your quota"}));
+        rate_limited.headers.push(("Retry-After", "0".to_string()));
+        api.script("quota-mention-key", vec![rate_limited, travelpayouts_page(&[test_flight("2030-03-01T10:00:00+03:00", 5000)])]);
+        
+        let api_keys = ApiKeyPool::new("quota-mention-key");
+        let paging = SearchPaging { limit: 30, max_results: 30 };
+        let flight_data = search_flights(&test_travelpayouts(Client::new()), "MOW", "AER", "2030-03-01", &api_keys, true, paging, "rub")
+            .await
+            .unwrap();
+        assert_eq!(flight_data.data.map(|flights| flights.len()), Some(1));
+        assert_eq!(api_keys.next_key(), Some((0, "quota-mention-key")));
+    }
+
+    fn refresh_buttons(keyboard: &Option<serde_json::Value>) -> usize {
+        keyboard.iter()
+            .flat_map(|keyboard| keyboard["inline_keyboard"][0].as_array().cloned().unwrap_or_default())
+            .filter(|button| button["callback_data"].as_str().is_some_and(|data| data.starts_with(REFRESH_CALLBACK_PREFIX)))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn refresh_button_only_with_webhook_receiver() {
+        let config = test_config(&[]);
+        let route = config.routes().remove(0);
+        let flight = test_flight("2030-03-01T10:00:00+03:00", 5000);
+        let mut deps = test_deps(MockSearcher::new(HashMap::new()));
+        
+        let keyboard = flight_keyboard(&deps, &config, &flight, &route, "2030-03-01").await;
+        assert_eq!(refresh_buttons(&keyboard), 0, "{:?}", keyboard);
+        
+        deps.webhook_running = true;
+        let keyboard = flight_keyboard(&deps, &config, &flight, &route, "2030-03-01").await;
+        assert_eq!(refresh_buttons(&keyboard), 1, "{:?}", keyboard);
+        assert_eq!(keyboard.unwrap()["inline_keyboard"][0][1]["callback_data"], "refresh:MOW-AER:2030-03-01");
+    }
+
+    #[tokio::test]
+    async fn refresh_reply_uses_the_cycle_exchange_rates() {
+        let config = test_config(&[("SECONDARY_CURRENCY", "usd")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 8000)])]));
+        let deps = test_deps(searcher);
+        let mut state = test_state();
+        state.exchange_rates = HashMap::from([("USD".to_string(), 0.0125)]);
+        let request = RefreshRequest {
+            callback_query_id: "42".to_string(),
+            origin: "MOW".to_string(),
+            destination: "AER".to_string(),
+            date: "2030-03-01".to_string(),
+            topic_id: String::new(),
+        };
+        
+        handle_refresh_request(&deps, &config, &state, request).await;
+        
+        let messages = deps.notifier.messages();
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert!(messages[0].contains("$100"), "{}", messages[0]);
+    }
+
+    #[test]
+    fn fare_history_tells_new_fares_from_repeated_ones() {
+        let path = std::env::temp_dir().join(format!("flights-test-fares-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let history = FareHistory::open(path.to_str().unwrap()).unwrap();
+        let now = Utc::now();
+        let flight = test_flight("2030-03-01T10:00:00+03:00", 5000);
+        
+        // A new tuple
+        assert!(history.is_new(&flight));
+        history.record(&flight, now).unwrap();
+        
+        // A repeated tuple keeps its row and when it was first seen
+        assert!(!history.is_new(&flight));
+        history.record(&flight, now + chrono::Duration::hours(6)).unwrap();
+        let (rows, first_seen, last_seen): (i64, String, String) = history.connection.lock().unwrap()
+            .query_row("SELECT COUNT(*), MIN(first_seen), MAX(last_seen) FROM fares", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        assert_eq!(rows, 1);
+        let seen_at = |value: &str| DateTime::parse_from_rfc3339(value).unwrap();
+        assert_eq!(seen_at(&last_seen) - seen_at(&first_seen), chrono::Duration::hours(6));
+        
+        // The same flight at a new price is a new fare
+        assert!(history.is_new(&test_flight("2030-03-01T10:00:00+03:00", 4500)));
+        
+        // The history outlives the process that wrote it
+        drop(history);
+        assert!(!FareHistory::open(path.to_str().unwrap()).unwrap().is_new(&flight));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn fare_history_prunes_fares_not_seen_within_the_retention() {
+        let history = FareHistory::open(":memory:").unwrap();
+        let now = Utc::now();
+        let retention = chrono::Duration::days(PRICE_HISTORY_RETENTION_DAYS);
+        let recent = test_flight("2030-03-01T10:00:00+03:00", 5000);
+        let stale = test_flight("2030-03-02T10:00:00+03:00", 5000);
+        history.record(&recent, now).unwrap();
+        history.record(&stale, now - retention - chrono::Duration::days(1)).unwrap();
+        
+        assert_eq!(history.prune(now, retention).unwrap(), 1);
+        assert!(!history.is_new(&recent));
+        assert!(history.is_new(&stale));
+    }
+
+    #[test]
+    fn fare_history_path_comes_from_sqlite_path_or_database_url() {
+        let path = |values: &[(&str, &str)]| fare_history_path(&test_source(values));
+        assert_eq!(path(&[]).unwrap(), None);
+        assert_eq!(path(&[("DATABASE_URL", "sqlite://fares.db")]).unwrap().as_deref(), Some("fares.db"));
+        assert_eq!(path(&[("DATABASE_URL", "sqlite:/var/lib/bot/fares.db")]).unwrap().as_deref(), Some("/var/lib/bot/fares.db"));
+        assert_eq!(path(&[("SQLITE_PATH", "local.db"), ("DATABASE_URL", "sqlite://fares.db")]).unwrap().as_deref(), Some("local.db"));
+        assert!(path(&[("DATABASE_URL", "postgres://localhost/fares")]).is_err());
+    }
+
+    fn test_flight_in(departure_at: &str, price: i64, currency: &str, transfers: i64) -> FlightResult {
+        let mut flight = test_flight(departure_at, price);
+        flight.currency = Some(currency.to_string());
+        flight.transfers = transfers;
+        flight
+    }
+
+    #[tokio::test]
+    async fn routes_are_searched_in_their_own_currency() {
+        let config = test_config(&[("DESTINATION", "AER,JFK"), ("ROUTE_CURRENCIES", "JFK:usd"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let mut searches = deps.searcher.searches.lock().unwrap().clone();
+        searches.sort();
+        assert_eq!(searches, ["MOW-AER rub", "MOW-JFK usd"]);
+        assert_eq!(config.route_currency("MOW-JFK"), "usd");
+        assert_eq!(config.route_currency("MOW-AER"), "rub");
+    }
+
+    #[test]
+    fn prices_are_formatted_in_the_route_currency() {
+        let config = test_config(&[("DESTINATION", "AER,JFK"), ("ROUTE_CURRENCIES", "JFK:usd")]);
+        
+        let recap = format_recap(&[("MOW-AER:2030-03-01".to_string(), 5000), ("MOW-JFK:2030-03-01".to_string(), 300)], &config);
+        assert!(recap.contains("5 000 ₽"), "{}", recap);
+        assert!(recap.contains("$300"), "{}", recap);
+        
+        let direct = test_flight_in("2030-03-01T10:00:00+03:00", 300, "usd", 0);
+        let connecting = test_flight_in("2030-03-01T12:00:00+03:00", 250, "usd", 1);
+        assert_eq!(format_direct_premium(&config.display, &[&direct, &connecting]).unwrap(), "прямой от $300 / с пересадкой от $250");
+        
+        assert_eq!(format_subscription_price(&config.display, &direct, 24000.0), "$300 (~24 000 ₽)");
+        assert_eq!(format_subscription_price(&config.display, &test_flight("2030-03-01T10:00:00+03:00", 5000), 5000.0), "5 000 ₽");
+    }
+
+    #[test]
+    fn summary_ranks_dates_in_the_base_currency() {
+        let display = DisplaySettings::default();
+        let summary_date = |date: &str, price: i64, currency: &str, base_price: f64| FlightDateSummary {
+            date: date.to_string(),
+            message_id: "100/1".to_string(),
+            price,
+            currency: currency.to_string(),
+            base_price: Some(base_price),
+        };
+        // $200 is the larger fare in rubles even though 200 < 9000
+        let snapshot = StatisticsSnapshot {
+            flight_dates: vec![
+                summary_date("1 марта", 9000, "rub", 9000.0),
+                summary_date("2 марта", 200, "usd", 16000.0),
+            ],
+            ..StatisticsSnapshot::default()
+        };
+        let summary = snapshot.format_summary(&display, 1);
+        assert!(summary.contains("1 марта</a> — 9 000 ₽"), "{}", summary);
+        assert!(!summary.contains("2 марта"), "{}", summary);
+        
+        let summary = snapshot.format_summary(&display, 2);
+        assert!(summary.contains("2 марта</a> — $200"), "{}", summary);
+    }
+
+    fn airlabs_flight(dep_time: &str) -> AirLabsFlight {
+        serde_json::from_value(json!({
+            "flight_number": "1234",
+            "airline_iata": "SU",
+            "airline_icao": null,
+            "dep_iata": "SVO",
+            "dep_icao": null,
+            "arr_iata": "AER",
+            "arr_icao": null,
+            "dep_time": dep_time,
+            "arr_time": null,
+            "duration": null,
+            "status": null,
+            "aircraft_icao": null,
+            "reg_number": null,
+            "seats_economy": null,
+            "seats_business": null,
+            "seats_first": null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn departure_at_with_and_without_offset() {
+        // Times are shown in the default display zone, UTC+5; naive times are Moscow time
+        let display = DisplaySettings::default();
+        assert_eq!(format_datetime_ru(&display, "2030-03-01T10:00:00+03:00"), "1 марта 2030 в 12:00");
+        assert_eq!(format_datetime_ru(&display, "2030-03-01T10:00:00"), "1 марта 2030 в 12:00");
+        assert_eq!(format_datetime_ru(&display, "2030-03-01 10:00"), "1 марта 2030 в 12:00");
+        assert_eq!(format_datetime_ru(&display, "скоро"), "скоро");
+        assert!(parse_departure_at(&display, "2030-13-01T10:00:00").is_none());
+    }
+
+    #[test]
+    fn airlabs_flight_matches_a_naive_departure() {
+        let flights = || vec![airlabs_flight("2030-03-01 06:00"), airlabs_flight("2030-03-01 10:10")];
+        
+        for departure_at in ["2030-03-01T10:00:00", "2030-03-01T10:00:00+03:00"] {
+            let matched = closest_airlabs_flight(flights(), departure_at).unwrap();
+            assert_eq!(matched.dep_time.as_deref(), Some("2030-03-01 10:10"));
+        }
+        assert!(closest_airlabs_flight(flights(), "2030-03-01T14:00:00").is_none());
+        assert!(closest_airlabs_flight(flights(), "скоро").is_none());
+    }
+
+    #[test]
+    fn window_summary_lists_the_cheapest_fares() {
+        let display = DisplaySettings::default();
+        let fares = vec![
+            test_flight("2030-03-02T10:00:00+03:00", 7000),
+            test_flight("2030-03-01T10:00:00+03:00", 5000),
+            test_flight("2030-03-03T10:00:00+03:00", 9000),
+        ];
+        
+        let summary = format_window_summary(&display, &fares, "1–3 марта", 2, &HashMap::new()).unwrap();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines[0], "🏆 <b>Самые дешёвые билеты на 1–3 марта</b>");
+        assert_eq!(lines[1], "");
+        assert!(lines[2].starts_with("1. Москва → Сочи: ") && lines[2].contains("5 000"), "{}", summary);
+        assert!(lines[3].starts_with("2. Москва → Сочи: ") && lines[3].contains("7 000"), "{}", summary);
+        assert!(summary.contains("... и еще 1"), "{}", summary);
+        assert!(format_window_summary(&display, &[], "1–3 марта", 2, &HashMap::new()).is_none());
+    }
+
+    // Source with only RUST_LOG set; an empty value means "not set" and never falls back to the host's
+    fn log_source(rust_log: &str) -> ConfigSource {
+        ConfigSource { file_values: HashMap::from([("RUST_LOG".to_string(), rust_log.to_string())]) }
+    }
+
+    // Function to evaluate `check` with a subscriber using `filter`
+    fn with_log_filter(filter: EnvFilter, check: impl FnOnce() -> bool) -> bool {
+        tracing::subscriber::with_default(tracing_subscriber::fmt().with_env_filter(filter).finish(), check)
+    }
+
+    #[test]
+    fn rust_log_takes_precedence_over_verbosity() {
+        let filter = || log_filter(&log_source("warn,flights_schedule=debug"), Verbosity::Trace).unwrap();
+        assert!(with_log_filter(filter(), || tracing::enabled!(target: "flights_schedule::api", Level::DEBUG)));
+        assert!(!with_log_filter(filter(), || tracing::enabled!(target: "flights_schedule::api", Level::TRACE)));
+        assert!(!with_log_filter(filter(), || tracing::enabled!(target: "hyper::proto", Level::INFO)));
+        
+        // Spans are recorded like events
+        assert!(with_log_filter(filter(), || !tracing::info_span!("cycle").is_disabled()));
+        
+        // A malformed filter stops startup instead of being half applied
+        assert!(matches!(log_filter(&log_source("flights_schedule=loud"), Verbosity::Normal), Err(BotError::Config(_))));
+    }
+
+    #[test]
+    fn place_label_override_wins_over_city_map_and_raw_code() {
+        let config = test_config(&[("PLACE_LABELS", "vog = Царицын, LON=Лондон (все), =Пусто, XYZ=")]);
+        assert_eq!(get_city_name(&config.display, "VOG"), "Царицын");
+        assert_eq!(get_city_name(&config.display, " lon "), "Лондон (все)");
+        assert_eq!(get_city_name(&config.display, "KZN"), "Казань");
+        assert_eq!(get_city_name(&config.display, "XYZ"), "XYZ");
+        
+        // Labels belong to the config that carries them
+        assert_eq!(get_city_name(&test_config(&[]).display, "VOG"), "Волгоград");
+    }
+
+    #[test]
+    fn changed_schema_is_reported_and_parsed_best_effort() {
+        // flight_number became a number and the origin airport field is gone
+        let response = json!({
+            "success": true,
+            "currency": "rub",
+            "data": [
+                {"origin": "MOW", "destination": "AER", "price": 5000, "airline": "SU", "flight_number": 1234,
+                 "departure_at": "2030-03-01T10:00:00+03:00", "transfers": 0, "link": "/search/1"},
+                {"origin": "MOW", "destination": "AER", "airline": "S7", "flight_number": "1020",
+                 "departure_at": "2030-03-01T12:00:00+03:00", "transfers": 0, "link": "/search/2"}
+            ]
+        });
+        
+        let flight_data = parse_flight_data(&response.to_string()).unwrap();
+        let warning = flight_data.parse_warning.expect("schema change not reported");
+        assert!(warning.contains("Top-level keys: [currency, data, success]"), "{}", warning);
+        
+        // The fare without a price is dropped; the other keeps what could be read
+        let flights = flight_data.data.unwrap();
+        assert_eq!(flights.len(), 1);
+        assert_eq!((flights[0].price, flights[0].airline.as_str()), (5000, "SU"));
+        assert_eq!(flights[0].currency.as_deref(), Some("rub"));
+    }
+
+    #[test]
+    fn expected_schema_has_no_parse_warning() {
+        let response = json!({"success": true, "currency": "rub", "data": [test_flight("2030-03-01T10:00:00+03:00", 5000)]});
+        let flight_data = parse_flight_data(&response.to_string()).unwrap();
+        assert!(flight_data.parse_warning.is_none());
+        assert_eq!(flight_data.data.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn airline_filter_allow_deny_and_precedence() {
+        let allow_only = AirlineFilter::new("su, s7", "");
+        assert!(allow_only.allows("SU") && allow_only.allows("s7"));
+        assert!(!allow_only.allows("DP"));
+        
+        let deny_only = AirlineFilter::new("", "DP");
+        assert!(deny_only.allows("SU"));
+        assert!(!deny_only.allows("dp"));
+        
+        // The allow-list wins when both are set
+        let both = AirlineFilter::new("SU", "SU,DP");
+        assert!(both.allows("SU"));
+        assert!(!both.allows("S7"));
+        
+        assert!(AirlineFilter::new("", "").allows("DP"));
+        assert_eq!(AirlineFilter::new("", "").describe(), None);
+        assert!(deny_only.describe().unwrap().starts_with("кроме "));
+        assert!(both.describe().unwrap().starts_with("только "));
+    }
+
+    // Path in the temp dir unique to this test process, removed first if left over
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("flights-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn csv_export_writes_header_once_and_a_row_per_flight() {
+        let path = temp_path("export.csv");
+        let found_at = Utc::now();
+        let mut second = test_flight("2030-03-02T08:30:00+03:00", 6100);
+        second.return_at = Some("2030-03-09T18:00:00+03:00".to_string());
+        
+        let exporter = CsvExporter::open(&path).unwrap();
+        exporter.write_flight(&test_flight("2030-03-01T10:00:00+03:00", 5000), "rub", found_at).unwrap();
+        exporter.write_flight(&second, "rub", found_at).unwrap();
+        exporter.flush().unwrap();
+        drop(exporter);
+        // Reopening an existing file appends without another header
+        CsvExporter::open(&path).unwrap().flush().unwrap();
+        
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let header: Vec<String> = reader.headers().unwrap().iter().map(str::to_string).collect();
+        assert_eq!(header[..3], ["found_at", "origin", "destination"]);
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.len() == header.len()));
+        let column = |row: &csv::StringRecord, name: &str| row[header.iter().position(|h| h == name).unwrap()].to_string();
+        assert_eq!(column(&rows[0], "price"), "5000");
+        assert_eq!(column(&rows[0], "return_at"), "");
+        assert_eq!(column(&rows[1], "return_at"), "2030-03-09T18:00:00+03:00");
+        assert_eq!(column(&rows[1], "duration"), "150");
+    }
+
+    // A request received by the mock API server
+    #[derive(Debug, Clone)]
+    struct MockRequest {
+        path: String,
+        query: HashMap<String, String>,
+        headers: HeaderMap,
+        body: String,
+        received_at: std::time::Instant,
+    }
+
+    impl MockRequest {
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_str(&self.body).unwrap_or_default()
+        }
+    }
+
+    // A scripted reply of the mock API server
+    #[derive(Clone)]
+    struct MockReply {
+        status: u16,
+        body: String,
+        headers: Vec<(&'static str, String)>,
+    }
+
+    impl MockReply {
+        fn json(status: u16, body: serde_json::Value) -> Self {
+            Self { status, body: body.to_string(), headers: Vec::new() }
+        }
+    }
+
+    // Local stand-in for the Travelpayouts, AirLabs and Telegram APIs. Replies are scripted and
+    // requests recorded per caller: the `token` or `api_key` query parameter, or the bot token
+    // in the path, so tests running in parallel each use their own
+    #[derive(Default)]
+    struct MockApi {
+        // Base URL the server listens on, used as every API base in tests
+        base: String,
+        replies: Mutex<HashMap<String, std::collections::VecDeque<MockReply>>>,
+        requests: Mutex<Vec<(String, MockRequest)>>,
+    }
+
+    impl MockApi {
+        // Queue replies for `caller`; the last one keeps being served once the others are used
+        fn script(&self, caller: &str, replies: Vec<MockReply>) {
+            self.replies.lock().unwrap().insert(caller.to_string(), replies.into());
+        }
+
+        fn requests(&self, caller: &str) -> Vec<MockRequest> {
+            self.requests.lock().unwrap().iter()
+                .filter(|(key, _)| key == caller)
+                .map(|(_, request)| request.clone())
+                .collect()
+        }
+
+        fn reply(&self, caller: &str) -> MockReply {
+            let mut replies = self.replies.lock().unwrap();
+            match replies.get_mut(caller) {
+                Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+                Some(queue) if !queue.is_empty() => queue[0].clone(),
+                _ => MockReply::json(200, json!({"ok": true, "result": {"message_id": 1}})),
+            }
+        }
+    }
+
+    async fn mock_api_handler(
+        State(api): State<Arc<MockApi>>,
+        uri: axum::http::Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> axum::response::Response {
+        let query: HashMap<String, String> = uri.query()
+            .map(|q| url::form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+        let path = uri.path().to_string();
+        let caller = query.get("token")
+            .or_else(|| query.get("api_key"))
+            .cloned()
+            .or_else(|| path.strip_prefix("/bot").and_then(|rest| rest.split('/').next()).map(str::to_string))
+            .unwrap_or_default();
+        
+        let request = MockRequest {
+            path,
+            query,
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            received_at: std::time::Instant::now(),
+        };
+        api.requests.lock().unwrap().push((caller.clone(), request));
+        
+        let reply = api.reply(&caller);
+        let mut response = axum::response::Response::builder().status(reply.status);
+        for (name, value) in reply.headers {
+            response = response.header(name, value);
+        }
+        response.body(axum::body::Body::from(reply.body)).unwrap()
+    }
+
+    // Start the mock API server once, on its own runtime so it outlives each test's runtime,
+    // and point every API base at it
+    fn mock_api() -> &'static MockApi {
+        static MOCK_API: OnceLock<Arc<MockApi>> = OnceLock::new();
+        MOCK_API.get_or_init(|| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let api = Arc::new(MockApi { base, ..MockApi::default() });
+            
+            let app = Router::new().fallback(mock_api_handler).with_state(api.clone());
+            std::thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(async move {
+                    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                    axum::serve(listener, app).await.unwrap();
+                });
+            });
+            api
+        })
+    }
+
+    // HTTP client with the default NETWORK_MAX_RETRIES
+    fn test_http(client: Client) -> HttpClient {
+        HttpClient { client, network_retries: 3 }
+    }
+
+    fn test_telegram() -> TelegramNotifier {
+        TelegramNotifier {
+            http: test_http(Client::new()),
+            api_base: mock_api().base.clone(),
+            topic_pipeline: 1,
+            throttle: RequestThrottle::default(),
+        }
+    }
+
+    fn test_travelpayouts(client: Client) -> TravelpayoutsSearcher {
+        TravelpayoutsSearcher {
+            http: test_http(client),
+            api_base: mock_api().base.clone(),
+            throttle: RequestThrottle::default(),
+        }
+    }
+
+    fn travelpayouts_page(flights: &[FlightResult]) -> MockReply {
+        MockReply::json(200, json!({"success": true, "currency": "rub", "data": flights}))
+    }
+
+    #[tokio::test]
+    async fn direct_and_connecting_searches_are_merged() {
+        let api = mock_api();
+        let config = test_config(&[("TRAVELPAYOUTS_API_KEY", "premium-key"), ("SHOW_DIRECT_PREMIUM", "true")]);
+        let direct = test_flight_in("2030-03-01T10:00:00+03:00", 5000, "rub", 0);
+        let connecting = test_flight_in("2030-03-01T12:00:00+03:00", 3500, "rub", 1);
+        api.script("premium-key", vec![travelpayouts_page(&[direct]), travelpayouts_page(&[connecting])]);
+        
+        let searcher = test_travelpayouts(Client::new());
+        let flight_data = searcher.search(&config, &config.routes()[0], "2030-03-01").await.unwrap();
+        
+        let requests = api.requests("premium-key");
+        assert!(requests.iter().all(|r| r.path == "/aviasales/v3/prices_for_dates"));
+        let direct_params: Vec<&str> = requests.iter().map(|r| r.query["direct"].as_str()).collect();
+        assert_eq!(direct_params, ["true", "false"]);
+        
+        let flights = flight_data.data.unwrap();
+        assert_eq!(flights.len(), 2);
+        let refs: Vec<&FlightResult> = flights.iter().collect();
+        assert_eq!(
+            format_direct_premium(&config.display, &refs).unwrap(),
+            format!("прямой от {} / с пересадкой от {}", flights[0].display_price(&config.display), flights[1].display_price(&config.display))
+        );
+    }
+
+    fn flight_messages(notifier: &MockNotifier) -> usize {
+        notifier.messages().iter().filter(|m| m.contains("Рейс SU 1234")).count()
+    }
+
+    #[tokio::test]
+    async fn cooldown_suppresses_renotifying_a_date_until_it_expires() {
+        let config = test_config(&[("END_DATE", "2030-03-01")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)])]));
+        let deps = test_deps(searcher);
+        let mut state = test_state();
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert_eq!(flight_messages(&deps.notifier), 1);
+        
+        // A new fare at the same price within the cooldown stays quiet
+        deps.searcher.set_fares("2030-03-01", vec![test_flight("2030-03-01T14:00:00+03:00", 5000)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert_eq!(flight_messages(&deps.notifier), 1);
+        
+        // Once the cooldown has passed, new fares are notified again
+        let key = route_date_key("MOW", "AER", "2030-03-01");
+        state.bot_state.notifications.get_mut(&key).unwrap().last_notified_at -= config.notify_cooldown + chrono::Duration::hours(1);
+        deps.searcher.set_fares("2030-03-01", vec![
+            test_flight("2030-03-01T14:00:00+03:00", 5000),
+            test_flight("2030-03-01T18:00:00+03:00", 5000),
+        ]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        assert!(flight_messages(&deps.notifier) > 1, "{:?}", deps.notifier.messages());
+    }
+
+    #[test]
+    fn cooldown_is_lifted_by_a_price_drop() {
+        let mut bot_state = BotState::default();
+        bot_state.record_notification("MOW-AER:2030-03-01".to_string(), 5000);
+        let cooldown = chrono::Duration::hours(24);
+        
+        assert!(bot_state.is_in_cooldown("MOW-AER:2030-03-01", 5000, cooldown));
+        assert!(bot_state.is_in_cooldown("MOW-AER:2030-03-01", 5200, cooldown));
+        assert!(!bot_state.is_in_cooldown("MOW-AER:2030-03-01", 4800, cooldown));
+        assert!(!bot_state.is_in_cooldown("MOW-AER:2030-03-02", 5000, cooldown));
+    }
+
+    #[tokio::test]
+    async fn status_mode_edits_or_posts_the_status_message() {
+        for (mode, expected) in [("edit", vec!["editMessageText"]), ("new", vec!["sendMessage", "deleteMessage"])] {
+            let config = test_config(&[("STATUS_MODE", mode), ("STATUS_DELETE_PREVIOUS", "true"), ("END_DATE", "2030-03-01")]);
+            let deps = test_deps(MockSearcher::new(HashMap::new()));
+            let mut state = test_state();
+            state.status_message.posted("7".to_string());
+            
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+            let _ = std::fs::remove_file(&deps.state_store.path);
+            
+            let calls = deps.notifier.calls.lock().unwrap().clone();
+            assert_eq!(deps.notifier.methods()[..expected.len()], expected, "{}: {:?}", mode, calls);
+            assert!(calls[0].2.contains("Начат цикл поиска рейсов"), "{}: {:?}", mode, calls);
+            if mode == "new" {
+                assert_eq!(calls[1].2, "7");
+                assert_ne!(state.status_message.id.as_deref(), Some("7"));
+            } else {
+                assert_eq!(state.status_message.id.as_deref(), Some("7"));
+            }
+        }
+    }
+
+    fn airlabs_reply(flights: &[(&str, Option<i64>)]) -> MockReply {
+        let flights: Vec<serde_json::Value> = flights
+            .iter()
+            .map(|(dep_time, seats)| json!({"flight_number": "1234", "airline_iata": "SU", "dep_time": dep_time, "seats_economy": seats}))
+            .collect();
+        MockReply::json(200, json!({"response": flights}))
+    }
+
+    #[test]
+    fn min_economy_seats_threshold() {
+        let mut flight = airlabs_flight("2030-03-01 10:00");
+        assert_eq!(meets_min_economy_seats(Some(&flight), 2), None);
+        assert_eq!(meets_min_economy_seats(None, 2), None);
+        for (seats, meets) in [(0, false), (1, false), (2, true), (3, true)] {
+            flight.seats_economy = Some(seats);
+            assert_eq!(meets_min_economy_seats(Some(&flight), 2), Some(meets), "{} seats", seats);
+        }
+    }
+
+    #[tokio::test]
+    async fn flights_below_min_economy_seats_are_not_notified() {
+        let api = mock_api();
+        api.script("seats-key", vec![airlabs_reply(&[
+            ("2030-03-01 10:00", Some(0)),
+            ("2030-03-01 14:00", Some(1)),
+            ("2030-03-01 18:00", Some(3)),
+        ])]);
+        let config = test_config(&[("AIRLABS_API_KEY", "seats-key"), ("MIN_ECONOMY_SEATS", "2"), ("END_DATE", "2030-03-01")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![
+            test_flight("2030-03-01T10:00:00+03:00", 5000),
+            test_flight("2030-03-01T14:00:00+03:00", 5100),
+            test_flight("2030-03-01T18:00:00+03:00", 5200),
+        ])]));
+        let deps = test_deps(searcher);
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(api.requests("seats-key").len(), 3);
+        // Only the 18:00 Moscow departure, shown as 20:00 in UTC+5, has enough seats
+        let messages = deps.notifier.messages();
+        let flights: Vec<&String> = messages.iter().filter(|m| m.contains("Рейс SU 1234")).collect();
+        assert_eq!(flights.len(), 1, "{:?}", messages);
+        assert!(flights[0].contains("20:00"), "{:?}", flights);
+        assert!(flights[0].contains("🔥 <b>Мест в эконом-классе: 3 (не меньше 2)</b>"), "{:?}", flights);
+        // The header counts only the flight that passed the threshold
+        assert!(messages.iter().any(|m| m.contains("✅ Найдено <b>1 рейс</b>")), "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("Мест в салоне, эконом (оценка)</b>: 3")), "{:?}", messages);
+        assert!(!messages.iter().any(|m| m.contains("эконом (оценка)</b>: 0") || m.contains("эконом (оценка)</b>: 1")), "{:?}", messages);
+    }
+
+    #[tokio::test]
+    async fn date_with_every_flight_below_min_economy_seats_sends_nothing() {
+        let api = mock_api();
+        api.script("no-seats-key", vec![airlabs_reply(&[
+            ("2030-03-01 10:00", Some(0)),
+            ("2030-03-01 14:00", Some(1)),
+        ])]);
+        let config = test_config(&[("AIRLABS_API_KEY", "no-seats-key"), ("MIN_ECONOMY_SEATS", "2"), ("END_DATE", "2030-03-01")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![
+            test_flight("2030-03-01T10:00:00+03:00", 5000),
+            test_flight("2030-03-01T14:00:00+03:00", 5100),
+        ])]));
+        let deps = test_deps(searcher);
+        let stats = SearchStatistics::new();
+        
+        run_cycle(&deps, &config, &mut test_state(), &stats).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let messages = deps.notifier.messages();
+        assert!(!messages.iter().any(|m| m.contains("Найдено") || m.contains("Рейс SU 1234")), "{:?}", messages);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.dates_with_flights, 0);
+        assert_eq!(snapshot.dates_without_flights, 1);
+    }
+
+    fn rate_limited_reply() -> MockReply {
+        MockReply::json(429, json!({"error": {"message": "Too many requests"}, "parameters": {"retry_after": 0}}))
+    }
+
+    #[tokio::test]
+    async fn airlabs_retries_rate_limits_and_tells_no_flight_from_failure() {
+        let api = mock_api();
+        let http = test_http(Client::new());
+        
+        api.script("airlabs-retry", vec![rate_limited_reply(), airlabs_reply(&[("2030-03-01 10:00", Some(150))])]);
+        let flight = get_airlabs_flight_info(&http, &api.base, "SU", "1234", Some("2030-03-01T10:00:00+03:00"), "airlabs-retry", 2).await.unwrap();
+        assert_eq!(flight.unwrap().seats_economy, Some(150));
+        let requests = api.requests("airlabs-retry");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].query["flight_iata"], "SU1234");
+        
+        // A valid empty response means AirLabs has no such flight
+        api.script("airlabs-empty", vec![airlabs_reply(&[])]);
+        assert!(get_airlabs_flight_info(&http, &api.base, "SU", "1234", None, "airlabs-empty", 2).await.unwrap().is_none());
+        
+        // Running out of retries is an error, not a missing flight
+        api.script("airlabs-exhausted", vec![rate_limited_reply()]);
+        assert!(get_airlabs_flight_info(&http, &api.base, "SU", "1234", None, "airlabs-exhausted", 1).await.is_err());
+        assert_eq!(api.requests("airlabs-exhausted").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn search_combines_pages_until_a_short_page() {
+        let api = mock_api();
+        let fares: Vec<FlightResult> = ["06", "10", "14", "18"]
+            .iter()
+            .map(|hour| test_flight(&format!("2030-03-01T{}:00:00+03:00", hour), 5000))
+            .collect();
+        // The short second page repeats the last fare of the first one
+        api.script("paging-key", vec![travelpayouts_page(&fares[..3]), travelpayouts_page(&fares[2..])]);
+        
+        let paging = SearchPaging { limit: 3, max_results: 10 };
+        let flight_data = search_flights(&test_travelpayouts(Client::new()), "MOW", "AER", "2030-03-01", &ApiKeyPool::new("paging-key"), true, paging, "rub")
+            .await
+            .unwrap();
+        
+        let pages: Vec<(String, String)> = api.requests("paging-key")
+            .iter()
+            .map(|r| (r.query["page"].clone(), r.query["limit"].clone()))
+            .collect();
+        assert_eq!(pages, [("1".to_string(), "3".to_string()), ("2".to_string(), "3".to_string())]);
+        
+        let departures: Vec<String> = flight_data.data.unwrap().iter().map(|f| f.departure_at.clone()).collect();
+        let expected: Vec<String> = fares.iter().map(|f| f.departure_at.clone()).collect();
+        assert_eq!(departures, expected);
+    }
+
+    #[test]
+    fn iata_codes_are_normalized_wherever_they_enter() {
+        for code in [" aer ", "aer", "AER"] {
+            assert_eq!(normalize_code(code), "AER");
+            assert_eq!(get_city_name(&DisplaySettings::default(), code), "Сочи", "{:?}", code);
+        }
+        
+        let config = test_config(&[("ORIGIN", " mow "), ("DESTINATION", "aer, Led ")]);
+        let routes: Vec<String> = config.routes().iter().map(|route| format!("{}-{}", route.origin, route.destination)).collect();
+        assert_eq!(routes, ["MOW-AER", "MOW-LED"]);
+    }
+
+    #[test]
+    fn recap_is_due_once_the_local_recap_hour_passes() {
+        // 09:00 in the default display zone, UTC+5, is 04:00 UTC
+        let at = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc);
+        let zone = DisplaySettings::default().timezone;
+        assert!(is_recap_due(at("2030-03-01T03:30:00Z"), at("2030-03-01T04:30:00Z"), 9, zone));
+        assert!(!is_recap_due(at("2030-03-01T04:30:00Z"), at("2030-03-01T05:00:00Z"), 9, zone));
+        assert!(!is_recap_due(at("2030-03-01T02:00:00Z"), at("2030-03-01T03:59:00Z"), 9, zone));
+        assert!(is_recap_due(at("2030-02-28T05:00:00Z"), at("2030-03-01T04:00:00Z"), 9, zone));
+        
+        // In Moscow, UTC+3, the same hour is 06:00 UTC
+        assert!(!is_recap_due(at("2030-03-01T03:30:00Z"), at("2030-03-01T04:30:00Z"), 9, Tz::Europe__Moscow));
+        assert!(is_recap_due(at("2030-03-01T05:30:00Z"), at("2030-03-01T06:00:00Z"), 9, Tz::Europe__Moscow));
+    }
+
+    #[tokio::test]
+    async fn crossing_the_recap_hour_sends_one_recap() {
+        let config = test_config(&[("TELEGRAM_RECAP_TOPIC_ID", "5"), ("END_DATE", "2030-03-01")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)])]));
+        let deps = test_deps(searcher);
+        let mut state = test_state();
+        state.bot_state.last_recap_check_at = Some(Utc::now() - chrono::Duration::hours(25));
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let messages = deps.notifier.messages();
+        let recaps: Vec<&String> = messages.iter().filter(|m| m.contains("Ежедневная сводка")).collect();
+        assert_eq!(recaps.len(), 1, "{:?}", messages);
+        assert!(recaps[0].contains(&format!("01.03.2030  MOW-AER  {:>10}", format_price_in(&config.display, 5000, "rub"))), "{}", recaps[0]);
+    }
+
+    #[test]
+    fn departure_days_restrict_the_searched_dates() {
+        let dates = |days: &str| -> Vec<NaiveDate> {
+            test_config(&[("DEPARTURE_DAYS", days), ("END_DATE", "2030-03-31")]).search_dates()
+        };
+        
+        let weekends = dates("weekends");
+        assert_eq!(weekends.len(), 10);
+        assert!(weekends.iter().all(|date| matches!(date.weekday(), Weekday::Sat | Weekday::Sun)));
+        assert_eq!(weekends[0], NaiveDate::from_ymd_opt(2030, 3, 2).unwrap());
+        
+        assert_eq!(dates("weekdays").len(), 21);
+        assert!(dates("mon,wed,fri").iter().all(|date| matches!(date.weekday(), Weekday::Mon | Weekday::Wed | Weekday::Fri)));
+    }
+
+    #[tokio::test]
+    async fn capped_cycles_rotate_through_the_whole_window() {
+        let config = test_config(&[("END_DATE", "2030-03-25"), ("MAX_DATES_PER_CYCLE", "10"), ("SEARCH_CONCURRENCY", "10")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        
+        let mut covered = HashSet::new();
+        for cycle in 0..3 {
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+            let dates = std::mem::take(&mut *deps.searcher.dates.lock().unwrap());
+            assert_eq!(dates.len(), 10, "cycle {}", cycle);
+            covered.extend(dates);
+        }
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(covered.len(), 25);
+        // The third cycle wrapped around to the start of the window
+        assert_eq!(state.bot_state.date_offset, 5);
+    }
+
+    #[tokio::test]
+    async fn date_empty_for_two_cycles_is_reported_sold_out_once() {
+        let config = test_config(&[("NOTIFY_SOLD_OUT", "true"), ("END_DATE", "2030-03-01")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)])]));
+        let deps = test_deps(searcher);
+        let mut state = test_state();
+        let sold_out_notices = |deps: &Deps<MockSearcher, MockNotifier>| {
+            deps.notifier.messages().iter().filter(|m| m.contains("Рейсы закончились")).count()
+        };
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        deps.searcher.set_fares("2030-03-01", Vec::new());
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert_eq!(sold_out_notices(&deps), 0);
+        
+        for _ in 0..2 {
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        }
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        assert_eq!(sold_out_notices(&deps), 1, "{:?}", deps.notifier.messages());
+    }
+
+    #[test]
+    fn secondary_currency_conversion_with_fixed_rates() {
+        let config = test_config(&[("SECONDARY_CURRENCY", "usd")]);
+        let rates = HashMap::from([("USD".to_string(), 0.011), ("EUR".to_string(), 0.01)]);
+        let conversion = secondary_conversion(&config, &rates).unwrap();
+        assert_eq!(conversion.currency, "USD");
+        
+        assert_eq!(format_price_converted(&config.display, 12340, Some(&conversion)), "12 340 ₽ (~$136)");
+        // Small amounts keep their cents
+        assert_eq!(format_price_converted(&config.display, 500, Some(&conversion)), "500 ₽ (~$5,50)");
+        assert_eq!(format_price_converted(&config.display, 12340, None), "12 340 ₽");
+        
+        // Fares already in another currency are not converted again
+        assert_eq!(test_flight_in("2030-03-01T10:00:00+03:00", 300, "eur", 0).display_price_converted(&config.display, Some(&conversion)), "€300");
+        
+        // Without a rate for the secondary currency only the primary price is shown
+        assert!(secondary_conversion(&config, &HashMap::new()).is_none());
+    }
+
+    fn write_config_file(path: &str, destination: &str, end_date: &str, modified: std::time::SystemTime) {
+        std::fs::write(path, format!(
+            "TELEGRAM_BOT_TOKEN=test-token\nTELEGRAM_CHAT_ID=-100\nTRAVELPAYOUTS_API_KEY=test-key\n\
+            ORIGIN=MOW\nDESTINATION={}\nSTART_DATE=2030-03-01\nEND_DATE={}\n",
+            destination, end_date
+        )).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn edited_config_file_applies_new_routes_on_the_next_cycle() {
+        let path = temp_path("reload.env");
+        let start = std::time::SystemTime::now();
+        write_config_file(&path, "AER", "2030-03-01", start);
+        let mut config = Arc::new(load_config(&path).unwrap());
+        let mut config_modified = config_file_modified(&path);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        write_config_file(&path, "LED", "2030-03-01", start + Duration::from_secs(60));
+        reload_config_if_changed(&deps, &path, &mut config, &mut config_modified).await;
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        
+        // An invalid edit is rejected and the last good routes stay
+        write_config_file(&path, "KZN", "not a date", start + Duration::from_secs(120));
+        reload_config_if_changed(&deps, &path, &mut config, &mut config_modified).await;
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let _ = std::fs::remove_file(&path);
+        
+        assert_eq!(*deps.searcher.searches.lock().unwrap(), ["MOW-AER rub", "MOW-LED rub", "MOW-LED rub"]);
+        let messages = deps.notifier.messages();
+        assert_eq!(messages.iter().filter(|m| m.contains("Конфигурация перезагружена")).count(), 1, "{:?}", messages);
+        assert_eq!(messages.iter().filter(|m| m.contains("Новая конфигурация отклонена")).count(), 1, "{:?}", messages);
+    }
+
+    #[test]
+    fn fastest_flight_is_found_independently_of_the_cheapest() {
+        let display = DisplaySettings::default();
+        let mut cheap_slow = test_flight("2030-03-01T06:00:00+03:00", 4000);
+        cheap_slow.duration = Some(400);
+        let mut pricey_fast = test_flight("2030-03-01T10:00:00+03:00", 9000);
+        pricey_fast.airline = "S7".to_string();
+        pricey_fast.duration = Some(130);
+        let mut middle = test_flight("2030-03-01T14:00:00+03:00", 6000);
+        middle.duration = Some(200);
+        
+        let line = format_fastest(&display, &[&cheap_slow, &pricey_fast, &middle], DurationPrecision::Exact).unwrap();
+        assert_eq!(line, format!(
+            "самый быстрый: 2 ч 10 мин ({}) за {} / самый дешёвый: {} ({})",
+            get_airline_name("S7"), pricey_fast.display_price(&display), cheap_slow.display_price(&display), get_airline_name("SU")
+        ));
+        
+        // The cheapest flight can also be the fastest
+        let line = format_fastest(&display, &[&cheap_slow, &middle], DurationPrecision::Exact).unwrap();
+        assert!(line.starts_with("самый быстрый: 3 ч 20 мин"), "{}", line);
+        assert!(format_fastest(&display, &[&middle], DurationPrecision::Exact).unwrap().ends_with("он же самый дешёвый"));
+        
+        // Nothing to say without durations
+        cheap_slow.duration = None;
+        assert!(format_fastest(&display, &[&cheap_slow], DurationPrecision::Exact).is_none());
+    }
+
+    #[tokio::test]
+    async fn no_flights_digest_only_when_enabled() {
+        for enabled in [true, false] {
+            let config = test_config(&[("NOTIFY_NO_FLIGHTS", if enabled { "true" } else { "false" })]);
+            let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), Vec::new())]));
+            let deps = test_deps(searcher);
+            
+            run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+            let _ = std::fs::remove_file(&deps.state_store.path);
+            
+            let messages = deps.notifier.messages();
+            let digests: Vec<&String> = messages.iter().filter(|m| m.contains("Рейсов не найдено")).collect();
+            if enabled {
+                assert_eq!(digests.len(), 1, "{:?}", messages);
+                // 2030-03-02 has no fares in the mock and fails
+                assert!(digests[0].contains("✓ Проверено дат: 2") && digests[0].contains("⚠️ Ошибок: 1"), "{}", digests[0]);
+            } else {
+                assert!(digests.is_empty(), "{:?}", messages);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn status_message_is_pinned_when_enabled() {
+        let config = test_config(&[("PIN_STATUS", "true"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        state.pinned_message_id = Some("3".to_string());
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let status_id = state.status_message.id.clone().unwrap();
+        let pin_calls: Vec<(&str, String)> = deps.notifier.calls.lock().unwrap()
+            .iter()
+            .filter(|(method, _, _)| method.contains("inChatMessage"))
+            .map(|(method, _, message_id)| (*method, message_id.clone()))
+            .collect();
+        // The previous pin is replaced once; the next cycle edits the pinned message in place
+        assert_eq!(pin_calls, [("unpinChatMessage", "3".to_string()), ("pinChatMessage", status_id.clone())]);
+        assert_eq!(state.pinned_message_id, Some(status_id));
+        
+        let config = test_config(&[("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        assert!(!deps.notifier.methods().contains(&"pinChatMessage"));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_token_is_retried_with_the_next_one() {
+        let api = mock_api();
+        let mut quota = MockReply::json(429, json!({"error": "quota exceeded"}));
+        quota.headers.push(("Retry-After", "0".to_string()));
+        api.script("rotation-key-1", vec![quota]);
+        api.script("rotation-key-2", vec![travelpayouts_page(&[test_flight("2030-03-01T10:00:00+03:00", 5000)])]);
+        let api_keys = ApiKeyPool::new("rotation-key-1, rotation-key-2");
+        
+        let paging = SearchPaging { limit: 30, max_results: 30 };
+        let flight_data = search_flights(&test_travelpayouts(Client::new()), "MOW", "AER", "2030-03-01", &api_keys, true, paging, "rub")
+            .await
+            .unwrap();
+        assert_eq!(flight_data.data.unwrap().len(), 1);
+        assert_eq!(api.requests("rotation-key-1").len(), 1);
+        assert_eq!(api.requests("rotation-key-2").len(), 1);
+        
+        // The exhausted token is skipped for the rest of the cycle
+        assert_eq!(api_keys.next_key().unwrap().1, "rotation-key-2");
+        assert_eq!(api_keys.next_key().unwrap().1, "rotation-key-2");
+    }
+
+    #[test]
+    fn places_resolve_from_city_names() {
+        assert_eq!(resolve_place("Москва").unwrap(), "MOW");
+        assert_eq!(resolve_place("  sochi ").unwrap(), "AER");
+        assert_eq!(resolve_place("Rostov on Don").unwrap(), "ROV");
+        assert_eq!(resolve_place("led").unwrap(), "LED");
+        
+        let error = resolve_place("Крас").unwrap_err().to_string();
+        assert!(error.contains("Ambiguous place") && error.contains("Краснодар (KRR)") && error.contains("Красноярск (KJA)"), "{}", error);
+        assert!(resolve_place("Атлантида").unwrap_err().to_string().contains("Unknown place"));
+    }
+
+    #[test]
+    fn durations_round_only_in_rounded_mode() {
+        let show = |precision: DurationPrecision, minutes: i64| format_duration(precision.apply(minutes));
+        
+        assert_eq!(show(DurationPrecision::Exact, 139), "2 ч 19 мин");
+        assert_eq!(show(DurationPrecision::Rounded, 139), "2 ч 15 мин");
+        // Short flights stay exact, very long ones round to whole hours
+        assert_eq!(show(DurationPrecision::Rounded, 47), "47 мин");
+        assert_eq!(show(DurationPrecision::Rounded, 700), "12 ч 0 мин");
+        assert_eq!(show(DurationPrecision::Exact, 700), "11 ч 40 мин");
+        assert_eq!(test_config(&[("DURATION_PRECISION", "rounded")]).duration_precision, DurationPrecision::Rounded);
+    }
+
+    #[test]
+    fn multi_point_history_renders_a_png_chart() {
+        let now = Utc::now();
+        let points: Vec<PricePoint> = [5200, 4900, 5100, 4700]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PricePoint { observed_at: now + chrono::Duration::hours(i as i64), price: *price })
+            .collect();
+        
+        let png = render_price_chart(&points).unwrap().unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let chart = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap();
+        assert_eq!((chart.width(), chart.height()), PRICE_CHART_SIZE);
+        
+        // A single observation falls back to a text notification
+        assert!(render_price_chart(&points[..1]).unwrap().is_none());
+        assert!(render_price_chart(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn fare_seats_display_and_threshold() {
+        assert_eq!(format_seats(Some(4), None), "💺 Мест по тарифу: 4\n");
+        assert_eq!(format_seats(Some(0), None), "");
+        assert_eq!(format_seats(None, None), "");
+        
+        // Unknown passes, a known zero does not
+        assert!(meets_min_fare_seats(None, 2));
+        assert!(!meets_min_fare_seats(Some(0), 2));
+        assert!(meets_min_fare_seats(Some(4), 2));
+    }
+
+    #[tokio::test]
+    async fn min_fare_seats_filters_notified_flights() {
+        let config = test_config(&[("MIN_FARE_SEATS", "2"), ("END_DATE", "2030-03-01")]);
+        let fares: Vec<FlightResult> = [("10", None), ("14", Some(0)), ("18", Some(4))]
+            .into_iter()
+            .map(|(hour, seats)| {
+                let mut flight = test_flight(&format!("2030-03-01T{}:00:00+03:00", hour), 5000);
+                flight.seats = seats;
+                flight
+            })
+            .collect();
+        let deps = test_deps(MockSearcher::new(HashMap::from([("2030-03-01".to_string(), fares)])));
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        // Departures shown in UTC+5: unknown seats at 12:00 and four seats at 20:00
+        let messages = deps.notifier.messages();
+        let flights: Vec<&String> = messages.iter().filter(|m| m.contains("Рейс SU 1234")).collect();
+        assert_eq!(flights.len(), 2, "{:?}", messages);
+        assert!(flights.iter().any(|m| m.contains("в 12:00") && !m.contains("Мест по тарифу")), "{:?}", flights);
+        assert!(flights.iter().any(|m| m.contains("в 20:00") && m.contains("💺 Мест по тарифу: 4")), "{:?}", flights);
+    }
+
+    #[tokio::test]
+    async fn configured_user_agent_and_headers_are_sent() {
+        let api = mock_api();
+        api.script("agent-key", vec![travelpayouts_page(&[])]);
+        let file_values = HashMap::from([
+            ("USER_AGENT".to_string(), "fare-watch/2.0".to_string()),
+            ("EXTRA_HEADERS".to_string(), "X-Api-Client: bot; X-Trace: on".to_string()),
+        ]);
+        let client = build_http_client(&ConfigSource { file_values }).unwrap();
+        
+        let paging = SearchPaging { limit: 30, max_results: 30 };
+        search_flights(&test_travelpayouts(client), "MOW", "AER", "2030-03-01", &ApiKeyPool::new("agent-key"), true, paging, "rub")
+            .await
+            .unwrap();
+        
+        let headers = &api.requests("agent-key")[0].headers;
+        assert_eq!(headers["user-agent"], "fare-watch/2.0");
+        assert_eq!(headers["x-api-client"], "bot");
+        assert_eq!(headers["x-trace"], "on");
+        
+        assert!(parse_extra_headers("X-Api-Client bot").is_err());
+        assert!(parse_extra_headers("Bad Name: value").is_err());
+    }
+
+    #[tokio::test]
+    async fn client_routes_requests_through_the_configured_proxy() {
+        let api = mock_api();
+        let proxy = api.base.clone();
+        let source = |values: &[(&str, &str)]| ConfigSource {
+            file_values: values.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        };
+        
+        let client = build_http_client(&source(&[
+            ("HTTP_PROXY", &proxy),
+            ("PROXY_USERNAME", "bot"),
+            ("PROXY_PASSWORD", "secret"),
+            ("NO_PROXY", "bypass.invalid"),
+        ])).unwrap();
+        let response = client.get("http://fares.invalid/prices?token=proxy-key").send().await.unwrap();
+        assert!(response.status().is_success());
+        
+        let requests = api.requests("proxy-key");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/prices");
+        assert_eq!(requests[0].headers["proxy-authorization"], "Basic Ym90OnNlY3JldA==");
+        
+        // Hosts in NO_PROXY are reached directly, and this one doesn't exist
+        assert!(client.get("http://bypass.invalid/prices?token=proxy-key").send().await.is_err());
+        assert_eq!(api.requests("proxy-key").len(), 1);
+        
+        assert!(build_http_client(&source(&[("HTTPS_PROXY", "not a url")])).is_err());
+        assert!(build_http_client(&source(&[("ALL_PROXY", "ftp://proxy.local:21")])).is_err());
+        assert!(build_http_client(&source(&[("ALL_PROXY", "socks5://127.0.0.1:1080")])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn watched_flight_status_change_notifies_once() {
+        let api = mock_api();
+        let status_reply = |status: Option<&str>| MockReply::json(200, json!({"response": [{"flight_number": "1234", "airline_iata": "SU", "status": status}]}));
+        api.script("watch-key", vec![
+            status_reply(Some("scheduled")),
+            status_reply(Some("scheduled")),
+            status_reply(None),
+            status_reply(Some("delayed")),
+            status_reply(Some("delayed")),
+        ]);
+        let config = test_config(&[("AIRLABS_API_KEY", "watch-key"), ("WATCH_FLIGHTS", "su 1234"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        
+        for _ in 0..5 {
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        }
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(api.requests("watch-key").len(), 5);
+        let messages = deps.notifier.messages();
+        let changes: Vec<&String> = messages.iter().filter(|m| m.contains("Статус рейса SU1234 изменился")).collect();
+        assert_eq!(changes.len(), 1, "{:?}", messages);
+        assert!(changes[0].contains(&format!("Было: {}", format_flight_status_ru("scheduled"))), "{}", changes[0]);
+        assert!(changes[0].contains(&format!("Стало: <b>{}</b>", format_flight_status_ru("delayed"))), "{}", changes[0]);
+    }
+
+    // Every tag opened in `chunk` is closed in it, in order, and no tag or entity is cut
+    fn assert_balanced_html(chunk: &str) {
+        let mut open: Vec<&str> = Vec::new();
+        let mut rest = chunk;
+        while let Some(start) = rest.find('<') {
+            let end = rest[start..].find('>').unwrap_or_else(|| panic!("cut tag in {:?}", chunk)) + start;
+            let tag = &rest[start..=end];
+            if tag.starts_with("</") {
+                assert_eq!(open.pop(), Some(html_tag_name(tag)), "unbalanced {:?}", chunk);
+            } else {
+                open.push(html_tag_name(tag));
+            }
+            rest = &rest[end + 1..];
+        }
+        assert!(open.is_empty(), "unclosed {:?} in {:?}", open, chunk);
+        for (index, _) in chunk.match_indices('&') {
+            assert!(chunk[index..].find(';').is_some_and(|end| end <= 6), "cut entity in {:?}", chunk);
+        }
+    }
+
+    #[test]
+    fn long_message_splits_into_html_safe_chunks() {
+        let mut message = String::from("📊 <b>Сводка</b>\n<pre>");
+        for i in 0..120 {
+            message.push_str(&format!("Дата {:03}: <a href=\"https://example.com/{}\">Москва &amp; Сочи</a>\n", i, i));
+        }
+        message.push_str("</pre>\n<i>конец</i>");
+        assert!(message.chars().count() > 5000);
+        
+        let chunks = split_message(&message, TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() >= 2);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+            assert!(chunk.starts_with("📊") || chunk.starts_with("<pre>"), "{:?}", &chunk[..20]);
+            assert_balanced_html(chunk);
+        }
+        
+        // Nothing is lost or reordered
+        let joined = chunks.concat();
+        let lines: Vec<usize> = (0..120).map(|i| joined.find(&format!("Дата {:03}:", i)).unwrap()).collect();
+        assert!(lines.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(joined.ends_with("<i>конец</i>"));
+        
+        // A single line longer than the limit is cut as well
+        let chunks = split_message(&"&amp;".repeat(1000), TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() >= 2 && chunks.iter().all(|chunk| chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT));
+        chunks.iter().for_each(|chunk| assert_balanced_html(chunk));
+    }
+
+    #[tokio::test]
+    async fn failing_date_does_not_abort_the_rest_of_the_cycle() {
+        let config = test_config(&[("END_DATE", "2030-03-05"), ("SEARCH_CONCURRENCY", "5")]);
+        let fares: HashMap<String, Vec<FlightResult>> = (1..=5)
+            .map(|day| {
+                let date = format!("2030-03-{:02}", day);
+                let flight = test_flight(&format!("{}T10:00:00+03:00", date), 5000);
+                (date, vec![flight])
+            })
+            .collect();
+        let deps = test_deps(MockSearcher::new(fares));
+        // Sending the flight of date 2 fails after its search succeeded
+        *deps.notifier.failing.lock().unwrap() = Some("2 марта 2030 в 12:00");
+        let stats = SearchStatistics::new();
+        
+        run_cycle(&deps, &config, &mut test_state(), &stats).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(stats.snapshot().errors_encountered, 1);
+        let messages = deps.notifier.messages();
+        for day in [1, 3, 4, 5] {
+            let departure = format!("{} марта 2030 в 12:00", day);
+            assert!(messages.iter().any(|m| m.contains("Рейс SU 1234") && m.contains(&departure)), "{}: {:?}", day, messages);
+        }
+        assert_eq!(messages.iter().filter(|m| m.contains("Ошибка при поиске рейсов")).count(), 1, "{:?}", messages);
+    }
+
+    #[test]
+    fn round_trip_shows_both_legs() {
+        let display = DisplaySettings::default();
+        let mut flight = test_flight("2030-03-01T10:00:00+03:00", 9000);
+        flight.return_at = Some("2030-03-08T19:30:00+03:00".to_string());
+        flight.duration_to = Some(150);
+        flight.duration_back = Some(165);
+        flight.return_transfers = Some(1);
+        flight.return_connections = vec!["KZN".to_string()];
+        
+        // Times are shown in the default display zone, UTC+5
+        assert_eq!(
+            format_trip(&display, &flight, DurationPrecision::Exact),
+            "➡️ Туда: 1 марта 2030 в 12:00 (2 ч 30 мин, прямой)\n\
+             ⬅️ Обратно: 8 марта 2030 в 21:30 (2 ч 45 мин, пересадки: Казань (KZN))\n"
+        );
+        
+        // Missing return details leave the return leg bare
+        flight.duration_back = None;
+        flight.return_transfers = None;
+        assert!(format_trip(&display, &flight, DurationPrecision::Exact).ends_with("⬅️ Обратно: 8 марта 2030 в 21:30\n"));
+        
+        flight.return_at = None;
+        assert_eq!(format_trip(&display, &flight, DurationPrecision::Exact), "🕒 Вылет: 1 марта 2030 в 12:00\n");
+    }
+
+    #[test]
+    fn dedup_window_suppresses_only_recent_messages() {
+        let window = chrono::Duration::hours(48);
+        let mut bot_state = BotState::default();
+        bot_state.sent_messages.insert("recent".to_string(), Utc::now() - chrono::Duration::hours(47));
+        bot_state.sent_messages.insert("old".to_string(), Utc::now() - chrono::Duration::hours(49));
+        
+        assert!(bot_state.was_sent_recently("recent", window));
+        assert!(!bot_state.was_sent_recently("old", window));
+        assert!(!bot_state.was_sent_recently("never", window));
+        
+        // Pruning uses the same window
+        bot_state.prune_sent_messages(&DisplaySettings::default(), window);
+        assert!(bot_state.sent_messages.contains_key("recent"));
+        assert!(!bot_state.sent_messages.contains_key("old"));
+        
+        assert_eq!(test_config(&[]).dedup_window, window);
+        assert_eq!(test_config(&[("DEDUP_WINDOW_HOURS", "12")]).dedup_window, chrono::Duration::hours(12));
+    }
+
+    fn telegram_ok(message_id: i64) -> MockReply {
+        MockReply::json(200, json!({"ok": true, "result": {"message_id": message_id}}))
+    }
+
+    #[tokio::test]
+    async fn self_test_reports_a_failing_topic_and_checks_the_rest() {
+        let api = mock_api();
+        api.script("selftest-token", vec![
+            telegram_ok(11),
+            MockReply::json(400, json!({"ok": false, "description": "Bad Request: message thread not found"})),
+            telegram_ok(12),
+        ]);
+        let config = test_config(&[
+            ("TELEGRAM_BOT_TOKEN", "selftest-token"),
+            ("TELEGRAM_DEVLOGS_TOPIC_ID", "2"),
+            ("TELEGRAM_FOUND_TOPIC_ID", "3"),
+            ("TELEGRAM_RECAP_TOPIC_ID", "4"),
+        ]);
+        let notifier = test_telegram();
+        
+        let results = run_self_test(&notifier, &config, false).await;
+        
+        let topics: Vec<serde_json::Value> = api.requests("selftest-token").iter().map(|r| r.json()["message_thread_id"].clone()).collect();
+        assert_eq!(topics, [json!("2"), json!("3"), json!("4")]);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].1.is_ok() && results[2].1.is_ok());
+        assert_eq!(results[1].0, "TELEGRAM_FOUND_TOPIC_ID");
+        assert!(results[1].1.as_ref().unwrap_err().contains("message thread not found"));
+        
+        let report = format_self_test_report(&results);
+        assert!(report.contains("TELEGRAM_FOUND_TOPIC_ID") && report.contains("message thread not found"), "{}", report);
+    }
+
+    #[test]
+    fn mixed_currency_fares_sort_by_base_currency_price() {
+        // Units per ruble: 1 ₽ = 0.01 $, 0.0125 €
+        let rates = HashMap::from([("USD".to_string(), 0.01), ("EUR".to_string(), 0.0125)]);
+        let rub = test_flight_in("2030-03-01T06:00:00+03:00", 12000, "rub", 0); // 12 000 ₽
+        let usd = test_flight_in("2030-03-01T10:00:00+03:00", 100, "usd", 0); // 10 000 ₽
+        let eur = test_flight_in("2030-03-01T14:00:00+03:00", 140, "eur", 0); // 11 200 ₽
+        let unknown = test_flight_in("2030-03-01T18:00:00+03:00", 1, "xyz", 0);
+        
+        assert_eq!(normalized_price(&eur, &rates, BASE_CURRENCY), Some(11200.0));
+        assert_eq!(normalized_price(&usd, &rates, "EUR"), Some(125.0));
+        assert_eq!(normalized_price(&unknown, &rates, BASE_CURRENCY), None);
+        
+        let mut flights = vec![&unknown, &rub, &eur, &usd];
+        sort_flights(&mut flights, SortBy::Price, &rates);
+        let order: Vec<&str> = flights.iter().map(|f| f.currency.as_deref().unwrap()).collect();
+        assert_eq!(order, ["usd", "eur", "rub", "xyz"]);
+        
+        // Display keeps the fare's own price
+        assert_eq!(flights[0].display_price(&DisplaySettings::default()), "$100");
+        assert_eq!(cheapest_flight(&[&rub, &eur, &usd], &rates).unwrap().currency.as_deref(), Some("usd"));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_pauses_concurrent_searches_until_the_backoff_elapses() {
+        let api = mock_api();
+        let mut rate_limited = MockReply::json(429, json!({"error": "Too many requests"}));
+        rate_limited.headers.push(("Retry-After", "1".to_string()));
+        api.script("throttle-key", vec![rate_limited, travelpayouts_page(&[])]);
+        
+        // Searches share the searcher's pause
+        let searcher = test_travelpayouts(Client::new());
+        let api_keys = ApiKeyPool::new("throttle-key");
+        let paging = SearchPaging { limit: 30, max_results: 30 };
+        let search = |date: &'static str| search_flights(&searcher, "MOW", "AER", date, &api_keys, true, paging, "rub");
+        let later = |date: &'static str| async move {
+            time::sleep(Duration::from_millis(200)).await;
+            search(date).await
+        };
+        
+        let (first, second, third) = tokio::join!(search("2030-03-01"), later("2030-03-02"), later("2030-03-03"));
+        assert!(first.is_ok() && second.is_ok() && third.is_ok());
+        
+        // The 429 came first; nothing else reached the server before its Retry-After passed
+        let requests = api.requests("throttle-key");
+        assert_eq!(requests.len(), 4);
+        let tripped_at = requests[0].received_at;
+        assert!(requests[1..].iter().all(|r| r.received_at.duration_since(tripped_at) >= Duration::from_millis(900)));
+    }
+
+    #[tokio::test]
+    async fn stored_status_message_is_reused_or_replaced_when_gone() {
+        // A cycle persists its status message per chat and topic
+        let config = test_config(&[("TELEGRAM_DEVLOGS_TOPIC_ID", "2"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let stored: BotState = deps.state_store.load();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let key = status_message_key("-100", config.topics.topic(MessageCategory::Status));
+        let stored_id = stored.status_messages.get(&key).cloned();
+        assert!(stored_id.is_some());
+        assert_eq!(stored_id, state.status_message.id);
+        
+        // After a restart the stored message is edited in place
+        let api = mock_api();
+        let notifier = test_telegram();
+        let restored = || StatusMessage { id: stored_id.clone(), posted_at: stored.status_posted_at.get(&key).copied() };
+        api.script("status-reuse-token", vec![MockReply::json(200, json!({"ok": true, "result": true}))]);
+        let mut status = restored();
+        update_status_message(&notifier, "status-reuse-token", "-100", "2", &mut status, "🛫 запущена", config.status_edit_max_age).await.unwrap();
+        let requests = api.requests("status-reuse-token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].path.ends_with("/editMessageText"));
+        assert_eq!(requests[0].json()["message_id"].as_str(), stored_id.as_deref());
+        assert_eq!(status.id, stored_id);
+        
+        // A deleted message is replaced by a new one
+        api.script("status-lost-token", vec![
+            MockReply::json(400, json!({"ok": false, "description": "Bad Request: message to edit not found"})),
+            telegram_ok(21),
+        ]);
+        let mut status = restored();
+        update_status_message(&notifier, "status-lost-token", "-100", "2", &mut status, "🛫 запущена", config.status_edit_max_age).await.unwrap();
+        let paths: Vec<String> = api.requests("status-lost-token").iter().map(|r| r.path.rsplit('/').next().unwrap().to_string()).collect();
+        assert_eq!(paths, ["editMessageText", "sendMessage"]);
+        assert_eq!(status.id.as_deref(), Some("21"));
+    }
+
+    #[tokio::test]
+    async fn price_below_the_alert_percentile_alerts() {
+        let key = route_date_key("MOW", "AER", "2030-03-01");
+        let seeded = || {
+            let mut state = test_state();
+            for price in (0..10).map(|i| 5000 + i * 100) {
+                state.bot_state.price_history.record(key.clone(), price, None);
+            }
+            state
+        };
+        assert_eq!(seeded().bot_state.price_history.percentile(&key, 10.0, 5), Some(5000));
+        assert_eq!(seeded().bot_state.price_history.percentile(&key, 50.0, 5), Some(5400));
+        assert_eq!(seeded().bot_state.price_history.percentile(&key, 10.0, 11), None);
+        
+        let config = test_config(&[("ALERT_PERCENTILE", "10"), ("ALERT_MIN_SAMPLES", "5"), ("END_DATE", "2030-03-01")]);
+        for (price, alerts) in [(4800, 1), (5400, 0)] {
+            let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", price)])]));
+            let deps = test_deps(searcher);
+            
+            run_cycle(&deps, &config, &mut seeded(), &SearchStatistics::new()).await.unwrap();
+            let _ = std::fs::remove_file(&deps.state_store.path);
+            
+            let messages = deps.notifier.messages();
+            assert_eq!(messages.iter().filter(|m| m.contains("Необычно низкая цена")).count(), alerts, "{}: {:?}", price, messages);
+        }
+    }
+
+    #[tokio::test]
+    async fn destinations_are_searched_and_ranked_by_cheapest_fare() {
+        let config = test_config(&[("DESTINATION", "AER,SIP,KRR"), ("END_DATE", "2030-03-01")]);
+        let fares = [("AER", 7000), ("SIP", 5000), ("KRR", 6000)]
+            .map(|(destination, price)| (route_date_key("MOW", destination, "2030-03-01"), vec![test_flight("2030-03-01T10:00:00+03:00", price)]));
+        let deps = test_deps(MockSearcher::new(HashMap::from(fares)));
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let mut searches = deps.searcher.searches.lock().unwrap().clone();
+        searches.sort();
+        assert_eq!(searches, ["MOW-AER rub", "MOW-KRR rub", "MOW-SIP rub"]);
+        
+        let messages = deps.notifier.messages();
+        let ranking = messages.iter().find(|m| m.contains("Лучшие направления")).unwrap();
+        let places: Vec<usize> = ["1. Симферополь (SIP)", "2. Краснодар (KRR)", "3. Сочи (AER)"]
+            .iter()
+            .map(|line| ranking.find(line).unwrap_or_else(|| panic!("{} in {}", line, ranking)))
+            .collect();
+        assert!(places.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn best_destination_only_notifies_the_cheapest() {
+        let config = test_config(&[("DESTINATION", "AER,SIP,KRR"), ("BEST_DESTINATION_ONLY", "true"), ("END_DATE", "2030-03-01")]);
+        let fares = [("AER", 7000), ("SIP", 5000), ("KRR", 6000)]
+            .map(|(destination, price)| (route_date_key("MOW", destination, "2030-03-01"), vec![test_flight("2030-03-01T10:00:00+03:00", price)]));
+        let deps = test_deps(MockSearcher::new(HashMap::from(fares)));
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let messages = deps.notifier.messages();
+        let flights: Vec<&String> = messages.iter().filter(|m| m.contains("Рейс SU 1234")).collect();
+        assert_eq!(flights.len(), 1, "{:?}", messages);
+        assert!(flights[0].contains(&format_price(&config.display, 5000)), "{}", flights[0]);
+    }
+
+    #[tokio::test]
+    async fn cycle_writes_one_ndjson_line_per_found_flight() {
+        let path = temp_path("flights.ndjson");
+        let config = test_config(&[("END_DATE", "2030-03-01")]);
+        let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![
+            test_flight("2030-03-01T10:00:00+03:00", 5000),
+            test_flight("2030-03-01T14:00:00+03:00", 6000),
+        ])]));
+        let mut deps = test_deps(searcher);
+        deps.ndjson_exporter = Some(NdjsonExporter::open(&path).unwrap());
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2, "{}", contents);
+        let mut prices: Vec<i64> = lines.iter().map(|line| line["price"].as_i64().unwrap()).collect();
+        prices.sort();
+        assert_eq!(prices, [5000, 6000]);
+        for line in &lines {
+            assert_eq!(line["route"], "MOW-AER");
+            assert_eq!(line["airline"], "SU");
+            assert!(DateTime::parse_from_rfc3339(line["found_at"].as_str().unwrap()).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_errors_are_notified_once_with_a_suppressed_count() {
+        let config = test_config(&[("END_DATE", "2030-03-10"), ("SEARCH_CONCURRENCY", "10")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let stats = SearchStatistics::new();
+        
+        run_cycle(&deps, &config, &mut test_state(), &stats).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(stats.snapshot().errors_encountered, 10);
+        assert_eq!(stats.snapshot().suppressed_errors, 9);
+        let messages = deps.notifier.messages();
+        assert_eq!(messages.iter().filter(|m| m.contains("Ошибка при поиске рейсов")).count(), 1, "{:?}", messages);
+        assert!(stats.format_summary(&config.display, config.max_summary_dates).contains("🔕 Повторных ошибок скрыто: 9"));
+        
+        // Different kinds of errors for the same route are not collapsed
+        let network: Box<dyn Error + Send + Sync> = Box::new(std::io::Error::other("connection reset"));
+        let parse: Box<dyn Error + Send + Sync> = serde_json::from_str::<serde_json::Value>("{").unwrap_err().into();
+        assert_ne!(error_kind(network.as_ref()), error_kind(parse.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn max_cycles_stops_after_exactly_that_many_cycles() {
+        let config = test_config(&[("MAX_CYCLES", "2"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        let started_at = std::time::Instant::now();
+        
+        // The stop check of the main loop, run after each cycle
+        let mut completed_cycles = 0;
+        let reason = loop {
+            run_cycle_with_watchdog(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+            completed_cycles += 1;
+            if let Some(reason) = run_limit_reached(&config, completed_cycles, started_at.elapsed()) {
+                break reason;
+            }
+            assert!(completed_cycles < 10, "MAX_CYCLES was ignored");
+        };
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(completed_cycles, 2);
+        assert_eq!(deps.searcher.searches.lock().unwrap().len(), 2);
+        assert_eq!(reason, "выполнено циклов: 2 из 2");
+        
+        // Runtime limit, and no limit at all
+        let timed = test_config(&[("MAX_RUNTIME_MINUTES", "30")]);
+        assert!(run_limit_reached(&timed, 100, Duration::from_secs(29 * 60)).is_none());
+        assert_eq!(run_limit_reached(&timed, 1, Duration::from_secs(30 * 60)).unwrap(), "истекло время работы: 30 мин.");
+        assert!(run_limit_reached(&test_config(&[]), u32::MAX, Duration::MAX).is_none());
+    }
+
+    #[test]
+    fn amounts_are_grouped_by_locale() {
+        let display = DisplaySettings::default();
+        for (amount, ru, en) in [
+            (950.0, "950", "950"),
+            (15230.0, "15 230", "15,230"),
+            (1234567.0, "1 234 567", "1,234,567"),
+            (-2500.0, "-2 500", "-2,500"),
+        ] {
+            assert_eq!(format_amount(amount, 0, Locale::Ru), ru);
+            assert_eq!(format_amount(amount, 0, Locale::En), en);
+        }
+        
+        // Fractional amounts for converted prices
+        assert_eq!(format_amount(1234.5, 2, Locale::Ru), "1 234,50");
+        assert_eq!(format_amount(1234.5, 2, Locale::En), "1,234.50");
+        assert_eq!(format_amount(-0.001, 2, Locale::En), "0.00");
+        
+        // The default locale is Russian
+        assert_eq!(format_price(&display, 15230), "15 230 ₽");
+        assert_eq!(format_price_in(&display, 15230, "usd"), "$15 230");
+        assert_eq!(Locale::from_env_value(" EN "), Locale::En);
+    }
+
+    #[test]
+    fn config_dates_accept_iso_and_dotted_formats() {
+        let date = NaiveDate::from_ymd_opt(2030, 3, 1).unwrap();
+        assert_eq!(parse_config_date("START_DATE", "2030-03-01").unwrap(), date);
+        assert_eq!(parse_config_date("START_DATE", " 01.03.2030 ").unwrap(), date);
+        
+        let error = parse_config_date("END_DATE", "2030/03/01").unwrap_err().to_string();
+        assert!(error.contains("END_DATE must be YYYY-MM-DD or DD.MM.YYYY") && error.contains("2030/03/01"), "{}", error);
+        assert!(parse_config_date("START_DATE", "").is_err());
+        
+        // The same error stops the configuration from loading
+        let error = Config::from_source(&test_source(&[("START_DATE", "31.02.2030")])).err().unwrap();
+        assert!(error.to_string().contains("START_DATE must be"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn empty_origin_is_rejected_instead_of_searched() {
+        for origin in ["", "  "] {
+            let config = test_config(&[("ORIGIN", origin)]);
+            let error = validate_config(&config).err().unwrap();
+            assert!(error.to_string().contains("ORIGIN must be set"), "{:?}: {}", origin, error);
+        }
+        
+        // The search itself refuses before any request reaches the API
+        let api = mock_api();
+        let paging = SearchPaging { limit: 3, max_results: 10 };
+        let error = search_flights(&test_travelpayouts(Client::new()), "", "AER", "2030-03-01", &ApiKeyPool::new("empty-origin-key"), true, paging, "rub")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error.downcast_ref::<BotError>(), Some(BotError::Config(_))), "{}", error);
+        assert!(api.requests("empty-origin-key").is_empty());
+    }
+
+    #[tokio::test]
+    async fn stalled_cycle_triggers_the_watchdog() {
+        let config = test_config(&[("WATCHDOG_TIMEOUT_SECS", "1"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        *deps.searcher.stalled_date.lock().unwrap() = Some("2030-03-01".to_string());
+        let mut state = test_state();
+        
+        let result = time::timeout(
+            Duration::from_secs(10),
+            run_cycle_with_watchdog(&deps, &config, &mut state, &SearchStatistics::new())
+        ).await.expect("the watchdog did not abort the stalled cycle");
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let error = result.err().unwrap();
+        assert!(error.to_string().contains("made no progress"), "{}", error);
+        assert_eq!(*deps.searcher.dates.lock().unwrap(), ["2030-03-01"]);
+        let messages = deps.notifier.messages();
+        assert!(messages.iter().any(|m| m.contains("Поиск завис")), "{:?}", messages);
+    }
+
+    #[tokio::test]
+    async fn blackout_dates_are_excluded_from_the_scan() {
+        let config = test_config(&[
+            ("END_DATE", "2030-03-07"),
+            ("BLACKOUT_DATES", "2030-03-02, 2030-03-04..2030-03-06"),
+            ("SEARCH_CONCURRENCY", "10"),
+        ]);
+        let expected = ["2030-03-01", "2030-03-03", "2030-03-07"];
+        let dates: Vec<String> = config.search_dates().iter().map(|d| d.format("%Y-%m-%d").to_string()).collect();
+        assert_eq!(dates, expected);
+        
+        // Blacked out dates are never sent to the API
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let mut searched = deps.searcher.dates.lock().unwrap().clone();
+        searched.sort();
+        assert_eq!(searched, expected);
+        
+        assert!(Config::from_source(&test_source(&[("BLACKOUT_DATES", "2030-03-02..soon")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn price_drop_alert_gets_a_fire_reaction() {
+        for react in [true, false] {
+            let config = test_config(&[("REACT_ON_DROP", if react { "true" } else { "false" }), ("END_DATE", "2030-03-01")]);
+            let deps = test_deps(MockSearcher::new(HashMap::from([
+                ("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)]),
+            ])));
+            let mut state = test_state();
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+            assert!(!deps.notifier.methods().contains(&"setMessageReaction"), "the first price is not a drop");
+            
+            deps.searcher.set_fares("2030-03-01", vec![
+                test_flight("2030-03-01T10:00:00+03:00", 5000),
+                test_flight("2030-03-01T14:00:00+03:00", 4000),
+            ]);
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+            let _ = std::fs::remove_file(&deps.state_store.path);
+            
+            let calls = deps.notifier.calls.lock().unwrap().clone();
+            let reactions: Vec<&str> = calls.iter().filter(|(method, _, _)| *method == "setMessageReaction").map(|(_, _, text)| text.as_str()).collect();
+            if !react {
+                assert!(reactions.is_empty(), "{:?}", calls);
+                continue;
+            }
+            // Message IDs of the mock are the 1-based positions of the calls
+            let drop_alert = calls.iter().rposition(|(method, _, text)| *method == "sendMessage" && text.contains("✅ Найдено")).unwrap();
+            assert!(reactions.contains(&format!("{} 🔥", drop_alert + 1).as_str()), "{:?}", calls);
+            assert!(calls.iter().all(|(method, chat_id, _)| *method != "setMessageReaction" || chat_id == "-100"));
+        }
+    }
+
+    #[test]
+    fn next_scheduled_run_follows_the_local_schedule() {
+        let times = parse_schedule("18:00, 06:00").unwrap();
+        assert_eq!(times, [NaiveTime::from_hms_opt(6, 0, 0).unwrap(), NaiveTime::from_hms_opt(18, 0, 0).unwrap()]);
+        let utc = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let moscow = chrono_tz::Europe::Moscow;
+        
+        // 10:00 in Moscow: the evening run is next; after it, tomorrow morning
+        assert_eq!(next_scheduled_run(utc("2030-03-01T07:00:00Z"), &times, &moscow), Some(utc("2030-03-01T15:00:00Z")));
+        assert_eq!(next_scheduled_run(utc("2030-03-01T15:00:00Z"), &times, &moscow), Some(utc("2030-03-02T03:00:00Z")));
+        
+        // Across the spring DST change in Berlin the offset moves from +01:00 to +02:00
+        let berlin = chrono_tz::Europe::Berlin;
+        assert_eq!(next_scheduled_run(utc("2030-03-30T19:00:00Z"), &times, &berlin), Some(utc("2030-03-31T04:00:00Z")));
+        // 02:30 does not exist on the night of the change and runs an hour later
+        let gap = parse_schedule("02:30").unwrap();
+        assert_eq!(next_scheduled_run(utc("2030-03-30T23:00:00Z"), &gap, &berlin), Some(utc("2030-03-31T01:30:00Z")));
+        
+        // Without a schedule the fixed interval is used
+        let interval = Duration::from_secs(6 * 60 * 60);
+        assert_eq!(next_cycle_delay(&test_config(&[]), utc("2030-03-01T07:00:00Z"), interval), interval);
+        assert!(parse_schedule("6pm").is_err());
+    }
+
+    #[test]
+    fn utc_instants_convert_into_the_display_timezone() {
+        let instant = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let local = |tz: Tz, s: &str| instant(s).with_timezone(&tz).format("%Y-%m-%d %H:%M %:z").to_string();
+        
+        let yekaterinburg = parse_display_timezone("Asia/Yekaterinburg", 0).unwrap();
+        assert_eq!(local(yekaterinburg, "2030-03-01T20:30:00Z"), "2030-03-02 01:30 +05:00");
+        
+        // A DST-observing zone changes its offset with the season
+        let london = parse_display_timezone(" Europe/London ", 0).unwrap();
+        assert_eq!(local(london, "2030-01-15T12:00:00Z"), "2030-01-15 12:00 +00:00");
+        assert_eq!(local(london, "2030-07-15T12:00:00Z"), "2030-07-15 13:00 +01:00");
+        
+        // Without a name the whole-hour offset is used
+        assert_eq!(local(parse_display_timezone("", 3).unwrap(), "2030-03-01T20:30:00Z"), "2030-03-01 23:30 +03:00");
+        assert_eq!(local(parse_display_timezone("", -4).unwrap(), "2030-03-01T02:30:00Z"), "2030-02-28 22:30 -04:00");
+        
+        assert!(parse_display_timezone("Mars/Olympus", 5).is_err());
+        assert!(parse_display_timezone("", 15).is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trip_alert_needs_flights_on_both_legs() {
+        let config = test_config(&[
+            ("END_DATE", "2030-03-01"),
+            ("OUTBOUND_DATE", "2030-03-01"),
+            ("RETURN_WINDOW", "2030-03-08..2030-03-09"),
+        ]);
+        let outbound = vec![test_flight("2030-03-01T10:00:00+03:00", 5000), test_flight("2030-03-01T14:00:00+03:00", 4500)];
+        let inbound = vec![test_flight("2030-03-09T18:00:00+03:00", 3500)];
+        
+        for (outbound, inbound, notified) in [
+            (outbound.clone(), inbound.clone(), true),
+            (outbound.clone(), Vec::new(), false),
+            (Vec::new(), inbound.clone(), false),
+        ] {
+            let deps = test_deps(MockSearcher::new(HashMap::from([
+                (route_date_key("MOW", "AER", "2030-03-01"), outbound.clone()),
+                (route_date_key("AER", "MOW", "2030-03-08"), Vec::new()),
+                (route_date_key("AER", "MOW", "2030-03-09"), inbound.clone()),
+            ])));
+            let mut state = test_state();
+            run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+            let _ = std::fs::remove_file(&deps.state_store.path);
+            
+            let searches = deps.searcher.searches.lock().unwrap().clone();
+            assert!(searches.contains(&"AER-MOW rub".to_string()), "{:?}", searches);
+            let messages = deps.notifier.messages();
+            let round_trips: Vec<&String> = messages.iter().filter(|m| m.contains("Туда и обратно")).collect();
+            if notified {
+                assert_eq!(round_trips.len(), 1, "{:?}", messages);
+                assert!(round_trips[0].contains("Итого: <b>8 000 ₽</b>"), "{}", round_trips[0]);
+            } else {
+                assert!(round_trips.is_empty(), "{:?}", messages);
+            }
+        }
+        
+        // Both legs and the total are shown in the route's currency
+        let config = test_config(&[
+            ("END_DATE", "2030-03-01"),
+            ("OUTBOUND_DATE", "2030-03-01"),
+            ("RETURN_WINDOW", "2030-03-08..2030-03-09"),
+            ("CURRENCY", "usd"),
+        ]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([
+            (route_date_key("MOW", "AER", "2030-03-01"), outbound),
+            (route_date_key("AER", "MOW", "2030-03-08"), Vec::new()),
+            (route_date_key("AER", "MOW", "2030-03-09"), inbound),
+        ])));
+        let mut state = test_state();
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let messages = deps.notifier.messages();
+        let round_trip = messages.iter().find(|m| m.contains("Туда и обратно")).expect("round trip sent");
+        assert!(round_trip.contains(&format!("SU 1234, {}\n", format_price_in(&config.display, 4500, "usd"))), "{}", round_trip);
+        assert!(round_trip.contains(&format!("Итого: <b>{}</b>", format_price_in(&config.display, 8000, "usd"))), "{}", round_trip);
+        assert!(!round_trip.contains('₽'), "{}", round_trip);
+    }
+
+    #[test]
+    fn identity_depends_only_on_flight_departure_and_price() {
+        let flight = test_flight("2030-03-01T10:00:00+03:00", 5000);
+        
+        // Fields outside the identity don't change it
+        let mut same = flight.clone();
+        same.link = "/search/MOW0103AER1?marker=42".to_string();
+        same.seats = Some(3);
+        same.duration = Some(155);
+        same.connections = vec!["KZN".to_string()];
+        assert_eq!(same.identity(), flight.identity());
+        
+        // The prefixed form of the same number is the same flight
+        let mut prefixed = flight.clone();
+        prefixed.flight_number = "SU1234".to_string();
+        assert_eq!(prefixed.identity(), flight.identity());
+        
+        let mut cheaper = flight.clone();
+        cheaper.price = 4500;
+        let mut later = flight.clone();
+        later.departure_at = "2030-03-01T14:00:00+03:00".to_string();
+        let mut other_number = flight.clone();
+        other_number.flight_number = "1235".to_string();
+        for different in [cheaper, later, other_number] {
+            assert_ne!(different.identity(), flight.identity());
+        }
+    }
+
+    #[tokio::test]
+    async fn airlabs_enrichment_stops_at_the_cycle_cap() {
+        let api = mock_api();
+        // Hours 06..15 Moscow time; the cheapest fares are not the earliest but are among the five listed
+        let prices = [5300, 5000, 5700, 5200, 5100, 5900, 5600, 5800, 5400, 5500];
+        let fares: Vec<FlightResult> = prices
+            .iter()
+            .enumerate()
+            .map(|(i, price)| test_flight(&format!("2030-03-01T{:02}:00:00+03:00", 6 + i), *price))
+            .collect();
+        let schedule: Vec<(String, Option<i64>)> = (0..prices.len()).map(|i| (format!("2030-03-01 {:02}:00", 6 + i), Some(40))).collect();
+        let schedule: Vec<(&str, Option<i64>)> = schedule.iter().map(|(time, seats)| (time.as_str(), *seats)).collect();
+        api.script("airlabs-cap-key", vec![airlabs_reply(&schedule)]);
+        
+        let config = test_config(&[("AIRLABS_API_KEY", "airlabs-cap-key"), ("AIRLABS_MAX_PER_CYCLE", "3"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([("2030-03-01".to_string(), fares)])));
+        let stats = SearchStatistics::new();
+        run_cycle(&deps, &config, &mut test_state(), &stats).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(api.requests("airlabs-cap-key").len(), 3);
+        // The three cheapest fares are the enriched ones
+        let messages = deps.notifier.messages();
+        let enriched: Vec<&String> = messages.iter().filter(|m| m.contains("Рейс SU 1234") && m.contains("💺")).collect();
+        assert_eq!(enriched.len(), 3, "{:?}", messages);
+        for price in ["5 000 ₽", "5 100 ₽", "5 200 ₽"] {
+            assert!(enriched.iter().any(|m| m.contains(price)), "{}: {:?}", price, enriched);
+        }
+        assert!(stats.format_summary(&config.display, config.max_summary_dates).contains("🛰 Без данных AirLabs (лимит за цикл): 7"));
+        
+        // The next cycle starts with a fresh allowance
+        assert!(SearchStatistics::new().reserve_airlabs_call(Some(3)));
+    }
+
+    #[test]
+    fn connection_data_renders_layover_airports() {
+        let display = DisplaySettings::default();
+        let response = json!({
+            "success": true,
+            "currency": "rub",
+            "data": [
+                {"origin": "MOW", "destination": "AER", "price": 4200, "airline": "SU", "flight_number": "1234",
+                 "departure_at": "2030-03-01T10:00:00+03:00", "transfers": 2, "connections": ["KZN", "xyz"], "link": "/a"},
+                {"origin": "MOW", "destination": "AER", "price": 4500, "airline": "SU", "flight_number": "1236",
+                 "departure_at": "2030-03-01T11:00:00+03:00", "transfers": 1, "link": "/b"}
+            ]
+        });
+        let flights = parse_flight_data(&response.to_string()).unwrap().data.unwrap();
+        
+        assert_eq!(flights[0].connections, ["KZN", "xyz"]);
+        let with_layovers = format_trip(&display, &flights[0], DurationPrecision::Exact);
+        assert!(with_layovers.contains("(пересадки: Казань (KZN), xyz)"), "{}", with_layovers);
+        
+        // Without layover details only the number of transfers is known
+        assert!(flights[1].connections.is_empty());
+        let count_only = format_trip(&display, &flights[1], DurationPrecision::Exact);
+        assert!(count_only.contains("(пересадок: 1)"), "{}", count_only);
+    }
+
+    #[tokio::test]
+    async fn new_route_record_is_celebrated_but_a_tie_is_not() {
+        let config = test_config(&[("NOTIFY_RECORD_LOW", "true"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([
+            ("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)]),
+        ])));
+        let mut state = test_state();
+        let records = |notifier: &MockNotifier| -> Vec<String> {
+            notifier.messages().into_iter().filter(|m| m.contains("Новый рекорд цены")).collect()
+        };
+        
+        // The first observation is the baseline
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert!(records(&deps.notifier).is_empty());
+        
+        deps.searcher.set_fares("2030-03-01", vec![test_flight("2030-03-01T14:00:00+03:00", 5000)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert!(records(&deps.notifier).is_empty(), "a tie is not a record");
+        
+        deps.searcher.set_fares("2030-03-01", vec![test_flight("2030-03-01T18:00:00+03:00", 4000)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let records = records(&deps.notifier);
+        assert_eq!(records.len(), 1, "{:?}", records);
+        assert!(records[0].contains("💰 <b>4 000 ₽</b> — прежний рекорд 5 000 ₽"), "{}", records[0]);
+        assert_eq!(state.bot_state.price_history.route_records.get("MOW-AER"), Some(&4000));
+    }
+
+    #[test]
+    fn saved_response_reports_field_coverage() {
+        let path = temp_path("validate-response.json");
+        let sample = json!({
+            "success": true,
+            "currency": "rub",
+            "data": [
+                {"origin": "MOW", "destination": "AER", "price": 4200, "airline": "SU", "flight_number": "1234",
+                 "departure_at": "2030-03-01T10:00:00+03:00", "transfers": "1", "link": "/a"},
+                {"origin": "MOW", "destination": "AER", "price": 4500, "airline": "SU", "flight_number": "1236",
+                 "departure_at": "2030-03-01T11:00:00+03:00", "transfers": 0, "duration": 150, "link": "/b"}
+            ]
+        });
+        std::fs::write(&path, sample.to_string()).unwrap();
+        
+        let report = response_field_coverage(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        
+        // The string transfer count forces the manual parse, which can't keep its value
+        assert!(report.starts_with("Parse path: manual fallback"), "{}", report);
+        assert!(report.contains("success: true, currency: rub, fares: 2\n"), "{}", report);
+        for line in [
+            "price                populated   2, mismatched   0, defaulted   0",
+            "duration             populated   1, mismatched   0, defaulted   1",
+            "seats                populated   0, mismatched   0, defaulted   2",
+            "transfers            populated   1, mismatched   1, defaulted   0",
+        ] {
+            assert!(report.lines().any(|l| l == line), "{}\n{}", line, report);
+        }
+        
+        let direct = response_field_coverage(&travelpayouts_page(&[test_flight("2030-03-01T10:00:00+03:00", 5000)]).body).unwrap();
+        assert!(direct.starts_with("Parse path: direct\n"), "{}", direct);
+    }
+
+    #[test]
+    fn each_message_category_routes_to_its_topic() {
+        let categories = [
+            MessageCategory::Status,
+            MessageCategory::Error,
+            MessageCategory::FoundFlight,
+            MessageCategory::SeatInfo,
+            MessageCategory::PriceDrop,
+        ];
+        let topics = |router: &TopicRouter| -> Vec<String> {
+            categories.iter().map(|category| router.topic(*category).to_string()).collect()
+        };
+        
+        // Defaults: status and errors to dev logs, everything else to found flights
+        let router = TopicRouter::from_source(&test_source(&[]), "10", "20", "30").unwrap();
+        assert_eq!(topics(&router), ["10", "10", "20", "20", "20"]);
+        
+        let router = TopicRouter::from_source(&test_source(&[
+            ("TELEGRAM_STATUS_TOPIC_ID", "found"),
+            ("TELEGRAM_ERROR_TOPIC_ID", "77"),
+            ("TELEGRAM_SEAT_INFO_TOPIC_ID", "88"),
+            ("TELEGRAM_PRICE_DROP_TOPIC_ID", "recap"),
+        ]), "10", "20", "30").unwrap();
+        assert_eq!(topics(&router), ["20", "77", "20", "88", "30"]);
+        
+        // Named topics must be configured, and anything else must be an id
+        let unset = TopicRouter::from_source(&test_source(&[("TELEGRAM_PRICE_DROP_TOPIC_ID", "recap")]), "10", "20", "").unwrap_err();
+        assert!(unset.to_string().contains("TELEGRAM_RECAP_TOPIC_ID, which is not set"), "{}", unset);
+        assert!(TopicRouter::from_source(&test_source(&[("TELEGRAM_ERROR_TOPIC_ID", "errors")]), "10", "20", "30").is_err());
+    }
+
+    #[tokio::test]
+    async fn persisted_pause_delays_the_first_send_after_a_restart() {
+        let path = temp_path("telegram-pause");
+        
+        // The previous run was told to back off and left the pause on disk
+        let previous_run = RequestThrottle::default();
+        previous_run.persist_to(&path);
+        previous_run.trip(Duration::from_millis(1500));
+        assert!(std::fs::read_to_string(&path).is_ok());
+        
+        let restarted = RequestThrottle::default();
+        restarted.persist_to(&path);
+        let started_at = std::time::Instant::now();
+        restarted.wait().await;
+        assert!(started_at.elapsed() >= Duration::from_secs(1), "{:?}", started_at.elapsed());
+        // The elapsed pause is cleared
+        assert!(std::fs::read_to_string(&path).is_err());
+        
+        // A pause that ran out while the bot was down is dropped without waiting
+        std::fs::write(&path, (Utc::now() - chrono::Duration::seconds(5)).to_rfc3339()).unwrap();
+        let late = RequestThrottle::default();
+        late.persist_to(&path);
+        assert!(std::fs::read_to_string(&path).is_err());
+        let started_at = std::time::Instant::now();
+        late.wait().await;
+        assert!(started_at.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn mixed_airline_date_is_grouped_with_airline_minima() {
+        let fares: Vec<FlightResult> = [("SU", "1234", 5000), ("DP", "101", 3000), ("SU", "1236", 4000), ("DP", "103", 3500), ("S7", "2020", 6000)]
+            .iter()
+            .enumerate()
+            .map(|(i, (airline, number, price))| {
+                let mut flight = test_flight(&format!("2030-03-01T{:02}:00:00+03:00", 8 + i), *price);
+                flight.airline = airline.to_string();
+                flight.flight_number = number.to_string();
+                flight
+            })
+            .collect();
+        let flights: Vec<&FlightResult> = fares.iter().collect();
+        
+        let digest = format_flights_by_airline(&DisplaySettings::default(), &flights, 4, None);
+        let headers: Vec<&str> = digest.lines().filter(|line| line.starts_with("✈️")).collect();
+        assert_eq!(headers, [
+            "✈️ <b>Победа</b> — от 3 000 ₽, рейсов: 2",
+            "✈️ <b>Аэрофлот</b> — от 4 000 ₽, рейсов: 2",
+            "✈️ <b>S7 Airlines</b> — от 6 000 ₽, рейсов: 1",
+        ]);
+        // The display cap applies across the groups
+        assert_eq!(digest.lines().filter(|line| line.starts_with("  • ")).count(), 4);
+        assert!(digest.contains("  • DP 101 SVO → AER, 1 марта 2030 в 11:00 — 3 000 ₽"), "{}", digest);
+        assert!(digest.ends_with("... и еще 1 рейс"), "{}", digest);
+        
+        // The flat list stays the default
+        assert_eq!(test_config(&[]).group_by, GroupBy::None);
+        assert_eq!(test_config(&[("GROUP_BY", "Airline")]).group_by, GroupBy::Airline);
+    }
+
+    #[test]
+    fn sub_threshold_price_changes_are_not_recorded() {
+        let mut history = PriceHistoryStore::default();
+        let key = route_date_key("MOW", "AER", "2030-03-01");
+        let noise = test_config(&[("PRICE_NOISE_THRESHOLD", "1")]).price_noise_threshold;
+        assert_eq!(noise, Some(1.0));
+        
+        assert!(history.record(key.clone(), 10000, noise), "the first price is always recorded");
+        assert!(!history.record(key.clone(), 10050, noise));
+        assert!(!history.record(key.clone(), 9900, noise));
+        assert!(history.record(key.clone(), 9850, noise));
+        // Later changes are measured from the last recorded point, not the first
+        assert!(!history.record(key.clone(), 9900, noise));
+        let prices: Vec<i64> = history.entries[&key].iter().map(|point| point.price).collect();
+        assert_eq!(prices, [10000, 9850]);
+        
+        // Without a threshold every observation is kept
+        assert!(history.record(key.clone(), 9850, None));
+        assert_eq!(history.entries[&key].len(), 3);
+    }
+
+    #[tokio::test]
+    async fn configured_telegram_base_is_used_for_requests() {
+        let api = mock_api();
+        let telegram = test_telegram();
+        assert_eq!(telegram_api_url(&telegram.api_base, "base-token", "sendMessage"), format!("{}/botbase-token/sendMessage", api.base));
+        
+        send_telegram_notification_with_id(&telegram, "base-token", "-100", "Проверка", "", None).await.unwrap();
+        let paths: Vec<String> = api.requests("base-token").iter().map(|r| r.path.clone()).collect();
+        assert_eq!(paths, ["/botbase-token/sendMessage"]);
+        
+        // The setting defaults to the public server and must be an http(s) URL
+        assert_eq!(parse_api_base("TELEGRAM_API_BASE", " ", DEFAULT_TELEGRAM_API_BASE).unwrap(), "https://api.telegram.org");
+        assert_eq!(parse_api_base("TELEGRAM_API_BASE", "http://localhost:8081/", DEFAULT_TELEGRAM_API_BASE).unwrap(), "http://localhost:8081/");
+        for invalid in ["api.telegram.org", "ftp://files.example.com"] {
+            assert!(parse_api_base("TELEGRAM_API_BASE", invalid, DEFAULT_TELEGRAM_API_BASE).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn link_marker_is_appended_to_relative_and_absolute_links() {
+        assert_eq!(
+            booking_url("/search/MOW0103AER1?t=SU1234", "12345").unwrap(),
+            "https://www.aviasales.ru/search/MOW0103AER1?t=SU1234&marker=12345"
+        );
+        assert_eq!(
+            booking_url("https://www.aviasales.com/search/MOW0103AER1", "12345").unwrap(),
+            "https://www.aviasales.com/search/MOW0103AER1?marker=12345"
+        );
+        // An existing marker is replaced rather than repeated
+        assert_eq!(
+            booking_url("https://www.aviasales.ru/search/MOW0103AER1?marker=old&utm_source=bot", "12345").unwrap(),
+            "https://www.aviasales.ru/search/MOW0103AER1?utm_source=bot&marker=12345"
+        );
+        
+        // Without a marker the link is only made absolute
+        assert_eq!(booking_url("/search/MOW0103AER1", "").unwrap(), "https://www.aviasales.ru/search/MOW0103AER1");
+        assert!(booking_url("  ", "12345").is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_statistics_updates_are_not_lost() {
+        let stats = Arc::new(SearchStatistics::new());
+        let tasks: Vec<_> = (0..50)
+            .map(|task| {
+                let stats = Arc::clone(&stats);
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        stats.update(|counts| {
+                            counts.total_dates_checked += 1;
+                            counts.total_flights_found += 3;
+                        });
+                        stats.reserve_airlabs_call(Some(25));
+                        tokio::task::yield_now().await;
+                    }
+                    stats.record_flight_date(FlightDateSummary {
+                        date: format!("{} марта 2030", task % 28 + 1),
+                        message_id: task.to_string(),
+                        price: 5000,
+                        currency: "rub".to_string(),
+                        base_price: Some(5000.0),
+                    });
+                })
+            })
+            .collect();
+        // Snapshots taken meanwhile never catch an update half applied
+        let reader = {
+            let stats = Arc::clone(&stats);
+            tokio::spawn(async move {
+                for _ in 0..1000 {
+                    let snapshot = stats.snapshot();
+                    assert_eq!(snapshot.total_flights_found, snapshot.total_dates_checked * 3);
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        for task in tasks {
+            task.await.unwrap();
+        }
+        reader.await.unwrap();
+        
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total_dates_checked, 10_000);
+        assert_eq!(snapshot.total_flights_found, 30_000);
+        assert_eq!(snapshot.flight_dates.len(), 50);
+        // The AirLabs cap holds under contention too
+        assert_eq!(snapshot.airlabs_enriched, 25);
+        assert_eq!(snapshot.airlabs_skipped, 10_000 - 25);
+    }
+
+    #[tokio::test]
+    async fn subscribe_command_persists_and_is_honored_next_cycle() {
+        let (webhook, mut commands) = webhook_state("s3cret");
+        let update = json!({"message": {
+            "message_id": 7,
+            "chat": {"id": -100},
+            "from": {"id": 42, "username": "traveler"},
+            "text": "/subscribe mow led 6000"
+        }});
+        let status = handle_webhook(State(webhook), webhook_headers(Some("s3cret")), Bytes::from(update.to_string())).await;
+        assert_eq!(status, StatusCode::OK);
+        let Some(WebhookCommand::Subscription(request)) = commands.recv().await else {
+            panic!("the update was not turned into a subscription command");
+        };
+        
+        let config = test_config(&[("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([
+            (route_date_key("MOW", "AER", "2030-03-01"), Vec::new()),
+            (route_date_key("MOW", "LED", "2030-03-01"), vec![test_flight("2030-03-01T10:00:00+03:00", 5500)]),
+        ])));
+        handle_subscription_request(&deps, &config, &mut test_state(), request).await;
+        assert!(deps.notifier.messages()[0].contains("Подписка оформлена: MOW → LED до 6 000 ₽"), "{:?}", deps.notifier.messages());
+        
+        // A restarted bot reads the subscription back and searches the subscribed route
+        let mut state = test_state();
+        state.bot_state = deps.state_store.load();
+        assert_eq!(state.bot_state.subscriptions.len(), 1);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert!(deps.searcher.searches.lock().unwrap().contains(&"MOW-LED rub".to_string()));
+        let calls = deps.notifier.calls.lock().unwrap().clone();
+        let alert = calls.iter().find(|(_, chat_id, _)| chat_id == "42").expect("the subscriber was not messaged");
+        assert!(alert.2.contains("Цена по вашей подписке"), "{}", alert.2);
+        assert!(alert.2.contains("5 500 ₽ (порог 6 000 ₽)"), "{}", alert.2);
+    }
+
+    #[tokio::test]
+    async fn empty_primary_search_falls_back_and_merges() {
+        let api = mock_api();
+        let base = &api.base;
+        let fallback_url = format!("{}/fares?api_key=fallback-json-key&from={{origin}}&to={{destination}}&date={{date}}", base);
+        let config = test_config(&[("TRAVELPAYOUTS_API_KEY", "fallback-primary-key"), ("FALLBACK_PROVIDER", &fallback_url)]);
+        api.script("fallback-primary-key", vec![travelpayouts_page(&[])]);
+        api.script("fallback-json-key", vec![MockReply::json(200, json!([
+            {"price": 6100, "airline": "DP", "flight_number": "101", "departure_at": "2030-03-01T07:00:00+03:00", "transfers": 0, "link": "/dp"},
+            {"price": 5900, "airline": "U6", "flight_number": "263", "departure_at": "2030-03-01T09:00:00+03:00", "transfers": 1, "link": "/u6"}
+        ]))]);
+        
+        let searcher = test_travelpayouts(Client::new());
+        let flight_data = searcher.search(&config, &config.routes()[0], "2030-03-01").await.unwrap();
+        
+        let fallback_requests = api.requests("fallback-json-key");
+        assert_eq!(fallback_requests.len(), 1);
+        assert_eq!(fallback_requests[0].query["from"], "MOW");
+        assert_eq!(fallback_requests[0].query["to"], "AER");
+        assert_eq!(fallback_requests[0].query["date"], "2030-03-01");
+        let flights = flight_data.data.unwrap();
+        let found: Vec<(String, String, i64)> = flights.iter().map(|f| (f.origin.clone(), f.airline.clone(), f.price)).collect();
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found.contains(&("MOW".to_string(), "DP".to_string(), 6100)), "{:?}", found);
+        assert!(found.contains(&("MOW".to_string(), "U6".to_string(), 5900)), "{:?}", found);
+        
+        // Fares from the primary leave the fallback alone
+        api.script("fallback-primary-key", vec![travelpayouts_page(&[test_flight("2030-03-01T10:00:00+03:00", 5000)])]);
+        let flight_data = searcher.search(&config, &config.routes()[0], "2030-03-01").await.unwrap();
+        assert_eq!(flight_data.data.unwrap().len(), 1);
+        assert_eq!(api.requests("fallback-json-key").len(), 1);
+    }
+
+    #[test]
+    fn cycle_duration_splits_minutes_and_remaining_seconds() {
+        for (seconds, expected) in [
+            (0, "0 секунд"),
+            (1, "1 секунда"),
+            (42, "42 секунды"),
+            (60, "1 минута 0 секунд"),
+            (130, "2 минуты 10 секунд"),
+            (321, "5 минут 21 секунда"),
+            (683, "11 минут 23 секунды"),
+            (1334, "22 минуты 14 секунд"),
+        ] {
+            assert_eq!(format_cycle_duration_ru(chrono::Duration::seconds(seconds)), expected, "{} s", seconds);
+        }
+        // Sub-second and negative durations never go below zero
+        assert_eq!(format_cycle_duration_ru(chrono::Duration::milliseconds(400)), "0 секунд");
+        assert_eq!(format_cycle_duration_ru(chrono::Duration::seconds(-5)), "0 секунд");
+    }
+
+    #[test]
+    fn plural_ru_follows_russian_plural_rules() {
+        let flights = |n: i64| format!("{} {}", n, plural_ru(n, "рейс", "рейса", "рейсов"));
+        for (n, expected) in [
+            (1, "1 рейс"),
+            (2, "2 рейса"),
+            (5, "5 рейсов"),
+            (11, "11 рейсов"),
+            (12, "12 рейсов"),
+            (14, "14 рейсов"),
+            (21, "21 рейс"),
+            (22, "22 рейса"),
+            (25, "25 рейсов"),
+            (111, "111 рейсов"),
+            (101, "101 рейс"),
+            (0, "0 рейсов"),
+        ] {
+            assert_eq!(flights(n), expected);
+        }
+        assert_eq!(plural_ru(-2, "день", "дня", "дней"), "дня");
+    }
+
+    #[test]
+    fn calendar_keyboard_lays_dates_out_by_week() {
+        let mut dates: Vec<NaiveDate> = (1..=9).map(|day| NaiveDate::from_ymd_opt(2030, 3, day).unwrap()).collect();
+        dates.reverse();
+        dates.push(NaiveDate::from_ymd_opt(2030, 3, 4).unwrap());
+        
+        let keyboard = calendar_keyboard(&dates).unwrap();
+        let rows = keyboard["inline_keyboard"].as_array().unwrap();
+        let grid: Vec<Vec<&str>> = rows
+            .iter()
+            .map(|row| row.as_array().unwrap().iter().map(|button| button["text"].as_str().unwrap()).collect())
+            .collect();
+        assert_eq!(grid, [
+            vec!["01.03", "02.03", "03.03", "04.03", "05.03", "06.03", "07.03"],
+            vec!["08.03", "09.03"],
+        ]);
+        assert_eq!(rows[1][1]["callback_data"], "calendar:2030-03-09");
+        
+        // The callback data of a tapped button leads back to its date
+        let update = json!({"callback_query": {"id": "q1", "data": rows[0][3]["callback_data"], "message": {"message_thread_id": 5}}});
+        let request = calendar_request(&update).unwrap();
+        assert_eq!((request.date.as_str(), request.topic_id.as_str()), ("2030-03-04", "5"));
+        
+        assert!(calendar_keyboard(&[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn route_and_date_concurrency_stay_within_the_combined_cap() {
+        let config = test_config(&[
+            ("DESTINATION", "AER,LED"),
+            ("END_DATE", "2030-03-06"),
+            ("ROUTE_CONCURRENCY", "2"),
+            ("DATE_CONCURRENCY", "4"),
+            ("SEARCH_CONCURRENCY", "3"),
+        ]);
+        assert_eq!(config.search_concurrency.total(), 3);
+        assert_eq!(SearchConcurrency::new(4, 4, 20).total(), MAX_CONCURRENT_SEARCHES);
+        
+        let dates: Vec<String> = (1..=6).map(|day| format!("2030-03-{:02}", day)).collect();
+        let deps = test_deps(MockSearcher::new(dates.iter().map(|date| (date.clone(), Vec::new())).collect()));
+        *deps.searcher.search_time.lock().unwrap() = Duration::from_millis(300);
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(deps.searcher.searches.lock().unwrap().len(), 12);
+        let peak = deps.searcher.peak_in_flight.load(Ordering::SeqCst);
+        assert!((2..=3).contains(&peak), "peak concurrency {}", peak);
+    }
+
+    #[test]
+    fn compact_mode_renders_a_flight_on_one_line() {
+        let display = DisplaySettings::default();
+        let mut flight = test_flight("2030-09-16T12:30:00+03:00", 12340);
+        flight.airline = "DP".to_string();
+        flight.flight_number = "403".to_string();
+        flight.duration = Some(160);
+        flight.duration_to = Some(160);
+        
+        // Times are shown in the default display zone, UTC+5
+        assert_eq!(format_flight_compact(&display, &flight), "16.09 12 340 ₽ DP 403 14:30→17:10 прямой");
+        
+        flight.transfers = 2;
+        flight.duration_to = None;
+        flight.duration = None;
+        assert_eq!(format_flight_compact(&display, &flight), "16.09 12 340 ₽ DP 403 14:30 2 пересадки");
+        
+        assert!(!test_config(&[]).compact);
+        assert!(test_config(&[("COMPACT", "true")]).compact);
+    }
+
+    #[tokio::test]
+    async fn critical_errors_escalate_while_routine_ones_stay_in_devlogs() {
+        let chat_messages = |notifier: &MockNotifier, chat: &str| -> Vec<String> {
+            notifier.calls.lock().unwrap().iter().filter(|(_, chat_id, _)| chat_id == chat).map(|(_, _, text)| text.clone()).collect()
+        };
+        
+        // A failed date search is routine
+        let config = test_config(&[("ALERT_CHAT_ID", "-200"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        assert!(chat_messages(&deps.notifier, "-100").iter().any(|m| m.contains("Ошибка при поиске рейсов")));
+        assert!(chat_messages(&deps.notifier, "-200").is_empty());
+        
+        // A rejected configuration reload is critical
+        let path = temp_path("escalation.env");
+        let start = std::time::SystemTime::now();
+        write_config_file(&path, "AER", "2030-03-01", start);
+        let mut config = load_config(&path).unwrap();
+        config.alert_chat_id = "-200".to_string();
+        let mut config = Arc::new(config);
+        let mut config_modified = config_file_modified(&path);
+        write_config_file(&path, "AER", "not a date", start + Duration::from_secs(60));
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        reload_config_if_changed(&deps, &path, &mut config, &mut config_modified).await;
+        let _ = std::fs::remove_file(&path);
+        
+        let alerts = chat_messages(&deps.notifier, "-200");
+        assert_eq!(alerts.len(), 1, "{:?}", alerts);
+        assert!(alerts[0].contains("🏷 Тип: config"), "{}", alerts[0]);
+        assert!(alerts[0].contains(&format!("📍 Контекст: {}", path)), "{}", alerts[0]);
+        assert!(chat_messages(&deps.notifier, "-100").iter().any(|m| m.contains("Новая конфигурация отклонена")));
+        
+        // Running out of API tokens is critical too
+        assert_eq!(search_error_severity(&BotError::TokensExhausted), Severity::Critical);
+        let routine: Box<dyn Error + Send + Sync> = "API error: service unavailable".into();
+        assert_eq!(search_error_severity(routine.as_ref()), Severity::Warning);
+    }
+
+    #[tokio::test]
+    async fn shortened_link_replaces_the_url_and_failures_keep_it() {
+        let api = mock_api();
+        let base = &api.base;
+        let long_url = "https://www.aviasales.ru/search/MOW0103AER1?marker=12345";
+        let client = test_http(Client::new());
+        
+        let shortener_url = format!("{}/shorten?token=shortener-ok", base);
+        let config = test_config(&[("SHORTENER_URL", &shortener_url), ("SHORTENER_API_KEY", "sh-key")]);
+        api.script("shortener-ok", vec![MockReply::json(200, json!({"short_url": "https://sho.rt/abc"}))]);
+        let shortener = LinkShortener::default();
+        assert_eq!(shortener.shorten(&client, &config, long_url).await, "https://sho.rt/abc");
+        // Repeated links come from the cache
+        assert_eq!(shortener.shorten(&client, &config, long_url).await, "https://sho.rt/abc");
+        let requests = api.requests("shortener-ok");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["url"], long_url);
+        assert_eq!(requests[0].headers["authorization"], "Bearer sh-key");
+        
+        let shortener_url = format!("{}/shorten?token=shortener-broken", base);
+        let config = test_config(&[("SHORTENER_URL", &shortener_url)]);
+        api.script("shortener-broken", vec![MockReply::json(400, json!({"error": "quota exceeded"}))]);
+        assert_eq!(LinkShortener::default().shorten(&client, &config, long_url).await, long_url);
+        
+        // Without a shortener nothing is requested
+        assert_eq!(LinkShortener::default().shorten(&client, &test_config(&[]), long_url).await, long_url);
+    }
+
+    #[test]
+    fn explicit_dates_override_the_range_in_sorted_order() {
+        let config = test_config(&[
+            ("START_DATE", "2030-03-01"),
+            ("END_DATE", "2030-03-02"),
+            ("DATES", "2030-05-17, 2030-04-12,2030-05-03, 2030-04-12"),
+        ]);
+        let dates: Vec<String> = config.search_dates().iter().map(|d| d.format("%Y-%m-%d").to_string()).collect();
+        assert_eq!(dates, ["2030-04-12", "2030-05-03", "2030-05-17"]);
+        
+        // Without DATES the range is used
+        let dates: Vec<String> = test_config(&[]).search_dates().iter().map(|d| d.format("%Y-%m-%d").to_string()).collect();
+        assert_eq!(dates, ["2030-03-01", "2030-03-02"]);
+        
+        let error = Config::from_source(&test_source(&[("DATES", "2030-04-12, next friday")])).err().unwrap();
+        assert!(error.to_string().contains("DATES"), "{}", error);
+    }
+
+    #[test]
+    fn store_round_trips_state_in_each_format() {
+        let mut bot_state = BotState::default();
+        bot_state.record_notification(route_date_key("MOW", "AER", "2030-03-01"), 5000);
+        bot_state.price_history.record(route_date_key("MOW", "AER", "2030-03-01"), 5000, None);
+        bot_state.record_sent("Найдено 1 рейс");
+        bot_state.apply_subscription_command(&DisplaySettings::default(), 42, "@traveler", SubscriptionCommand::Subscribe {
+            route: Route { origin: "MOW".to_string(), destination: "LED".to_string(), currency: None, target_price: None },
+            max_price: 6000,
+        });
+        bot_state.acknowledged_flights.insert("SU 1234|2030-03-01T10:00:00+03:00".to_string());
+        
+        for format in [PersistFormat::Json, PersistFormat::Bincode] {
+            let store = Store { path: temp_path(&format!("store-{:?}", format)), format };
+            store.save(&bot_state).unwrap();
+            let loaded: BotState = store.load();
             
-            // Add a small delay between API calls to avoid rate limiting
-            time::sleep(Duration::from_secs(1)).await;
-        }
-        
-        let search_end_time = Utc::now();
-        let formatted_end_time = format_utc_datetime_ru(search_end_time);
-        let duration = search_end_time.signed_duration_since(search_start_time);
-        let duration_minutes = duration.num_minutes();
-        let duration_seconds = duration.num_seconds();
-        
-        println!("Completed flight search cycle at {}. Waiting {} hours before next check.", formatted_end_time, hours_interval);
-        
-        // Final status update with complete statistics
-        if enable_telegram && status_message_id.is_some() {
-            let final_message = format!(
-                "🛫 <b>Программа поиска авиабилетов</b>\n\n\
-                ✅ <b>Цикл поиска завершен!</b>\n\
-                🕒 Начало: {}\n\
-                🕕 Окончание: {}\n\
-                ⏱ Длительность: {} минут {} секунд\n\
-                🗓 Проверено дат: {}\n\n\
-                {}\n\n\
-                🔄 Следующий цикл через <b>{} часов</b>",
-                formatted_start_time,
-                formatted_end_time,
-                duration_minutes,
-                duration_seconds,
-                dates.len(),
-                stats.format_summary(),
-                hours_interval
-            );
+            // A store written in one format is still read after PERSIST_FORMAT changes
+            let migrated: BotState = Store { path: store.path.clone(), format: format.other() }.load();
+            let _ = std::fs::remove_file(&store.path);
             
-            if let Err(e) = update_telegram_message(
-                &client,
-                &telegram_bot_token,
-                &telegram_chat_id,
-                &status_message_id.as_ref().unwrap(),
-                &final_message,
-                &telegram_devlogs_topic_id
-            ).await {
-                eprintln!("Failed to update final status message: {}", e);
+            for state in [&loaded, &migrated] {
+                assert_eq!(state.notifications.len(), 1, "{:?}", format);
+                assert_eq!(state.price_history.entries[&route_date_key("MOW", "AER", "2030-03-01")][0].price, 5000);
+                assert!(state.sent_messages.contains_key("Найдено 1 рейс"));
+                assert_eq!(state.subscriptions.len(), 1);
+                assert_eq!(state.subscriptions[0].route.destination, "LED");
+                assert_eq!(state.subscriptions[0].max_price, 6000);
+                assert_eq!(state.acknowledged_flights, bot_state.acknowledged_flights);
+            }
+        }
+        
+        // A missing file starts from empty state
+        let missing: BotState = Store { path: temp_path("store-missing"), format: PersistFormat::Bincode }.load();
+        assert!(missing.notifications.is_empty());
+    }
+
+    #[test]
+    fn price_trend_shows_direction_and_delta() {
+        let display = DisplaySettings::default();
+        assert_eq!(format_price_trend(&display, 12340, Some(13140), "rub"), "12 340 ₽ ↓ 800");
+        assert_eq!(format_price_trend(&display, 13140, Some(12340), "rub"), "13 140 ₽ ↑ 800");
+        assert_eq!(format_price_trend(&display, 12340, Some(12340), "rub"), "12 340 ₽ →");
+        assert_eq!(format_price_trend(&display, 1500, Some(2750), "rub"), "1 500 ₽ ↓ 1 250");
+        // A first sighting has nothing to compare against
+        assert_eq!(format_price_trend(&display, 12340, None, "rub"), "12 340 ₽");
+    }
+
+    #[tokio::test]
+    async fn acknowledged_flight_is_not_notified_again() {
+        let config = test_config(&[("NOTIFY_COOLDOWN_HOURS", "0"), ("END_DATE", "2030-03-01")]);
+        let fare = |hour: u32, price: i64| test_flight(&format!("2030-03-01T{:02}:00:00+03:00", hour), price);
+        let deps = test_deps(MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![fare(10, 5000), fare(14, 5200)])])));
+        let mut state = test_state();
+        // The 10:00 Moscow departure is shown as 12:00 in UTC+5
+        let notified_10am = |notifier: &MockNotifier| notifier.messages().iter().filter(|m| m.contains("Рейс SU 1234") && m.contains("в 12:00")).count();
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert_eq!(notified_10am(&deps.notifier), 1);
+        
+        // Reactions on the flight message; message IDs of the mock are the 1-based positions of the calls
+        let calls = deps.notifier.calls.lock().unwrap().clone();
+        let message_id = calls.iter().position(|(_, _, text)| text.contains("Рейс SU 1234") && text.contains("в 12:00")).unwrap() + 1;
+        let react = |bot_state: &mut BotState, emoji: &str| {
+            let update = json!({"message_reaction": {"chat": {"id": -100}, "message_id": message_id, "new_reaction": [{"type": "emoji", "emoji": emoji}]}});
+            let Some(WebhookCommand::Reaction { message_id, acknowledged }) = reaction_command(&update) else {
+                panic!("the {} reaction was not recognized", emoji);
+            };
+            bot_state.acknowledge_flight(&message_id, acknowledged)
+        };
+        let flight_key = Some(fare(10, 5000).flight_key());
+        assert_eq!(react(&mut state.bot_state, "👍"), flight_key);
+        assert_eq!(react(&mut state.bot_state, "👎"), flight_key);
+        assert!(state.bot_state.acknowledged_flights.is_empty());
+        
+        // A reaction after the dedup window still finds its flight
+        let age_sent_messages = |bot_state: &mut BotState| {
+            for sent_at in bot_state.sent_messages.values_mut() {
+                *sent_at -= config.dedup_window + chrono::Duration::hours(1);
+            }
+        };
+        age_sent_messages(&mut state.bot_state);
+        state.bot_state.prune_sent_messages(&config.display, config.dedup_window);
+        assert_eq!(react(&mut state.bot_state, "👍"), flight_key);
+        
+        // Only the acknowledged flight stays quiet, even once its price changes
+        deps.searcher.set_fares("2030-03-01", vec![fare(10, 4800), fare(14, 5200), fare(18, 5400)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        assert_eq!(notified_10am(&deps.notifier), 1, "{:?}", deps.notifier.messages());
+        assert_eq!(flight_messages(&deps.notifier), 4, "{:?}", deps.notifier.messages());
+    }
+
+    #[test]
+    fn flight_messages_and_acknowledgements_are_kept_until_departure() {
+        let mut bot_state = BotState::default();
+        let departed = test_flight("2020-03-01T10:00:00+03:00", 5000).flight_key();
+        let upcoming = test_flight("2030-03-01T10:00:00+03:00", 5000).flight_key();
+        for (message_id, flight_key) in [("1", &departed), ("2", &upcoming)] {
+            bot_state.flight_messages.insert(message_id.to_string(), flight_key.clone());
+            bot_state.acknowledged_flights.insert(flight_key.clone());
+        }
+        
+        bot_state.prune_sent_messages(&DisplaySettings::default(), chrono::Duration::hours(48));
+        assert_eq!(bot_state.flight_messages, HashMap::from([("2".to_string(), upcoming.clone())]));
+        assert_eq!(bot_state.acknowledged_flights, HashSet::from([upcoming]));
+    }
+
+    #[tokio::test]
+    async fn airlabs_response_is_matched_to_the_travelpayouts_departure() {
+        let api = mock_api();
+        let http = test_http(Client::new());
+        // Several daily frequencies share the flight number; seats tell them apart
+        api.script("airlabs-match", vec![airlabs_reply(&[
+            ("2030-03-01 06:05", Some(10)),
+            ("2030-03-01 14:20", Some(30)),
+            ("2030-03-01 10:15", Some(20)),
+        ])]);
+        
+        for (departure_at, seats) in [
+            ("2030-03-01T10:00:00+03:00", Some(20)),
+            ("2030-03-01T14:00:00+03:00", Some(30)),
+            ("2030-03-01T06:00:00+03:00", Some(10)),
+        ] {
+            let flight = get_airlabs_flight_info(&http, &api.base, "SU", "1234", Some(departure_at), "airlabs-match", 0).await.unwrap();
+            assert_eq!(flight.and_then(|f| f.seats_economy), seats, "{}", departure_at);
+        }
+        
+        // Nothing departs within two hours of 22:00
+        let flight = get_airlabs_flight_info(&http, &api.base, "SU", "1234", Some("2030-03-01T22:00:00+03:00"), "airlabs-match", 0).await.unwrap();
+        assert!(flight.is_none());
+        assert_eq!(api.requests("airlabs-match")[0].query["flight_iata"], "SU1234");
+    }
+
+    #[tokio::test]
+    async fn summary_only_sends_one_ranked_message_per_cycle() {
+        let config = test_config(&[("SUMMARY_ONLY", "true"), ("END_DATE", "2030-03-03"), ("SEARCH_CONCURRENCY", "3")]);
+        let fares = |date: &str, prices: &[i64]| -> Vec<FlightResult> {
+            prices.iter().enumerate().map(|(i, price)| test_flight(&format!("{}T{:02}:00:00+03:00", date, 8 + 2 * i), *price)).collect()
+        };
+        let deps = test_deps(MockSearcher::new(HashMap::from([
+            ("2030-03-01".to_string(), fares("2030-03-01", &[7000, 5200])),
+            ("2030-03-02".to_string(), fares("2030-03-02", &[4800, 6100, 9000])),
+            ("2030-03-03".to_string(), fares("2030-03-03", &[5500, 8000])),
+        ])));
+        let mut state = test_state();
+        let summaries = |notifier: &MockNotifier| -> Vec<String> {
+            notifier.messages().into_iter().filter(|m| m.contains("Самые дешёвые билеты")).collect()
+        };
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let summary = summaries(&deps.notifier);
+        assert_eq!(summary.len(), 1, "{:?}", deps.notifier.messages());
+        assert_eq!(flight_messages(&deps.notifier), 0, "per-date notifications are suppressed");
+        assert!(!deps.notifier.messages().iter().any(|m| m.contains("✅ Найдено")));
+        
+        // Ranked across the window and capped, with the dates shown
+        let ranked: Vec<&str> = summary[0].lines().filter(|line| line.starts_with(char::is_numeric)).collect();
+        assert_eq!(ranked.len(), MAX_FLIGHTS_PER_DATE);
+        assert!(ranked[0].starts_with("1. Москва → Сочи: 02.03 4 800 ₽"), "{}", ranked[0]);
+        assert!(ranked[1].starts_with("2. Москва → Сочи: 01.03 5 200 ₽"), "{}", ranked[1]);
+        assert!(ranked[4].starts_with("5. Москва → Сочи: 01.03 7 000 ₽"), "{}", ranked[4]);
+        assert!(summary[0].ends_with("... и еще 2 варианта"), "{}", summary[0]);
+        
+        // The next cycle sends its own single summary
+        deps.searcher.set_fares("2030-03-03", fares("2030-03-03", &[4500, 8000]));
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let summary = summaries(&deps.notifier);
+        assert_eq!(summary.len(), 2);
+        assert!(summary[1].contains("1. Москва → Сочи: 03.03 4 500 ₽"), "{}", summary[1]);
+    }
+
+    #[tokio::test]
+    async fn edit_rejected_for_age_falls_back_to_a_new_message() {
+        let api = mock_api();
+        let not_editable = || MockReply::json(400, json!({
+            "ok": false,
+            "error_code": 400,
+            "description": "Bad Request: message can't be edited"
+        }));
+        api.script("edit-token", vec![not_editable(), MockReply::json(200, json!({"ok": true, "result": {"message_id": 77}}))]);
+        let notifier = test_telegram();
+        let mut status = StatusMessage::default();
+        status.posted("41".to_string());
+        
+        update_status_message(&notifier, "edit-token", "-100", "", &mut status, "Статус", chrono::Duration::hours(47)).await.unwrap();
+        
+        // One rejected edit, no retries, then a new message whose id replaces the old one
+        let paths: Vec<String> = api.requests("edit-token").iter().map(|r| r.path.clone()).collect();
+        assert_eq!(paths, ["/botedit-token/editMessageText", "/botedit-token/sendMessage"]);
+        assert_eq!(status.id.as_deref(), Some("77"));
+        
+        // The rejection surfaces as its own error rather than a generic failure
+        api.script("edit-token", vec![not_editable()]);
+        let e = update_telegram_message(&test_telegram(), "edit-token", "-100", "77", "Статус", "").await.unwrap_err();
+        assert!(matches!(e.downcast_ref::<BotError>(), Some(BotError::MessageNotEditable(id)) if id == "77"), "{}", e);
+        assert_eq!(api.requests("edit-token").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn bidirectional_searches_and_counts_the_reverse_route() {
+        let config = test_config(&[("BIDIRECTIONAL", "true"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([
+            (route_date_key("MOW", "AER", "2030-03-01"), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)]),
+            (route_date_key("AER", "MOW", "2030-03-01"), vec![test_flight("2030-03-01T18:00:00+03:00", 6000)]),
+        ])));
+        let stats = SearchStatistics::new();
+        
+        run_cycle(&deps, &config, &mut test_state(), &stats).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let mut searches = deps.searcher.searches.lock().unwrap().clone();
+        searches.sort();
+        assert_eq!(searches, ["AER-MOW rub", "MOW-AER rub"]);
+        
+        let messages = deps.notifier.messages();
+        assert_eq!(messages.iter().filter(|m| m.contains("➡️ <b>Туда</b>")).count(), 1, "{:?}", messages);
+        assert_eq!(messages.iter().filter(|m| m.contains("⬅️ <b>Обратно</b>")).count(), 1, "{:?}", messages);
+        
+        // Both directions are found, and the summary tells them apart
+        assert_eq!(stats.snapshot().return_searches, 1);
+        assert_eq!(stats.snapshot().return_flights_found, 1);
+        assert!(stats.snapshot().format_summary(&config.display, 10).contains("↔️ Туда: 1 / обратно: 1"));
+        
+        // Without BIDIRECTIONAL only the configured direction is searched
+        assert_eq!(test_config(&[]).routes().len(), 1);
+    }
+
+    #[test]
+    fn summary_links_only_the_cheapest_dates_in_date_order() {
+        let display = DisplaySettings::default();
+        let summary_date = |date: &str, message_id: &str, price: i64| FlightDateSummary {
+            date: date.to_string(),
+            message_id: message_id.to_string(),
+            price,
+            currency: "rub".to_string(),
+            base_price: Some(price as f64),
+        };
+        let snapshot = StatisticsSnapshot {
+            flight_dates: vec![
+                summary_date("1 марта", "100/1", 9000),
+                summary_date("2 марта", "100/2", 4000),
+                summary_date("3 марта", "100/3", 7000),
+                summary_date("4 марта", "100/4", 3000),
+            ],
+            ..StatisticsSnapshot::default()
+        };
+        
+        let summary = snapshot.format_summary(&display, 2);
+        let second = summary.find("\">2 марта</a> — 4 000 ₽").expect(&summary);
+        let fourth = summary.find("\">4 марта</a> — 3 000 ₽").expect(&summary);
+        assert!(second < fourth, "{}", summary);
+        assert!(summary.contains("<a href=\"https://t.me/c/100/2\">"), "{}", summary);
+        assert!(!summary.contains("1 марта") && !summary.contains("3 марта"), "{}", summary);
+        assert!(summary.ends_with("... и ещё 2 даты\n"), "{}", summary);
+        
+        // A cap above the number of dates lists them all without a remainder line
+        assert!(!snapshot.format_summary(&display, 10).contains("... и ещё"));
+        
+        assert_eq!(test_config(&[]).max_summary_dates, 20);
+        assert_eq!(test_config(&[("MAX_SUMMARY_DATES", "5")]).max_summary_dates, 5);
+    }
+
+    #[test]
+    fn zero_and_negative_prices_are_skipped() {
+        // Well-formed response: the typed parse keeps only the positive fare
+        let response = json!({
+            "success": true,
+            "currency": "rub",
+            "data": [
+                test_flight("2030-03-01T08:00:00+03:00", 0),
+                test_flight("2030-03-01T10:00:00+03:00", 5000),
+                test_flight("2030-03-01T12:00:00+03:00", -1),
+            ]
+        });
+        let flights = parse_flight_data(&response.to_string()).unwrap().data.unwrap();
+        assert_eq!(flights.iter().map(|flight| flight.price).collect::<Vec<_>>(), [5000]);
+        
+        // The best-effort parse of a changed schema skips them the same way
+        let fare = |price: i64, flight_number: i64| json!({
+            "origin": "MOW", "destination": "AER", "price": price, "airline": "SU", "flight_number": flight_number,
+            "departure_at": "2030-03-01T10:00:00+03:00", "transfers": 0, "link": "/search/1"
+        });
+        let response = json!({"success": true, "currency": "rub", "data": [fare(0, 1), fare(4000, 2), fare(-100, 3)]});
+        let flight_data = parse_flight_data(&response.to_string()).unwrap();
+        assert!(flight_data.parse_warning.is_some());
+        let flights = flight_data.data.unwrap();
+        assert_eq!(flights.iter().map(|flight| flight.price).collect::<Vec<_>>(), [4000]);
+    }
+
+    #[tokio::test]
+    async fn wait_between_cycles_ends_early_when_the_config_file_changes() {
+        let path = temp_path("wait.env");
+        let start = std::time::SystemTime::now();
+        write_config_file(&path, "AER", "2030-03-01", start);
+        let config = test_config(&[("WAIT_CHECKPOINT_SECS", "1")]);
+        let config_modified = config_file_modified(&path);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        let (_commands, mut receiver) = mpsc::unbounded_channel();
+        
+        // An unchanged file lets a short wait run out
+        let outcome = wait_for_next_cycle(&deps, &config, &mut state, &mut receiver, Duration::from_millis(50), &path, config_modified).await;
+        assert_eq!(outcome, WaitOutcome::Elapsed);
+        
+        // An edit is noticed at the next checkpoint instead of after the hour
+        let edited = path.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(100)).await;
+            write_config_file(&edited, "LED", "2030-03-01", start + Duration::from_secs(60));
+        });
+        let waited = time::Instant::now();
+        let outcome = wait_for_next_cycle(&deps, &config, &mut state, &mut receiver, Duration::from_secs(3600), &path, config_modified).await;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(outcome, WaitOutcome::ConfigChanged);
+        assert!(waited.elapsed() < Duration::from_secs(5), "{:?}", waited.elapsed());
+        
+        assert_eq!(test_config(&[]).wait_checkpoint, Duration::from_secs(30));
+        assert_eq!(test_config(&[("WAIT_CHECKPOINT_SECS", "0")]).wait_checkpoint, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn duplicate_fares_collapse_to_the_lowest_price() {
+        let morning = |price: i64| test_flight("2030-03-01T10:00:00+03:00", price);
+        let evening = test_flight("2030-03-01T19:00:00+03:00", 4500);
+        let mut next_day = morning(4000);
+        next_day.departure_at = "2030-03-02T10:00:00+03:00".to_string();
+        
+        // The cheaper copy takes the place where the flight was first seen
+        let mut flights = vec![morning(6000), evening, morning(5000), next_day, morning(5500)];
+        dedup_flights(&mut flights);
+        let fares: Vec<(&str, i64)> = flights.iter().map(|f| (f.departure_at.as_str(), f.price)).collect();
+        assert_eq!(fares, [
+            ("2030-03-01T10:00:00+03:00", 5000),
+            ("2030-03-01T19:00:00+03:00", 4500),
+            ("2030-03-02T10:00:00+03:00", 4000),
+        ]);
+        
+        // Merging another source's results goes through the same rule
+        let mut merged = flight_data(vec![morning(5000)]);
+        merge_flight_results(&mut merged, flight_data(vec![morning(4800), morning(5200)]));
+        let prices: Vec<i64> = merged.data.unwrap().iter().map(|f| f.price).collect();
+        assert_eq!(prices, [4800]);
+    }
+
+    #[test]
+    fn verbosity_presets_set_this_crate_level_and_keep_dependencies_at_warn() {
+        assert_eq!(Verbosity::from_env_value(" Quiet "), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_env_value("warn"), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_env_value("debug"), Verbosity::Debug);
+        assert_eq!(Verbosity::from_env_value("TRACE"), Verbosity::Trace);
+        assert_eq!(Verbosity::from_env_value(""), Verbosity::Normal);
+        assert_eq!(Verbosity::from_env_value("loud"), Verbosity::Normal);
+        
+        assert_eq!(Verbosity::Quiet.filter_directive(), "warn,flights_schedule=warn");
+        assert_eq!(Verbosity::Debug.filter_directive(), "warn,flights_schedule=debug");
+        
+        let preset = |verbosity| log_filter(&log_source(""), verbosity).unwrap();
+        assert!(with_log_filter(preset(Verbosity::Quiet), || tracing::enabled!(target: "flights_schedule", Level::WARN)));
+        assert!(!with_log_filter(preset(Verbosity::Quiet), || tracing::enabled!(target: "flights_schedule", Level::INFO)));
+        assert!(with_log_filter(preset(Verbosity::Normal), || tracing::enabled!(target: "flights_schedule", Level::INFO)));
+        assert!(!with_log_filter(preset(Verbosity::Normal), || tracing::enabled!(target: "flights_schedule", Level::DEBUG)));
+        
+        // Raw responses are only printed at trace, and dependencies never get chattier than warn
+        assert!(with_log_filter(preset(Verbosity::Trace), || tracing::enabled!(target: "flights_schedule::api", Level::TRACE)));
+        assert!(!with_log_filter(preset(Verbosity::Trace), || tracing::enabled!(target: "reqwest::connect", Level::INFO)));
+        assert!(!with_log_filter(preset(Verbosity::Debug), || tracing::enabled!(target: "flights_schedule", Level::TRACE)));
+    }
+
+    #[tokio::test]
+    async fn timed_out_request_is_retried_but_an_http_error_is_returned() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v3/prices", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            // The first connection never gets an answer, the retry gets a server error
+            let (silent, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let (mut answered, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let mut request = [0; 1024];
+            let _ = answered.read(&mut request).await;
+            answered.write_all(b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await.unwrap();
+            time::sleep(Duration::from_secs(30)).await;
+            drop(silent);
+        });
+        
+        let client = Client::builder().timeout(Duration::from_millis(300)).build().unwrap();
+        let response = send_retrying_network_errors(|| client.get(&url), "Test", 3).await.unwrap();
+        
+        // One retry after the timeout; the 500 is left to the caller instead of being retried here
+        assert_eq!(response.status().as_u16(), 500);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fare_at_the_target_price_mentions_the_user() {
+        let config = test_config(&[("TARGET_PRICES", "aer:6000"), ("MENTION_USER", "@fare_watcher"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)])])));
+        
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let messages = deps.notifier.messages();
+        assert_eq!(
+            messages.iter().filter(|m| m.starts_with("🎯 @fare_watcher, цена 5 000 ₽ не выше цели 6 000 ₽!\n")).count(),
+            1,
+            "{:?}",
+            messages
+        );
+        
+        // A fare above the target, or no target at all, says nothing
+        let route = &config.routes()[0];
+        assert!(format_target_hit(&config.display, route, 6001, "rub", None).is_none());
+        assert!(format_target_hit(&config.display, &test_config(&[]).routes()[0], 1, "rub", None).is_none());
+        assert_eq!(format_target_hit(&config.display, route, 6000, "rub", None).unwrap(), "🎯 цена 6 000 ₽ не выше цели 6 000 ₽!\n");
+    }
+
+    #[test]
+    fn mention_user_accepts_usernames_and_user_ids() {
+        assert_eq!(parse_mention("").unwrap(), None);
+        assert_eq!(parse_mention(" @fare_watcher ").unwrap(), Some(Mention::Username("fare_watcher".to_string())));
+        assert_eq!(parse_mention("fare_watcher").unwrap(), Some(Mention::Username("fare_watcher".to_string())));
+        assert_eq!(parse_mention("123456789").unwrap().unwrap().html(), "<a href=\"tg://user?id=123456789\">123456789</a>");
+        
+        for invalid in ["@abc", "@1watcher", "@fare-watcher"] {
+            assert!(matches!(parse_mention(invalid), Err(BotError::Config(_))), "{}", invalid);
+        }
+        assert!(parse_target_prices("AER:0").is_err());
+        assert!(parse_target_prices("AER 8000").is_err());
+    }
+
+    #[test]
+    fn fare_seats_and_cabin_estimate_are_labeled_apart() {
+        assert_eq!(format_seats(None, Some(120)), "💺 Мест в салоне (оценка): 120\n");
+        
+        // With both numbers a note explains why they disagree
+        let both = format_seats(Some(3), Some(120));
+        assert!(both.starts_with("💺 Мест по тарифу: 3 · мест в салоне (оценка): 120\n"), "{}", both);
+        assert!(both.contains("примерная вместимость эконом-класса по данным AirLabs"), "{}", both);
+        
+        // A zero fare count is noise, but the cabin estimate still shows
+        assert_eq!(format_seats(Some(0), Some(120)), "💺 Мест в салоне (оценка): 120\n");
+    }
+
+    #[tokio::test]
+    async fn long_message_chunks_arrive_in_order_as_replies_to_the_first() {
+        let api = mock_api();
+        let message: String = (0..300).map(|i| format!("Строка {:03}: Москва → Сочи\n", i)).collect();
+        let chunks = split_message(&message, TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() >= 3);
+        api.script("chunks-token", (0..chunks.len() as i64).map(|i| telegram_ok(500 + i)).collect());
+        let keyboard = json!({"inline_keyboard": [[{"text": "Купить", "url": "https://example.com"}]]});
+        
+        let message_id = send_telegram_notification_with_id(&test_telegram(), "chunks-token", "-100", &message, "", Some(keyboard.clone()))
+            .await
+            .unwrap();
+        assert_eq!(message_id, "500");
+        
+        let bodies: Vec<serde_json::Value> = api.requests("chunks-token").iter().map(|r| serde_json::from_str(&r.body).unwrap()).collect();
+        let texts: Vec<&str> = bodies.iter().map(|body| body["text"].as_str().unwrap()).collect();
+        assert_eq!(texts, chunks.iter().map(String::as_str).collect::<Vec<_>>());
+        
+        // Later chunks reply to the first one, and only the last carries the keyboard
+        assert!(bodies[0].get("reply_to_message_id").is_none());
+        assert!(bodies[1..].iter().all(|body| body["reply_to_message_id"] == "500"));
+        let with_keyboard: Vec<bool> = bodies.iter().map(|body| body.get("reply_markup").is_some()).collect();
+        assert_eq!(with_keyboard.iter().filter(|has| **has).count(), 1);
+        assert_eq!(bodies.last().unwrap()["reply_markup"], keyboard);
+    }
+
+    #[tokio::test]
+    async fn first_run_records_fares_silently_and_the_next_cycle_notifies_new_finds() {
+        let config = test_config(&[("SUPPRESS_FIRST_RUN", "true")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([
+            ("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)]),
+            ("2030-03-02".to_string(), Vec::new()),
+        ])));
+        let mut state = test_state();
+        state.first_run = true;
+        
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let messages = deps.notifier.messages();
+        assert_eq!(flight_messages(&deps.notifier), 0, "{:?}", messages);
+        assert_eq!(messages.iter().filter(|m| m.contains("Запомнено 1 рейс на 1 дату")).count(), 1, "{:?}", messages);
+        assert!(!state.first_run);
+        
+        // The recorded fare stays quiet, a fare on another date is news
+        deps.searcher.set_fares("2030-03-02", vec![test_flight("2030-03-02T10:00:00+03:00", 6000)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        let messages = deps.notifier.messages();
+        assert_eq!(flight_messages(&deps.notifier), 1, "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("Рейс SU 1234") && m.contains("2 марта 2030")), "{:?}", messages);
+        assert_eq!(messages.iter().filter(|m| m.contains("Инициализация завершена")).count(), 1);
+    }
+
+    #[test]
+    fn flight_designator_prefixes_the_airline_code_once() {
+        assert_eq!(format_flight_designator("DP", "123"), "DP 123");
+        assert_eq!(format_flight_designator(" DP ", " 123 "), "DP 123");
+        
+        // Numbers that already carry the airline code aren't prefixed again
+        assert_eq!(format_flight_designator("DP", "DP123"), "DP 123");
+        assert_eq!(format_flight_designator("DP", "dp-123"), "DP 123");
+        assert_eq!(format_flight_designator("SU", "S7123"), "SU S7123");
+        
+        // Whatever part is known is shown on its own
+        assert_eq!(format_flight_designator("", "123"), "123");
+        assert_eq!(format_flight_designator("DP", ""), "DP");
+        
+        // The same flight written either way is one flight
+        let mut prefixed = test_flight("2030-03-01T10:00:00+03:00", 5000);
+        prefixed.flight_number = "SU1234".to_string();
+        assert_eq!(prefixed.flight_key(), test_flight("2030-03-01T10:00:00+03:00", 5000).flight_key());
+    }
+
+    #[tokio::test]
+    async fn http_client_reuses_connections_and_times_out_hung_requests() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        let source = |timeout: &str| ConfigSource {
+            file_values: HashMap::from([("HTTP_TIMEOUT_SECS".to_string(), timeout.to_string())]),
+        };
+        assert!(build_http_client(&source("0")).is_err());
+        let client = build_http_client(&source("1")).unwrap();
+        
+        // Keep-alive server answering every request on a connection, counting the connections
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    while stream.read(&mut request).await.is_ok_and(|read| read > 0) {
+                        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").await.unwrap();
+                    }
+                });
+            }
+        });
+        for _ in 0..3 {
+            assert_eq!(client.get(&url).send().await.unwrap().text().await.unwrap(), "ok");
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        
+        // A server that accepts but never answers is given up on after HTTP_TIMEOUT_SECS
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_url = format!("http://{}/", silent.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _held = silent.accept().await;
+            time::sleep(Duration::from_secs(30)).await;
+        });
+        let started = time::Instant::now();
+        let e = client.get(&silent_url).send().await.unwrap_err();
+        assert!(e.is_timeout(), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn dates_are_searched_three_at_once_and_notified_in_date_order() {
+        let config = test_config(&[("END_DATE", "2030-03-06")]);
+        assert_eq!(config.search_concurrency.total(), 3);
+        
+        let fares: HashMap<String, Vec<FlightResult>> = (1..=6)
+            .map(|day| {
+                let date = format!("2030-03-{:02}", day);
+                let flight = test_flight(&format!("{}T10:00:00+03:00", date), 5000);
+                (date, vec![flight])
+            })
+            .collect();
+        let deps = test_deps(MockSearcher::new(fares));
+        *deps.searcher.search_time.lock().unwrap() = Duration::from_millis(200);
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        assert_eq!(deps.searcher.peak_in_flight.load(Ordering::SeqCst), 3);
+        
+        // Whatever order the searches finish in, the flights are posted date by date
+        let days: Vec<usize> = deps.notifier.messages()
+            .iter()
+            .filter(|m| m.contains("Рейс SU 1234"))
+            .filter_map(|m| (1..=6).find(|day| m.contains(&format!("{} марта 2030 в 12:00", day))))
+            .collect();
+        assert_eq!(days, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn price_drop_only_notifies_when_the_stored_minimum_is_beaten() {
+        let config = test_config(&[("PRICE_DROP_ONLY", "true"), ("END_DATE", "2030-03-01")]);
+        let deps = test_deps(MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![test_flight("2030-03-01T10:00:00+03:00", 5000)])])));
+        let mut state = test_state();
+        let key = route_date_key("MOW", "AER", "2030-03-01");
+        
+        // The first price is only the baseline, and a new but dearer fare doesn't beat it
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        deps.searcher.set_fares("2030-03-01", vec![test_flight("2030-03-01T14:00:00+03:00", 5200)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        assert_eq!(flight_messages(&deps.notifier), 0, "{:?}", deps.notifier.messages());
+        assert_eq!(state.bot_state.price_history.date_low(&key), Some(5000));
+        
+        deps.searcher.set_fares("2030-03-01", vec![test_flight("2030-03-01T18:00:00+03:00", 4000)]);
+        run_cycle(&deps, &config, &mut state, &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        
+        let messages = deps.notifier.messages();
+        assert_eq!(flight_messages(&deps.notifier), 1, "{:?}", messages);
+        assert!(messages.iter().any(|m| m.contains("📉 Цена снизилась: было 5 000 ₽, стало 4 000 ₽, −1 000 ₽")), "{:?}", messages);
+        assert_eq!(state.bot_state.price_history.date_low(&key), Some(4000));
+    }
+
+    #[tokio::test]
+    async fn persistently_rate_limited_token_gives_up_after_the_retry_cap() {
+        let api = mock_api();
+        let rate_limited = || {
+            let mut reply = MockReply::json(429, json!({"error": "Too many requests"}));
+            reply.headers.push(("Retry-After", "0".to_string()));
+            reply
+        };
+        api.script("stuck-key", (0..10).map(|_| rate_limited()).collect());
+        
+        // Rate limits have their own cap, independent of NETWORK_MAX_RETRIES
+        let mut searcher = test_travelpayouts(Client::new());
+        searcher.http.network_retries = 0;
+        let paging = SearchPaging { limit: 30, max_results: 30 };
+        let e = search_flights(&searcher, "MOW", "AER", "2030-03-01", &ApiKeyPool::new("stuck-key"), true, paging, "rub")
+            .await
+            .unwrap_err();
+        
+        // The first attempt and TRAVELPAYOUTS_MAX_RETRIES retries, then an error
+        assert!(matches!(e.downcast_ref::<BotError>(), Some(BotError::RateLimited(4))), "{}", e);
+        assert_eq!(api.requests("stuck-key").len(), 4);
+    }
+
+
+    #[tokio::test]
+    async fn ordered_tasks_yield_outputs_in_spawn_order() {
+        let mut tasks = OrderedTasks::new();
+        for (i, millis) in [30, 10, 20].into_iter().enumerate() {
+            tasks.spawn(async move {
+                time::sleep(Duration::from_millis(millis)).await;
+                i
+            });
+        }
+        tasks.spawn(async { panic!("search task panicked") });
+        
+        // Tasks finishing out of order still come back in spawn order, a panic in its place
+        for i in 0..3 {
+            assert_eq!(tasks.next().await.unwrap().unwrap(), i);
+        }
+        assert!(tasks.next().await.unwrap().unwrap_err().is_panic());
+        assert!(tasks.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn search_delay_holds_the_search_slot() {
+        let config = test_config(&[("SEARCH_CONCURRENCY", "1"), ("SEARCH_DELAY_MS", "100"), ("END_DATE", "2030-03-03")]);
+        assert_eq!(config.search_delay, Duration::from_millis(100));
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        
+        // With one slot, each of the three searches waits out the delay of the one before
+        let started = time::Instant::now();
+        run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+        let _ = std::fs::remove_file(&deps.state_store.path);
+        assert!(started.elapsed() >= Duration::from_millis(300), "{:?}", started.elapsed());
+        assert_eq!(deps.searcher.dates.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn searchers_with_different_bases_and_retries_coexist() {
+        let api = mock_api();
+        let rate_limited = || {
+            let mut reply = MockReply::json(429, json!({"error": "Too many requests"}));
+            reply.headers.push(("Retry-After", "0".to_string()));
+            reply
+        };
+        api.script("coexist-key", (0..10).map(|_| rate_limited()).collect());
+        
+        // Each searcher carries its own base and retry limit, so neither affects the other
+        let patient = test_travelpayouts(Client::new());
+        let mut offline = test_travelpayouts(Client::new());
+        offline.api_base = "http://127.0.0.1:1".to_string();
+        offline.http.network_retries = 0;
+        
+        let paging = SearchPaging { limit: 30, max_results: 30 };
+        let e = search_flights(&patient, "MOW", "AER", "2030-03-01", &ApiKeyPool::new("coexist-key"), true, paging, "rub")
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref::<BotError>(), Some(BotError::RateLimited(4))), "{}", e);
+        assert!(search_flights(&offline, "MOW", "AER", "2030-03-01", &ApiKeyPool::new("coexist-key"), true, paging, "rub").await.is_err());
+        assert_eq!(api.requests("coexist-key").len(), 4);
+    }
+
+    #[test]
+    fn display_settings_are_read_per_config() {
+        let moscow = test_config(&[("DISPLAY_TIMEZONE", "Europe/Moscow"), ("LOCALE", "en")]);
+        let omsk = test_config(&[("DISPLAY_UTC_OFFSET_HOURS", "6"), ("SOURCE_TIMEZONE", "Asia/Yekaterinburg")]);
+        
+        // Each config formats with its own zones and locale, whichever was loaded first
+        assert_eq!(format_datetime_ru(&moscow.display, "2030-03-01T10:00:00"), "1 марта 2030 в 10:00");
+        assert_eq!(format_datetime_ru(&omsk.display, "2030-03-01T10:00:00"), "1 марта 2030 в 11:00");
+        assert_eq!(format_price(&moscow.display, 15230), "15,230 ₽");
+        assert_eq!(format_price(&omsk.display, 15230), "15 230 ₽");
+        
+        assert!(Config::from_source(&test_source(&[("DISPLAY_TIMEZONE", "Mars/Olympus")])).is_err());
+        assert!(Config::from_source(&test_source(&[("SOURCE_TIMEZONE", "nowhere")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn fares_enter_the_history_only_once_a_message_showing_them_was_sent() {
+        for mode in [("GROUP_BY", ""), ("GROUP_BY", "airline"), ("COMPACT", "true")] {
+            let config = test_config(&[("END_DATE", "2030-03-01"), mode]);
+            let flight = test_flight("2030-03-01T10:00:00+03:00", 5000);
+            let searcher = MockSearcher::new(HashMap::from([("2030-03-01".to_string(), vec![flight.clone()])]));
+            let mut deps = test_deps(searcher);
+            deps.fare_history = Some(FareHistory::open(":memory:").unwrap());
+            let fare_messages = |notifier: &MockNotifier| {
+                notifier.messages().iter().filter(|m| m.contains("✅ Найдено") || m.contains("SU 1234")).count()
+            };
+            
+            // A failed send leaves the fare out of the history
+            *deps.notifier.failing.lock().unwrap() = Some("SU 1234");
+            run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+            assert!(deps.fare_history.as_ref().unwrap().is_new(&flight), "{:?}", mode);
+            
+            // Once sent the fare is known, so even without cooldowns it isn't notified again
+            *deps.notifier.failing.lock().unwrap() = None;
+            run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+            assert!(!deps.fare_history.as_ref().unwrap().is_new(&flight), "{:?}", mode);
+            let sent = fare_messages(&deps.notifier);
+            run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+            assert_eq!(fare_messages(&deps.notifier), sent, "{:?}", mode);
+            
+            // The same flight at a new price is a new fare
+            deps.searcher.set_fares("2030-03-01", vec![test_flight("2030-03-01T10:00:00+03:00", 4500)]);
+            run_cycle(&deps, &config, &mut test_state(), &SearchStatistics::new()).await.unwrap();
+            assert!(fare_messages(&deps.notifier) > sent, "{:?}", mode);
+        }
+    }
+
+
+    #[test]
+    fn field_coverage_compares_each_fare_with_its_own_parse() {
+        // A zero-price fare in the middle is dropped; the fares after it must still line up
+        let fares = |transfers: serde_json::Value| json!({
+            "success": true,
+            "currency": "rub",
+            "data": [
+                {"origin": "MOW", "destination": "AER", "origin_airport": "SVO", "destination_airport": "AER", "price": 4200, "airline": "SU", "flight_number": "1234",
+                 "departure_at": "2030-03-01T10:00:00+03:00", "transfers": 0, "duration": 150, "link": "/a"},
+                {"origin": "MOW", "destination": "AER", "origin_airport": "SVO", "destination_airport": "AER", "price": 0, "airline": "SU", "flight_number": "1235",
+                 "departure_at": "2030-03-01T10:30:00+03:00", "transfers": 0, "link": "/b"},
+                {"origin": "MOW", "destination": "AER", "origin_airport": "SVO", "destination_airport": "AER", "price": 4500, "airline": "DP", "flight_number": "401",
+                 "departure_at": "2030-03-01T11:00:00+03:00", "transfers": transfers, "duration": 160, "seats": 3, "link": "/c"}
+            ]
+        }).to_string();
+        
+        for (sample, path) in [(fares(json!(0)), "direct"), (fares(json!("0")), "manual fallback")] {
+            let report = response_field_coverage(&sample).unwrap();
+            assert!(report.starts_with(&format!("Parse path: {}", path)), "{}", report);
+            assert!(report.contains("fares: 2\ndropped fares: 1\n"), "{}", report);
+            for line in [
+                "airline              populated   2, mismatched   0, defaulted   0",
+                "duration             populated   2, mismatched   0, defaulted   0",
+                "seats                populated   1, mismatched   0, defaulted   1",
+                "flight_number        populated   2, mismatched   0, defaulted   0",
+            ] {
+                assert!(report.lines().any(|l| l == line), "{}\n{}", line, report);
             }
         }
+    }
+
+
+    #[test]
+    fn optional_settings_are_unset_by_invalid_values() {
+        assert_eq!(test_config(&[("MAX_CYCLES", "10")]).max_cycles, Some(10));
+        for invalid in ["ten", " "] {
+            assert_eq!(test_config(&[("MAX_CYCLES", invalid)]).max_cycles, None, "{:?}", invalid);
+        }
+        
+        assert_eq!(test_config(&[("PRICE_NOISE_THRESHOLD", "50")]).price_noise_threshold, Some(50.0));
+        assert_eq!(test_config(&[("PRICE_NOISE_THRESHOLD", "50 rub")]).price_noise_threshold, None);
+        
+        assert_eq!(test_config(&[("AIRLABS_MAX_PER_CYCLE", "5")]).airlabs_max_per_cycle, Some(5));
+        assert_eq!(test_config(&[("AIRLABS_MAX_PER_CYCLE", "-1")]).airlabs_max_per_cycle, None);
+        
+        assert_eq!(test_config(&[("ALERT_PERCENTILE", "10.5")]).alert_percentile, Some(10.5));
+        assert_eq!(test_config(&[("ALERT_PERCENTILE", "10%")]).alert_percentile, None);
+        
+        assert_eq!(test_config(&[("MIN_FARE_SEATS", "3")]).min_fare_seats, Some(3));
+        assert_eq!(test_config(&[("MIN_FARE_SEATS", "3 seats")]).min_fare_seats, None);
+        
+        assert_eq!(test_config(&[("MIN_ECONOMY_SEATS", "2")]).min_economy_seats, Some(2));
+        assert_eq!(test_config(&[("MIN_ECONOMY_SEATS", "two")]).min_economy_seats, None);
+    }
+
+
+    #[tokio::test]
+    async fn header_too_long_for_a_caption_is_sent_as_text_without_a_chart() {
+        let config = test_config(&[("SEND_PRICE_CHART", "true")]);
+        let deps = test_deps(MockSearcher::new(HashMap::new()));
+        let mut state = test_state();
+        let cooldown_key = route_date_key("MOW", "AER", "2030-03-01");
+        let now = Utc::now();
+        state.bot_state.price_history.entries.insert(cooldown_key.clone(), [5200, 4900, 5100]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PricePoint { observed_at: now + chrono::Duration::hours(i as i64), price: *price })
+            .collect());
+        let route = Route { origin: "MOW".to_string(), destination: "AER".to_string(), currency: None, target_price: None };
+        let date = DateSearch {
+            route: &route,
+            departure_date: "2030-03-01",
+            formatted_date: "1 марта 2030",
+            currency_conversion: None,
+            exchange_rates: &HashMap::new(),
+        };
+        
+        let long_header = "✈️".repeat(TELEGRAM_CAPTION_LIMIT);
+        for (header, method, charted) in [("✅ Найдено 1 рейс", "sendPhoto", true), (long_header.as_str(), "sendMessage", false)] {
+            let mut chart_cache = HashMap::new();
+            send_found_header(&deps, &config, &state, &mut chart_cache, &date, &cooldown_key, header).await.unwrap();
+            let calls = std::mem::take(&mut *deps.notifier.calls.lock().unwrap());
+            assert_eq!(calls.len(), 1, "{:?}", calls);
+            assert_eq!((calls[0].0, calls[0].2.as_str()), (method, header));
+            // A header that can't be a caption doesn't cost a chart render
+            assert_eq!(chart_cache.contains_key(&cooldown_key), charted);
+        }
+    }
+
+    #[tokio::test]
+    async fn chart_photo_is_rebuilt_and_retried_after_a_rate_limit() {
+        let api = mock_api();
+        api.script("photo-token", vec![rate_limited_reply(), telegram_ok(31)]);
+        let png = b"\x89PNG\r\n\x1a\nchart".to_vec();
+        
+        let message_id = send_telegram_photo_with_id(&test_telegram(), "photo-token", "-100", png.clone(), "<b>Найдено</b>", "3").await.unwrap();
+        assert_eq!(message_id, "31");
+        let requests = api.requests("photo-token");
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.body.contains("price_chart.png") && r.body.contains("<b>Найдено</b>")), "{:?}", requests.len());
         
-        time::sleep(check_interval).await;
+        // Telegram would reject a caption over its limit, so it isn't sent at all
+        let caption = "x".repeat(TELEGRAM_CAPTION_LIMIT + 1);
+        assert!(send_telegram_photo_with_id(&test_telegram(), "photo-long-token", "-100", png, &caption, "3").await.is_err());
+        assert!(api.requests("photo-long-token").is_empty());
     }
 }